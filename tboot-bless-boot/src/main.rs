@@ -32,6 +32,7 @@ enum Commands {
     Good,
     Bad,
     Status,
+    Next,
 }
 
 impl FromStr for Commands {
@@ -42,25 +43,50 @@ impl FromStr for Commands {
             "good" => Self::Good,
             "bad" => Self::Bad,
             "status" => Self::Status,
+            "next" => Self::Next,
             _ => return Err(Error::InvalidArgs),
         })
     }
 }
 
 #[derive(FromArgs, Debug)]
-/// Mark the boot process as good or bad
+/// Mark the boot process as good or bad, or choose the entry to boot next
 struct Args {
     /// the mount point of the ESP
     #[argh(option)]
     efi_sys_mount_point: PathBuf,
+    /// the mount point of the Extended Boot Loader partition, if there is one. Entries are
+    /// searched for on it after the ESP, like tinyboot does.
+    #[argh(option)]
+    xbootldr_mount_point: Option<PathBuf>,
     /// the action for tboot-bless-boot to take
     #[argh(positional)]
     command: Commands,
+    /// the entry to boot once on the next boot, only used by "next". The one-shot entry is cleared
+    /// when no entry is given.
+    #[argh(positional)]
+    entry: Option<String>,
 }
 
 fn main() -> Result<(), Error> {
     let args: Args = argh::from_env();
 
+    let boot_dirs: Vec<&Path> = [
+        Some(args.efi_sys_mount_point.as_path()),
+        args.xbootldr_mount_point.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if let Commands::Next = args.command {
+        return set_oneshot_entry(
+            args.efi_sys_mount_point.as_path(),
+            &boot_dirs,
+            args.entry.as_deref(),
+        );
+    }
+
     let kernel_cmdline = std::fs::read_to_string("/proc/cmdline")?;
 
     let tboot_bls_entry = kernel_cmdline
@@ -70,36 +96,52 @@ fn main() -> Result<(), Error> {
         .find_map(|cmdline_part| cmdline_part.strip_prefix("tboot.bls-entry="))
         .ok_or(Error::MissingBlsEntry)?;
 
-    let entry = find_entry(args.efi_sys_mount_point.as_path(), tboot_bls_entry)
-        .ok_or(Error::MissingEntry)?;
+    let entry = find_entry(&boot_dirs, tboot_bls_entry).ok_or(Error::MissingEntry)?;
 
     match args.command {
         Commands::Good => mark_as_good(entry)?,
         Commands::Bad => mark_as_bad(entry)?,
         Commands::Status => print_status(entry),
+        Commands::Next => unreachable!(),
     }
 
     Ok(())
 }
 
-fn find_entry(esp: impl AsRef<Path>, entry_name: &str) -> Option<(PathBuf, BlsEntryMetadata)> {
-    let entries_dir = std::fs::read_dir(esp.as_ref().join("loader/entries")).ok()?;
+/// Finds an entry on the ESP or the Extended Boot Loader partition, given by their mount points.
+fn find_entry(boot_dirs: &[&Path], entry_name: &str) -> Option<(PathBuf, BlsEntryMetadata)> {
+    // Type #1 entries live in /loader/entries, type #2 entries (UKIs) live in /EFI/Linux.
+    let dirs = boot_dirs.iter().flat_map(|boot_dir| {
+        [
+            (
+                boot_dir.join("loader/entries"),
+                tboot::bls::parse_entry_filename as fn(&str) -> _,
+            ),
+            (boot_dir.join("EFI/Linux"), tboot::bls::parse_uki_filename),
+        ]
+    });
 
-    for entry in entries_dir {
-        let Ok(entry) = entry else {
+    for (dir, parse_filename) in dirs {
+        let Ok(entries_dir) = std::fs::read_dir(dir) else {
             continue;
         };
 
-        if !entry.metadata().map(|md| md.is_file()).unwrap_or_default() {
-            continue;
-        }
+        for entry in entries_dir {
+            let Ok(entry) = entry else {
+                continue;
+            };
+
+            if !entry.metadata().map(|md| md.is_file()).unwrap_or_default() {
+                continue;
+            }
 
-        let filename = entry.file_name();
-        let filename = filename.to_str().expect("invalid UTF-8");
+            let filename = entry.file_name();
+            let filename = filename.to_str().expect("invalid UTF-8");
 
-        if let Ok(bls_entry) = tboot::bls::parse_entry_filename(filename) {
-            if bls_entry.0 == entry_name {
-                return Some((entry.path(), bls_entry));
+            if let Ok(bls_entry) = parse_filename(filename) {
+                if bls_entry.0 == entry_name {
+                    return Some((entry.path(), bls_entry));
+                }
             }
         }
     }
@@ -107,6 +149,14 @@ fn find_entry(esp: impl AsRef<Path>, entry_name: &str) -> Option<(PathBuf, BlsEn
     None
 }
 
+/// The file extension of an entry, "conf" for type #1 entries and "efi" for type #2 entries.
+fn entry_extension(entry_path: &Path) -> &str {
+    entry_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("conf")
+}
+
 fn mark_as_good(
     (entry_path, (name, tries_left, _tries_done)): (PathBuf, BlsEntryMetadata),
 ) -> Result<(), Error> {
@@ -114,7 +164,7 @@ fn mark_as_good(
         return Ok(());
     };
 
-    let new_entry_path = parent.join(format!("{}.conf", name));
+    let new_entry_path = parent.join(format!("{}.{}", name, entry_extension(&entry_path)));
     if tries_left.is_some() {
         std::fs::rename(entry_path, new_entry_path)?;
     }
@@ -129,16 +179,51 @@ fn mark_as_bad(
         return Ok(());
     };
 
+    let extension = entry_extension(&entry_path);
     let new_entry_path = parent.join(if let Some(tries_done) = tries_done {
-        format!("{}+0-{}.conf", name, tries_done)
+        format!("{}+0-{}.{}", name, tries_done, extension)
     } else {
-        format!("{}+0.conf", name)
+        format!("{}+0.{}", name, extension)
     });
     std::fs::rename(entry_path, new_entry_path)?;
 
     Ok(())
 }
 
+/// Writes the one-shot entry to the ESP, which is the only place tinyboot reads it from.
+fn set_oneshot_entry(
+    esp: &Path,
+    boot_dirs: &[&Path],
+    entry_name: Option<&str>,
+) -> Result<(), Error> {
+    let oneshot_entry_path = esp.join(tboot::bls::ONESHOT_ENTRY_PATH);
+
+    let Some(entry_name) = entry_name else {
+        return match std::fs::remove_file(oneshot_entry_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    };
+
+    let entry_name = entry_name
+        .strip_suffix(".conf")
+        .or_else(|| entry_name.strip_suffix(".efi"))
+        .unwrap_or(entry_name);
+
+    let (entry_path, _) = find_entry(boot_dirs, entry_name).ok_or(Error::MissingEntry)?;
+
+    if let Some(parent) = oneshot_entry_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    std::fs::write(
+        oneshot_entry_path,
+        format!("{}.{}\n", entry_name, entry_extension(&entry_path)),
+    )?;
+
+    Ok(())
+}
+
 fn print_status((entry_path, (_name, tries_left, _tries_done)): (PathBuf, BlsEntryMetadata)) {
     println!("{}:", entry_path.display());
 
@@ -0,0 +1,736 @@
+use log::{debug, error, info, warn};
+use sha2::{Digest, Sha512};
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tboot::dev::DiskEvent;
+
+use super::{
+    disk::{block_devices, changed_disks, probe_in_parallel, BlockDevice},
+    fit::Fit,
+    BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout,
+};
+use crate::{fdt, keys};
+
+const CHROMEOS_EXTRACT_PATH: &str = "/run/tboot/chromeos";
+
+const CHROMEOS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the ChromeOS attributes live in the attribute field of a GPT partition entry.
+/// https://chromium.googlesource.com/chromiumos/platform/vboot_reference/+/refs/heads/main/firmware/include/gpt_misc.h
+const PRIORITY_SHIFT: u64 = 48;
+const TRIES_SHIFT: u64 = 52;
+const SUCCESSFUL_SHIFT: u64 = 56;
+const ATTRIBUTES_MASK: u64 = 0xf << PRIORITY_SHIFT | 0xf << TRIES_SHIFT | 1 << SUCCESSFUL_SHIFT;
+
+/// Offset of the attribute field in a GPT partition entry.
+const GPT_ENTRY_ATTRIBUTES_OFFSET: usize = 48;
+/// Offsets of the CRC32 of the header itself and of the partition entry array in a GPT header.
+const GPT_HEADER_CRC32_OFFSET: usize = 16;
+const GPT_HEADER_CRC32_PARTS_OFFSET: usize = 88;
+
+/// Layout of the vboot kernel partition, a keyblock followed by the kernel preamble and the kernel
+/// body.
+/// https://chromium.googlesource.com/chromiumos/platform/vboot_reference/+/refs/heads/main/firmware/2lib/include/2struct.h
+const KEYBLOCK_MAGIC: &[u8] = b"CHROMEOS";
+const KEYBLOCK_VERSION_MAJOR: u32 = 2;
+const KEYBLOCK_HEADER_SIZE: usize = 112;
+const KEYBLOCK_HASH_OFFSET: usize = 48;
+const KERNEL_PREAMBLE_VERSION_MAJOR: u32 = 2;
+/// The size of the kernel command line and of the x86 boot parameters, which come between the
+/// kernel and the bootloader stub in the kernel body.
+/// https://chromium.googlesource.com/chromiumos/platform/vboot_reference/+/refs/heads/main/futility/vb1_helper.c
+const CROS_CONFIG_SIZE: u64 = 4096;
+const CROS_PARAMS_SIZE: u64 = 4096;
+/// Keyblocks and preambles take up a few pages, anything larger is not a kernel partition.
+const MAX_HEADER_SIZE: u32 = 1 << 20;
+
+const FDT_MAGIC: &[u8] = &[0xd0, 0x0d, 0xfe, 0xed];
+
+fn le_u32(buf: &[u8], offset: usize) -> anyhow::Result<u32> {
+    buf.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().expect("slice has 4 bytes")))
+        .ok_or_else(|| anyhow::anyhow!("offset {offset} is out of bounds"))
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> anyhow::Result<u64> {
+    buf.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().expect("slice has 8 bytes")))
+        .ok_or_else(|| anyhow::anyhow!("offset {offset} is out of bounds"))
+}
+
+/// The vboot attributes of a kernel partition, which decide the order kernel partitions are tried
+/// in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Attributes {
+    priority: u8,
+    tries: u8,
+    successful: bool,
+}
+
+impl Attributes {
+    fn from_flags(flags: u64) -> Self {
+        Self {
+            priority: (flags >> PRIORITY_SHIFT & 0xf) as u8,
+            tries: (flags >> TRIES_SHIFT & 0xf) as u8,
+            successful: flags >> SUCCESSFUL_SHIFT & 1 == 1,
+        }
+    }
+
+    /// Replaces the vboot attributes in the flags of a partition, leaving the other bits alone.
+    fn to_flags(self, flags: u64) -> u64 {
+        flags & !ATTRIBUTES_MASK
+            | (self.priority as u64 & 0xf) << PRIORITY_SHIFT
+            | (self.tries as u64 & 0xf) << TRIES_SHIFT
+            | (self.successful as u64) << SUCCESSFUL_SHIFT
+    }
+
+    /// A partition is bootable if it has a priority and either booted successfully before or has
+    /// tries left.
+    fn is_bootable(&self) -> bool {
+        self.priority > 0 && (self.successful || self.tries > 0)
+    }
+
+    /// The attributes after an attempt to boot the partition, if they change. A partition that
+    /// booted successfully before is not counted.
+    fn tried(&self) -> Option<Self> {
+        if self.successful || self.tries == 0 {
+            return None;
+        }
+
+        Some(Self {
+            tries: self.tries - 1,
+            ..*self
+        })
+    }
+}
+
+/// The parts of a `vb2_signature` needed to find the signed data and the signature, both of which
+/// are relative to where the `vb2_signature` is.
+struct Signature {
+    sig_offset: u32,
+    sig_size: u32,
+    data_size: u32,
+}
+
+impl Signature {
+    fn parse(buf: &[u8], offset: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            sig_offset: le_u32(buf, offset)?,
+            sig_size: le_u32(buf, offset + 8)?,
+            data_size: le_u32(buf, offset + 16)?,
+        })
+    }
+}
+
+/// The kernel and command line out of the body of a vboot kernel partition.
+#[derive(Debug, PartialEq)]
+struct VbootKernel {
+    linux: Vec<u8>,
+    cmdline: String,
+}
+
+/// Checks the SHA-512 hash the keyblock carries of itself. This only catches a corrupt keyblock,
+/// the signatures are not checked, which is why these kernels are not booted with boot
+/// verification on.
+fn verify_keyblock_hash(keyblock: &[u8]) -> anyhow::Result<()> {
+    let hash = Signature::parse(keyblock, KEYBLOCK_HASH_OFFSET)?;
+
+    let hash_start = KEYBLOCK_HASH_OFFSET + hash.sig_offset as usize;
+    let (Some(data), Some(expected)) = (
+        keyblock.get(..hash.data_size as usize),
+        keyblock.get(hash_start..hash_start + hash.sig_size as usize),
+    ) else {
+        anyhow::bail!("keyblock hash is out of bounds");
+    };
+
+    if Sha512::digest(data).as_slice() != expected {
+        anyhow::bail!("keyblock hash mismatch");
+    }
+
+    Ok(())
+}
+
+/// Reads a vboot kernel partition. On x86 the kernel body holds a bzImage with its real-mode part
+/// moved to the end (the "vmlinuz header"), which is put back in front of the kernel here.
+fn parse_kernel_partition(mut partition: impl Read) -> anyhow::Result<VbootKernel> {
+    let mut keyblock = vec![0u8; KEYBLOCK_HEADER_SIZE];
+    partition.read_exact(&mut keyblock)?;
+
+    if &keyblock[..KEYBLOCK_MAGIC.len()] != KEYBLOCK_MAGIC {
+        anyhow::bail!("not a vboot kernel partition");
+    }
+
+    let keyblock_version = le_u32(&keyblock, 8)?;
+    if keyblock_version != KEYBLOCK_VERSION_MAJOR {
+        anyhow::bail!("unsupported keyblock version {keyblock_version}");
+    }
+
+    let keyblock_size = le_u32(&keyblock, 16)?;
+    if !(KEYBLOCK_HEADER_SIZE as u32..=MAX_HEADER_SIZE).contains(&keyblock_size) {
+        anyhow::bail!("invalid keyblock size {keyblock_size}");
+    }
+    keyblock.resize(keyblock_size as usize, 0);
+    partition.read_exact(&mut keyblock[KEYBLOCK_HEADER_SIZE..])?;
+
+    verify_keyblock_hash(&keyblock)?;
+
+    let mut preamble = vec![0u8; 4];
+    partition.read_exact(&mut preamble)?;
+    let preamble_size = le_u32(&preamble, 0)?;
+    if !(4..=MAX_HEADER_SIZE).contains(&preamble_size) {
+        anyhow::bail!("invalid preamble size {preamble_size}");
+    }
+    preamble.resize(preamble_size as usize, 0);
+    partition.read_exact(&mut preamble[4..])?;
+
+    let preamble_version = (le_u32(&preamble, 32)?, le_u32(&preamble, 36)?);
+    if preamble_version.0 != KERNEL_PREAMBLE_VERSION_MAJOR {
+        anyhow::bail!("unsupported kernel preamble version {}", preamble_version.0);
+    }
+
+    let body_load_address = le_u64(&preamble, 48)?;
+    let bootloader_address = le_u64(&preamble, 56)?;
+    let body_size = Signature::parse(&preamble, 72)?.data_size;
+
+    let mut body = Vec::new();
+    partition.take(body_size as u64).read_to_end(&mut body)?;
+    if body.len() != body_size as usize {
+        anyhow::bail!("kernel body is truncated");
+    }
+
+    let cmdline_start = bootloader_address
+        .checked_sub(body_load_address)
+        .and_then(|offset| offset.checked_sub(CROS_CONFIG_SIZE + CROS_PARAMS_SIZE))
+        .ok_or_else(|| anyhow::anyhow!("invalid bootloader address {bootloader_address:#x}"))?
+        as usize;
+
+    let Some(cmdline) = body.get(cmdline_start..cmdline_start + CROS_CONFIG_SIZE as usize) else {
+        anyhow::bail!("command line is out of bounds");
+    };
+    let cmdline = match cmdline.iter().position(|byte| *byte == 0) {
+        Some(end) => &cmdline[..end],
+        None => cmdline,
+    };
+    let cmdline = std::str::from_utf8(cmdline)?.trim().to_string();
+
+    let mut linux = Vec::new();
+
+    // the vmlinuz header was added in version 2.1
+    if preamble_version.1 >= 1 {
+        let vmlinuz_header_address = le_u64(&preamble, 96)?;
+        let vmlinuz_header_size = le_u32(&preamble, 104)? as usize;
+
+        if vmlinuz_header_size > 0 {
+            let vmlinuz_header = vmlinuz_header_address
+                .checked_sub(body_load_address)
+                .and_then(|start| body.get(start as usize..start as usize + vmlinuz_header_size))
+                .ok_or_else(|| anyhow::anyhow!("vmlinuz header is out of bounds"))?;
+            linux.extend_from_slice(vmlinuz_header);
+        }
+    }
+
+    linux.extend_from_slice(&body[..cmdline_start]);
+
+    Ok(VbootKernel { linux, cmdline })
+}
+
+/// Writes new flags for a partition to one copy of the partition entry array and updates the
+/// checksums in its header.
+fn write_partition_flags<D: Read + Write + Seek>(
+    disk: &mut D,
+    header: &gpt::header::Header,
+    lb_size: u64,
+    part_idx: u32,
+    flags: u64,
+) -> anyhow::Result<()> {
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+    let entries_start = header.part_start * lb_size;
+    let mut entries = vec![0u8; header.num_parts as usize * header.part_size as usize];
+    disk.seek(SeekFrom::Start(entries_start))?;
+    disk.read_exact(&mut entries)?;
+
+    let attributes_start =
+        (part_idx as usize - 1) * header.part_size as usize + GPT_ENTRY_ATTRIBUTES_OFFSET;
+    let Some(attributes) = entries.get_mut(attributes_start..attributes_start + 8) else {
+        anyhow::bail!("partition {part_idx} is not in the partition entry array");
+    };
+    attributes.copy_from_slice(&flags.to_le_bytes());
+
+    disk.seek(SeekFrom::Start(entries_start))?;
+    disk.write_all(&entries)?;
+
+    let header_start = header.current_lba * lb_size;
+    let mut header_bytes = vec![0u8; header.header_size_le as usize];
+    disk.seek(SeekFrom::Start(header_start))?;
+    disk.read_exact(&mut header_bytes)?;
+
+    header_bytes[GPT_HEADER_CRC32_PARTS_OFFSET..GPT_HEADER_CRC32_PARTS_OFFSET + 4]
+        .copy_from_slice(&crc.checksum(&entries).to_le_bytes());
+    header_bytes[GPT_HEADER_CRC32_OFFSET..GPT_HEADER_CRC32_OFFSET + 4].fill(0);
+    let header_crc32 = crc.checksum(&header_bytes);
+    header_bytes[GPT_HEADER_CRC32_OFFSET..GPT_HEADER_CRC32_OFFSET + 4]
+        .copy_from_slice(&header_crc32.to_le_bytes());
+
+    disk.seek(SeekFrom::Start(header_start))?;
+    disk.write_all(&header_bytes)?;
+
+    Ok(())
+}
+
+/// Changes the vboot attributes of a partition in both the primary and the backup GPT. The gpt
+/// crate is not used for writing since it compacts the partition entry array, which would change
+/// partition numbers.
+fn set_attributes(disk_path: &Path, part_idx: u32, attributes: Attributes) -> anyhow::Result<()> {
+    let gpt_disk = gpt::GptConfig::new().writable(false).open(disk_path)?;
+
+    let Some(partition) = gpt_disk.partitions().get(&part_idx) else {
+        anyhow::bail!("partition {part_idx} does not exist");
+    };
+
+    let flags = attributes.to_flags(partition.flags);
+    let lb_size: u64 = (*gpt_disk.logical_block_size()).into();
+
+    let mut disk = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(disk_path)?;
+
+    for header in [gpt_disk.primary_header(), gpt_disk.backup_header()]
+        .into_iter()
+        .flatten()
+    {
+        write_partition_flags(&mut disk, header, lb_size, part_idx, flags)?;
+    }
+
+    disk.sync_all()?;
+
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+struct ChromeOsEntry {
+    disk_chardev_path: PathBuf,
+    partition_chardev_path: PathBuf,
+    /// The 1-based index of the partition in the GPT.
+    part_idx: u32,
+    /// Replaces "%U" in the kernel command line, so that the root partition can be found relative
+    /// to the kernel partition.
+    part_guid: String,
+    name: String,
+    attributes: Attributes,
+    is_default: bool,
+    extract_dir: PathBuf,
+}
+
+impl Display for ChromeOsEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "Partition {}", self.part_idx)
+        } else {
+            write!(f, "{}", self.name)
+        }
+    }
+}
+
+impl BootEntry for ChromeOsEntry {
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    fn is_bad(&self) -> bool {
+        !self.attributes.is_bootable()
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        // Only the keyblock's hash is checked, not the vboot signatures, and the kernel written
+        // out of the blob has neither an IMA xattr nor an appended signature, so it could never
+        // pass verification.
+        if keys::verification_on() {
+            anyhow::bail!(
+                "ChromeOS kernel partitions cannot be booted while boot verification is on, \
+                 their vboot signatures are not verified"
+            );
+        }
+
+        let kernel = parse_kernel_partition(std::fs::File::open(&self.partition_chardev_path)?)?;
+        let cmdline = kernel.cmdline.replace("%U", &self.part_guid);
+
+        // arm Chromebooks have a FIT image holding the kernel and devicetrees for every board
+        let parts = if kernel.linux.starts_with(FDT_MAGIC) {
+            let fit = Fit::parse(kernel.linux)?;
+            let Some(config) = fit.best_config(&fdt::machine_compatible()) else {
+                anyhow::bail!("FIT image has no configurations");
+            };
+            debug!("extracting FIT configuration '{config}'");
+            LinuxBootParts {
+                cmdline: Some(cmdline),
+                ..fit.extract(&config, &self.extract_dir)?
+            }
+        } else {
+            std::fs::create_dir_all(&self.extract_dir)?;
+            let linux = self.extract_dir.join("linux");
+            debug!("extracting kernel to {}", linux.display());
+            std::fs::write(&linux, kernel.linux)?;
+
+            LinuxBootParts {
+                linux,
+                initrd: Vec::new(),
+                cmdline: Some(cmdline),
+                devicetree: None,
+                devicetree_overlay: Vec::new(),
+                initrd_verified: false,
+            }
+        };
+
+        self.boot_count();
+
+        Ok(parts)
+    }
+}
+
+impl ChromeOsEntry {
+    /// Orders kernel partitions the way vboot tries them, bootable partitions first by descending
+    /// priority and then by partition number.
+    fn cmp_boot_order(a: &ChromeOsEntry, b: &ChromeOsEntry) -> Ordering {
+        b.attributes
+            .is_bootable()
+            .cmp(&a.attributes.is_bootable())
+            .then(b.attributes.priority.cmp(&a.attributes.priority))
+            .then(a.part_idx.cmp(&b.part_idx))
+    }
+
+    fn boot_count(&self) {
+        let Some(tried) = self.attributes.tried() else {
+            return;
+        };
+
+        info!(
+            "counting boot from {} tries left to {} tries left",
+            self.attributes.tries, tried.tries
+        );
+
+        if let Err(e) = set_attributes(&self.disk_chardev_path, self.part_idx, tried) {
+            error!(
+                "failed to update attributes of {}: {e}",
+                self.partition_chardev_path.display()
+            );
+        }
+    }
+}
+
+/// Finds the kernel partitions on a disk, in the order they should be tried.
+fn kernel_partitions(block_dev: &BlockDevice) -> anyhow::Result<Vec<ChromeOsEntry>> {
+    let gpt_disk = gpt::GptConfig::new()
+        .writable(false)
+        .open(&block_dev.chardev_path)?;
+
+    let mut entries: Vec<ChromeOsEntry> = gpt_disk
+        .partitions()
+        .iter()
+        .filter(|(_, part)| part.part_type_guid == gpt::partition_types::CHROME_KERNEL)
+        .map(|(part_idx, part)| ChromeOsEntry {
+            disk_chardev_path: block_dev.chardev_path.clone(),
+            partition_chardev_path: block_dev.partition_chardev_path(*part_idx),
+            part_idx: *part_idx,
+            part_guid: part.part_guid.to_string(),
+            name: part.name.clone(),
+            attributes: Attributes::from_flags(part.flags),
+            is_default: false,
+            extract_dir: PathBuf::from(CHROMEOS_EXTRACT_PATH)
+                .join(format!("{}-{}", block_dev.diskseq, part_idx)),
+        })
+        .collect();
+
+    entries.sort_by(ChromeOsEntry::cmp_boot_order);
+
+    if let Some(first) = entries.first_mut() {
+        first.is_default = first.attributes.is_bootable();
+    }
+
+    Ok(entries)
+}
+
+/// ChromeOsBootLoader boots the kernels ChromeOS keeps on its kernel partitions, choosing between
+/// them with the same GPT attributes vboot uses for A/B updates.
+#[derive(Default)]
+pub struct ChromeOsBootLoader {
+    disks: Vec<BlockDevice>,
+}
+
+impl ChromeOsBootLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add_disks(&mut self, block_devs: Vec<BlockDevice>) {
+        let block_devs: Vec<BlockDevice> = block_devs
+            .into_iter()
+            .filter(|block_dev| {
+                !self
+                    .disks
+                    .iter()
+                    .any(|disk| disk.diskseq == block_dev.diskseq)
+            })
+            .collect();
+
+        let has_kernels = probe_in_parallel(&block_devs, |block_dev| {
+            kernel_partitions(block_dev)
+                .map(|entries| !entries.is_empty())
+                .unwrap_or_default()
+        });
+
+        self.disks.extend(
+            block_devs
+                .into_iter()
+                .zip(has_kernels)
+                .filter_map(|(block_dev, has_kernels)| has_kernels.then_some(block_dev)),
+        );
+
+        // removable disks come first, like in block_devices
+        self.disks.sort_by_key(|disk| !disk.removable);
+    }
+}
+
+impl BootLoader for ChromeOsBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        self.add_disks(block_devices()?);
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        let mut devs = Vec::new();
+
+        for block_dev in &self.disks {
+            // the attributes are read again, since booting counts down tries
+            let entries = match kernel_partitions(block_dev) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("{}: {e}", block_dev.chardev_path.display());
+                    continue;
+                }
+            };
+
+            devs.push(BootDevice {
+                name: format!("{} (ChromeOS)", block_dev.name()),
+                selectors: block_dev.selectors(),
+                entries: entries
+                    .into_iter()
+                    .map(|entry| Box::new(entry) as Box<dyn BootEntry>)
+                    .collect(),
+                timeout: Timeout::Countdown(CHROMEOS_TIMEOUT),
+                editor: true,
+            });
+        }
+
+        devs
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        self.disks.clear();
+
+        if let Err(e) = std::fs::remove_dir_all(CHROMEOS_EXTRACT_PATH) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove {}: {e}", CHROMEOS_EXTRACT_PATH);
+            }
+        }
+    }
+
+    fn disks_changed(&mut self, events: &[DiskEvent]) -> bool {
+        let (added, removed) = changed_disks(events);
+        let count = self.disks.len();
+
+        self.disks.retain(|disk| !removed.contains(&disk.diskseq));
+
+        let removed_any = self.disks.len() != count;
+        let count = self.disks.len();
+
+        self.add_disks(added);
+
+        removed_any || self.disks.len() != count
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::ChromeOs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Cursor, path::PathBuf};
+
+    use crate::boot_loader::{disk::BlockDevice, BootEntry};
+
+    use super::{Attributes, VbootKernel};
+
+    const KEYBLOCK: &[u8] = include_bytes!("../../../test/keys/firmware/key.keyblock");
+
+    /// Builds a kernel partition the way futility lays out an x86 kernel: the protected-mode
+    /// kernel, the command line, the boot parameters, the bootloader stub and the vmlinuz header.
+    fn kernel_partition(keyblock: &[u8], cmdline: &str) -> Vec<u8> {
+        const BODY_LOAD_ADDRESS: u64 = 0x100000;
+
+        let mut body = vec![0u8; 4096 * 5];
+        body[..6].copy_from_slice(b"kernel");
+        body[4096..4096 + cmdline.len()].copy_from_slice(cmdline.as_bytes());
+        body[4096 * 4..4096 * 4 + 5].copy_from_slice(b"setup");
+
+        let mut preamble = vec![0u8; 128];
+        preamble[0..4].copy_from_slice(&128u32.to_le_bytes());
+        preamble[32..36].copy_from_slice(&2u32.to_le_bytes());
+        preamble[36..40].copy_from_slice(&2u32.to_le_bytes());
+        preamble[48..56].copy_from_slice(&BODY_LOAD_ADDRESS.to_le_bytes());
+        preamble[56..64].copy_from_slice(&(BODY_LOAD_ADDRESS + 4096 * 3).to_le_bytes());
+        preamble[88..92].copy_from_slice(&(body.len() as u32).to_le_bytes());
+        preamble[96..104].copy_from_slice(&(BODY_LOAD_ADDRESS + 4096 * 4).to_le_bytes());
+        preamble[104..108].copy_from_slice(&512u32.to_le_bytes());
+
+        [keyblock, &preamble, &body].concat()
+    }
+
+    #[test]
+    fn test_attributes() {
+        let flags = 0x0123_0000_0000_0001;
+        let attributes = Attributes::from_flags(flags);
+        assert_eq!(
+            attributes,
+            Attributes {
+                priority: 3,
+                tries: 2,
+                successful: true,
+            }
+        );
+        assert_eq!(attributes.to_flags(1), flags);
+        assert!(attributes.is_bootable());
+        assert_eq!(attributes.tried(), None);
+
+        let attributes = Attributes {
+            priority: 1,
+            tries: 1,
+            successful: false,
+        };
+        assert!(attributes.is_bootable());
+        let tried = attributes.tried().unwrap();
+        assert_eq!(tried.tries, 0);
+        assert!(!tried.is_bootable());
+        assert_eq!(tried.tried(), None);
+
+        assert!(!Attributes::from_flags(0x0001_0000_0000_0000 * 0xf0).is_bootable());
+    }
+
+    #[test]
+    fn test_parse_kernel_partition() {
+        let partition = kernel_partition(KEYBLOCK, "root=PARTUUID=%U/PARTNROFF=1 quiet\n");
+
+        let mut linux = b"setup".to_vec();
+        linux.resize(512, 0);
+        linux.extend_from_slice(b"kernel");
+        linux.resize(512 + 4096, 0);
+
+        assert_eq!(
+            super::parse_kernel_partition(Cursor::new(&partition)).unwrap(),
+            VbootKernel {
+                linux,
+                cmdline: "root=PARTUUID=%U/PARTNROFF=1 quiet".to_string(),
+            }
+        );
+
+        assert!(
+            super::parse_kernel_partition(Cursor::new(&partition[..partition.len() - 1])).is_err()
+        );
+
+        let mut corrupted = KEYBLOCK.to_vec();
+        corrupted[200] ^= 0x01;
+        assert!(
+            super::parse_kernel_partition(Cursor::new(kernel_partition(&corrupted, "")))
+                .unwrap_err()
+                .to_string()
+                .contains("keyblock hash mismatch")
+        );
+
+        assert!(super::parse_kernel_partition(Cursor::new(vec![0u8; 4096])).is_err());
+    }
+
+    #[test]
+    fn test_kernel_partitions() {
+        let image = std::env::temp_dir().join(format!("tboot-chromeos-{}", std::process::id()));
+        std::fs::File::create(&image)
+            .unwrap()
+            .set_len(1 << 20)
+            .unwrap();
+
+        // the gpt crate puts partitions in the order they are added
+        let mut gpt_disk = gpt::GptConfig::new()
+            .writable(true)
+            .initialized(false)
+            .open(&image)
+            .unwrap();
+        gpt_disk.update_partitions(BTreeMap::new()).unwrap();
+        for (name, part_type, flags) in [
+            ("STATE", gpt::partition_types::LINUX_FS, 0),
+            (
+                "KERN-A",
+                gpt::partition_types::CHROME_KERNEL,
+                0x0101_0000_0000_0000,
+            ),
+            (
+                "KERN-B",
+                gpt::partition_types::CHROME_KERNEL,
+                0x0022_0000_0000_0004,
+            ),
+            ("KERN-C", gpt::partition_types::CHROME_KERNEL, 0),
+        ] {
+            gpt_disk
+                .add_partition(name, 64 * 1024, part_type, flags, None)
+                .unwrap();
+        }
+        gpt_disk.write().unwrap();
+
+        let block_dev = BlockDevice::new(7, PathBuf::from("/nonexistent"), image.clone());
+        let entries = super::kernel_partitions(&block_dev).unwrap();
+
+        let names: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        assert_eq!(names, vec!["KERN-B", "KERN-A", "KERN-C"]);
+        assert!(entries[0].is_default());
+        assert!(!entries[1].is_default());
+        assert!(entries[2].is_bad());
+        assert_eq!(
+            entries[0].partition_chardev_path,
+            PathBuf::from("/dev/part/7/3")
+        );
+
+        entries[0].boot_count();
+        // a partition that booted successfully is not counted
+        entries[1].boot_count();
+
+        let gpt_disk = gpt::GptConfig::new().writable(false).open(&image).unwrap();
+        let partitions = gpt_disk.partitions();
+        assert_eq!(partitions[&2].name, "KERN-A");
+        assert_eq!(partitions[&2].flags, 0x0101_0000_0000_0000);
+        assert_eq!(partitions[&3].name, "KERN-B");
+        assert_eq!(partitions[&3].flags, 0x0012_0000_0000_0004);
+
+        // the backup partition entry array is updated as well
+        let backup_partitions = gpt::partition::file_read_partitions(
+            &mut std::fs::File::open(&image).unwrap(),
+            gpt_disk.backup_header().unwrap(),
+            gpt::disk::LogicalBlockSize::Lb512,
+        )
+        .unwrap();
+        assert_eq!(backup_partitions[&3].flags, 0x0012_0000_0000_0004);
+
+        std::fs::remove_file(&image).unwrap();
+    }
+}
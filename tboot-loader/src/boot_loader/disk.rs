@@ -1,19 +1,29 @@
-use crate::boot_loader::BootLoader;
+use crate::{boot_loader::BootLoader, kexec, keys, pe, verify};
 use gpt::mbr;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use nix::mount::{self, MntFlags, MsFlags};
 use std::{
     cmp::Ordering,
     collections::HashMap,
     fmt::Display,
+    io::{Read, Seek},
     path::{Path, PathBuf},
     str::FromStr,
     time::Duration,
 };
+use tboot::dev::DiskEvent;
 
-use super::{BootDevice, BootEntry, LinuxBootParts, LoaderType};
+use super::{
+    BootDevice, BootEntry, DeviceSelector, EntryAction, LinuxBootParts, LoaderType, Timeout,
+};
 
 const DISK_MNT_PATH: &str = "/mnt/disk";
+const UKI_EXTRACT_PATH: &str = "/run/tboot/uki";
+
+/// loader.conf value that makes the last entry booted from the menu the default.
+const SAVED_ENTRY: &str = "@saved";
+/// Where the id of the last entry booted from the menu is kept, relative to the ESP.
+const SAVED_ENTRY_PATH: &str = "loader/tboot/saved-entry";
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum EfiArch {
@@ -42,6 +52,22 @@ impl EfiArch {
             _ => false,
         }
     }
+
+    /// Maps the machine type from a PE/COFF header to an EFI architecture.
+    /// https://learn.microsoft.com/en-us/windows/win32/debug/pe-format#machine-types
+    fn from_pe_machine(machine: u16) -> Option<Self> {
+        Some(match machine {
+            0x14c => Self::Ia32,
+            0x8664 => Self::X64,
+            0x1c2 | 0x1c4 => Self::Arm,
+            0xaa64 => Self::Aa64,
+            0x5032 => Self::Riscv32,
+            0x5064 => Self::Riscv64,
+            0x6232 => Self::LoongArch32,
+            0x6264 => Self::LoongArch64,
+            _ => return None,
+        })
+    }
 }
 
 impl FromStr for EfiArch {
@@ -62,9 +88,28 @@ impl FromStr for EfiArch {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum EntryType {
+    /// A drop-in config file under /loader/entries
+    #[default]
+    Conf,
+    /// A unified kernel image under /EFI/Linux
+    Uki,
+}
+
+impl EntryType {
+    fn suffix(&self) -> &str {
+        match self {
+            Self::Conf => "conf",
+            Self::Uki => "efi",
+        }
+    }
+}
+
 // Documentation: https://uapi-group.org/specifications/specs/boot_loader_specification/#type-1-boot-loader-specification-entries
 #[derive(Default, Clone)]
 struct BlsEntry {
+    entry_type: EntryType,
     entry_path: PathBuf,
     tries_left: Option<u32>,
     tries_done: Option<u32>,
@@ -82,6 +127,10 @@ struct BlsEntry {
     initrd: Option<Vec<PathBuf>>,
     options: Vec<String>,
     is_default: bool,
+    /// Set when loader.conf has "default @saved".
+    saved_entry_path: Option<PathBuf>,
+    /// Cleared when any entry on the disk is selected.
+    oneshot_entry_path: Option<PathBuf>,
 }
 
 impl Display for BlsEntry {
@@ -95,21 +144,63 @@ impl BootEntry for BlsEntry {
         self.is_default
     }
 
-    fn select(&self) -> LinuxBootParts {
-        // this should be checked by "impl TryInto<Box<dyn BootEntry>> for BlsEntry"
-        let linux = self
-            .linux
-            .clone()
-            .expect("path to linux kernel is not present");
+    fn is_bad(&self) -> bool {
+        BlsEntry::is_bad(self)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(saved_entry_path) = &self.saved_entry_path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = saved_entry_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        debug!("saving {} as the default entry", self.id());
+        std::fs::write(saved_entry_path, format!("{}\n", self.id()))?;
+
+        Ok(())
+    }
+
+    fn set_oneshot(&self) -> anyhow::Result<()> {
+        let Some(oneshot_entry_path) = &self.oneshot_entry_path else {
+            anyhow::bail!("entry is not on an ESP");
+        };
+
+        if let Some(parent) = oneshot_entry_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        debug!("booting {} next", self.id());
+        std::fs::write(oneshot_entry_path, format!("{}\n", self.id()))?;
+
+        Ok(())
+    }
+
+    fn linux(&self) -> Option<PathBuf> {
+        match self.entry_type {
+            // the kernel has to be extracted first
+            EntryType::Uki => None,
+            EntryType::Conf => self.linux.clone(),
+        }
+    }
 
-        let initrd = if let Some(initrds) = self.initrd.clone() {
-            if initrds.len() > 1 {
-                info!("cannot use multiple initrds with KEXEC_FILE_LOAD and modsig appraisal");
-                info!("using first initrd");
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        let (linux, initrd, initrd_verified) = match self.entry_type {
+            EntryType::Uki => {
+                let (linux, initrd, verified) = self.extract_uki()?;
+                (linux, initrd.into_iter().collect(), verified)
+            }
+            EntryType::Conf => {
+                // this should be checked by "impl TryInto<Box<dyn BootEntry>> for BlsEntry"
+                let linux = self
+                    .linux
+                    .clone()
+                    .expect("path to linux kernel is not present");
+
+                (linux, self.initrd.clone().unwrap_or_default(), false)
             }
-            initrds.into_iter().next()
-        } else {
-            None
         };
 
         let mut options = self.options.clone();
@@ -117,12 +208,16 @@ impl BootEntry for BlsEntry {
         let cmdline = Some(options.join(" "));
 
         self.boot_count();
+        self.clear_oneshot();
 
-        LinuxBootParts {
+        Ok(LinuxBootParts {
             linux,
             initrd,
             cmdline,
-        }
+            devicetree: self.devicetree.clone(),
+            devicetree_overlay: self.devicetree_overlay.clone().unwrap_or_default(),
+            initrd_verified,
+        })
     }
 }
 
@@ -243,6 +338,200 @@ impl BlsEntry {
         Ok(entry)
     }
 
+    fn parse_uki<T>(uki_path: impl AsRef<Path>, mut uki: T) -> anyhow::Result<BlsEntry>
+    where
+        T: Read + Seek,
+    {
+        let mut entry = BlsEntry {
+            entry_type: EntryType::Uki,
+            entry_path: uki_path.as_ref().to_path_buf(),
+            ..Default::default()
+        };
+
+        let filename = uki_path
+            .as_ref()
+            .file_name()
+            .and_then(|filename| filename.to_str())
+            .ok_or(tboot::bls::BlsEntryError::MissingFileName)
+            .and_then(tboot::bls::parse_uki_filename)
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+
+        (entry.name, entry.tries_left, entry.tries_done) = filename;
+
+        let pe = pe::parse(&mut uki)?;
+
+        if pe.section(".linux").is_none() {
+            anyhow::bail!("missing .linux section");
+        }
+
+        entry.architecture = EfiArch::from_pe_machine(pe.machine);
+
+        let mut has_pretty_name = false;
+
+        // https://uapi-group.org/specifications/specs/boot_loader_specification/#type-2-efi-unified-kernel-images
+        if let Some(osrel) = pe.section(".osrel") {
+            let osrel = String::from_utf8(pe::read_section(&mut uki, osrel)?)?;
+            let os_release = parse_os_release(&osrel);
+
+            has_pretty_name = os_release.contains_key("PRETTY_NAME");
+            entry.title = ["PRETTY_NAME", "NAME", "ID"]
+                .iter()
+                .find_map(|key| os_release.get(key).cloned());
+            entry.version = ["IMAGE_VERSION", "VERSION_ID", "VERSION"]
+                .iter()
+                .find_map(|key| os_release.get(key).cloned());
+            entry.sort_key = ["IMAGE_ID", "ID"]
+                .iter()
+                .find_map(|key| os_release.get(key).cloned());
+        }
+
+        if let Some(uname) = pe.section(".uname") {
+            let uname = String::from_utf8(pe::read_section(&mut uki, uname)?)?;
+            let uname = uname.trim_end_matches('\0').trim();
+            if entry.version.is_none() && !uname.is_empty() {
+                entry.version = Some(uname.to_string());
+            }
+        }
+
+        if let Some(cmdline) = pe.section(".cmdline") {
+            let cmdline = String::from_utf8(pe::read_section(&mut uki, cmdline)?)?;
+            entry.options = cmdline
+                .trim_end_matches('\0')
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+        }
+
+        entry.pretty_name = match (&entry.title, &entry.version) {
+            // PRETTY_NAME is meant to be shown as it is
+            (Some(title), _) if has_pretty_name => title.clone(),
+            (Some(title), Some(version)) => format!("{} {}", title, version),
+            (Some(title), None) => title.clone(),
+            _ => entry.name.clone(),
+        };
+
+        Ok(entry)
+    }
+
+    /// Copies the kernel and initrd sections out of a unified kernel image onto tmpfs so they can
+    /// be passed to kexec_file_load, returning whether the UKI was verified. The UKI is read into a
+    /// sealed memfd first, so that the sections come from the same copy that was verified, and
+    /// not from a disk that may have changed in the meantime. The initrd has no signature of its
+    /// own, so when boot verification is on, the UKI is verified as a whole and its initrd is
+    /// trusted from then on. The kernel is still appraised by the kernel when it is loaded, so it
+    /// needs an appended signature of its own.
+    fn extract_uki(&self) -> anyhow::Result<(PathBuf, Option<PathBuf>, bool)> {
+        let extract_dir = PathBuf::from(UKI_EXTRACT_PATH).join(&self.name);
+        std::fs::create_dir_all(&extract_dir)?;
+
+        let mut uki = kexec::memfd::load(&self.entry_path, None)?;
+        let keyring_id = keys::ima_keyring()?;
+        if let Some(keyring_id) = keyring_id {
+            kexec::appraise(&mut uki, &self.entry_path, keyring_id)?;
+        }
+        let pe = pe::parse(&mut uki)?;
+
+        let linux_section = pe
+            .section(".linux")
+            .ok_or(anyhow::anyhow!("missing .linux section"))?;
+        let linux = extract_dir.join("linux");
+        debug!("extracting kernel to {}", linux.display());
+        pe::copy_section(&mut uki, linux_section, &mut std::fs::File::create(&linux)?)?;
+
+        // the extracted kernel has no IMA xattr to carry the UKI's signature over with
+        if keyring_id.is_some()
+            && verify::unsigned_len(std::fs::File::open(&linux)?)? == linux_section.len()
+        {
+            anyhow::bail!(
+                "the kernel in {} has no appended signature of its own, which it needs to be \
+                 booted with boot verification on, sign it with sign-file before building the UKI",
+                self.entry_path.display()
+            );
+        }
+
+        let initrd = if let Some(initrd_section) = pe.section(".initrd") {
+            let initrd = extract_dir.join("initrd");
+            debug!("extracting initrd to {}", initrd.display());
+            pe::copy_section(
+                &mut uki,
+                initrd_section,
+                &mut std::fs::File::create(&initrd)?,
+            )?;
+            Some(initrd)
+        } else {
+            None
+        };
+
+        Ok((linux, initrd, keyring_id.is_some()))
+    }
+
+    /// The entry's filename without boot counting, e.g. "nixos-generation-1.conf".
+    fn id(&self) -> String {
+        format!("{}.{}", self.name, self.entry_type.suffix())
+    }
+
+    /// Matches a glob pattern from loader.conf against the entry's id. The suffix may be left out.
+    fn matches_id(&self, pattern: &str) -> bool {
+        glob_match(pattern, &self.name) || glob_match(pattern, &self.id())
+    }
+
+    /// Indicates whether the entry has run out of boot attempts.
+    fn is_bad(&self) -> bool {
+        self.tries_left == Some(0)
+    }
+
+    /// Orders entries as described in the BootLoaderSpec, where entries that come first are
+    /// preferred. Entries with a sort-key come first, grouped by sort-key and machine-id and ordered
+    /// by descending version. Entries without a sort-key follow, ordered by descending entry name.
+    /// Entries with no tries left are always put at the end.
+    /// https://uapi-group.org/specifications/specs/boot_loader_specification/#sorting
+    fn cmp_boot_order(a: &BlsEntry, b: &BlsEntry) -> Ordering {
+        fn cmp_versions(a: &Option<String>, b: &Option<String>) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => tboot::bls::compare_versions(a, b),
+                _ => a.is_some().cmp(&b.is_some()),
+            }
+        }
+
+        a.is_bad()
+            .cmp(&b.is_bad())
+            .then(a.sort_key.is_none().cmp(&b.sort_key.is_none()))
+            .then_with(|| {
+                if a.sort_key.is_some() && b.sort_key.is_some() {
+                    a.sort_key
+                        .cmp(&b.sort_key)
+                        .then_with(|| a.machine_id.cmp(&b.machine_id))
+                        .then_with(|| cmp_versions(&b.version, &a.version))
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .then_with(|| tboot::bls::compare_versions(&b.name, &a.name))
+            .then_with(|| match (a.tries_left, b.tries_left) {
+                // prefer the entry with more tries left, then the one that was tried fewer times
+                (Some(a_left), Some(b_left)) => {
+                    b_left.cmp(&a_left).then(a.tries_done.cmp(&b.tries_done))
+                }
+                _ => Ordering::Equal,
+            })
+    }
+
+    fn clear_oneshot(&self) {
+        let Some(oneshot_entry_path) = &self.oneshot_entry_path else {
+            return;
+        };
+
+        match std::fs::remove_file(oneshot_entry_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                error!(
+                    "failed to remove one-shot entry {}: {e}",
+                    oneshot_entry_path.display()
+                );
+            }
+            _ => {}
+        }
+    }
+
     fn boot_count(&self) {
         let Some(tries_left) = self.tries_left else {
             return;
@@ -256,13 +545,19 @@ impl BlsEntry {
 
         let new_entry_path = entry_dir.join(if let Some(tries_done) = self.tries_done {
             format!(
-                "{}+{}-{}.conf",
+                "{}+{}-{}.{}",
                 self.name,
                 new_tries_left,
                 tries_done.checked_add(1).unwrap_or(tries_done),
+                self.entry_type.suffix(),
             )
         } else {
-            format!("{}+{}.conf", self.name, new_tries_left)
+            format!(
+                "{}+{}.{}",
+                self.name,
+                new_tries_left,
+                self.entry_type.suffix()
+            )
         });
 
         info!(
@@ -288,94 +583,99 @@ impl TryInto<Box<dyn BootEntry>> for BlsEntry {
             anyhow::bail!("cannot boot efi");
         }
 
-        if self.linux.is_none() {
+        if self.entry_type == EntryType::Conf && self.linux.is_none() {
             anyhow::bail!("cannot boot without linux");
         }
 
-        if self
-            .tries_left
-            .map(|tries_left| tries_left == 0)
-            .unwrap_or_default()
-        {
-            anyhow::bail!("entry is bad");
-        }
-
         Ok(Box::new(self) as _)
     }
 }
 
-#[derive(Clone)]
-struct Disk {
-    diskseq: u64,
-    device_path: PathBuf,
-    entries: Vec<BlsEntry>,
-    mountpoint: Option<PathBuf>,
-    timeout: Duration,
-    removable: bool,
-    vendor: Option<String>,
-    model: Option<String>,
+/// A whole disk, as opposed to one of its partitions.
+#[derive(Clone, Debug)]
+pub(super) struct BlockDevice {
+    /// Unique across all disks, even ones that were removed.
+    /// https://github.com/torvalds/linux/blob/9c5d00cb7b6bbc5a7965d9ab7d223b5402d1f02c/block/genhd.c#L53
+    pub diskseq: u64,
+    /// The path under /sys/devices that /sys/class/block/<devname>/device points to.
+    pub device_path: PathBuf,
+    pub chardev_path: PathBuf,
+    pub removable: bool,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
 }
 
-impl Into<BootDevice> for Disk {
-    fn into(self) -> BootDevice {
-        let timeout = self.timeout;
-
-        BootDevice {
-            name: format!(
-                "{} {}",
-                if let Some(vendor) = &self.vendor {
-                    vendor
-                } else {
-                    "Unknown Vendor"
-                },
-                if let Some(model) = &self.model {
-                    model
-                } else {
-                    "Unknown Model"
-                },
-            ),
-            timeout,
-            entries: self
-                .entries
-                .into_iter()
-                .filter_map(|entry| match entry.try_into() {
-                    Ok(entry) => Some(entry),
-                    Err(e) => {
-                        info!("could not convert entry: {e}");
-                        None
-                    }
-                })
-                .collect(),
+impl BlockDevice {
+    pub fn new(diskseq: u64, device_path: PathBuf, chardev_path: PathBuf) -> Self {
+        Self {
+            removable: Self::get_attribute_bool(&device_path, "removable"),
+            vendor: Self::get_attribute_string(&device_path, "vendor"),
+            model: Self::get_attribute_string(&device_path, "model"),
+            diskseq,
+            device_path,
+            chardev_path,
         }
     }
-}
 
-impl Disk {
-    pub fn new(diskseq: u64, device_path: PathBuf) -> Self {
-        let mut disk = Self {
-            entries: Vec::new(),
-            diskseq,
-            device_path,
-            removable: false,
-            vendor: None,
-            model: None,
-            mountpoint: None,
-            timeout: Duration::from_secs(10),
-        };
+    pub fn name(&self) -> String {
+        format!(
+            "{} {}",
+            if let Some(vendor) = &self.vendor {
+                vendor
+            } else {
+                "Unknown Vendor"
+            },
+            if let Some(model) = &self.model {
+                model
+            } else {
+                "Unknown Model"
+            },
+        )
+    }
 
-        disk.removable = Self::get_attribute_bool(&disk.device_path, "removable");
-        disk.vendor = Self::get_attribute_string(&disk.device_path, "vendor");
-        disk.model = Self::get_attribute_string(&disk.device_path, "model");
+    pub fn partition_chardev_path(&self, part_idx: u32) -> PathBuf {
+        PathBuf::from("/dev/part")
+            .join(self.diskseq.to_string())
+            .join(part_idx.to_string())
+    }
+
+    /// Finds the bus the disk is attached to from its path under /sys/devices, e.g.
+    /// /sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host0/target0:0:0/0:0:0:0
+    pub fn selectors(&self) -> Vec<DeviceSelector> {
+        let mut selectors = Vec::new();
+
+        let bus = self.device_path.components().find_map(|component| {
+            let component = component.as_os_str().to_str()?;
+            Some(if component.starts_with("usb") {
+                DeviceSelector::Usb
+            } else if component == "nvme" {
+                DeviceSelector::Nvme
+            } else if component == "mmc_host" {
+                DeviceSelector::Mmc
+            } else if component.starts_with("ata") {
+                DeviceSelector::Sata
+            } else if component.starts_with("virtio") {
+                DeviceSelector::Virtio
+            } else {
+                return None;
+            })
+        });
 
-        disk
+        selectors.extend(bus);
+
+        if self.removable {
+            selectors.push(DeviceSelector::Removable);
+        }
+
+        selectors
     }
 
     fn get_attribute_string(device_path: impl AsRef<Path>, attribute: &str) -> Option<String> {
-        Disk::get_disk_attribute(device_path, attribute).ok()
+        Self::get_disk_attribute(device_path, attribute).ok()
     }
 
     fn get_attribute_bool(device_path: impl AsRef<Path>, attribute: &str) -> bool {
-        Disk::get_disk_attribute(device_path, attribute)
+        Self::get_disk_attribute(device_path, attribute)
             .map(|val| val == "1\n")
             .unwrap_or_default()
     }
@@ -387,42 +687,331 @@ impl Disk {
         std::fs::read_to_string(device_path.as_ref().join(attribute))
             .map(|val| val.trim().to_string())
     }
+}
 
-    fn mount(&mut self, partition_chardev_path: impl AsRef<Path>) -> anyhow::Result<()> {
-        // We can use diskseq as value that is unique across all disks
-        // https://github.com/torvalds/linux/blob/9c5d00cb7b6bbc5a7965d9ab7d223b5402d1f02c/block/genhd.c#L53
+/// Lists the disks that are backed by a physical device, skipping partitions and virtual block
+/// devices.
+pub(super) fn block_devices() -> anyhow::Result<Vec<BlockDevice>> {
+    let Ok(block_class_dir) = std::fs::read_dir("/sys/class/block") else {
+        anyhow::bail!("/sys/class/block missing");
+    };
 
-        let mountpoint = PathBuf::from(DISK_MNT_PATH).join(self.diskseq.to_string());
-        std::fs::create_dir(&mountpoint)?;
+    let mut block_devs = Vec::new();
 
-        mount::mount(
-            Some(partition_chardev_path.as_ref()),
-            &mountpoint,
-            Some(
-                crate::fs::detect_fs_type(std::fs::File::open(&partition_chardev_path)?)
-                    .ok_or(anyhow::anyhow!("could not detect fstype"))?
-                    .as_str(),
-            ),
-            MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
-            None::<&[u8]>,
-        )?;
+    for block_dev in block_class_dir {
+        let Ok(block_dev) = block_dev else {
+            continue;
+        };
 
-        self.mountpoint = Some(mountpoint);
+        // the path under /sys/devices that /sys/class/<class>/<devname> points to, the device
+        // may have gone away since the directory was read
+        let block_dev_path = match std::fs::canonicalize(block_dev.path()) {
+            Ok(path) => path,
+            Err(e) => {
+                debug!("{}: {e}", block_dev.path().display());
+                continue;
+            }
+        };
 
-        Ok(())
+        // the path under /sys/devices that /sys/class/<class>/<devname>/device points to
+        let device_path = {
+            let device_path = block_dev_path.join("device");
+            if !std::fs::metadata(&device_path).is_ok() {
+                // There is no physical backing device for this device. This could happen if
+                // the device is a logical partition (e.g. `/dev/sda1` where the parent device
+                // is `/dev/sda`)
+                continue;
+            }
+            match std::fs::canonicalize(&device_path) {
+                Ok(path) => path,
+                Err(e) => {
+                    debug!("{}: {e}", device_path.display());
+                    continue;
+                }
+            }
+        };
+
+        let diskseq = {
+            let diskseq_path = block_dev_path.join("diskseq");
+            let Ok(Ok(diskseq)) = std::fs::read_to_string(diskseq_path)
+                .map(|diskseq_str| diskseq_str.trim_end_matches('\n').parse::<u64>())
+            else {
+                continue;
+            };
+
+            diskseq
+        };
+
+        let uevent = match get_uevent(&block_dev_path) {
+            Ok(uevent) => uevent,
+            Err(e) => {
+                debug!("{}: {e}", block_dev_path.display());
+                continue;
+            }
+        };
+        let Some(devname) = uevent.get("DEVNAME") else {
+            continue;
+        };
+
+        // add the disk if it does not already exist
+        if block_devs
+            .iter()
+            .any(|block_dev: &BlockDevice| block_dev.diskseq == diskseq)
+        {
+            continue;
+        }
+
+        block_devs.push(BlockDevice::new(
+            diskseq,
+            device_path,
+            get_dev_path(devname),
+        ));
+    }
+
+    // prioritize disk by removable status
+    block_devs.sort_by(|a, b| {
+        if a.removable && !b.removable {
+            Ordering::Less
+        } else if !a.removable && b.removable {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    });
+
+    Ok(block_devs)
+}
+
+/// Runs `f` on every disk at the same time, since mounting a slow USB stick should not hold up
+/// the other disks. The results are in the order of the disks.
+pub(super) fn probe_in_parallel<D: Sync, T: Send>(
+    disks: &[D],
+    f: impl Fn(&D) -> T + Sync,
+) -> Vec<T> {
+    std::thread::scope(|scope| {
+        let f = &f;
+        let handles: Vec<_> = disks
+            .iter()
+            .map(|disk| scope.spawn(move || f(disk)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("probing a disk panicked"))
+            .collect()
+    })
+}
+
+/// Splits disk events into the disks that were added and are still there, and the diskseqs of
+/// the ones that were removed.
+pub(super) fn changed_disks(events: &[DiskEvent]) -> (Vec<BlockDevice>, Vec<u64>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+
+    for event in events {
+        match event {
+            DiskEvent::Added(diskseq) => added.push(*diskseq),
+            DiskEvent::Removed(diskseq) => removed.push(*diskseq),
+        }
+    }
+
+    if added.is_empty() {
+        return (Vec::new(), removed);
+    }
+
+    let block_devs = match block_devices() {
+        Ok(block_devs) => block_devs,
+        Err(e) => {
+            error!("failed to list block devices: {e}");
+            Vec::new()
+        }
+    };
+
+    (
+        block_devs
+            .into_iter()
+            .filter(|block_dev| added.contains(&block_dev.diskseq))
+            .collect(),
+        removed,
+    )
+}
+
+/// Mounts a partition with whatever filesystem is on it, creating the mountpoint if needed.
+pub(super) fn mount_partition(
+    partition_chardev_path: impl AsRef<Path>,
+    mountpoint: &Path,
+    read_only: bool,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(mountpoint)?;
+
+    let mut flags = MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+    if read_only {
+        flags |= MsFlags::MS_RDONLY;
+    }
+
+    mount::mount(
+        Some(partition_chardev_path.as_ref()),
+        mountpoint,
+        Some(
+            crate::fs::detect_fs_type(std::fs::File::open(&partition_chardev_path)?)
+                .ok_or(anyhow::anyhow!("could not detect fstype"))?
+                .as_str(),
+        ),
+        flags,
+        None::<&[u8]>,
+    )?;
+
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Disk {
+    block_dev: BlockDevice,
+    entries: Vec<BlsEntry>,
+    /// Where the ESP is mounted, this is the only partition loader.conf is read from.
+    mountpoint: Option<PathBuf>,
+    /// Where the Extended Boot Loader partition is mounted, if the disk has one.
+    xbootldr_mountpoint: Option<PathBuf>,
+    loader_conf: LoaderConf,
+}
+
+impl Into<BootDevice> for Disk {
+    fn into(self) -> BootDevice {
+        let selectors = self.block_dev.selectors();
+
+        let mut entries: Vec<Box<dyn BootEntry>> = self
+            .entries
+            .into_iter()
+            .filter_map(|entry| match entry.try_into() {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    info!("could not convert entry: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        if self.loader_conf.auto_reboot {
+            entries.push(Box::new(EntryAction::Reboot));
+        }
+
+        if self.loader_conf.auto_poweroff {
+            entries.push(Box::new(EntryAction::Poweroff));
+        }
+
+        BootDevice {
+            selectors,
+            name: self.block_dev.name(),
+            timeout: self.loader_conf.timeout,
+            editor: self.loader_conf.editor,
+            entries,
+        }
+    }
+}
+
+impl Disk {
+    pub fn new(block_dev: BlockDevice) -> Self {
+        Self {
+            block_dev,
+            entries: Vec::new(),
+            mountpoint: None,
+            xbootldr_mountpoint: None,
+            loader_conf: LoaderConf::default(),
+        }
+    }
+
+    fn mount(
+        &self,
+        partition_chardev_path: impl AsRef<Path>,
+        partition_name: &str,
+    ) -> anyhow::Result<PathBuf> {
+        let mountpoint = PathBuf::from(DISK_MNT_PATH)
+            .join(self.block_dev.diskseq.to_string())
+            .join(partition_name);
+        mount_partition(partition_chardev_path, &mountpoint, false)?;
+
+        Ok(mountpoint)
+    }
+
+    /// Both the ESP and the XBOOTLDR partition can contain type #1 and type #2 entries. Paths in
+    /// an entry are relative to the partition the entry was found on.
+    fn mountpoints(&self) -> Vec<PathBuf> {
+        [&self.mountpoint, &self.xbootldr_mountpoint]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
     }
 
-    fn discover_entries(&mut self, default_entry_name: Option<String>) {
+    fn discover_entries(&mut self) {
         trace!("searching for BLS entries");
 
-        let Some(mountpoint) = self.mountpoint.as_ref() else {
+        let mountpoints = self.mountpoints();
+        if mountpoints.is_empty() {
             error!("disk not mounted");
             return;
+        }
+
+        for mountpoint in mountpoints {
+            self.discover_type1_entries(&mountpoint);
+            self.discover_type2_entries(&mountpoint);
+        }
+
+        self.entries.sort_by(BlsEntry::cmp_boot_order);
+
+        let oneshot_entry_path = self
+            .mountpoint
+            .as_ref()
+            .map(|mountpoint| mountpoint.join(tboot::bls::ONESHOT_ENTRY_PATH));
+
+        for entry in self.entries.iter_mut() {
+            entry.oneshot_entry_path = oneshot_entry_path.clone();
+        }
+
+        let oneshot_entry = oneshot_entry_path.and_then(|oneshot_entry_path| {
+            std::fs::read_to_string(oneshot_entry_path)
+                .map(|oneshot_entry| oneshot_entry.trim().to_string())
+                .ok()
+        });
+
+        let default_entry = match self.loader_conf.default_entry.as_deref() {
+            Some(SAVED_ENTRY) => {
+                let saved_entry_path = self
+                    .mountpoint
+                    .as_ref()
+                    .map(|mountpoint| mountpoint.join(SAVED_ENTRY_PATH));
+
+                for entry in self.entries.iter_mut() {
+                    entry.saved_entry_path = saved_entry_path.clone();
+                }
+
+                saved_entry_path.and_then(|saved_entry_path| {
+                    std::fs::read_to_string(saved_entry_path)
+                        .map(|saved_entry| saved_entry.trim().to_string())
+                        .ok()
+                })
+            }
+            default_entry => default_entry.map(str::to_string),
         };
 
+        // The one-shot entry wins over the default from loader.conf, as long as it still exists.
+        // When a pattern matches multiple entries, the one that sorts first is the default.
+        for default_entry in [oneshot_entry, default_entry].into_iter().flatten() {
+            if let Some(entry) = self
+                .entries
+                .iter_mut()
+                .find(|entry| entry.matches_id(&default_entry))
+            {
+                entry.is_default = true;
+                break;
+            }
+        }
+    }
+
+    fn discover_type1_entries(&mut self, mountpoint: &Path) {
         if let Ok(entries_srel) = std::fs::read_to_string(mountpoint.join("loader/entries.srel")) {
             if entries_srel != "type1\n" {
-                debug!("/loader/entries.srel not type1, skipping disk");
+                debug!("/loader/entries.srel not type1, skipping type #1 entries");
                 return;
             }
         }
@@ -455,121 +1044,297 @@ impl Disk {
                 }
             };
 
-            let mut parsed_entry = match BlsEntry::parse_entry_conf(
-                mountpoint.as_path(),
-                &entry_path,
-                &entry_conf_contents,
-            ) {
-                Ok(entry) => entry,
-                Err(e) => {
-                    error!("failed to parse entry at {:?}: {e}", entry_path);
-                    continue;
-                }
-            };
-
-            parsed_entry.is_default =
-                Some(parsed_entry.name.as_str()) == default_entry_name.as_deref();
-
-            if self
-                .entries
-                .iter()
-                .find(|entry| entry.name == parsed_entry.name)
-                .is_some()
-            {
-                debug!("entry {} already present, skipping", entry_path.display());
-                continue;
+            let parsed_entry =
+                match BlsEntry::parse_entry_conf(mountpoint, &entry_path, &entry_conf_contents) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        error!("failed to parse entry at {:?}: {e}", entry_path);
+                        continue;
+                    }
+                };
+
+            self.add_entry(parsed_entry);
+        }
+    }
+
+    fn discover_type2_entries(&mut self, mountpoint: &Path) {
+        let uki_dir = mountpoint.join("EFI/Linux");
+        let ukis = match std::fs::read_dir(&uki_dir) {
+            Ok(u) => u,
+            Err(e) => {
+                debug!("failed to read UKI dir {}: {e}", uki_dir.display());
+                return;
             }
+        };
 
-            // assume entry is meant for running architecture if not specified
-            if !parsed_entry
-                .architecture
-                .as_ref()
-                .map(|arch| arch.is_running_arch())
-                .unwrap_or(true)
-            {
-                debug!(
-                    "entry {} is not for current running architecture, skipping",
-                    entry_path.display()
-                );
+        for uki in ukis {
+            let Ok(uki) = uki else {
                 continue;
-            }
+            };
 
-            debug!("new entry added {}", entry_path.display());
-            self.entries.push(parsed_entry);
-        }
-
-        // TODO(jared): this could be a lot better, it depends on sequential entries having the
-        // same fields defined, but this isn't always necessarily the case.
-        self.entries.sort_by(|a, b| {
-            if a.version < b.version {
-                Ordering::Greater
-            } else if a.version > b.version {
-                Ordering::Less
-            } else if a.title > b.title {
-                Ordering::Greater
-            } else if a.title < b.title {
-                Ordering::Less
-            } else if a.name > b.name {
-                Ordering::Greater
-            } else {
-                Ordering::Less
+            let uki_path = uki.path();
+
+            let is_efi_file = uki.metadata().map(|md| md.is_file()).unwrap_or_default()
+                && uki_path
+                    .extension()
+                    .map(|ext| ext == "efi")
+                    .unwrap_or_default();
+            if !is_efi_file {
+                debug!("skipping {}", uki_path.display());
+                continue;
             }
-        });
+
+            let parsed_entry = match std::fs::File::open(&uki_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|uki| BlsEntry::parse_uki(&uki_path, uki))
+            {
+                Ok(entry) => entry,
+                Err(e) => {
+                    error!("failed to parse UKI at {}: {e}", uki_path.display());
+                    continue;
+                }
+            };
+
+            self.add_entry(parsed_entry);
+        }
+    }
+
+    fn add_entry(&mut self, parsed_entry: BlsEntry) {
+        let entry_path = parsed_entry.entry_path.clone();
+
+        if self
+            .entries
+            .iter()
+            .any(|entry| entry.name == parsed_entry.name)
+        {
+            debug!("entry {} already present, skipping", entry_path.display());
+            return;
+        }
+
+        // assume entry is meant for running architecture if not specified
+        if !parsed_entry
+            .architecture
+            .as_ref()
+            .map(|arch| arch.is_running_arch())
+            .unwrap_or(true)
+        {
+            debug!(
+                "entry {} is not for current running architecture, skipping",
+                entry_path.display()
+            );
+            return;
+        }
+
+        debug!("new entry added {}", entry_path.display());
+        self.entries.push(parsed_entry);
     }
 
     fn unmount(&self) {
-        if let Some(mountpoint) = &self.mountpoint {
-            if let Err(e) = mount::umount2(mountpoint, MntFlags::MNT_DETACH) {
+        for mountpoint in self.mountpoints() {
+            if let Err(e) = mount::umount2(&mountpoint, MntFlags::MNT_DETACH) {
                 error!("failed to unmount {}: {e}", mountpoint.display());
             }
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, PartialEq)]
+enum LoaderConfError {
+    UnknownKey(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    /// A key that tinyboot cannot honor, which is ignored.
+    Unsupported(String),
+}
+
+impl Display for LoaderConfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownKey(key) => write!(f, "unknown key '{key}'"),
+            Self::MissingValue(key) => write!(f, "missing value for '{key}'"),
+            Self::InvalidValue(key, val) => write!(f, "invalid value '{val}' for '{key}'"),
+            Self::Unsupported(key) => write!(f, "'{key}' is not supported by tinyboot, ignoring"),
+        }
+    }
+}
+
+/// Keys that systemd-boot understands, but that have no meaning for tinyboot.
+const IGNORED_LOADER_CONF_KEYS: &[&str] = &[
+    "beep",
+    "console-mode",
+    "random-seed-mode",
+    "reboot-for-bitlocker",
+    "reboot-on-error",
+    "secure-boot-enroll",
+];
+
+// Documentation: https://www.freedesktop.org/software/systemd/man/latest/loader.conf.html
+#[derive(Clone, Debug, PartialEq)]
 struct LoaderConf {
+    /// A glob pattern matched against entry ids.
     default_entry: Option<String>,
-    timeout: Duration,
+    timeout: Timeout,
+    editor: bool,
+    /// Show an entry that powers off the machine.
+    auto_poweroff: bool,
+    /// Show an entry that reboots the machine.
+    auto_reboot: bool,
 }
 
 impl Default for LoaderConf {
     fn default() -> Self {
         Self {
-            timeout: Duration::from_secs(10),
+            timeout: Timeout::Countdown(Duration::from_secs(10)),
             default_entry: None,
+            editor: true,
+            auto_poweroff: false,
+            auto_reboot: false,
         }
     }
 }
 
+fn parse_bool(val: &str) -> Option<bool> {
+    match val {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Some(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Some(false),
+        _ => None,
+    }
+}
+
 impl LoaderConf {
-    fn parse_loader_conf(contents: &str) -> Self {
-        let mut default_entry = None;
-        let mut timeout = Duration::from_secs(5);
+    /// Parses loader.conf, returning any errors along with the (1-based) line they occurred on.
+    /// Lines with errors are skipped.
+    fn parse_loader_conf(contents: &str) -> (Self, Vec<(usize, LoaderConfError)>) {
+        let mut loader_conf = LoaderConf {
+            timeout: Timeout::Countdown(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let mut errors = Vec::new();
 
-        for line in contents.lines() {
-            if line.starts_with("timeout") {
-                let Some(found_timeout) = line.split_whitespace().last() else {
-                    continue;
-                };
-                let Ok(timeout_secs) = found_timeout.parse::<u64>() else {
-                    continue;
-                };
-                timeout = Duration::from_secs(timeout_secs);
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
 
-            if line.starts_with("default") {
-                let Some(found_default) = line.split_whitespace().last() else {
-                    continue;
-                };
-                default_entry = Some(found_default.trim_end_matches(".conf").to_string());
+            let (key, val) = line
+                .split_once(char::is_whitespace)
+                .map(|(key, val)| (key, val.trim()))
+                .unwrap_or((line, ""));
+
+            if let Err(e) = loader_conf.parse_line(key, val) {
+                errors.push((line_idx + 1, e));
             }
         }
 
-        LoaderConf {
-            default_entry,
-            timeout,
+        (loader_conf, errors)
+    }
+
+    fn parse_line(&mut self, key: &str, val: &str) -> Result<(), LoaderConfError> {
+        if IGNORED_LOADER_CONF_KEYS.contains(&key) {
+            return Ok(());
+        }
+
+        if val.is_empty() {
+            return Err(
+                if matches!(
+                    key,
+                    "default"
+                        | "timeout"
+                        | "editor"
+                        | "auto-entries"
+                        | "auto-firmware"
+                        | "auto-poweroff"
+                        | "auto-reboot"
+                ) {
+                    LoaderConfError::MissingValue(key.to_string())
+                } else {
+                    LoaderConfError::UnknownKey(key.to_string())
+                },
+            );
+        }
+
+        let invalid_value = || LoaderConfError::InvalidValue(key.to_string(), val.to_string());
+
+        match key {
+            "default" => self.default_entry = Some(val.to_string()),
+            "timeout" => {
+                self.timeout = match val {
+                    "menu-force" => Timeout::MenuForce,
+                    "menu-hidden" | "0" => Timeout::MenuHidden,
+                    secs => Timeout::Countdown(Duration::from_secs(
+                        secs.parse::<u64>().map_err(|_| invalid_value())?,
+                    )),
+                }
+            }
+            "editor" => self.editor = parse_bool(val).ok_or_else(invalid_value)?,
+            "auto-poweroff" => self.auto_poweroff = parse_bool(val).ok_or_else(invalid_value)?,
+            "auto-reboot" => self.auto_reboot = parse_bool(val).ok_or_else(invalid_value)?,
+            // tinyboot cannot discover other operating systems or reboot into firmware setup, it
+            // is the firmware
+            "auto-entries" | "auto-firmware" => {
+                if parse_bool(val).ok_or_else(invalid_value)? {
+                    return Err(LoaderConfError::Unsupported(key.to_string()));
+                }
+            }
+            _ => return Err(LoaderConfError::UnknownKey(key.to_string())),
+        }
+
+        Ok(())
+    }
+}
+
+/// Matches a string against a shell-style glob pattern supporting "*", "?" and "[...]" classes.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+        let (negated, start) = match class.first() {
+            Some('!') | Some('^') => (true, 1),
+            _ => (false, 0),
+        };
+
+        let mut idx = start;
+        let mut matched = false;
+        loop {
+            let first = *class.get(idx)?;
+            // a "]" right at the start is part of the class
+            if first == ']' && idx > start {
+                return Some((matched != negated, idx + 1));
+            }
+
+            if class.get(idx + 1) == Some(&'-') && class.get(idx + 2).is_some_and(|c| *c != ']') {
+                let last = class[idx + 2];
+                matched |= (first..=last).contains(&c);
+                idx += 3;
+            } else {
+                matched |= first == c;
+                idx += 1;
+            }
+        }
+    }
+
+    fn matches(pattern: &[char], s: &[char]) -> bool {
+        match pattern.first() {
+            None => s.is_empty(),
+            Some('*') => (0..=s.len()).any(|skip| matches(&pattern[1..], &s[skip..])),
+            Some('?') => !s.is_empty() && matches(&pattern[1..], &s[1..]),
+            Some('[') => {
+                let Some(c) = s.first() else {
+                    return false;
+                };
+                match match_class(&pattern[1..], *c) {
+                    Some((true, len)) => matches(&pattern[1 + len..], &s[1..]),
+                    Some((false, _)) => false,
+                    // an unterminated class is matched literally
+                    None => *c == '[' && matches(&pattern[1..], &s[1..]),
+                }
+            }
+            Some(p) => s.first() == Some(p) && matches(&pattern[1..], &s[1..]),
         }
     }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    matches(&pattern, &s)
 }
 
 /// BlsBootLoader implements (a small part of) the Boot Loader Specification for booting from a
@@ -583,123 +1348,68 @@ impl BlsBootLoader {
     pub fn new() -> Self {
         Self::default()
     }
-}
 
-impl BootLoader for BlsBootLoader {
-    fn setup(&mut self) -> anyhow::Result<()> {
-        debug!("setup");
-
-        std::fs::create_dir_all(DISK_MNT_PATH).unwrap();
-
-        let Ok(block_class_dir) = std::fs::read_dir("/sys/class/block") else {
-            anyhow::bail!("/sys/class/block missing");
-        };
-
-        for block_dev in block_class_dir {
-            let Ok(block_dev) = block_dev else {
-                continue;
-            };
-
-            // the path under /sys/devices that /sys/class/<class>/<devname> points to
-            let block_dev_path = std::fs::canonicalize(block_dev.path()).unwrap();
-
-            // the path under /sys/devices that /sys/class/<class>/<devname>/device points to
-            let device_path = {
-                let device_path = block_dev_path.join("device");
-                if !std::fs::metadata(&device_path).is_ok() {
-                    // There is no physical backing device for this device. This could happen if
-                    // the device is a logical partition (e.g. `/dev/sda1` where the parent device
-                    // is `/dev/sda`)
-                    continue;
-                }
-                std::fs::canonicalize(device_path).unwrap()
-            };
-
-            let diskseq = {
-                let diskseq_path = block_dev_path.join("diskseq");
-                let Ok(Ok(diskseq)) = std::fs::read_to_string(diskseq_path)
-                    .map(|diskseq_str| u64::from_str_radix(diskseq_str.trim_end_matches('\n'), 10))
-                else {
-                    continue;
-                };
+    /// Mounts the ESP and XBOOTLDR partition of a disk, if it has any.
+    fn mount_disk(block_dev: &BlockDevice) -> Option<Disk> {
+        let boot_partitions = find_boot_partitions(&block_dev.chardev_path);
+        if boot_partitions.esp.is_none() && boot_partitions.xbootldr.is_none() {
+            return None;
+        }
 
-                diskseq
-            };
+        let mut disk = Disk::new(block_dev.clone());
 
-            let uevent = get_uevent(&block_dev_path);
-            let Some(devname) = uevent.get("DEVNAME") else {
-                continue;
-            };
+        if let Some(esp_idx) = boot_partitions.esp {
+            let esp_chardev_path = disk.block_dev.partition_chardev_path(esp_idx);
+            match disk.mount(&esp_chardev_path, "esp") {
+                Ok(mountpoint) => disk.mountpoint = Some(mountpoint),
+                Err(e) => debug!("failed to mount {}: {e}", esp_chardev_path.display()),
+            }
+        }
 
-            let disk_chardev_path = get_dev_path(devname);
+        if let Some(xbootldr_idx) = boot_partitions.xbootldr {
+            let xbootldr_chardev_path = disk.block_dev.partition_chardev_path(xbootldr_idx);
+            match disk.mount(&xbootldr_chardev_path, "xbootldr") {
+                Ok(mountpoint) => disk.xbootldr_mountpoint = Some(mountpoint),
+                Err(e) => debug!("failed to mount {}: {e}", xbootldr_chardev_path.display()),
+            }
+        }
 
-            let gpt_cfg = gpt::GptConfig::new().writable(false);
+        if disk.mountpoint.is_none() && disk.xbootldr_mountpoint.is_none() {
+            return None;
+        }
 
-            let boot_part_idx = {
-                if let Ok(Some(gpt_idx)) = gpt_cfg.open(&disk_chardev_path).map(|disk| {
-                    disk.partitions().iter().find_map(|(part_idx, part)| {
-                        if part.part_type_guid == gpt::partition_types::EFI {
-                            Some(part_idx.to_owned())
-                        } else {
-                            None
-                        }
-                    })
-                }) {
-                    gpt_idx
-                } else if let Ok(Ok(Some(mbr_idx))) =
-                    std::fs::File::open(&disk_chardev_path).map(|mut disk| {
-                        mbr::ProtectiveMBR::from_disk(&mut disk, gpt::disk::LogicalBlockSize::Lb512)
-                            .map(|mbr_disk| {
-                                for idx in 0u32..=3 {
-                                    let maybe_esp = mbr_disk.partition(idx as usize).unwrap();
-                                    // https://uapi-group.org/specifications/specs/boot_loader_specification/#the-partitions
-                                    if maybe_esp.os_type == 0xEA {
-                                        return Some(idx + 1);
-                                    }
-                                }
-
-                                None
-                            })
-                    })
-                {
-                    mbr_idx
-                } else {
-                    continue;
-                }
-            };
+        Some(disk)
+    }
 
-            let mut disk = Disk::new(diskseq, device_path.clone());
+    fn add_disks(&mut self, block_devs: Vec<BlockDevice>) {
+        let block_devs: Vec<BlockDevice> = block_devs
+            .into_iter()
+            .filter(|block_dev| {
+                !self
+                    .disks
+                    .iter()
+                    .any(|disk| disk.block_dev.diskseq == block_dev.diskseq)
+            })
+            .collect();
+
+        self.disks.extend(
+            probe_in_parallel(&block_devs, Self::mount_disk)
+                .into_iter()
+                .flatten(),
+        );
 
-            let partition_chardev_path = PathBuf::from("/dev/part")
-                .join(disk.diskseq.to_string())
-                .join(boot_part_idx.to_string());
+        // removable disks come first, like in block_devices
+        self.disks.sort_by_key(|disk| !disk.block_dev.removable);
+    }
+}
 
-            if let Err(e) = disk.mount(&partition_chardev_path) {
-                debug!("failed to mount {}: {e}", partition_chardev_path.display());
-                continue;
-            }
+impl BootLoader for BlsBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
 
-            // add the disk if it does not already exist
-            if self
-                .disks
-                .iter()
-                .find(|disk| disk.diskseq == diskseq)
-                .is_none()
-            {
-                self.disks.push(disk);
-            }
-        }
+        std::fs::create_dir_all(DISK_MNT_PATH).unwrap();
 
-        // prioritize disk by removable status
-        self.disks.sort_by(|a, b| {
-            if a.removable && !b.removable {
-                Ordering::Less
-            } else if !a.removable && b.removable {
-                Ordering::Greater
-            } else {
-                Ordering::Equal
-            }
-        });
+        self.add_disks(block_devices()?);
 
         Ok(())
     }
@@ -710,28 +1420,38 @@ impl BootLoader for BlsBootLoader {
         let mut devs = Vec::new();
 
         for disk in self.disks.iter_mut() {
-            if let Some(mountpoint) = &disk.mountpoint {
-                let loader_conf_path = mountpoint.join("loader/loader.conf");
-
-                let loader_conf_contents = match std::fs::read_to_string(&loader_conf_path) {
-                    Ok(l) => l,
-                    Err(e) => {
-                        debug!(
-                            "failed to read loader.conf {}: {e}",
-                            loader_conf_path.display()
-                        );
-                        continue;
+            // loader.conf is optional, an ESP may only contain type #2 entries and a disk may only
+            // have an XBOOTLDR partition
+            let loader_conf = match &disk.mountpoint {
+                Some(mountpoint) => {
+                    let loader_conf_path = mountpoint.join("loader/loader.conf");
+                    match std::fs::read_to_string(&loader_conf_path) {
+                        Ok(contents) => {
+                            let (loader_conf, errors) = LoaderConf::parse_loader_conf(&contents);
+                            for (line, e) in errors {
+                                warn!("{}:{line}: {e}", loader_conf_path.display());
+                            }
+                            loader_conf
+                        }
+                        Err(e) => {
+                            debug!(
+                                "failed to read loader.conf {}: {e}",
+                                loader_conf_path.display()
+                            );
+                            LoaderConf::default()
+                        }
                     }
-                };
-
-                let loader_conf = LoaderConf::parse_loader_conf(&loader_conf_contents);
+                }
+                None => LoaderConf::default(),
+            };
 
-                disk.timeout = loader_conf.timeout;
+            disk.loader_conf = loader_conf;
 
-                disk.discover_entries(loader_conf.default_entry);
+            // disks are probed again whenever other disks are added or removed
+            disk.entries.clear();
+            disk.discover_entries();
 
-                devs.push(disk.to_owned().into());
-            }
+            devs.push(disk.to_owned().into());
         }
 
         devs
@@ -740,7 +1460,7 @@ impl BootLoader for BlsBootLoader {
     fn teardown(&mut self) {
         debug!("teardown");
 
-        for disk in &self.disks {
+        for disk in self.disks.drain(..) {
             disk.unmount();
         }
 
@@ -749,23 +1469,114 @@ impl BootLoader for BlsBootLoader {
         }
     }
 
+    fn disks_changed(&mut self, events: &[DiskEvent]) -> bool {
+        let (added, removed) = changed_disks(events);
+        let count = self.disks.len();
+
+        self.disks.retain(|disk| {
+            let is_removed = removed.contains(&disk.block_dev.diskseq);
+            if is_removed {
+                disk.unmount();
+            }
+            !is_removed
+        });
+
+        let removed_any = self.disks.len() != count;
+        let count = self.disks.len();
+
+        self.add_disks(added);
+
+        removed_any || self.disks.len() != count
+    }
+
     fn loader_type(&mut self) -> super::LoaderType {
         LoaderType::Disk
     }
 }
 
+#[derive(Debug, Default, PartialEq)]
+struct BootPartitions {
+    esp: Option<u32>,
+    xbootldr: Option<u32>,
+}
+
+/// Finds the indices of the ESP and the Extended Boot Loader partition on a disk.
+/// https://uapi-group.org/specifications/specs/boot_loader_specification/#the-partitions
+fn find_boot_partitions(disk_chardev_path: &Path) -> BootPartitions {
+    let gpt_cfg = gpt::GptConfig::new().writable(false);
+
+    if let Ok(disk) = gpt_cfg.open(disk_chardev_path) {
+        let find_partition = |part_type: &gpt::partition_types::Type| {
+            disk.partitions().iter().find_map(|(part_idx, part)| {
+                (part.part_type_guid == *part_type).then_some(*part_idx)
+            })
+        };
+
+        return BootPartitions {
+            esp: find_partition(&gpt::partition_types::EFI),
+            xbootldr: find_partition(&gpt::partition_types::FREEDESK_BOOT),
+        };
+    }
+
+    let Ok(Ok(mbr_disk)) = std::fs::File::open(disk_chardev_path).map(|mut disk| {
+        mbr::ProtectiveMBR::from_disk(&mut disk, gpt::disk::LogicalBlockSize::Lb512)
+    }) else {
+        return BootPartitions::default();
+    };
+
+    let find_partition = |os_type: u8| {
+        (0u32..=3)
+            .find(|idx| {
+                mbr_disk
+                    .partition(*idx as usize)
+                    .map(|part| part.os_type == os_type)
+                    .unwrap_or_default()
+            })
+            .map(|idx| idx + 1)
+    };
+
+    BootPartitions {
+        esp: find_partition(0xEF),
+        xbootldr: find_partition(0xEA),
+    }
+}
+
+/// Parses the contents of an os-release file into a map of keys to unquoted values.
+/// https://www.freedesktop.org/software/systemd/man/latest/os-release.html
+fn parse_os_release(contents: &str) -> HashMap<&str, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, val)| {
+            let val = val
+                .strip_prefix('"')
+                .and_then(|val| val.strip_suffix('"'))
+                .or_else(|| {
+                    val.strip_prefix('\'')
+                        .and_then(|val| val.strip_suffix('\''))
+                })
+                .unwrap_or(val);
+
+            (key, val.to_string())
+        })
+        .collect()
+}
+
 fn get_dev_path(devname: &str) -> PathBuf {
     PathBuf::from("/dev").join(devname)
 }
 
-fn get_uevent(sys_dev: &Path) -> HashMap<String, String> {
+fn get_uevent(sys_dev: &Path) -> std::io::Result<HashMap<String, String>> {
     let uevent = sys_dev.join("uevent");
-    let contents = std::fs::read_to_string(uevent).unwrap();
-    tboot::dev::parse_uevent(contents)
+    let contents = std::fs::read_to_string(uevent)?;
+    Ok(tboot::dev::parse_uevent(contents))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Cursor;
     use std::path::Path;
     use std::path::PathBuf;
 
@@ -806,4 +1617,462 @@ machine-id 00000000000000000000000000000000
             vec!["init=/nix/store/00000000000000000000000000000000-nixos-system-beetroot-23.05.20230506.0000000/init".to_string(), "systemd.show_status=auto".to_string(), "loglevel=4".to_string()]
         );
     }
+
+    #[test]
+    fn test_parse_uki() {
+        let uki = crate::pe::tests::build_pe(
+            crate::pe::tests::MACHINE_X64,
+            &[
+                (
+                    ".osrel",
+                    b"NAME=NixOS\nID=nixos\nVERSION_ID=\"23.11\"\nPRETTY_NAME=\"NixOS 23.11 (Tapir)\"\n",
+                ),
+                (".cmdline", b"init=/nix/store/foo/init loglevel=4\0"),
+                (".uname", b"6.1.27"),
+                (".linux", b"kernel"),
+                (".initrd", b"initrd"),
+            ],
+        );
+
+        let entry = super::BlsEntry::parse_uki(
+            Path::new("/foo/EFI/Linux/nixos-6.1.27+3-0.efi"),
+            Cursor::new(&uki),
+        )
+        .unwrap();
+
+        assert_eq!(entry.entry_type, super::EntryType::Uki);
+        assert_eq!(entry.name, String::from("nixos-6.1.27"));
+        assert_eq!(entry.tries_left, Some(3));
+        assert_eq!(entry.tries_done, Some(0));
+        assert_eq!(entry.architecture, Some(super::EfiArch::X64));
+        assert_eq!(entry.title, Some(String::from("NixOS 23.11 (Tapir)")));
+        assert_eq!(entry.version, Some(String::from("23.11")));
+        assert_eq!(entry.sort_key, Some(String::from("nixos")));
+        assert_eq!(
+            entry.options,
+            vec![
+                "init=/nix/store/foo/init".to_string(),
+                "loglevel=4".to_string()
+            ]
+        );
+        assert_eq!(entry.pretty_name, String::from("NixOS 23.11 (Tapir)"));
+
+        let uki = crate::pe::tests::build_pe(
+            crate::pe::tests::MACHINE_X64,
+            &[
+                (".osrel", b"NAME=NixOS\nID=nixos\nVERSION_ID=\"23.11\"\n"),
+                (".linux", b"kernel"),
+            ],
+        );
+        let entry =
+            super::BlsEntry::parse_uki(Path::new("/foo/EFI/Linux/nixos.efi"), Cursor::new(&uki))
+                .unwrap();
+        assert_eq!(entry.pretty_name, String::from("NixOS 23.11"));
+    }
+
+    #[test]
+    fn test_parse_uki_without_linux() {
+        let uki =
+            crate::pe::tests::build_pe(crate::pe::tests::MACHINE_X64, &[(".osrel", b"ID=nixos\n")]);
+
+        assert!(super::BlsEntry::parse_uki(
+            Path::new("/foo/EFI/Linux/nixos.efi"),
+            Cursor::new(&uki)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_boot_order() {
+        fn entry(
+            name: &str,
+            sort_key: Option<&str>,
+            machine_id: Option<&str>,
+            version: Option<&str>,
+            tries_left: Option<u32>,
+        ) -> super::BlsEntry {
+            super::BlsEntry {
+                name: name.to_string(),
+                sort_key: sort_key.map(String::from),
+                machine_id: machine_id.map(String::from),
+                version: version.map(String::from),
+                tries_left,
+                ..Default::default()
+            }
+        }
+
+        let mut entries = vec![
+            entry("no-sort-key-1", None, None, None, None),
+            entry("bad", Some("a"), None, Some("7.0"), Some(0)),
+            entry("fedora-6.9", Some("fedora"), None, Some("6.9"), None),
+            entry("no-sort-key-2", None, None, None, None),
+            entry("nixos-6.9", Some("nixos"), Some("1"), Some("6.9"), None),
+            entry(
+                "nixos-6.10",
+                Some("nixos"),
+                Some("1"),
+                Some("6.10"),
+                Some(2),
+            ),
+            entry("nixos-other", Some("nixos"), Some("2"), Some("6.11"), None),
+            entry("fedora-6.10", Some("fedora"), None, Some("6.10"), None),
+        ];
+
+        entries.sort_by(super::BlsEntry::cmp_boot_order);
+
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.name.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "fedora-6.10",
+                "fedora-6.9",
+                "nixos-6.10",
+                "nixos-6.9",
+                "nixos-other",
+                "no-sort-key-2",
+                "no-sort-key-1",
+                "bad",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disk_selectors() {
+        use crate::boot_loader::DeviceSelector;
+
+        for (device_path, expected) in [
+            (
+                "/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host0/target0:0:0/0:0:0:0",
+                vec![DeviceSelector::Usb],
+            ),
+            (
+                "/sys/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0",
+                vec![DeviceSelector::Nvme],
+            ),
+            (
+                "/sys/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0",
+                vec![DeviceSelector::Sata],
+            ),
+            (
+                "/sys/devices/pci0000:00/0000:00:1a.0/mmc_host/mmc0/mmc0:0001",
+                vec![DeviceSelector::Mmc],
+            ),
+            (
+                "/sys/devices/pci0000:00/0000:00:04.0/virtio1",
+                vec![DeviceSelector::Virtio],
+            ),
+            ("/sys/devices/platform/unknown", vec![]),
+        ] {
+            let mut block_dev =
+                super::BlockDevice::new(0, PathBuf::from(device_path), PathBuf::from("/dev/sda"));
+            assert_eq!(block_dev.selectors(), expected, "{device_path}");
+
+            block_dev.removable = true;
+            assert_eq!(
+                block_dev.selectors().last(),
+                Some(&DeviceSelector::Removable)
+            );
+        }
+    }
+
+    #[test]
+    fn test_discover_xbootldr_entries() {
+        let root = std::env::temp_dir().join(format!("tboot-xbootldr-{}", std::process::id()));
+        let esp = root.join("esp");
+        let xbootldr = root.join("xbootldr");
+
+        for (mountpoint, name) in [(&esp, "esp-entry"), (&xbootldr, "xbootldr-entry")] {
+            std::fs::create_dir_all(mountpoint.join("loader/entries")).unwrap();
+            std::fs::write(
+                mountpoint.join(format!("loader/entries/{name}.conf")),
+                format!("title {name}\nlinux /{name}/linux\ninitrd /{name}/initrd\n"),
+            )
+            .unwrap();
+        }
+
+        let mut disk = super::Disk::new(super::BlockDevice::new(
+            0,
+            root.join("device"),
+            PathBuf::from("/dev/sda"),
+        ));
+        disk.mountpoint = Some(esp.clone());
+        disk.xbootldr_mountpoint = Some(xbootldr.clone());
+        disk.loader_conf.default_entry = Some(String::from("xbootldr-entry"));
+        disk.discover_entries();
+
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(disk.entries.len(), 2);
+
+        let esp_entry = disk
+            .entries
+            .iter()
+            .find(|entry| entry.name == "esp-entry")
+            .unwrap();
+        assert_eq!(esp_entry.linux, Some(esp.join("esp-entry/linux")));
+        assert!(!esp_entry.is_default);
+
+        let xbootldr_entry = disk
+            .entries
+            .iter()
+            .find(|entry| entry.name == "xbootldr-entry")
+            .unwrap();
+        assert_eq!(
+            xbootldr_entry.linux,
+            Some(xbootldr.join("xbootldr-entry/linux"))
+        );
+        assert_eq!(
+            xbootldr_entry.initrd,
+            Some(vec![xbootldr.join("xbootldr-entry/initrd")])
+        );
+        assert!(xbootldr_entry.is_default);
+    }
+
+    #[test]
+    fn test_parse_loader_conf() {
+        let (loader_conf, errors) = super::LoaderConf::parse_loader_conf(
+            r#"# comment
+timeout 3
+default nixos-*
+editor no
+auto-entries 0
+console-mode max
+timeoutfoo 10
+timeout soon
+auto-reboot
+auto-poweroff yes
+auto-firmware yes
+auto-entries maybe
+"#,
+        );
+
+        assert_eq!(
+            loader_conf,
+            super::LoaderConf {
+                default_entry: Some(String::from("nixos-*")),
+                timeout: super::Timeout::Countdown(std::time::Duration::from_secs(3)),
+                editor: false,
+                auto_poweroff: true,
+                auto_reboot: false,
+            }
+        );
+
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    7,
+                    super::LoaderConfError::UnknownKey(String::from("timeoutfoo"))
+                ),
+                (
+                    8,
+                    super::LoaderConfError::InvalidValue(
+                        String::from("timeout"),
+                        String::from("soon")
+                    )
+                ),
+                (
+                    9,
+                    super::LoaderConfError::MissingValue(String::from("auto-reboot"))
+                ),
+                (
+                    11,
+                    super::LoaderConfError::Unsupported(String::from("auto-firmware"))
+                ),
+                (
+                    12,
+                    super::LoaderConfError::InvalidValue(
+                        String::from("auto-entries"),
+                        String::from("maybe")
+                    )
+                ),
+            ]
+        );
+
+        for (timeout, expected) in [
+            ("menu-force", super::Timeout::MenuForce),
+            ("menu-hidden", super::Timeout::MenuHidden),
+            ("0", super::Timeout::MenuHidden),
+        ] {
+            let (loader_conf, errors) =
+                super::LoaderConf::parse_loader_conf(&format!("timeout {timeout}\n"));
+            assert_eq!(loader_conf.timeout, expected);
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(super::glob_match("nixos-*", "nixos-generation-1"));
+        assert!(super::glob_match("*.conf", "nixos-generation-1.conf"));
+        assert!(super::glob_match(
+            "nixos-generation-?",
+            "nixos-generation-1"
+        ));
+        assert!(super::glob_match(
+            "nixos-generation-[0-9]",
+            "nixos-generation-1"
+        ));
+        assert!(super::glob_match(
+            "nixos-generation-[!a-z]",
+            "nixos-generation-1"
+        ));
+        assert!(super::glob_match("nixos", "nixos"));
+        assert!(!super::glob_match("nixos-*", "fedora-1"));
+        assert!(!super::glob_match(
+            "nixos-generation-?",
+            "nixos-generation-10"
+        ));
+        assert!(!super::glob_match(
+            "nixos-generation-[2-9]",
+            "nixos-generation-1"
+        ));
+        assert!(super::glob_match("[", "["));
+    }
+
+    #[test]
+    fn test_probe_in_parallel() {
+        let disks = [3u64, 1, 2];
+        assert_eq!(
+            super::probe_in_parallel(&disks, |diskseq| {
+                std::thread::sleep(std::time::Duration::from_millis(*diskseq * 10));
+                diskseq * 2
+            }),
+            vec![6, 2, 4]
+        );
+    }
+
+    #[test]
+    fn test_default_entry_glob() {
+        let mut disk = super::Disk::new(super::BlockDevice::new(
+            0,
+            PathBuf::from("/nonexistent"),
+            PathBuf::from("/dev/sda"),
+        ));
+        disk.loader_conf.default_entry = Some(String::from("nixos-generation-*.conf"));
+
+        for name in ["nixos-generation-1", "nixos-generation-2", "other"] {
+            disk.entries.push(super::BlsEntry {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        }
+        disk.entries.sort_by(super::BlsEntry::cmp_boot_order);
+
+        // nothing is found on a nonexistent mountpoint, but the default is still picked
+        disk.mountpoint = Some(PathBuf::from("/nonexistent"));
+        disk.discover_entries();
+
+        assert_eq!(
+            disk.entries
+                .iter()
+                .filter(|entry| entry.is_default)
+                .map(|entry| entry.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["nixos-generation-2"]
+        );
+    }
+
+    #[test]
+    fn test_saved_entry() {
+        let esp = std::env::temp_dir().join(format!("tboot-saved-{}", std::process::id()));
+        std::fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        for name in ["nixos-generation-1", "nixos-generation-2"] {
+            std::fs::write(
+                esp.join(format!("loader/entries/{name}.conf")),
+                format!("title {name}\nlinux /linux\n"),
+            )
+            .unwrap();
+        }
+
+        let discover = || {
+            let mut disk = super::Disk::new(super::BlockDevice::new(
+                0,
+                esp.join("device"),
+                PathBuf::from("/dev/sda"),
+            ));
+            disk.mountpoint = Some(esp.clone());
+            disk.loader_conf.default_entry = Some(String::from(super::SAVED_ENTRY));
+            disk.discover_entries();
+            disk
+        };
+
+        let default_entry = |disk: &super::Disk| {
+            disk.entries
+                .iter()
+                .find(|entry| entry.is_default)
+                .map(|entry| entry.name.clone())
+        };
+
+        // nothing has been saved yet
+        let disk = discover();
+        assert_eq!(default_entry(&disk), None);
+
+        let older = disk
+            .entries
+            .iter()
+            .find(|entry| entry.name == "nixos-generation-1")
+            .unwrap();
+        super::BootEntry::save(older).unwrap();
+
+        let saved = std::fs::read_to_string(esp.join(super::SAVED_ENTRY_PATH)).unwrap();
+        let disk = discover();
+
+        std::fs::remove_dir_all(&esp).unwrap();
+
+        assert_eq!(saved, "nixos-generation-1.conf\n");
+        assert_eq!(
+            default_entry(&disk),
+            Some(String::from("nixos-generation-1"))
+        );
+    }
+
+    #[test]
+    fn test_oneshot_entry() {
+        let esp = std::env::temp_dir().join(format!("tboot-oneshot-{}", std::process::id()));
+        std::fs::create_dir_all(esp.join("loader/entries")).unwrap();
+        for name in ["nixos-generation-1", "nixos-generation-2"] {
+            std::fs::write(
+                esp.join(format!("loader/entries/{name}.conf")),
+                format!("title {name}\nlinux /linux\n"),
+            )
+            .unwrap();
+        }
+
+        let default_entry = || {
+            let mut disk = super::Disk::new(super::BlockDevice::new(
+                0,
+                esp.join("device"),
+                PathBuf::from("/dev/sda"),
+            ));
+            disk.mountpoint = Some(esp.clone());
+            disk.loader_conf.default_entry = Some(String::from("nixos-generation-2"));
+            disk.discover_entries();
+            disk.entries
+                .into_iter()
+                .find(|entry| entry.is_default)
+                .unwrap()
+        };
+
+        let default = default_entry();
+        assert_eq!(default.name, "nixos-generation-2");
+
+        let older = super::BlsEntry {
+            name: String::from("nixos-generation-1"),
+            ..default.clone()
+        };
+        super::BootEntry::set_oneshot(&older).unwrap();
+
+        let oneshot = default_entry();
+        assert_eq!(oneshot.name, "nixos-generation-1");
+
+        super::BootEntry::select(&oneshot).unwrap();
+        let after_oneshot = default_entry();
+
+        std::fs::remove_dir_all(&esp).unwrap();
+
+        assert_eq!(after_oneshot.name, "nixos-generation-2");
+    }
 }
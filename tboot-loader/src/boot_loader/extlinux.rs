@@ -0,0 +1,700 @@
+use gpt::mbr;
+use log::{debug, error, warn};
+use nix::mount::{self, MntFlags};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tboot::dev::DiskEvent;
+
+use super::{
+    disk::{block_devices, changed_disks, mount_partition, probe_in_parallel, BlockDevice},
+    BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout,
+};
+use crate::fdt::{self, Fdt};
+
+const EXTLINUX_MNT_PATH: &str = "/mnt/extlinux";
+
+/// Where U-Boot looks for extlinux.conf on a partition.
+const EXTLINUX_CONF_PATHS: &[&str] = &["extlinux/extlinux.conf", "boot/extlinux/extlinux.conf"];
+
+/// Used when extlinux.conf has no TIMEOUT.
+const EXTLINUX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// GPT partition attribute that U-Boot uses to find the partitions to boot from.
+const LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+/// Boot indicator of an active MBR partition.
+const MBR_ACTIVE: u8 = 0x80;
+
+/// A LABEL block of extlinux.conf.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ExtlinuxLabel {
+    label: String,
+    menu_label: Option<String>,
+    kernel: Option<String>,
+    initrd: Vec<String>,
+    append: Option<String>,
+    fdt: Option<String>,
+    /// A directory of devicetrees to pick the one matching the machine from.
+    fdtdir: Option<String>,
+    fdtoverlays: Vec<String>,
+    /// Set by MENU DEFAULT.
+    menu_default: bool,
+}
+
+// Documentation: https://wiki.syslinux.org/wiki/index.php?title=Config and
+// https://github.com/u-boot/u-boot/blob/master/doc/develop/distro.rst
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ExtlinuxConf {
+    default: Option<String>,
+    /// In tenths of a second.
+    timeout: Option<u32>,
+    labels: Vec<ExtlinuxLabel>,
+}
+
+impl ExtlinuxConf {
+    /// Parses extlinux.conf. Keywords are case-insensitive and the ones tinyboot has no use for
+    /// are skipped. An APPEND before the first LABEL is used by labels that have none.
+    fn parse(contents: &str) -> Self {
+        let mut conf = Self::default();
+        let mut global_append = None;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, val) = match line.split_once(char::is_whitespace) {
+                Some((keyword, val)) => (keyword.to_ascii_lowercase(), val.trim()),
+                None => (line.to_ascii_lowercase(), ""),
+            };
+
+            let (keyword, val) = if keyword == "menu" {
+                match val.split_once(char::is_whitespace) {
+                    Some((keyword, val)) => {
+                        (format!("menu {}", keyword.to_ascii_lowercase()), val.trim())
+                    }
+                    None => (format!("menu {}", val.to_ascii_lowercase()), ""),
+                }
+            } else {
+                (keyword, val)
+            };
+
+            if keyword == "label" {
+                conf.labels.push(ExtlinuxLabel {
+                    label: val.to_string(),
+                    ..Default::default()
+                });
+                continue;
+            }
+
+            let Some(label) = conf.labels.last_mut() else {
+                match keyword.as_str() {
+                    "default" => conf.default = Some(val.to_string()),
+                    "timeout" => match val.parse() {
+                        Ok(timeout) => conf.timeout = Some(timeout),
+                        Err(_) => warn!("invalid timeout '{val}'"),
+                    },
+                    "append" => global_append = Some(val.to_string()),
+                    _ => debug!("ignoring '{line}'"),
+                }
+                continue;
+            };
+
+            match keyword.as_str() {
+                "kernel" | "linux" => label.kernel = Some(val.to_string()),
+                "initrd" => label.initrd.extend(
+                    val.split(',')
+                        .map(str::trim)
+                        .filter(|initrd| !initrd.is_empty())
+                        .map(str::to_string),
+                ),
+                "append" => label.append = Some(val.to_string()),
+                "fdt" | "devicetree" => label.fdt = Some(val.to_string()),
+                "fdtdir" | "devicetreedir" => label.fdtdir = Some(val.to_string()),
+                "fdtoverlays" | "devicetree-overlay" => label
+                    .fdtoverlays
+                    .extend(val.split_whitespace().map(str::to_string)),
+                "menu label" => label.menu_label = Some(val.to_string()),
+                "menu default" => label.menu_default = true,
+                _ => debug!("ignoring '{line}'"),
+            }
+        }
+
+        if let Some(global_append) = global_append {
+            for label in conf
+                .labels
+                .iter_mut()
+                .filter(|label| label.append.is_none())
+            {
+                label.append = Some(global_append.clone());
+            }
+        }
+
+        conf
+    }
+
+    /// The label named by DEFAULT, else the one with MENU DEFAULT, else the first one.
+    fn default_label(&self) -> Option<&str> {
+        self.default
+            .as_deref()
+            .filter(|default| self.labels.iter().any(|label| label.label == *default))
+            .or_else(|| {
+                self.labels
+                    .iter()
+                    .find(|label| label.menu_default)
+                    .map(|label| label.label.as_str())
+            })
+            .or_else(|| self.labels.first().map(|label| label.label.as_str()))
+    }
+
+    fn timeout(&self) -> Timeout {
+        match self.timeout {
+            None => Timeout::Countdown(EXTLINUX_TIMEOUT),
+            // syslinux waits for the user when the timeout is 0
+            Some(0) => Timeout::MenuForce,
+            Some(tenths) => Timeout::Countdown(Duration::from_millis(tenths as u64 * 100)),
+        }
+    }
+}
+
+/// Picks the devicetree for the machine out of a directory, which may have a subdirectory per
+/// vendor. The devicetree matching the machine's most specific compatible string wins.
+fn find_devicetree(fdtdir: &Path, machine_compatible: &[String]) -> Option<PathBuf> {
+    let mut dtbs = Vec::new();
+    let mut dirs = vec![(fdtdir.to_path_buf(), 0)];
+
+    while let Some((dir, depth)) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() && depth == 0 {
+                dirs.push((path, depth + 1));
+            } else if path.extension().is_some_and(|ext| ext == "dtb") {
+                dtbs.push(path);
+            }
+        }
+    }
+
+    dtbs.sort();
+
+    dtbs.into_iter()
+        .filter_map(|dtb| {
+            let fdt = Fdt::parse(&std::fs::read(&dtb).ok()?).ok()?;
+            let compatible = fdt::prop_str_list(fdt.root.prop("compatible")?);
+            let rank = machine_compatible
+                .iter()
+                .position(|machine| compatible.contains(machine))?;
+            Some((rank, dtb))
+        })
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, dtb)| dtb)
+}
+
+#[derive(Clone, Debug)]
+struct ExtlinuxEntry {
+    label: ExtlinuxLabel,
+    /// Where the partition extlinux.conf was found on is mounted, absolute paths are relative to
+    /// it.
+    root: PathBuf,
+    /// The directory extlinux.conf is in, relative paths are relative to it.
+    conf_dir: PathBuf,
+    is_default: bool,
+}
+
+impl Display for ExtlinuxEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.label.menu_label.as_ref().unwrap_or(&self.label.label)
+        )
+    }
+}
+
+impl BootEntry for ExtlinuxEntry {
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    fn linux(&self) -> Option<PathBuf> {
+        self.label
+            .kernel
+            .as_deref()
+            .map(|kernel| self.resolve(kernel))
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        let Some(kernel) = &self.label.kernel else {
+            anyhow::bail!("'{self}' has no kernel");
+        };
+
+        let devicetree = match (&self.label.fdt, &self.label.fdtdir) {
+            (Some(fdt), _) => Some(self.resolve(fdt)),
+            (None, Some(fdtdir)) => {
+                let devicetree = find_devicetree(&self.resolve(fdtdir), &fdt::machine_compatible());
+                if devicetree.is_none() {
+                    warn!("no devicetree in {fdtdir} matches the machine");
+                }
+                devicetree
+            }
+            (None, None) => None,
+        };
+
+        Ok(LinuxBootParts {
+            linux: self.resolve(kernel),
+            initrd: self
+                .label
+                .initrd
+                .iter()
+                .map(|initrd| self.resolve(initrd))
+                .collect(),
+            cmdline: self.label.append.clone(),
+            devicetree,
+            devicetree_overlay: self
+                .label
+                .fdtoverlays
+                .iter()
+                .map(|overlay| self.resolve(overlay))
+                .collect(),
+            initrd_verified: false,
+        })
+    }
+}
+
+impl ExtlinuxEntry {
+    fn resolve(&self, path: &str) -> PathBuf {
+        match path.strip_prefix('/') {
+            Some(path) => self.root.join(path),
+            None => self.conf_dir.join(path),
+        }
+    }
+}
+
+/// A partition with an extlinux.conf on it.
+struct ExtlinuxPartition {
+    block_dev: BlockDevice,
+    mountpoint: PathBuf,
+    conf_path: PathBuf,
+}
+
+impl ExtlinuxPartition {
+    fn boot_device(&self) -> anyhow::Result<BootDevice> {
+        let conf = ExtlinuxConf::parse(&std::fs::read_to_string(&self.conf_path)?);
+        let default_label = conf.default_label().map(str::to_string);
+
+        let conf_dir = self
+            .conf_path
+            .parent()
+            .unwrap_or(&self.mountpoint)
+            .to_path_buf();
+
+        let entries = conf
+            .labels
+            .iter()
+            .filter(|label| {
+                if label.kernel.is_none() {
+                    debug!("skipping label '{}' without a kernel", label.label);
+                }
+                label.kernel.is_some()
+            })
+            .map(|label| {
+                Box::new(ExtlinuxEntry {
+                    is_default: default_label.as_deref() == Some(label.label.as_str()),
+                    label: label.clone(),
+                    root: self.mountpoint.clone(),
+                    conf_dir: conf_dir.clone(),
+                }) as Box<dyn BootEntry>
+            })
+            .collect();
+
+        Ok(BootDevice {
+            name: format!("{} (extlinux)", self.block_dev.name()),
+            selectors: self.block_dev.selectors(),
+            entries,
+            timeout: conf.timeout(),
+            editor: true,
+        })
+    }
+}
+
+/// Finds extlinux.conf below the root of a partition.
+fn find_conf(mountpoint: &Path) -> Option<PathBuf> {
+    EXTLINUX_CONF_PATHS
+        .iter()
+        .map(|conf_path| mountpoint.join(conf_path))
+        .find(|conf_path| conf_path.is_file())
+}
+
+/// Lists the partitions U-Boot would look for boot files like extlinux.conf on, which are the
+/// ones marked bootable, or all of them if none are.
+pub(super) fn candidate_partitions(disk_chardev_path: &Path) -> Vec<u32> {
+    let gpt_cfg = gpt::GptConfig::new().writable(false);
+
+    let partitions: Vec<(u32, bool)> = if let Ok(disk) = gpt_cfg.open(disk_chardev_path) {
+        disk.partitions()
+            .iter()
+            .map(|(part_idx, part)| (*part_idx, part.flags & LEGACY_BIOS_BOOTABLE != 0))
+            .collect()
+    } else if let Ok(Ok(mbr_disk)) = std::fs::File::open(disk_chardev_path).map(|mut disk| {
+        mbr::ProtectiveMBR::from_disk(&mut disk, gpt::disk::LogicalBlockSize::Lb512)
+    }) {
+        (0u32..=3)
+            .filter_map(|idx| {
+                let part = mbr_disk.partition(idx as usize)?;
+                (part.os_type != 0).then_some((idx + 1, part.boot_indicator == MBR_ACTIVE))
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let bootable: Vec<u32> = partitions
+        .iter()
+        .filter(|(_, bootable)| *bootable)
+        .map(|(part_idx, _)| *part_idx)
+        .collect();
+
+    if bootable.is_empty() {
+        partitions
+            .into_iter()
+            .map(|(part_idx, _)| part_idx)
+            .collect()
+    } else {
+        bootable
+    }
+}
+
+/// ExtlinuxBootLoader boots the extlinux.conf files that distributions targeting U-Boot ship,
+/// either on the ESP, a separate /boot partition or the root partition.
+#[derive(Default)]
+pub struct ExtlinuxBootLoader {
+    partitions: Vec<ExtlinuxPartition>,
+}
+
+impl ExtlinuxBootLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts the partitions of a disk that have an extlinux.conf.
+    fn mount_partitions(block_dev: &BlockDevice) -> Vec<ExtlinuxPartition> {
+        let mut partitions = Vec::new();
+
+        for part_idx in candidate_partitions(&block_dev.chardev_path) {
+            let partition_chardev_path = block_dev.partition_chardev_path(part_idx);
+            let mountpoint = PathBuf::from(EXTLINUX_MNT_PATH)
+                .join(block_dev.diskseq.to_string())
+                .join(part_idx.to_string());
+
+            if let Err(e) = mount_partition(&partition_chardev_path, &mountpoint, true) {
+                debug!("failed to mount {}: {e}", partition_chardev_path.display());
+                continue;
+            }
+
+            match find_conf(&mountpoint) {
+                Some(conf_path) => {
+                    debug!("found {}", conf_path.display());
+                    partitions.push(ExtlinuxPartition {
+                        block_dev: block_dev.clone(),
+                        mountpoint,
+                        conf_path,
+                    });
+                }
+                None => {
+                    if let Err(e) = mount::umount2(&mountpoint, MntFlags::MNT_DETACH) {
+                        error!("failed to unmount {}: {e}", mountpoint.display());
+                    }
+                }
+            }
+        }
+
+        partitions
+    }
+
+    fn add_disks(&mut self, block_devs: Vec<BlockDevice>) {
+        let block_devs: Vec<BlockDevice> = block_devs
+            .into_iter()
+            .filter(|block_dev| {
+                !self
+                    .partitions
+                    .iter()
+                    .any(|partition| partition.block_dev.diskseq == block_dev.diskseq)
+            })
+            .collect();
+
+        self.partitions.extend(
+            probe_in_parallel(&block_devs, Self::mount_partitions)
+                .into_iter()
+                .flatten(),
+        );
+
+        // removable disks come first, like in block_devices
+        self.partitions
+            .sort_by_key(|partition| !partition.block_dev.removable);
+    }
+}
+
+impl BootLoader for ExtlinuxBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        self.add_disks(block_devices()?);
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        let mut devs = Vec::new();
+
+        for partition in &self.partitions {
+            match partition.boot_device() {
+                Ok(dev) => devs.push(dev),
+                Err(e) => warn!("{}: {e}", partition.conf_path.display()),
+            }
+        }
+
+        devs
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        for partition in self.partitions.drain(..) {
+            if let Err(e) = mount::umount2(&partition.mountpoint, MntFlags::MNT_DETACH) {
+                error!("failed to unmount {}: {e}", partition.mountpoint.display());
+            }
+        }
+
+        if let Err(e) = std::fs::remove_dir_all(EXTLINUX_MNT_PATH) {
+            error!("failed to remove {}: {e}", EXTLINUX_MNT_PATH);
+        }
+    }
+
+    fn disks_changed(&mut self, events: &[DiskEvent]) -> bool {
+        let (added, removed) = changed_disks(events);
+        let count = self.partitions.len();
+
+        self.partitions.retain(|partition| {
+            let is_removed = removed.contains(&partition.block_dev.diskseq);
+            if is_removed {
+                if let Err(e) = mount::umount2(&partition.mountpoint, MntFlags::MNT_DETACH) {
+                    error!("failed to unmount {}: {e}", partition.mountpoint.display());
+                }
+            }
+            !is_removed
+        });
+
+        let removed_any = self.partitions.len() != count;
+        let count = self.partitions.len();
+
+        self.add_disks(added);
+
+        removed_any || self.partitions.len() != count
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::Extlinux
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use crate::{
+        boot_loader::{BootEntry, Timeout},
+        fdt::{Fdt, Node},
+    };
+
+    use super::{ExtlinuxConf, ExtlinuxEntry, ExtlinuxLabel};
+
+    #[test]
+    fn test_parse_extlinux_conf() {
+        // as written by Debian's u-boot-menu
+        let conf = ExtlinuxConf::parse(
+            r#"## /boot/extlinux/extlinux.conf
+##
+## IMPORTANT WARNING
+##
+## The configuration of this file is generated automatically.
+
+default l0
+menu title U-Boot menu
+prompt 0
+timeout 50
+
+
+label l0
+	menu label Debian GNU/Linux trixie/sid 6.6.15-arm64
+	linux /boot/vmlinuz-6.6.15-arm64
+	initrd /boot/initrd.img-6.6.15-arm64
+	fdtdir /usr/lib/linux-image-6.6.15-arm64/
+	append root=UUID=5d6e2a8c ro quiet
+
+label l0r
+	menu label Debian GNU/Linux trixie/sid 6.6.15-arm64 (rescue target)
+	linux /boot/vmlinuz-6.6.15-arm64
+	initrd /boot/initrd.img-6.6.15-arm64
+	fdtdir /usr/lib/linux-image-6.6.15-arm64/
+	APPEND root=UUID=5d6e2a8c ro single
+"#,
+        );
+
+        assert_eq!(conf.default_label(), Some("l0"));
+        assert_eq!(conf.timeout(), Timeout::Countdown(Duration::from_secs(5)));
+        assert_eq!(conf.labels.len(), 2);
+        assert_eq!(
+            conf.labels[0],
+            ExtlinuxLabel {
+                label: "l0".to_string(),
+                menu_label: Some("Debian GNU/Linux trixie/sid 6.6.15-arm64".to_string()),
+                kernel: Some("/boot/vmlinuz-6.6.15-arm64".to_string()),
+                initrd: vec!["/boot/initrd.img-6.6.15-arm64".to_string()],
+                append: Some("root=UUID=5d6e2a8c ro quiet".to_string()),
+                fdt: None,
+                fdtdir: Some("/usr/lib/linux-image-6.6.15-arm64/".to_string()),
+                fdtoverlays: Vec::new(),
+                menu_default: false,
+            }
+        );
+        assert_eq!(
+            conf.labels[1].append.as_deref(),
+            Some("root=UUID=5d6e2a8c ro single")
+        );
+
+        let conf = ExtlinuxConf::parse(
+            r#"TIMEOUT 0
+APPEND console=ttyS2,1500000
+LABEL first
+    KERNEL ../Image
+    INITRD ../initrd-a, ../initrd-b
+    FDT ../rk3399-rockpro64.dtb
+    FDTOVERLAYS ../a.dtbo ../b.dtbo
+LABEL second
+    MENU DEFAULT
+    KERNEL ../Image
+    APPEND console=tty0
+LABEL local
+    LOCALBOOT 0
+"#,
+        );
+
+        assert_eq!(conf.default_label(), Some("second"));
+        assert_eq!(conf.timeout(), Timeout::MenuForce);
+        assert_eq!(
+            conf.labels[0].initrd,
+            vec!["../initrd-a".to_string(), "../initrd-b".to_string()]
+        );
+        assert_eq!(
+            conf.labels[0].append.as_deref(),
+            Some("console=ttyS2,1500000")
+        );
+        assert_eq!(conf.labels[0].fdtoverlays.len(), 2);
+        assert_eq!(conf.labels[1].append.as_deref(), Some("console=tty0"));
+        assert_eq!(conf.labels[2].kernel, None);
+
+        assert_eq!(ExtlinuxConf::parse("").default_label(), None);
+        assert_eq!(
+            ExtlinuxConf::parse("").timeout(),
+            Timeout::Countdown(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_select_extlinux_entry() {
+        let entry = ExtlinuxEntry {
+            label: ExtlinuxLabel {
+                label: "fedora".to_string(),
+                kernel: Some("/vmlinuz-6.7.4".to_string()),
+                initrd: vec!["../initramfs-6.7.4.img".to_string()],
+                append: Some("ro root=LABEL=fedora".to_string()),
+                fdt: Some("/dtb-6.7.4/mediatek/mt8183-kukui-jacuzzi-fennel-sku6.dtb".to_string()),
+                ..Default::default()
+            },
+            root: PathBuf::from("/mnt/extlinux/1/2"),
+            conf_dir: PathBuf::from("/mnt/extlinux/1/2/extlinux"),
+            is_default: true,
+        };
+
+        let parts = entry.select().unwrap();
+        assert_eq!(
+            parts.linux,
+            PathBuf::from("/mnt/extlinux/1/2/vmlinuz-6.7.4")
+        );
+        assert_eq!(
+            parts.initrd,
+            vec![PathBuf::from(
+                "/mnt/extlinux/1/2/extlinux/../initramfs-6.7.4.img"
+            )]
+        );
+        assert_eq!(parts.cmdline.as_deref(), Some("ro root=LABEL=fedora"));
+        assert_eq!(
+            parts.devicetree,
+            Some(PathBuf::from(
+                "/mnt/extlinux/1/2/dtb-6.7.4/mediatek/mt8183-kukui-jacuzzi-fennel-sku6.dtb"
+            ))
+        );
+        assert_eq!(entry.to_string(), "fedora");
+    }
+
+    #[test]
+    fn test_find_devicetree() {
+        let dir = std::env::temp_dir().join(format!("tboot-fdtdir-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("mediatek")).unwrap();
+
+        for (name, compatible) in [
+            (
+                "mediatek/mt8183-kukui-jacuzzi-fennel-sku6.dtb",
+                &b"google,fennel-sku6\0google,fennel\0mediatek,mt8183\0"[..],
+            ),
+            (
+                "mediatek/mt8183-kukui-jacuzzi-fennel14.dtb",
+                &b"google,fennel-sku2\0google,fennel\0mediatek,mt8183\0"[..],
+            ),
+            (
+                "mediatek/mt8192-asurada-spherion-r0.dtb",
+                &b"google,spherion-rev0\0google,spherion\0mediatek,mt8192\0"[..],
+            ),
+        ] {
+            let mut root = Node::new("");
+            root.set_prop("compatible", compatible);
+            let fdt = Fdt {
+                root,
+                ..Default::default()
+            };
+            std::fs::write(dir.join(name), fdt.to_bytes()).unwrap();
+        }
+        std::fs::write(dir.join("README"), "not a devicetree").unwrap();
+
+        let machine = |compatible: &[&str]| -> Vec<String> {
+            compatible.iter().map(|c| c.to_string()).collect()
+        };
+
+        assert_eq!(
+            super::find_devicetree(
+                &dir,
+                &machine(&["google,fennel-sku2", "google,fennel", "mediatek,mt8183"])
+            ),
+            Some(dir.join("mediatek/mt8183-kukui-jacuzzi-fennel14.dtb"))
+        );
+        // an unknown SKU falls back to a devicetree for the board
+        assert_eq!(
+            super::find_devicetree(
+                &dir,
+                &machine(&["google,fennel-sku9", "google,fennel", "mediatek,mt8183"])
+            ),
+            Some(dir.join("mediatek/mt8183-kukui-jacuzzi-fennel-sku6.dtb"))
+        );
+        assert_eq!(super::find_devicetree(&dir, &machine(&["qemu,virt"])), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,806 @@
+use log::{debug, error, warn};
+use nix::mount::{self, MntFlags};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::{
+    fmt::Display,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tboot::dev::DiskEvent;
+
+use super::{
+    disk::{block_devices, changed_disks, mount_partition, probe_in_parallel, BlockDevice},
+    extlinux::candidate_partitions,
+    BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout,
+};
+use crate::fdt::{self, Fdt, Node};
+
+const FIT_MNT_PATH: &str = "/mnt/fit";
+const FIT_EXTRACT_PATH: &str = "/run/tboot/fit";
+
+/// The directories of a partition that are searched for FIT images.
+const FIT_DIRS: &[&str] = &["", "boot"];
+const FIT_EXTENSIONS: &[&str] = &["itb", "fit"];
+
+const FIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The architecture tinyboot runs on, as named in FIT images.
+fn fit_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        "riscv64" => "riscv",
+        "x86" => "x86",
+        arch => arch,
+    }
+}
+
+fn prop_u32(node: &Node, name: &str) -> Option<u32> {
+    Some(u32::from_be_bytes(node.prop(name)?.try_into().ok()?))
+}
+
+fn prop_string(node: &Node, name: &str) -> Option<String> {
+    node.prop(name).and_then(fdt::prop_str).map(str::to_string)
+}
+
+/// Checks the data of an image against every hash node of the image.
+fn verify_hashes(image: &Node, data: &[u8]) -> anyhow::Result<()> {
+    for hash in image
+        .children
+        .iter()
+        .filter(|child| child.name == "hash" || child.name.starts_with("hash-"))
+    {
+        let algo = prop_string(hash, "algo").unwrap_or_default();
+        let Some(expected) = hash.prop("value") else {
+            anyhow::bail!("{} of image '{}' has no value", hash.name, image.name);
+        };
+
+        let actual = match algo.as_str() {
+            "crc32" => crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC)
+                .checksum(data)
+                .to_be_bytes()
+                .to_vec(),
+            "sha1" => Sha1::digest(data).to_vec(),
+            "sha256" => Sha256::digest(data).to_vec(),
+            "sha384" => Sha384::digest(data).to_vec(),
+            "sha512" => Sha512::digest(data).to_vec(),
+            _ => anyhow::bail!("unsupported hash '{algo}' for image '{}'", image.name),
+        };
+
+        if actual != expected {
+            anyhow::bail!("{algo} hash mismatch for image '{}'", image.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn decompress(compression: &str, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match compression {
+        "none" => Ok(data),
+        "gzip" => {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        "lzma" => {
+            let mut decompressed = Vec::new();
+            lzma_rs::lzma_decompress(&mut data.as_slice(), &mut decompressed)
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            Ok(decompressed)
+        }
+        _ => anyhow::bail!("unsupported compression '{compression}'"),
+    }
+}
+
+/// A configuration of a FIT image, which names the images to boot together.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct FitConfig {
+    name: String,
+    description: Option<String>,
+    kernel: String,
+    ramdisk: Option<String>,
+    /// The devicetree followed by the overlays to apply to it.
+    fdt: Vec<String>,
+    compatible: Vec<String>,
+}
+
+impl Display for FitConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.description.as_ref().unwrap_or(&self.name))
+    }
+}
+
+// Documentation: https://fitspec.osfw.foundation/
+pub(super) struct Fit {
+    fdt: Fdt,
+    /// The whole file, images with external data point into it.
+    data: Vec<u8>,
+}
+
+impl Fit {
+    pub fn parse(data: Vec<u8>) -> anyhow::Result<Self> {
+        let fdt = Fdt::parse(&data)?;
+
+        if fdt.root.child("images").is_none() || fdt.root.child("configurations").is_none() {
+            anyhow::bail!("devicetree is not a FIT image");
+        }
+
+        Ok(Self { fdt, data })
+    }
+
+    fn configurations(&self) -> Vec<FitConfig> {
+        let Some(configurations) = self.fdt.root.child("configurations") else {
+            return Vec::new();
+        };
+
+        configurations
+            .children
+            .iter()
+            .filter_map(|config| {
+                let Some(kernel) = prop_string(config, "kernel") else {
+                    debug!("skipping configuration '{}' without a kernel", config.name);
+                    return None;
+                };
+
+                Some(FitConfig {
+                    name: config.name.clone(),
+                    description: prop_string(config, "description"),
+                    kernel,
+                    ramdisk: prop_string(config, "ramdisk"),
+                    fdt: config
+                        .prop("fdt")
+                        .map(fdt::prop_str_list)
+                        .unwrap_or_default(),
+                    compatible: config
+                        .prop("compatible")
+                        .map(fdt::prop_str_list)
+                        .unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    /// The compatible strings of a configuration, from its compatible property or else from the
+    /// root node of its devicetree.
+    fn config_compatible(&self, config: &FitConfig) -> Vec<String> {
+        if !config.compatible.is_empty() {
+            return config.compatible.clone();
+        }
+
+        config
+            .fdt
+            .first()
+            .and_then(|devicetree| self.image_data(devicetree).ok())
+            .and_then(|data| Fdt::parse(&data).ok())
+            .and_then(|devicetree| devicetree.root.prop("compatible").map(fdt::prop_str_list))
+            .unwrap_or_default()
+    }
+
+    /// Picks the configuration to boot the way U-Boot does: the one matching the most specific
+    /// compatible string of the machine, else the default one, else the first one.
+    pub fn best_config(&self, machine_compatible: &[String]) -> Option<String> {
+        let configs = self.configurations();
+
+        let matching = configs
+            .iter()
+            .filter(|_| !machine_compatible.is_empty())
+            .filter_map(|config| {
+                let compatible = self.config_compatible(config);
+                let rank = machine_compatible
+                    .iter()
+                    .position(|machine| compatible.contains(machine))?;
+                Some((rank, config))
+            })
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, config)| config.name.clone());
+
+        matching
+            .or_else(|| {
+                self.fdt
+                    .root
+                    .child("configurations")
+                    .and_then(|configurations| prop_string(configurations, "default"))
+                    .filter(|default| configs.iter().any(|config| config.name == *default))
+            })
+            .or_else(|| configs.first().map(|config| config.name.clone()))
+    }
+
+    /// The data of an image, from the image node or from after the devicetree for images with
+    /// external data.
+    fn raw_image_data<'a>(&'a self, image: &'a Node) -> anyhow::Result<&'a [u8]> {
+        if let Some(data) = image.prop("data") {
+            return Ok(data);
+        }
+
+        let Some(size) = prop_u32(image, "data-size") else {
+            anyhow::bail!("image '{}' has no data", image.name);
+        };
+
+        let start = if let Some(position) = prop_u32(image, "data-position") {
+            position as usize
+        } else if let Some(offset) = prop_u32(image, "data-offset") {
+            // external data starts after the devicetree, aligned to 4 bytes
+            let totalsize = u32::from_be_bytes(self.data[4..8].try_into().expect("4 bytes"));
+            (totalsize as usize).next_multiple_of(4) + offset as usize
+        } else {
+            anyhow::bail!("image '{}' has no data", image.name);
+        };
+
+        self.data
+            .get(start..start + size as usize)
+            .ok_or_else(|| anyhow::anyhow!("data of image '{}' is out of bounds", image.name))
+    }
+
+    /// The data of an image after checking its hashes and decompressing it.
+    fn image_data(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let Some(image) = self.image(name) else {
+            anyhow::bail!("image '{name}' does not exist");
+        };
+
+        let data = self.raw_image_data(image)?;
+        verify_hashes(image, data)?;
+
+        let compression = prop_string(image, "compression").unwrap_or_else(|| "none".to_string());
+        decompress(&compression, data.to_vec())
+    }
+
+    fn image(&self, name: &str) -> Option<&Node> {
+        self.fdt.root.child("images")?.child(name)
+    }
+
+    fn check_image(&self, name: &str, types: &[&str]) -> anyhow::Result<()> {
+        let Some(image) = self.image(name) else {
+            anyhow::bail!("image '{name}' does not exist");
+        };
+
+        let image_type = prop_string(image, "type").unwrap_or_default();
+        if !types.contains(&image_type.as_str()) {
+            anyhow::bail!("image '{name}' has type '{image_type}'");
+        }
+
+        if let Some(arch) = prop_string(image, "arch") {
+            if image_type != "flat_dt" && arch != fit_arch() {
+                anyhow::bail!("image '{name}' is for {arch}, not {}", fit_arch());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the images of a configuration to a directory.
+    pub fn extract(&self, config_name: &str, dir: &Path) -> anyhow::Result<LinuxBootParts> {
+        let Some(config) = self
+            .configurations()
+            .into_iter()
+            .find(|config| config.name == config_name)
+        else {
+            anyhow::bail!("configuration '{config_name}' does not exist");
+        };
+
+        std::fs::create_dir_all(dir)?;
+
+        let extract = |image: &str, types: &[&str], file_name: &str| {
+            self.check_image(image, types)?;
+            let path = dir.join(file_name);
+            debug!("extracting image '{image}' to {}", path.display());
+            std::fs::write(&path, self.image_data(image)?)?;
+            anyhow::Ok(path)
+        };
+
+        let linux = extract(&config.kernel, &["kernel", "kernel_noload"], "linux")?;
+
+        let initrd = match &config.ramdisk {
+            Some(ramdisk) => vec![extract(ramdisk, &["ramdisk"], "initrd")?],
+            None => Vec::new(),
+        };
+
+        let (devicetree, devicetree_overlay) = match config.fdt.split_first() {
+            Some((devicetree, overlays)) => (
+                Some(extract(devicetree, &["flat_dt"], "devicetree")?),
+                overlays
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, overlay)| {
+                        extract(overlay, &["flat_dt"], &format!("devicetree-overlay-{idx}"))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            None => (None, Vec::new()),
+        };
+
+        Ok(LinuxBootParts {
+            linux,
+            initrd,
+            cmdline: None,
+            devicetree,
+            devicetree_overlay,
+            initrd_verified: false,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FitEntry {
+    fit_path: PathBuf,
+    config: FitConfig,
+    extract_dir: PathBuf,
+    is_default: bool,
+}
+
+impl Display for FitEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({})",
+            self.config,
+            self.fit_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        )
+    }
+}
+
+impl BootEntry for FitEntry {
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        let fit = Fit::parse(std::fs::read(&self.fit_path)?)?;
+        fit.extract(&self.config.name, &self.extract_dir)
+    }
+}
+
+/// Finds FIT images below the root of a partition.
+fn find_fits(mountpoint: &Path) -> Vec<PathBuf> {
+    let mut fits = Vec::new();
+
+    for dir in FIT_DIRS {
+        let Ok(entries) = std::fs::read_dir(mountpoint.join(dir)) else {
+            continue;
+        };
+
+        let mut dir_fits: Vec<PathBuf> = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.is_file()
+                    && path
+                        .extension()
+                        .is_some_and(|ext| FIT_EXTENSIONS.iter().any(|fit_ext| ext == *fit_ext))
+            })
+            .collect();
+        dir_fits.sort();
+
+        fits.extend(dir_fits);
+    }
+
+    fits
+}
+
+/// A partition with FIT images on it.
+struct FitPartition {
+    block_dev: BlockDevice,
+    part_idx: u32,
+    mountpoint: PathBuf,
+    fits: Vec<PathBuf>,
+}
+
+impl FitPartition {
+    fn boot_device(&self) -> Option<BootDevice> {
+        let machine_compatible = fdt::machine_compatible();
+        let mut entries: Vec<Box<dyn BootEntry>> = Vec::new();
+
+        for fit_path in &self.fits {
+            let fit = match std::fs::read(fit_path)
+                .map_err(anyhow::Error::from)
+                .and_then(Fit::parse)
+            {
+                Ok(fit) => fit,
+                Err(e) => {
+                    warn!("{}: {e}", fit_path.display());
+                    continue;
+                }
+            };
+
+            // only the first FIT image of a partition has a default configuration
+            let default_config = entries
+                .is_empty()
+                .then(|| fit.best_config(&machine_compatible))
+                .flatten();
+
+            let fit_stem = fit_path.file_stem().unwrap_or_default().to_string_lossy();
+
+            for config in fit.configurations() {
+                entries.push(Box::new(FitEntry {
+                    fit_path: fit_path.clone(),
+                    extract_dir: PathBuf::from(FIT_EXTRACT_PATH)
+                        .join(format!("{}-{}", self.block_dev.diskseq, self.part_idx))
+                        .join(format!("{fit_stem}-{}", config.name)),
+                    is_default: default_config.as_deref() == Some(config.name.as_str()),
+                    config,
+                }));
+            }
+        }
+
+        if entries.is_empty() {
+            return None;
+        }
+
+        Some(BootDevice {
+            name: format!("{} (FIT)", self.block_dev.name()),
+            selectors: self.block_dev.selectors(),
+            entries,
+            timeout: Timeout::Countdown(FIT_TIMEOUT),
+            editor: true,
+        })
+    }
+}
+
+/// FitBootLoader boots the configurations of FIT images (.itb files), like the ones built by
+/// fitimage/make-image-its.bash.
+#[derive(Default)]
+pub struct FitBootLoader {
+    partitions: Vec<FitPartition>,
+}
+
+impl FitBootLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts the partitions of a disk that have FIT images.
+    fn mount_partitions(block_dev: &BlockDevice) -> Vec<FitPartition> {
+        let mut partitions = Vec::new();
+
+        for part_idx in candidate_partitions(&block_dev.chardev_path) {
+            let partition_chardev_path = block_dev.partition_chardev_path(part_idx);
+            let mountpoint = PathBuf::from(FIT_MNT_PATH)
+                .join(block_dev.diskseq.to_string())
+                .join(part_idx.to_string());
+
+            if let Err(e) = mount_partition(&partition_chardev_path, &mountpoint, true) {
+                debug!("failed to mount {}: {e}", partition_chardev_path.display());
+                continue;
+            }
+
+            let fits = find_fits(&mountpoint);
+            if fits.is_empty() {
+                if let Err(e) = mount::umount2(&mountpoint, MntFlags::MNT_DETACH) {
+                    error!("failed to unmount {}: {e}", mountpoint.display());
+                }
+                continue;
+            }
+
+            debug!(
+                "found {} FIT images on {}",
+                fits.len(),
+                mountpoint.display()
+            );
+            partitions.push(FitPartition {
+                block_dev: block_dev.clone(),
+                part_idx,
+                mountpoint,
+                fits,
+            });
+        }
+
+        partitions
+    }
+
+    fn add_disks(&mut self, block_devs: Vec<BlockDevice>) {
+        let block_devs: Vec<BlockDevice> = block_devs
+            .into_iter()
+            .filter(|block_dev| {
+                !self
+                    .partitions
+                    .iter()
+                    .any(|partition| partition.block_dev.diskseq == block_dev.diskseq)
+            })
+            .collect();
+
+        self.partitions.extend(
+            probe_in_parallel(&block_devs, Self::mount_partitions)
+                .into_iter()
+                .flatten(),
+        );
+
+        // removable disks come first, like in block_devices
+        self.partitions
+            .sort_by_key(|partition| !partition.block_dev.removable);
+    }
+}
+
+impl BootLoader for FitBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        self.add_disks(block_devices()?);
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        self.partitions
+            .iter()
+            .filter_map(FitPartition::boot_device)
+            .collect()
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        for partition in self.partitions.drain(..) {
+            if let Err(e) = mount::umount2(&partition.mountpoint, MntFlags::MNT_DETACH) {
+                error!("failed to unmount {}: {e}", partition.mountpoint.display());
+            }
+        }
+
+        for dir in [FIT_MNT_PATH, FIT_EXTRACT_PATH] {
+            if let Err(e) = std::fs::remove_dir_all(dir) {
+                error!("failed to remove {dir}: {e}");
+            }
+        }
+    }
+
+    fn disks_changed(&mut self, events: &[DiskEvent]) -> bool {
+        let (added, removed) = changed_disks(events);
+        let count = self.partitions.len();
+
+        self.partitions.retain(|partition| {
+            let is_removed = removed.contains(&partition.block_dev.diskseq);
+            if is_removed {
+                if let Err(e) = mount::umount2(&partition.mountpoint, MntFlags::MNT_DETACH) {
+                    error!("failed to unmount {}: {e}", partition.mountpoint.display());
+                }
+            }
+            !is_removed
+        });
+
+        let removed_any = self.partitions.len() != count;
+        let count = self.partitions.len();
+
+        self.add_disks(added);
+
+        removed_any || self.partitions.len() != count
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::Fit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+    use std::io::Write;
+
+    use crate::fdt::{Fdt, Node};
+
+    use super::Fit;
+
+    fn image(name: &str, image_type: &str, compression: &str, data: &[u8]) -> Node {
+        let mut image = Node::new(name);
+        image.set_prop("data", data);
+        image.set_prop_str("type", image_type);
+        image.set_prop_str("arch", super::fit_arch());
+        image.set_prop_str("compression", compression);
+        image
+    }
+
+    fn devicetree(compatible: &[u8]) -> Vec<u8> {
+        let mut root = Node::new("");
+        root.set_prop("compatible", compatible);
+        Fdt {
+            root,
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    fn config(name: &str, fdt: &[u8]) -> Node {
+        let mut config = Node::new(name);
+        config.set_prop_str("kernel", "kernel");
+        config.set_prop_str("ramdisk", "ramdisk");
+        config.set_prop("fdt", fdt);
+        config
+    }
+
+    /// A FIT image like the ones make-image-its.bash describes.
+    fn fit_image() -> Fdt {
+        let mut kernel_lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut b"kernel".as_slice(), &mut kernel_lzma).unwrap();
+        let mut kernel = image("kernel", "kernel", "lzma", &kernel_lzma);
+        kernel.set_prop_str("os", "linux");
+        let mut hash = Node::new("hash-1");
+        hash.set_prop_str("algo", "crc32");
+        hash.set_prop(
+            "value",
+            crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC)
+                .checksum(&kernel_lzma)
+                .to_be_bytes(),
+        );
+        kernel.children.push(hash);
+
+        let mut ramdisk_gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        ramdisk_gzip.write_all(b"ramdisk").unwrap();
+        let ramdisk_gzip = ramdisk_gzip.finish().unwrap();
+        let mut ramdisk = image("ramdisk", "ramdisk", "gzip", &ramdisk_gzip);
+        let mut hash = Node::new("hash-1");
+        hash.set_prop_str("algo", "sha1");
+        hash.set_prop("value", Sha1::digest(&ramdisk_gzip).to_vec());
+        ramdisk.children.push(hash);
+
+        let mut images = Node::new("images");
+        images.children.push(kernel);
+        images.children.push(ramdisk);
+        images.children.push(image(
+            "fdt-0",
+            "flat_dt",
+            "none",
+            &devicetree(b"google,fennel-sku6\0google,fennel\0mediatek,mt8183\0"),
+        ));
+        images.children.push(image(
+            "fdt-1",
+            "flat_dt",
+            "none",
+            &devicetree(b"google,spherion-rev0\0google,spherion\0mediatek,mt8192\0"),
+        ));
+        images
+            .children
+            .push(image("overlay-0", "flat_dt", "none", &devicetree(b"")));
+
+        let mut configurations = Node::new("configurations");
+        configurations.set_prop_str("default", "conf-1");
+        configurations.children.push(config("conf-0", b"fdt-0\0"));
+        configurations
+            .children
+            .push(config("conf-1", b"fdt-1\0overlay-0\0"));
+
+        let mut root = Node::new("");
+        root.set_prop_str("description", "kernel, dtbs, and initramfs");
+        root.children.push(images);
+        root.children.push(configurations);
+
+        Fdt {
+            root,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fit_configurations() {
+        let fit = Fit::parse(fit_image().to_bytes()).unwrap();
+
+        let configs = fit.configurations();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[1].fdt, vec!["fdt-1", "overlay-0"]);
+        assert_eq!(configs[1].ramdisk.as_deref(), Some("ramdisk"));
+
+        let machine = |compatible: &[&str]| -> Vec<String> {
+            compatible.iter().map(|c| c.to_string()).collect()
+        };
+
+        assert_eq!(
+            fit.best_config(&machine(&["google,fennel-sku2", "google,fennel"])),
+            Some("conf-0".to_string())
+        );
+        assert_eq!(fit.best_config(&[]), Some("conf-1".to_string()));
+
+        let not_fit = Fdt {
+            root: Node::new(""),
+            ..Default::default()
+        };
+        assert!(Fit::parse(not_fit.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_fit_extract() {
+        let dir = std::env::temp_dir().join(format!("tboot-fit-{}", std::process::id()));
+
+        let fit = Fit::parse(fit_image().to_bytes()).unwrap();
+        let parts = fit.extract("conf-1", &dir).unwrap();
+        assert_eq!(std::fs::read(&parts.linux).unwrap(), b"kernel");
+        assert_eq!(std::fs::read(&parts.initrd[0]).unwrap(), b"ramdisk");
+        assert_eq!(
+            std::fs::read(parts.devicetree.as_ref().unwrap()).unwrap(),
+            devicetree(b"google,spherion-rev0\0google,spherion\0mediatek,mt8192\0")
+        );
+        assert_eq!(parts.devicetree_overlay.len(), 1);
+        assert_eq!(parts.cmdline, None);
+
+        // images with external data, as written by mkimage -E
+        let mut external = fit_image();
+        let kernel = external.root.node_mut("images/kernel").unwrap();
+        let kernel_data = kernel.prop("data").unwrap().to_vec();
+        kernel.remove_prop("data");
+        kernel.set_prop("data-offset", 0u32.to_be_bytes());
+        kernel.set_prop("data-size", (kernel_data.len() as u32).to_be_bytes());
+        let mut data = external.to_bytes();
+        data.resize(data.len().next_multiple_of(4), 0);
+        data.extend_from_slice(&kernel_data);
+        let parts = Fit::parse(data).unwrap().extract("conf-0", &dir).unwrap();
+        assert_eq!(std::fs::read(&parts.linux).unwrap(), b"kernel");
+
+        let mut corrupted = fit_image();
+        let ramdisk = corrupted.root.node_mut("images/ramdisk").unwrap();
+        let mut ramdisk_data = ramdisk.prop("data").unwrap().to_vec();
+        *ramdisk_data.last_mut().unwrap() ^= 0x01;
+        ramdisk.set_prop("data", ramdisk_data);
+        assert!(Fit::parse(corrupted.to_bytes())
+            .unwrap()
+            .extract("conf-0", &dir)
+            .unwrap_err()
+            .to_string()
+            .contains("sha1 hash mismatch"));
+
+        let mut wrong_arch = fit_image();
+        wrong_arch
+            .root
+            .node_mut("images/kernel")
+            .unwrap()
+            .set_prop_str("arch", "mips");
+        assert!(Fit::parse(wrong_arch.to_bytes())
+            .unwrap()
+            .extract("conf-0", &dir)
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fit_truncated() {
+        let data = fit_image().to_bytes();
+        assert!(Fit::parse(data[..20].to_vec()).is_err());
+        assert!(Fit::parse(data[..data.len() / 2].to_vec()).is_err());
+
+        let dir = std::env::temp_dir().join(format!("tboot-fit-truncated-{}", std::process::id()));
+
+        // external data that runs past the end of the file
+        let mut external = fit_image();
+        let kernel = external.root.node_mut("images/kernel").unwrap();
+        let kernel_data = kernel.prop("data").unwrap().to_vec();
+        kernel.remove_prop("data");
+        kernel.set_prop("data-offset", 0u32.to_be_bytes());
+        kernel.set_prop("data-size", (kernel_data.len() as u32).to_be_bytes());
+        let mut data = external.to_bytes();
+        data.resize(data.len().next_multiple_of(4), 0);
+        data.extend_from_slice(&kernel_data[..kernel_data.len() / 2]);
+        assert!(Fit::parse(data)
+            .unwrap()
+            .extract("conf-0", &dir)
+            .unwrap_err()
+            .to_string()
+            .contains("out of bounds"));
+
+        let mut no_data = fit_image();
+        no_data
+            .root
+            .node_mut("images/kernel")
+            .unwrap()
+            .remove_prop("data");
+        assert!(Fit::parse(no_data.to_bytes())
+            .unwrap()
+            .extract("conf-0", &dir)
+            .is_err());
+
+        // a configuration that refers to an image that does not exist
+        let mut missing_image = fit_image();
+        missing_image
+            .root
+            .node_mut("configurations/conf-0")
+            .unwrap()
+            .set_prop_str("ramdisk", "ramdisk-1");
+        assert!(Fit::parse(missing_image.to_bytes())
+            .unwrap()
+            .extract("conf-0", &dir)
+            .is_err());
+
+        _ = std::fs::remove_dir_all(&dir);
+    }
+}
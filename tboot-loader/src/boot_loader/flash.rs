@@ -0,0 +1,872 @@
+use log::{debug, error, warn};
+use std::{
+    fmt::Display,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{fit::Fit, BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout};
+use crate::fdt;
+
+const FLASH_EXTRACT_PATH: &str = "/run/tboot/flash";
+
+const FLASH_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MTD_SYSFS_PATH: &str = "/sys/class/mtd";
+
+/// The copy of the FMAP that coreboot leaves in CBMEM, which saves searching the whole flash for
+/// it. The kernel exposes it with CONFIG_GOOGLE_CBMEM.
+/// https://github.com/torvalds/linux/blob/master/drivers/firmware/google/cbmem.c
+const CBMEM_FMAP_PATH: &str = "/sys/bus/coreboot/devices/cbmem-464d4150/mem";
+
+/// The read-only CBFS, which holds the recovery kernel as CBFS files.
+const COREBOOT_REGION: &str = "COREBOOT";
+/// An FMAP region holding a FIT image.
+const RECOVERY_REGION: &str = "TBOOT_RECOVERY";
+
+const FDT_MAGIC: [u8; 4] = [0xd0, 0x0d, 0xfe, 0xed];
+
+const RECOVERY_LINUX: &str = "tboot/recovery/linux";
+const RECOVERY_INITRD: &str = "tboot/recovery/initrd";
+const RECOVERY_CMDLINE: &str = "tboot/recovery/cmdline";
+
+/// Flashmap documentation: https://github.com/coreboot/coreboot/blob/main/src/commonlib/bsd/include/commonlib/bsd/fmap_serialized.h
+mod fmap_constants {
+    pub const SIGNATURE: &[u8] = b"__FMAP__";
+    pub const VERSION_MAJOR: u8 = 1;
+    pub const HEADER_LENGTH: usize = 56;
+    pub const AREA_LENGTH: usize = 42;
+    pub const NAME_LENGTH: usize = 32;
+    /// coreboot places the FMAP at the start of an erase block, which is at least 4 KiB.
+    pub const SEARCH_ALIGNMENT: u64 = 0x1000;
+}
+
+/// CBFS documentation: https://github.com/coreboot/coreboot/blob/main/src/commonlib/bsd/include/commonlib/bsd/cbfs_serialized.h
+mod cbfs_constants {
+    pub const MAGIC: &[u8] = b"LARCHIVE";
+    pub const HEADER_LENGTH: usize = 24;
+    pub const ALIGNMENT: u64 = 64;
+
+    pub const TYPE_NULL: u32 = 0xffff_ffff;
+    pub const TYPE_DELETED: u32 = 0;
+
+    pub const ATTR_TAG_COMPRESSION: u32 = 0x4243_5a4c;
+    pub const COMPRESS_NONE: u32 = 0;
+    pub const COMPRESS_LZMA: u32 = 1;
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn c_str(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FmapArea {
+    name: String,
+    /// Relative to the start of the flash.
+    offset: u32,
+    size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Fmap {
+    /// The size of the flash the FMAP describes.
+    size: u32,
+    areas: Vec<FmapArea>,
+}
+
+impl Fmap {
+    fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < fmap_constants::HEADER_LENGTH
+            || !bytes.starts_with(fmap_constants::SIGNATURE)
+        {
+            anyhow::bail!("no FMAP signature");
+        }
+
+        if bytes[8] != fmap_constants::VERSION_MAJOR {
+            anyhow::bail!("unsupported FMAP version {}.{}", bytes[8], bytes[9]);
+        }
+
+        let size = le_u32(bytes, 18);
+        let nareas = u16::from_le_bytes([bytes[54], bytes[55]]) as usize;
+
+        let areas_end = fmap_constants::HEADER_LENGTH + nareas * fmap_constants::AREA_LENGTH;
+        let Some(areas) = bytes.get(fmap_constants::HEADER_LENGTH..areas_end) else {
+            anyhow::bail!("FMAP is truncated");
+        };
+
+        let areas = areas
+            .chunks_exact(fmap_constants::AREA_LENGTH)
+            .map(|area| FmapArea {
+                offset: le_u32(area, 0),
+                size: le_u32(area, 4),
+                name: c_str(&area[8..8 + fmap_constants::NAME_LENGTH]),
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(area) = areas
+            .iter()
+            .find(|area| area.offset as u64 + area.size as u64 > size as u64)
+        {
+            anyhow::bail!("FMAP area {} is outside of the flash", area.name);
+        }
+
+        Ok(Self { size, areas })
+    }
+
+    /// Searches the flash for the FMAP. Like flashrom, only offsets aligned to SEARCH_ALIGNMENT
+    /// are looked at, coarsest alignment first, and only the signature is read at each of them,
+    /// so that the flash, which can be slow to read, is not read in its entirety.
+    fn find<R: Read + Seek>(flash: &mut R) -> anyhow::Result<Self> {
+        let flash_size = flash.seek(SeekFrom::End(0))?;
+        let mut stride = flash_size
+            .next_power_of_two()
+            .max(fmap_constants::SEARCH_ALIGNMENT);
+        let mut first = true;
+
+        while stride >= fmap_constants::SEARCH_ALIGNMENT {
+            // offsets aligned to twice the stride were looked at in the previous round
+            let offsets = (0..flash_size)
+                .step_by(stride as usize)
+                .filter(|offset| first || offset % (stride * 2) != 0);
+
+            for offset in offsets {
+                match Self::read_at(flash, offset) {
+                    Ok(Some(fmap)) => return Ok(fmap),
+                    Ok(None) => {}
+                    Err(e) => debug!("ignoring FMAP signature at {offset:#x}: {e}"),
+                }
+            }
+
+            stride /= 2;
+            first = false;
+        }
+
+        anyhow::bail!("no FMAP found");
+    }
+
+    /// Reads the FMAP at the given offset, if there is one.
+    fn read_at<R: Read + Seek>(flash: &mut R, offset: u64) -> anyhow::Result<Option<Self>> {
+        let mut header = vec![0u8; fmap_constants::HEADER_LENGTH];
+        flash.seek(SeekFrom::Start(offset))?;
+        if read_up_to(flash, &mut header[..fmap_constants::SIGNATURE.len()])?
+            < fmap_constants::SIGNATURE.len()
+            || !header.starts_with(fmap_constants::SIGNATURE)
+        {
+            return Ok(None);
+        }
+
+        flash.read_exact(&mut header[fmap_constants::SIGNATURE.len()..])?;
+        let nareas = u16::from_le_bytes([header[54], header[55]]) as usize;
+        let mut fmap = header;
+        fmap.resize(
+            fmap_constants::HEADER_LENGTH + nareas * fmap_constants::AREA_LENGTH,
+            0,
+        );
+        flash.read_exact(&mut fmap[fmap_constants::HEADER_LENGTH..])?;
+
+        Self::parse(&fmap).map(Some)
+    }
+
+    fn area(&self, name: &str) -> Option<&FmapArea> {
+        self.areas.iter().find(|area| area.name == name)
+    }
+}
+
+/// Reads until the buffer is full or the end of the file, returning how much was read.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CbfsFile {
+    name: String,
+    /// Relative to the start of the flash.
+    data_offset: u64,
+    len: u32,
+    compression: u32,
+}
+
+impl CbfsFile {
+    fn read<R: Read + Seek>(&self, flash: &mut R) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0u8; self.len as usize];
+        flash.seek(SeekFrom::Start(self.data_offset))?;
+        flash.read_exact(&mut data)?;
+
+        match self.compression {
+            cbfs_constants::COMPRESS_NONE => Ok(data),
+            cbfs_constants::COMPRESS_LZMA => {
+                let mut decompressed = Vec::new();
+                lzma_rs::lzma_decompress(&mut data.as_slice(), &mut decompressed)
+                    .map_err(|e| anyhow::anyhow!("{}: {e}", self.name))?;
+                Ok(decompressed)
+            }
+            compression => anyhow::bail!("{}: unsupported compression {compression}", self.name),
+        }
+    }
+}
+
+/// Lists the files of the CBFS in an FMAP area. Only the headers are read, so that the flash,
+/// which can be slow to read, is not read in its entirety.
+fn cbfs_files<R: Read + Seek>(flash: &mut R, area: &FmapArea) -> anyhow::Result<Vec<CbfsFile>> {
+    let area_start = area.offset as u64;
+    let area_end = area_start + area.size as u64;
+
+    let mut files = Vec::new();
+    let mut offset = area_start;
+
+    while offset + cbfs_constants::HEADER_LENGTH as u64 <= area_end {
+        let mut header = [0u8; cbfs_constants::HEADER_LENGTH];
+        flash.seek(SeekFrom::Start(offset))?;
+        flash.read_exact(&mut header)?;
+
+        if !header.starts_with(cbfs_constants::MAGIC) {
+            offset += cbfs_constants::ALIGNMENT;
+            continue;
+        }
+
+        let len = be_u32(&header, 8);
+        let file_type = be_u32(&header, 12);
+        let attributes_offset = be_u32(&header, 16) as usize;
+        let data_offset = be_u32(&header, 20) as usize;
+
+        if data_offset < cbfs_constants::HEADER_LENGTH
+            || offset + data_offset as u64 + len as u64 > area_end
+        {
+            anyhow::bail!("CBFS file at {offset:#x} is out of bounds");
+        }
+
+        let mut metadata = vec![0u8; data_offset - cbfs_constants::HEADER_LENGTH];
+        flash.read_exact(&mut metadata)?;
+
+        // the name is followed by the attributes, if there are any, and then by the data
+        let name_end = if attributes_offset > cbfs_constants::HEADER_LENGTH {
+            attributes_offset
+        } else {
+            data_offset
+        } - cbfs_constants::HEADER_LENGTH;
+        let name = c_str(&metadata[..name_end.min(metadata.len())]);
+
+        let mut compression = cbfs_constants::COMPRESS_NONE;
+        if attributes_offset >= cbfs_constants::HEADER_LENGTH {
+            let mut attributes = &metadata
+                [(attributes_offset - cbfs_constants::HEADER_LENGTH).min(metadata.len())..];
+            while attributes.len() >= 8 {
+                let tag = be_u32(attributes, 0);
+                let attribute_len = be_u32(attributes, 4) as usize;
+                if attribute_len < 8 || attribute_len > attributes.len() {
+                    break;
+                }
+                if tag == cbfs_constants::ATTR_TAG_COMPRESSION && attribute_len >= 12 {
+                    compression = be_u32(attributes, 8);
+                }
+                attributes = &attributes[attribute_len..];
+            }
+        }
+
+        if !matches!(
+            file_type,
+            cbfs_constants::TYPE_NULL | cbfs_constants::TYPE_DELETED
+        ) {
+            files.push(CbfsFile {
+                name,
+                data_offset: offset + data_offset as u64,
+                len,
+                compression,
+            });
+        }
+
+        offset =
+            (offset + data_offset as u64 + len as u64).next_multiple_of(cbfs_constants::ALIGNMENT);
+    }
+
+    Ok(files)
+}
+
+/// Where a recovery kernel is stored in the flash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RecoveryImage {
+    /// CBFS files holding the kernel and optionally an initrd and command line.
+    Cbfs {
+        linux: CbfsFile,
+        initrd: Option<CbfsFile>,
+        cmdline: Option<CbfsFile>,
+    },
+    /// An FMAP region holding a FIT image.
+    Fit(FmapArea),
+}
+
+/// Finds the recovery kernels stored in the flash.
+fn recovery_images<R: Read + Seek>(flash: &mut R, fmap: &Fmap) -> Vec<RecoveryImage> {
+    let mut images = Vec::new();
+
+    if let Some(area) = fmap.area(COREBOOT_REGION) {
+        match cbfs_files(flash, area) {
+            Ok(files) => {
+                let find = |name: &str| files.iter().find(|file| file.name == name).cloned();
+                if let Some(linux) = find(RECOVERY_LINUX) {
+                    images.push(RecoveryImage::Cbfs {
+                        linux,
+                        initrd: find(RECOVERY_INITRD),
+                        cmdline: find(RECOVERY_CMDLINE),
+                    });
+                }
+            }
+            Err(e) => warn!("failed to read CBFS in {COREBOOT_REGION}: {e}"),
+        }
+    }
+
+    if let Some(area) = fmap.area(RECOVERY_REGION) {
+        let mut magic = [0u8; 4];
+        let is_fit = flash
+            .seek(SeekFrom::Start(area.offset as u64))
+            .and_then(|_| flash.read_exact(&mut magic))
+            .is_ok()
+            && magic == FDT_MAGIC;
+
+        if is_fit {
+            images.push(RecoveryImage::Fit(area.clone()));
+        } else {
+            debug!("no FIT image in {RECOVERY_REGION}");
+        }
+    }
+
+    images
+}
+
+struct FlashEntry {
+    flash_path: PathBuf,
+    image: RecoveryImage,
+    extract_dir: PathBuf,
+    is_default: bool,
+}
+
+impl Display for FlashEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.image {
+            RecoveryImage::Cbfs { .. } => write!(f, "Recovery ({COREBOOT_REGION})"),
+            RecoveryImage::Fit(area) => write!(f, "Recovery ({})", area.name),
+        }
+    }
+}
+
+impl BootEntry for FlashEntry {
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        let mut flash = std::fs::File::open(&self.flash_path)?;
+        std::fs::create_dir_all(&self.extract_dir)?;
+
+        match &self.image {
+            RecoveryImage::Cbfs {
+                linux,
+                initrd,
+                cmdline,
+            } => {
+                let linux_path = self.extract_dir.join("linux");
+                debug!("extracting {} to {}", linux.name, linux_path.display());
+                std::fs::write(&linux_path, linux.read(&mut flash)?)?;
+
+                let initrd = match initrd {
+                    Some(initrd) => {
+                        let initrd_path = self.extract_dir.join("initrd");
+                        debug!("extracting {} to {}", initrd.name, initrd_path.display());
+                        std::fs::write(&initrd_path, initrd.read(&mut flash)?)?;
+                        vec![initrd_path]
+                    }
+                    None => Vec::new(),
+                };
+
+                let cmdline = match cmdline {
+                    Some(cmdline) => Some(
+                        String::from_utf8(cmdline.read(&mut flash)?)?
+                            .trim_end_matches('\0')
+                            .trim()
+                            .to_string(),
+                    ),
+                    None => None,
+                };
+
+                Ok(LinuxBootParts {
+                    linux: linux_path,
+                    initrd,
+                    cmdline,
+                    devicetree: None,
+                    devicetree_overlay: Vec::new(),
+                    initrd_verified: false,
+                })
+            }
+            RecoveryImage::Fit(area) => {
+                let mut data = vec![0u8; area.size as usize];
+                flash.seek(SeekFrom::Start(area.offset as u64))?;
+                flash.read_exact(&mut data)?;
+
+                let fit = Fit::parse(data)?;
+                let Some(config) = fit.best_config(&fdt::machine_compatible()) else {
+                    anyhow::bail!("FIT image has no configurations");
+                };
+                fit.extract(&config, &self.extract_dir)
+            }
+        }
+    }
+}
+
+/// Lists the MTD devices that can hold the flash for a flashrom programmer. Only programmers
+/// that read the flash of the machine tinyboot runs on make sense here, those all end up at the
+/// kernel's MTD devices.
+fn flash_devices(programmer: &str) -> Vec<PathBuf> {
+    let (name, params) = programmer.split_once(':').unwrap_or((programmer, ""));
+
+    if name == "linux_mtd" {
+        if let Some(dev) = params
+            .split(',')
+            .find_map(|param| param.strip_prefix("dev="))
+        {
+            return vec![PathBuf::from(format!("/dev/mtd{dev}"))];
+        }
+    } else if name != "internal" {
+        warn!("programmer '{programmer}' cannot read the flash, using every MTD device");
+    }
+
+    let Ok(entries) = std::fs::read_dir(MTD_SYSFS_PATH) else {
+        return Vec::new();
+    };
+
+    let mut devices: Vec<PathBuf> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            // mtdXro is the read-only view of mtdX
+            (name.starts_with("mtd") && !name.ends_with("ro"))
+                .then(|| PathBuf::from("/dev").join(name))
+        })
+        .collect();
+    devices.sort();
+    devices
+}
+
+fn mtd_attribute(device: &Path, attribute: &str) -> Option<String> {
+    let name = device.file_name()?;
+    std::fs::read_to_string(Path::new(MTD_SYSFS_PATH).join(name).join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+/// A flash device with recovery kernels in it.
+struct FlashDevice {
+    path: PathBuf,
+    name: String,
+    images: Vec<RecoveryImage>,
+}
+
+/// FlashBootLoader boots a recovery kernel stored in the firmware flash, either as files in the
+/// read-only CBFS or as a FIT image in its own FMAP region. It does not depend on any disk, so it
+/// is a last resort when nothing else boots.
+pub struct FlashBootLoader {
+    programmer: String,
+    devices: Vec<FlashDevice>,
+}
+
+impl FlashBootLoader {
+    pub fn new(programmer: &str) -> Self {
+        Self {
+            programmer: programmer.to_string(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl BootLoader for FlashBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        let cbmem_fmap = std::fs::read(CBMEM_FMAP_PATH)
+            .map_err(anyhow::Error::from)
+            .and_then(|fmap| Fmap::parse(&fmap));
+
+        for path in flash_devices(&self.programmer) {
+            let mut flash = match std::fs::File::open(&path) {
+                Ok(flash) => flash,
+                Err(e) => {
+                    debug!("failed to open {}: {e}", path.display());
+                    continue;
+                }
+            };
+
+            let size = mtd_attribute(&path, "size").and_then(|size| size.parse::<u64>().ok());
+
+            // the FMAP in CBMEM only describes the flash coreboot booted from
+            let fmap = match &cbmem_fmap {
+                Ok(fmap) if size == Some(fmap.size as u64) => Ok(fmap.clone()),
+                _ => Fmap::find(&mut flash),
+            };
+
+            let fmap = match fmap {
+                Ok(fmap) => fmap,
+                Err(e) => {
+                    debug!("{}: {e}", path.display());
+                    continue;
+                }
+            };
+
+            let images = recovery_images(&mut flash, &fmap);
+            if images.is_empty() {
+                debug!("no recovery kernel in {}", path.display());
+                continue;
+            }
+
+            self.devices.push(FlashDevice {
+                name: mtd_attribute(&path, "name").unwrap_or_else(|| "Flash".to_string()),
+                path,
+                images,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        self.devices
+            .iter()
+            .map(|device| {
+                let device_name = device
+                    .path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy();
+
+                BootDevice {
+                    name: format!("{} (flash)", device.name),
+                    selectors: Vec::new(),
+                    entries: device
+                        .images
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, image)| {
+                            Box::new(FlashEntry {
+                                flash_path: device.path.clone(),
+                                image: image.clone(),
+                                extract_dir: PathBuf::from(FLASH_EXTRACT_PATH)
+                                    .join(format!("{device_name}-{idx}")),
+                                is_default: idx == 0,
+                            }) as Box<dyn BootEntry>
+                        })
+                        .collect(),
+                    timeout: Timeout::Countdown(FLASH_TIMEOUT),
+                    editor: true,
+                }
+            })
+            .collect()
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        self.devices.clear();
+
+        if let Err(e) = std::fs::remove_dir_all(FLASH_EXTRACT_PATH) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove {FLASH_EXTRACT_PATH}: {e}");
+            }
+        }
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::Flash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::fdt::{Fdt, Node};
+
+    use super::{CbfsFile, Fmap, FmapArea, RecoveryImage};
+
+    fn fmap_bytes(size: u32, areas: &[(&str, u32, u32)]) -> Vec<u8> {
+        let mut fmap = b"__FMAP__".to_vec();
+        fmap.extend_from_slice(&[1, 1]);
+        fmap.extend_from_slice(&0xff00_0000u64.to_le_bytes());
+        fmap.extend_from_slice(&size.to_le_bytes());
+        let mut name = b"FLASH".to_vec();
+        name.resize(32, 0);
+        fmap.extend_from_slice(&name);
+        fmap.extend_from_slice(&(areas.len() as u16).to_le_bytes());
+
+        for (name, offset, size) in areas {
+            fmap.extend_from_slice(&offset.to_le_bytes());
+            fmap.extend_from_slice(&size.to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize(32, 0);
+            fmap.extend_from_slice(&name);
+            fmap.extend_from_slice(&0u16.to_le_bytes());
+        }
+
+        fmap
+    }
+
+    /// A CBFS file as cbfstool writes it, optionally with a compression attribute.
+    fn cbfs_file(name: &str, file_type: u32, data: &[u8], compression: Option<u32>) -> Vec<u8> {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        name.resize(name.len().next_multiple_of(4), 0);
+
+        let attributes_offset = if compression.is_some() {
+            24 + name.len() as u32
+        } else {
+            0
+        };
+        let data_offset = 24 + name.len() as u32 + if compression.is_some() { 16 } else { 0 };
+
+        let mut file = b"LARCHIVE".to_vec();
+        file.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        file.extend_from_slice(&file_type.to_be_bytes());
+        file.extend_from_slice(&attributes_offset.to_be_bytes());
+        file.extend_from_slice(&data_offset.to_be_bytes());
+        file.extend_from_slice(&name);
+        if let Some(compression) = compression {
+            file.extend_from_slice(&0x4243_5a4cu32.to_be_bytes());
+            file.extend_from_slice(&16u32.to_be_bytes());
+            file.extend_from_slice(&compression.to_be_bytes());
+            file.extend_from_slice(&0u32.to_be_bytes());
+        }
+        file.extend_from_slice(data);
+        file.resize(file.len().next_multiple_of(64), 0xff);
+        file
+    }
+
+    fn flash() -> Vec<u8> {
+        let mut linux_lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut b"linux".as_slice(), &mut linux_lzma).unwrap();
+
+        let mut cbfs = Vec::new();
+        cbfs.extend(cbfs_file("cbfs master header", 2, &[0; 32], None));
+        cbfs.extend(cbfs_file("fallback/romstage", 0x10, b"romstage", Some(1)));
+        cbfs.extend(cbfs_file(
+            "tboot/recovery/linux",
+            0x50,
+            &linux_lzma,
+            Some(1),
+        ));
+        cbfs.extend(cbfs_file(
+            "tboot/recovery/cmdline",
+            0x50,
+            b"console=ttyS0\0",
+            None,
+        ));
+        cbfs.extend(cbfs_file("", 0xffff_ffff, &[0xff; 256], None));
+
+        let mut flash = vec![0xffu8; 0x10_0000];
+        let fmap = fmap_bytes(
+            0x10_0000,
+            &[
+                ("FMAP", 0x1_0000, 0x800),
+                ("COREBOOT", 0x2_0000, 0x4_0000),
+                ("TBOOT_RECOVERY", 0x8_0000, 0x1_0000),
+            ],
+        );
+        flash[0x1_0000..0x1_0000 + fmap.len()].copy_from_slice(&fmap);
+        flash[0x2_0000..0x2_0000 + cbfs.len()].copy_from_slice(&cbfs);
+        flash
+    }
+
+    fn fit() -> Vec<u8> {
+        let mut root = Node::new("");
+        root.children.push(Node::new("images"));
+        root.children.push(Node::new("configurations"));
+        Fdt {
+            root,
+            ..Default::default()
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_fmap() {
+        let flash = flash();
+
+        let fmap = Fmap::find(&mut Cursor::new(&flash)).unwrap();
+        assert_eq!(fmap.size, 0x10_0000);
+        assert_eq!(
+            fmap.area("COREBOOT"),
+            Some(&FmapArea {
+                name: "COREBOOT".to_string(),
+                offset: 0x2_0000,
+                size: 0x4_0000,
+            })
+        );
+        assert_eq!(Fmap::parse(&flash[0x1_0000..]).unwrap(), fmap);
+
+        // an area that does not fit in the flash is rejected
+        let mut bad_flash = flash.clone();
+        bad_flash[0x1_0000..0x1_0000 + 56 + 42]
+            .copy_from_slice(&fmap_bytes(0x10_0000, &[("COREBOOT", 0xf_0000, 0x2_0000)]));
+        assert!(Fmap::find(&mut Cursor::new(&bad_flash)).is_err());
+
+        assert!(Fmap::find(&mut Cursor::new(vec![0xffu8; 0x1000])).is_err());
+
+        // only aligned offsets are searched
+        let mut unaligned_flash = vec![0xffu8; 0x10_0000];
+        let fmap = fmap_bytes(0x10_0000, &[]);
+        unaligned_flash[0x1_0040..0x1_0040 + fmap.len()].copy_from_slice(&fmap);
+        assert!(Fmap::find(&mut Cursor::new(&unaligned_flash)).is_err());
+    }
+
+    #[test]
+    fn test_fmap_truncated() {
+        let fmap = fmap_bytes(0x10_0000, &[("COREBOOT", 0x2_0000, 0x4_0000)]);
+        assert!(Fmap::parse(&fmap[..40]).is_err());
+
+        // the header claims more areas than there are
+        let mut missing_areas = fmap.clone();
+        missing_areas[54..56].copy_from_slice(&3u16.to_le_bytes());
+        assert!(Fmap::parse(&missing_areas)
+            .unwrap_err()
+            .to_string()
+            .contains("truncated"));
+
+        let mut future_version = fmap.clone();
+        future_version[8] = 2;
+        assert!(Fmap::parse(&future_version).is_err());
+
+        // the areas run past the end of the flash
+        let mut flash = vec![0xffu8; 0x2000];
+        flash[0x1000..0x1000 + fmap.len()].copy_from_slice(&fmap);
+        flash[0x1000 + 54..0x1000 + 56].copy_from_slice(&0xffffu16.to_le_bytes());
+        assert!(Fmap::find(&mut Cursor::new(&flash)).is_err());
+
+        // the header is cut off by the end of the flash
+        let mut flash = vec![0xffu8; 0x1020];
+        flash[0x1000..].copy_from_slice(&fmap[..0x20]);
+        assert!(Fmap::find(&mut Cursor::new(&flash)).is_err());
+    }
+
+    #[test]
+    fn test_cbfs_truncated() {
+        let flash = flash();
+
+        // a file that runs past the end of its area
+        let area = FmapArea {
+            name: "COREBOOT".to_string(),
+            offset: 0x2_0000,
+            size: 0xa0,
+        };
+        assert!(super::cbfs_files(&mut Cursor::new(&flash), &area)
+            .unwrap_err()
+            .to_string()
+            .contains("out of bounds"));
+
+        // a file whose data would start inside its own header
+        let mut file = cbfs_file("tboot/recovery/linux", 0x50, b"linux", None);
+        file[20..24].copy_from_slice(&8u32.to_be_bytes());
+        let area = FmapArea {
+            name: "COREBOOT".to_string(),
+            offset: 0,
+            size: file.len() as u32,
+        };
+        assert!(super::cbfs_files(&mut Cursor::new(&file), &area).is_err());
+
+        // an area that is larger than the flash
+        let area = FmapArea {
+            name: "COREBOOT".to_string(),
+            offset: 0,
+            size: 0x1000,
+        };
+        assert!(super::cbfs_files(&mut Cursor::new(vec![0xffu8; 0x40]), &area).is_err());
+
+        let mut cursor = Cursor::new(&flash);
+        let fmap = Fmap::find(&mut cursor).unwrap();
+        let files = super::cbfs_files(&mut cursor, fmap.area("COREBOOT").unwrap()).unwrap();
+        let linux = files
+            .iter()
+            .find(|file| file.name == "tboot/recovery/linux")
+            .unwrap();
+
+        // the data is cut off by the end of the flash
+        let past_end = CbfsFile {
+            data_offset: flash.len() as u64 - 2,
+            ..linux.clone()
+        };
+        assert!(past_end.read(&mut cursor).is_err());
+
+        // the compressed data is cut short
+        let short = CbfsFile {
+            len: linux.len / 2,
+            ..linux.clone()
+        };
+        assert!(short.read(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_recovery_images() {
+        let mut flash = flash();
+        let mut cursor = Cursor::new(&flash);
+        let fmap = Fmap::find(&mut cursor).unwrap();
+
+        // TBOOT_RECOVERY is empty
+        assert_eq!(super::recovery_images(&mut cursor, &fmap).len(), 1);
+
+        let fit = fit();
+        flash[0x8_0000..0x8_0000 + fit.len()].copy_from_slice(&fit);
+        let mut cursor = Cursor::new(&flash);
+
+        let files = super::cbfs_files(&mut cursor, fmap.area("COREBOOT").unwrap()).unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| file.name.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "cbfs master header",
+                "fallback/romstage",
+                "tboot/recovery/linux",
+                "tboot/recovery/cmdline"
+            ]
+        );
+
+        let images = super::recovery_images(&mut cursor, &fmap);
+        assert_eq!(images.len(), 2);
+        let RecoveryImage::Cbfs {
+            linux,
+            initrd,
+            cmdline,
+        } = &images[0]
+        else {
+            panic!("expected CBFS recovery image, got {:?}", images[0]);
+        };
+        assert_eq!(linux.read(&mut cursor).unwrap(), b"linux");
+        assert_eq!(*initrd, None);
+        assert_eq!(
+            cmdline.as_ref().unwrap().read(&mut cursor).unwrap(),
+            b"console=ttyS0\0"
+        );
+        assert!(matches!(&images[1], RecoveryImage::Fit(area) if area.name == "TBOOT_RECOVERY"));
+
+        let lz4 = CbfsFile {
+            compression: 2,
+            ..linux.clone()
+        };
+        assert!(lz4.read(&mut cursor).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use log::{debug, error};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use super::{BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout};
+
+/// The fw_cfg files that QEMU was given with `-fw_cfg name=...`, by their name. The kernel exposes
+/// them with CONFIG_FW_CFG_SYSFS.
+/// https://qemu-project.gitlab.io/qemu/specs/fw_cfg.html
+const FW_CFG_BY_NAME_PATH: &str = "/sys/firmware/qemu_fw_cfg/by_name";
+
+const FW_CFG_EXTRACT_PATH: &str = "/run/tboot/fw_cfg";
+
+const FW_CFG_KERNEL: &str = "opt/org.tboot/kernel";
+const FW_CFG_INITRD: &str = "opt/org.tboot/initrd";
+const FW_CFG_CMDLINE: &str = "opt/org.tboot/cmdline";
+
+fn fw_cfg_file(by_name: &Path, name: &str) -> PathBuf {
+    by_name.join(name).join("raw")
+}
+
+struct FwCfgEntry {
+    by_name: PathBuf,
+    extract_dir: PathBuf,
+}
+
+impl Display for FwCfgEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fw_cfg kernel")
+    }
+}
+
+impl BootEntry for FwCfgEntry {
+    fn is_default(&self) -> bool {
+        true
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        std::fs::create_dir_all(&self.extract_dir)?;
+
+        // The raw files in sysfs report a size of zero, so the kernel cannot be loaded from them
+        // directly.
+        let linux = self.extract_dir.join("linux");
+        debug!("copying {FW_CFG_KERNEL} to {}", linux.display());
+        std::fs::copy(fw_cfg_file(&self.by_name, FW_CFG_KERNEL), &linux)?;
+
+        let mut initrd = Vec::new();
+        let initrd_file = fw_cfg_file(&self.by_name, FW_CFG_INITRD);
+        if initrd_file.exists() {
+            let initrd_path = self.extract_dir.join("initrd");
+            debug!("copying {FW_CFG_INITRD} to {}", initrd_path.display());
+            std::fs::copy(initrd_file, &initrd_path)?;
+            initrd.push(initrd_path);
+        }
+
+        let cmdline_file = fw_cfg_file(&self.by_name, FW_CFG_CMDLINE);
+        let cmdline = if cmdline_file.exists() {
+            Some(
+                std::fs::read_to_string(cmdline_file)?
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string(),
+            )
+        } else {
+            None
+        };
+
+        Ok(LinuxBootParts {
+            linux,
+            initrd,
+            cmdline,
+            devicetree: None,
+            devicetree_overlay: Vec::new(),
+            initrd_verified: false,
+        })
+    }
+}
+
+/// FwCfgBootLoader boots a kernel that QEMU passes in through fw_cfg, with
+/// `-fw_cfg name=opt/org.tboot/kernel,file=...` and optionally `opt/org.tboot/initrd` and
+/// `opt/org.tboot/cmdline`. This allows testing a kernel without building a disk image for it.
+pub struct FwCfgBootLoader {
+    /// None when tinyboot is built without the fw_cfg feature.
+    by_name: Option<PathBuf>,
+    extract_dir: PathBuf,
+    has_kernel: bool,
+}
+
+impl FwCfgBootLoader {
+    pub fn new() -> Self {
+        Self::with_paths(
+            cfg!(feature = "fw_cfg").then(|| PathBuf::from(FW_CFG_BY_NAME_PATH)),
+            PathBuf::from(FW_CFG_EXTRACT_PATH),
+        )
+    }
+
+    fn with_paths(by_name: Option<PathBuf>, extract_dir: PathBuf) -> Self {
+        Self {
+            by_name,
+            extract_dir,
+            has_kernel: false,
+        }
+    }
+}
+
+impl BootLoader for FwCfgBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        let Some(by_name) = &self.by_name else {
+            debug!("built without fw_cfg support");
+            return Ok(());
+        };
+
+        self.has_kernel = fw_cfg_file(by_name, FW_CFG_KERNEL).exists();
+        if !self.has_kernel {
+            debug!("no {FW_CFG_KERNEL} in fw_cfg");
+        }
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        let Some(by_name) = self.by_name.as_ref().filter(|_| self.has_kernel) else {
+            return Vec::new();
+        };
+
+        vec![BootDevice {
+            name: "QEMU fw_cfg".to_string(),
+            selectors: Vec::new(),
+            entries: vec![Box::new(FwCfgEntry {
+                by_name: by_name.clone(),
+                extract_dir: self.extract_dir.clone(),
+            })],
+            // the kernel was passed in explicitly, there is nothing to choose from
+            timeout: Timeout::MenuHidden,
+            editor: true,
+        }]
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        self.has_kernel = false;
+
+        if let Err(e) = std::fs::remove_dir_all(&self.extract_dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove {}: {e}", self.extract_dir.display());
+            }
+        }
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::FwCfg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fw_cfg_kernel() {
+        let root = std::env::temp_dir().join(format!("tboot-fw-cfg-{}", std::process::id()));
+        let by_name = root.join("by_name");
+        let extract_dir = root.join("extract");
+
+        let mut loader = FwCfgBootLoader::with_paths(Some(by_name.clone()), extract_dir.clone());
+        loader.setup().unwrap();
+        assert!(loader.probe().is_empty());
+
+        for (name, contents) in [
+            (FW_CFG_KERNEL, "kernel"),
+            (FW_CFG_CMDLINE, "console=ttyS0 quiet\n\0"),
+        ] {
+            let file = fw_cfg_file(&by_name, name);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, contents).unwrap();
+        }
+
+        loader.setup().unwrap();
+        let devices = loader.probe();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].entries.len(), 1);
+        assert!(devices[0].entries[0].is_default());
+
+        let parts = devices[0].entries[0].select().unwrap();
+        assert_eq!(
+            parts,
+            LinuxBootParts {
+                linux: extract_dir.join("linux"),
+                initrd: Vec::new(),
+                cmdline: Some("console=ttyS0 quiet".to_string()),
+                devicetree: None,
+                devicetree_overlay: Vec::new(),
+                initrd_verified: false,
+            }
+        );
+        assert_eq!(std::fs::read_to_string(&parts.linux).unwrap(), "kernel");
+
+        let initrd = fw_cfg_file(&by_name, FW_CFG_INITRD);
+        std::fs::create_dir_all(initrd.parent().unwrap()).unwrap();
+        std::fs::write(initrd, "initrd").unwrap();
+
+        let parts = devices[0].entries[0].select().unwrap();
+        assert_eq!(parts.initrd, vec![extract_dir.join("initrd")]);
+        assert_eq!(std::fs::read_to_string(&parts.initrd[0]).unwrap(), "initrd");
+
+        loader.teardown();
+        assert!(!extract_dir.exists());
+        assert!(loader.probe().is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_fw_cfg_missing_selectors() {
+        let root =
+            std::env::temp_dir().join(format!("tboot-fw-cfg-missing-{}", std::process::id()));
+        let by_name = root.join("by_name");
+        let extract_dir = root.join("extract");
+
+        // built without fw_cfg support
+        let mut loader = FwCfgBootLoader::with_paths(None, extract_dir.clone());
+        loader.setup().unwrap();
+        assert!(loader.probe().is_empty());
+
+        // only the initrd and command line were passed in
+        let mut loader = FwCfgBootLoader::with_paths(Some(by_name.clone()), extract_dir.clone());
+        for (name, contents) in [(FW_CFG_INITRD, "initrd"), (FW_CFG_CMDLINE, "quiet")] {
+            let file = fw_cfg_file(&by_name, name);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, contents).unwrap();
+        }
+        loader.setup().unwrap();
+        assert!(loader.probe().is_empty());
+
+        // the selector exists, but not its raw file
+        std::fs::create_dir_all(by_name.join(FW_CFG_KERNEL)).unwrap();
+        loader.setup().unwrap();
+        assert!(loader.probe().is_empty());
+
+        let kernel = fw_cfg_file(&by_name, FW_CFG_KERNEL);
+        std::fs::write(&kernel, "kernel").unwrap();
+        loader.setup().unwrap();
+        let devices = loader.probe();
+        assert_eq!(devices.len(), 1);
+
+        // the kernel went away after probing
+        std::fs::remove_file(kernel).unwrap();
+        assert!(devices[0].entries[0].select().is_err());
+
+        loader.teardown();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,1823 @@
+use gpt::mbr;
+use log::{debug, error, warn};
+use nix::mount::{self, MntFlags};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::Chars,
+    time::Duration,
+};
+use tboot::dev::DiskEvent;
+
+use super::{
+    disk::{block_devices, mount_partition, probe_in_parallel, BlockDevice},
+    BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout,
+};
+use crate::fs::fs_uuid;
+
+const GRUB_MNT_PATH: &str = "/mnt/grub";
+
+/// Where distributions put grub.cfg on a partition, besides EFI/*/grub.cfg on the ESP.
+const GRUB_CFG_PATHS: &[&str] = &[
+    "boot/grub/grub.cfg",
+    "boot/grub2/grub.cfg",
+    "grub/grub.cfg",
+    "grub2/grub.cfg",
+];
+
+/// Bounds how deep configfile and source can nest, so that a config including itself ends.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Variables GRUB sets before running grub.cfg. Generated configs check the features to decide
+/// which syntax to use.
+const GRUB_FEATURES: &[&str] = &[
+    "feature_menuentry_id",
+    "feature_platform_search_hint",
+    "feature_timeout_style",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum WordPart {
+    Literal(String),
+    /// Expansions outside of double quotes are split on whitespace.
+    Var {
+        name: String,
+        quoted: bool,
+    },
+}
+
+type Word = Vec<WordPart>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(Word),
+    /// A newline or a semicolon.
+    Separator,
+    OpenBrace,
+    CloseBrace,
+}
+
+/// Reads the name of a variable after a '$', which is either enclosed in braces or made of
+/// alphanumeric characters and underscores.
+fn read_var_name(chars: &mut Peekable<Chars>) -> anyhow::Result<Option<String>> {
+    let mut name = String::new();
+
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => name.push(c),
+                None => anyhow::bail!("unterminated variable name"),
+            }
+        }
+        return Ok(Some(name));
+    }
+
+    while let Some(c) = chars.peek() {
+        if !(c.is_ascii_alphanumeric() || *c == '_' || *c == '?') {
+            break;
+        }
+        name.push(*c);
+        chars.next();
+    }
+
+    Ok((!name.is_empty()).then_some(name))
+}
+
+fn push_literal(word: &mut Word, literal: &mut String) {
+    if !literal.is_empty() {
+        word.push(WordPart::Literal(std::mem::take(literal)));
+    }
+}
+
+// Documentation: https://www.gnu.org/software/grub/manual/grub/grub.html#Shell_002dlike-scripting
+fn tokenize(script: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = script.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\r' => {
+                chars.next();
+                continue;
+            }
+            '\n' | ';' => {
+                chars.next();
+                tokens.push(Token::Separator);
+                continue;
+            }
+            '#' => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut word = Word::new();
+        let mut literal = String::new();
+        let mut quoted = false;
+
+        while let Some(&c) = chars.peek() {
+            if matches!(c, ' ' | '\t' | '\r' | '\n' | ';') {
+                break;
+            }
+            chars.next();
+
+            match c {
+                '\\' => match chars.next() {
+                    Some('\n') => {}
+                    Some(c) => literal.push(c),
+                    None => literal.push('\\'),
+                },
+                '\'' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => literal.push(c),
+                            None => anyhow::bail!("unterminated single quote"),
+                        }
+                    }
+                }
+                '"' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('$' | '"' | '\\')) => literal.push(c),
+                                Some('\n') => {}
+                                Some(c) => {
+                                    literal.push('\\');
+                                    literal.push(c);
+                                }
+                                None => anyhow::bail!("unterminated double quote"),
+                            },
+                            Some('$') => match read_var_name(&mut chars)? {
+                                Some(name) => {
+                                    push_literal(&mut word, &mut literal);
+                                    word.push(WordPart::Var { name, quoted: true });
+                                }
+                                None => literal.push('$'),
+                            },
+                            Some(c) => literal.push(c),
+                            None => anyhow::bail!("unterminated double quote"),
+                        }
+                    }
+                }
+                '$' => match read_var_name(&mut chars)? {
+                    Some(name) => {
+                        push_literal(&mut word, &mut literal);
+                        word.push(WordPart::Var {
+                            name,
+                            quoted: false,
+                        });
+                    }
+                    None => literal.push('$'),
+                },
+                c => literal.push(c),
+            }
+        }
+
+        push_literal(&mut word, &mut literal);
+
+        tokens.push(match word.as_slice() {
+            [WordPart::Literal(brace)] if !quoted && brace == "{" => Token::OpenBrace,
+            [WordPart::Literal(brace)] if !quoted && brace == "}" => Token::CloseBrace,
+            // an empty pair of quotes is still a word
+            [] => Token::Word(vec![WordPart::Literal(String::new())]),
+            _ => Token::Word(word),
+        });
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Command {
+    Simple(Vec<Word>),
+    If {
+        /// Conditions and the commands run when they succeed, for if and every elif.
+        branches: Vec<(Vec<Command>, Vec<Command>)>,
+        otherwise: Vec<Command>,
+    },
+    Menu {
+        submenu: bool,
+        args: Vec<Word>,
+        body: Vec<Command>,
+    },
+    /// Loops and function definitions, which are parsed but never run.
+    Skipped,
+}
+
+/// The word as written, if it has no variables in it. Only such words are keywords.
+fn keyword(word: &Word) -> Option<&str> {
+    match word.as_slice() {
+        [WordPart::Literal(keyword)] => Some(keyword),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn parse(script: &str) -> anyhow::Result<Vec<Command>> {
+        let mut parser = Self {
+            tokens: tokenize(script)?.into_iter().peekable(),
+        };
+        let (commands, _) = parser.parse_commands(&[])?;
+        Ok(commands)
+    }
+
+    /// Parses commands up to one of the terminators, which is consumed and returned. A
+    /// terminator of "}" matches a closing brace.
+    fn parse_commands(
+        &mut self,
+        terminators: &[&'static str],
+    ) -> anyhow::Result<(Vec<Command>, &'static str)> {
+        let mut commands = Vec::new();
+
+        loop {
+            let Some(token) = self.tokens.peek() else {
+                if terminators.is_empty() {
+                    return Ok((commands, ""));
+                }
+                anyhow::bail!("expected '{}'", terminators.join("' or '"));
+            };
+
+            let word = match token {
+                Token::Separator => {
+                    self.tokens.next();
+                    continue;
+                }
+                Token::CloseBrace => {
+                    self.tokens.next();
+                    if terminators.contains(&"}") {
+                        return Ok((commands, "}"));
+                    }
+                    anyhow::bail!("unexpected '}}'");
+                }
+                Token::OpenBrace => anyhow::bail!("unexpected '{{'"),
+                Token::Word(word) => word,
+            };
+
+            let keyword = keyword(word).map(str::to_string);
+
+            if let Some(terminator) = terminators
+                .iter()
+                .find(|terminator| keyword.as_deref() == Some(**terminator))
+            {
+                self.tokens.next();
+                return Ok((commands, *terminator));
+            }
+
+            let command = match keyword.as_deref() {
+                Some("if") => {
+                    self.tokens.next();
+                    self.parse_if()?
+                }
+                Some("for" | "while" | "until") => {
+                    self.tokens.next();
+                    self.parse_commands(&["do"])?;
+                    self.parse_commands(&["done"])?;
+                    Command::Skipped
+                }
+                Some("function") => {
+                    self.tokens.next();
+                    self.parse_words();
+                    self.expect_open_brace()?;
+                    self.parse_commands(&["}"])?;
+                    Command::Skipped
+                }
+                Some(menu @ ("menuentry" | "submenu")) => {
+                    let submenu = menu == "submenu";
+                    self.tokens.next();
+                    let args = self.parse_words();
+                    self.expect_open_brace()?;
+                    let (body, _) = self.parse_commands(&["}"])?;
+                    Command::Menu {
+                        submenu,
+                        args,
+                        body,
+                    }
+                }
+                _ => Command::Simple(self.parse_words()),
+            };
+
+            commands.push(command);
+        }
+    }
+
+    fn parse_if(&mut self) -> anyhow::Result<Command> {
+        let mut branches = Vec::new();
+
+        loop {
+            let (condition, _) = self.parse_commands(&["then"])?;
+            let (body, terminator) = self.parse_commands(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+
+            match terminator {
+                "elif" => continue,
+                "else" => {
+                    let (otherwise, _) = self.parse_commands(&["fi"])?;
+                    return Ok(Command::If {
+                        branches,
+                        otherwise,
+                    });
+                }
+                _ => {
+                    return Ok(Command::If {
+                        branches,
+                        otherwise: Vec::new(),
+                    })
+                }
+            }
+        }
+    }
+
+    fn parse_words(&mut self) -> Vec<Word> {
+        let mut words = Vec::new();
+        while let Some(Token::Word(word)) =
+            self.tokens.next_if(|token| matches!(token, Token::Word(_)))
+        {
+            words.push(word);
+        }
+        words
+    }
+
+    fn expect_open_brace(&mut self) -> anyhow::Result<()> {
+        match self.tokens.next() {
+            Some(Token::OpenBrace) => Ok(()),
+            _ => anyhow::bail!("expected '{{'"),
+        }
+    }
+}
+
+/// A menuentry block, kept even when it has no kernel since it still counts when the default is
+/// given as an index.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct GrubMenuEntry {
+    title: String,
+    id: Option<String>,
+    linux: Option<PathBuf>,
+    cmdline: Option<String>,
+    initrd: Vec<PathBuf>,
+    devicetree: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum MenuItem {
+    Entry(GrubMenuEntry),
+    Submenu {
+        title: String,
+        id: Option<String>,
+        items: Vec<MenuItem>,
+    },
+}
+
+impl MenuItem {
+    fn matches(&self, selector: &str) -> bool {
+        let (title, id) = match self {
+            Self::Entry(entry) => (&entry.title, &entry.id),
+            Self::Submenu { title, id, .. } => (title, id),
+        };
+        title == selector || id.as_deref() == Some(selector)
+    }
+}
+
+/// The indices of the menu items leading to the entry that `default` refers to. Each
+/// '>'-separated part of it is an index, a title or an id at its level of the menu.
+fn default_path(items: &[MenuItem], default: &str) -> Option<Vec<usize>> {
+    let mut path = Vec::new();
+    let mut items = items;
+    let mut parts = default.split('>').peekable();
+
+    while let Some(part) = parts.next() {
+        let idx = match part.parse::<usize>() {
+            Ok(idx) if idx < items.len() => idx,
+            _ => items.iter().position(|item| item.matches(part))?,
+        };
+        path.push(idx);
+
+        match &items[idx] {
+            MenuItem::Submenu {
+                items: sub_items, ..
+            } if parts.peek().is_some() => items = sub_items,
+            MenuItem::Entry(_) if parts.peek().is_none() => return Some(path),
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+/// Flattens the menu into its entries with a kernel, along with their titles and their path in
+/// the menu.
+fn flatten<'a>(
+    items: &'a [MenuItem],
+    titles: &mut Vec<String>,
+    path: &mut Vec<usize>,
+    out: &mut Vec<(Vec<String>, Vec<usize>, &'a GrubMenuEntry)>,
+) {
+    for (idx, item) in items.iter().enumerate() {
+        path.push(idx);
+        match item {
+            MenuItem::Entry(entry) => {
+                if entry.linux.is_some() {
+                    let mut entry_titles = titles.clone();
+                    entry_titles.push(entry.title.clone());
+                    out.push((entry_titles, path.clone(), entry));
+                } else {
+                    debug!("skipping menuentry '{}' without a kernel", entry.title);
+                }
+            }
+            MenuItem::Submenu {
+                title,
+                items: sub_items,
+                ..
+            } => {
+                titles.push(title.clone());
+                flatten(sub_items, titles, path, out);
+                titles.pop();
+            }
+        }
+        path.pop();
+    }
+}
+
+/// A mounted partition that grub.cfg can refer to.
+#[derive(Clone, Debug)]
+struct GrubPartition {
+    block_dev: BlockDevice,
+    /// The GRUB device name, like hd0,gpt2.
+    device: String,
+    mountpoint: PathBuf,
+    uuid: Option<String>,
+}
+
+/// Makes device names comparable, GRUB accepts hd0,2 for hd0,gpt2 and hd0,msdos2.
+fn normalize_device(device: &str) -> String {
+    let device = device.trim_start_matches('(').trim_end_matches(')');
+    match device.split_once(',') {
+        Some((disk, part)) => format!(
+            "{disk},{}",
+            part.trim_start_matches("gpt").trim_start_matches("msdos")
+        ),
+        None => device.to_string(),
+    }
+}
+
+/// The outcome of running a grub.cfg.
+#[derive(Debug, Default)]
+struct GrubMenu {
+    items: Vec<MenuItem>,
+    vars: HashMap<String, String>,
+    /// Every file pulled in with configfile or source.
+    included: Vec<PathBuf>,
+}
+
+impl GrubMenu {
+    fn timeout(&self) -> Timeout {
+        match self
+            .vars
+            .get("timeout")
+            .and_then(|timeout| timeout.parse::<i64>().ok())
+        {
+            None => Timeout::MenuForce,
+            Some(timeout) if timeout < 0 => Timeout::MenuForce,
+            Some(0) => Timeout::MenuHidden,
+            Some(timeout) => Timeout::Countdown(Duration::from_secs(timeout as u64)),
+        }
+    }
+
+    fn entries(&self) -> Vec<GrubEntry> {
+        let mut entries = Vec::new();
+        flatten(&self.items, &mut Vec::new(), &mut Vec::new(), &mut entries);
+
+        let default = match self.vars.get("default").map(String::as_str) {
+            None | Some("") => "0",
+            Some(default) => default,
+        };
+        let default_path = default_path(&self.items, default);
+        let default_idx = entries
+            .iter()
+            .position(|(_, path, _)| Some(path) == default_path.as_ref())
+            .unwrap_or(0);
+
+        entries
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (titles, _, entry))| GrubEntry {
+                titles,
+                // flatten only keeps entries with a kernel
+                linux: entry.linux.clone().unwrap_or_default(),
+                initrd: entry.initrd.clone(),
+                cmdline: entry.cmdline.clone(),
+                devicetree: entry.devicetree.clone(),
+                is_default: idx == default_idx,
+            })
+            .collect()
+    }
+}
+
+/// Runs the commands of grub.cfg that describe the menu. Nothing is ever executed, conditions
+/// can only test variables, search for partitions and check that files exist.
+struct Interpreter<'a> {
+    partitions: &'a [GrubPartition],
+    vars: HashMap<String, String>,
+    items: Vec<MenuItem>,
+    /// The menuentry being run, if any.
+    entry: Option<GrubMenuEntry>,
+    included: Vec<PathBuf>,
+    depth: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    fn run(
+        partitions: &'a [GrubPartition],
+        cfg_partition: &GrubPartition,
+        cfg_path: &Path,
+    ) -> anyhow::Result<GrubMenu> {
+        let mut vars: HashMap<String, String> = GRUB_FEATURES
+            .iter()
+            .map(|feature| (feature.to_string(), "y".to_string()))
+            .collect();
+
+        let cfg_dir = cfg_path
+            .parent()
+            .and_then(|dir| dir.strip_prefix(&cfg_partition.mountpoint).ok())
+            .unwrap_or(Path::new(""));
+        vars.insert("root".to_string(), cfg_partition.device.clone());
+        vars.insert(
+            "prefix".to_string(),
+            format!("({})/{}", cfg_partition.device, cfg_dir.display()),
+        );
+
+        let mut interpreter = Self {
+            partitions,
+            vars,
+            items: Vec::new(),
+            entry: None,
+            included: Vec::new(),
+            depth: 0,
+        };
+
+        interpreter.run_file(cfg_path)?;
+
+        Ok(GrubMenu {
+            items: interpreter.items,
+            vars: interpreter.vars,
+            included: interpreter.included,
+        })
+    }
+
+    fn run_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            anyhow::bail!("too many nested configs at {}", path.display());
+        }
+
+        let commands = Parser::parse(&std::fs::read_to_string(path)?)?;
+
+        self.depth += 1;
+        self.run_commands(&commands);
+        self.depth -= 1;
+
+        Ok(())
+    }
+
+    /// Runs the commands and returns whether the last one succeeded.
+    fn run_commands(&mut self, commands: &[Command]) -> bool {
+        let mut status = true;
+
+        for command in commands {
+            status = match command {
+                Command::Simple(words) => {
+                    let args = self.expand_words(words);
+                    match args.split_first() {
+                        Some((cmd, args)) => self.run_simple(cmd, args),
+                        None => true,
+                    }
+                }
+                Command::If {
+                    branches,
+                    otherwise,
+                } => match branches
+                    .iter()
+                    .find(|(condition, _)| self.run_commands(condition))
+                {
+                    Some((_, body)) => self.run_commands(body),
+                    None => self.run_commands(otherwise),
+                },
+                Command::Menu {
+                    submenu,
+                    args,
+                    body,
+                } => {
+                    let args = self.expand_words(args);
+                    self.run_menu(*submenu, &args, body);
+                    true
+                }
+                Command::Skipped => true,
+            };
+        }
+
+        status
+    }
+
+    fn run_menu(&mut self, submenu: bool, args: &[String], body: &[Command]) {
+        let mut title = None;
+        let mut id = None;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--id" => id = args.next().cloned(),
+                "--class" | "--users" | "--hotkey" => {
+                    args.next();
+                }
+                "--unrestricted" => {}
+                arg => match arg.strip_prefix("--id=") {
+                    Some(arg_id) => id = Some(arg_id.to_string()),
+                    None if arg.starts_with("--") => {}
+                    None => {
+                        if title.is_none() {
+                            title = Some(arg.to_string());
+                        }
+                    }
+                },
+            }
+        }
+
+        let title = title.unwrap_or_default();
+
+        // the body runs with a copy of the variables, changes to them do not leak out of it
+        let vars = self.vars.clone();
+
+        if submenu {
+            let items = std::mem::take(&mut self.items);
+            self.run_commands(body);
+            let sub_items = std::mem::replace(&mut self.items, items);
+            self.items.push(MenuItem::Submenu {
+                title,
+                id,
+                items: sub_items,
+            });
+        } else {
+            let entry = self.entry.replace(GrubMenuEntry {
+                title,
+                id,
+                ..Default::default()
+            });
+            self.run_commands(body);
+            let new_entry = std::mem::replace(&mut self.entry, entry);
+            self.items
+                .push(MenuItem::Entry(new_entry.expect("entry is set above")));
+        }
+
+        self.vars = vars;
+    }
+
+    fn run_simple(&mut self, cmd: &str, args: &[String]) -> bool {
+        match cmd {
+            "set" => {
+                for arg in args {
+                    if let Some((name, val)) = arg.split_once('=') {
+                        self.vars.insert(name.to_string(), val.to_string());
+                    }
+                }
+                true
+            }
+            "unset" => {
+                for arg in args {
+                    self.vars.remove(arg);
+                }
+                true
+            }
+            "true" => true,
+            "false" => false,
+            "[" => match args.split_last() {
+                Some((last, args)) if last == "]" => self.test(args),
+                _ => false,
+            },
+            "test" => self.test(args),
+            "search" | "search.fs_uuid" | "search.file" | "search.fs_label" => {
+                self.search(cmd, args)
+            }
+            "configfile" | "source" => {
+                let Some(path) = args.first().and_then(|path| self.resolve(path)) else {
+                    return false;
+                };
+                self.included.push(path.clone());
+                match self.run_file(&path) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("{}: {e}", path.display());
+                        false
+                    }
+                }
+            }
+            "load_env" => self.load_env(args),
+            "linux" | "linuxefi" | "linux16" => {
+                let Some((linux, cmdline)) = args.split_first() else {
+                    return false;
+                };
+                let linux = self.resolve(linux);
+                let Some(entry) = &mut self.entry else {
+                    return false;
+                };
+                entry.linux = linux;
+                entry.cmdline = (!cmdline.is_empty()).then(|| cmdline.join(" "));
+                entry.linux.is_some()
+            }
+            "initrd" | "initrdefi" | "initrd16" => {
+                let initrd: Option<Vec<PathBuf>> =
+                    args.iter().map(|initrd| self.resolve(initrd)).collect();
+                match (&mut self.entry, initrd) {
+                    (Some(entry), Some(initrd)) => {
+                        entry.initrd = initrd;
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            "devicetree" => {
+                let devicetree = args.first().and_then(|devicetree| self.resolve(devicetree));
+                match (&mut self.entry, devicetree) {
+                    (Some(entry), Some(devicetree)) => {
+                        entry.devicetree = Some(devicetree);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            // GRUB allows setting a variable without set
+            assignment if args.is_empty() && assignment.contains('=') => {
+                self.run_simple("set", &[assignment.to_string()])
+            }
+            _ => {
+                debug!("ignoring '{cmd}'");
+                false
+            }
+        }
+    }
+
+    // Documentation: https://www.gnu.org/software/grub/manual/grub/grub.html#test
+    fn test(&self, args: &[String]) -> bool {
+        let file_test = |path: &str, test: fn(&std::fs::Metadata) -> bool| {
+            self.resolve(path)
+                .and_then(|path| std::fs::metadata(path).ok())
+                .is_some_and(|metadata| test(&metadata))
+        };
+        let numeric_test = |a: &str, b: &str, test: fn(i64, i64) -> bool| match (
+            a.parse::<i64>(),
+            b.parse::<i64>(),
+        ) {
+            (Ok(a), Ok(b)) => test(a, b),
+            _ => false,
+        };
+
+        match args {
+            [] => false,
+            [bang, args @ ..] if bang == "!" => !self.test(args),
+            [arg] => !arg.is_empty(),
+            [op, arg] => match op.as_str() {
+                "-z" => arg.is_empty(),
+                "-n" => !arg.is_empty(),
+                "-e" => file_test(arg, |_| true),
+                "-f" => file_test(arg, std::fs::Metadata::is_file),
+                "-d" => file_test(arg, std::fs::Metadata::is_dir),
+                "-s" => file_test(arg, |metadata| metadata.len() > 0),
+                _ => false,
+            },
+            [a, op, b] => match op.as_str() {
+                "=" | "==" => a == b,
+                "!=" => a != b,
+                "-eq" => numeric_test(a, b, |a, b| a == b),
+                "-ne" => numeric_test(a, b, |a, b| a != b),
+                "-lt" => numeric_test(a, b, |a, b| a < b),
+                "-le" => numeric_test(a, b, |a, b| a <= b),
+                "-gt" => numeric_test(a, b, |a, b| a > b),
+                "-ge" => numeric_test(a, b, |a, b| a >= b),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Documentation: https://www.gnu.org/software/grub/manual/grub/grub.html#search
+    fn search(&mut self, cmd: &str, args: &[String]) -> bool {
+        let mut kind = cmd.strip_prefix("search.").map(str::to_string);
+        let mut var = None;
+        let mut positional = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--fs-uuid" | "-u" => kind = Some("fs_uuid".to_string()),
+                "--file" | "-f" => kind = Some("file".to_string()),
+                "--label" | "-l" => kind = Some("fs_label".to_string()),
+                "--set" | "-s" => var = Some("root".to_string()),
+                "--hint" | "-h" => {
+                    args.next();
+                }
+                arg => {
+                    if let Some(name) = arg.strip_prefix("--set=") {
+                        var = Some(name.to_string());
+                    } else if !arg.starts_with('-') {
+                        positional.push(arg.to_string());
+                    }
+                }
+            }
+        }
+
+        let Some(needle) = positional.first() else {
+            return false;
+        };
+
+        // search.fs_uuid and friends take the variable to set after what to search for
+        if cmd != "search" {
+            var = Some(
+                positional
+                    .get(1)
+                    .cloned()
+                    .unwrap_or_else(|| "root".to_string()),
+            );
+        }
+
+        let found = match kind.as_deref() {
+            Some("fs_uuid") => self.partitions.iter().find(|partition| {
+                partition
+                    .uuid
+                    .as_ref()
+                    .is_some_and(|uuid| uuid.eq_ignore_ascii_case(needle))
+            }),
+            Some("file") => self.partitions.iter().find(|partition| {
+                partition
+                    .mountpoint
+                    .join(needle.trim_start_matches('/'))
+                    .exists()
+            }),
+            _ => {
+                warn!("unsupported search '{cmd} {needle}'");
+                None
+            }
+        };
+
+        let Some(partition) = found else {
+            debug!("search for '{needle}' found nothing");
+            return false;
+        };
+
+        if let Some(var) = var {
+            self.vars.insert(var, partition.device.clone());
+        }
+
+        true
+    }
+
+    /// Reads variables from a grubenv file, which holds one name=value per line.
+    fn load_env(&mut self, args: &[String]) -> bool {
+        let mut file = None;
+        let mut whitelist = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-f" | "--file" => file = args.next().cloned(),
+                "-s" | "--skip-sig" => {}
+                arg => match arg.strip_prefix("--file=") {
+                    Some(arg_file) => file = Some(arg_file.to_string()),
+                    None => whitelist.push(arg.to_string()),
+                },
+            }
+        }
+
+        let file = file.unwrap_or_else(|| {
+            format!(
+                "{}/grubenv",
+                self.vars.get("prefix").map(String::as_str).unwrap_or("")
+            )
+        });
+
+        let Some(contents) = self
+            .resolve(&file)
+            .and_then(|path| std::fs::read_to_string(path).ok())
+        else {
+            return false;
+        };
+
+        for (name, val) in contents
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+        {
+            if whitelist.is_empty() || whitelist.iter().any(|allowed| allowed == name) {
+                self.vars.insert(name.to_string(), val.to_string());
+            }
+        }
+
+        true
+    }
+
+    /// Maps a GRUB path, either (device)/path or a path on $root, to where it is mounted.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let (device, path) = match path.strip_prefix('(') {
+            Some(path) => path.split_once(')')?,
+            None => (self.vars.get("root")?.as_str(), path),
+        };
+
+        let device = normalize_device(device);
+        let Some(partition) = self
+            .partitions
+            .iter()
+            .find(|partition| normalize_device(&partition.device) == device)
+        else {
+            debug!("no partition for GRUB device '{device}'");
+            return None;
+        };
+
+        Some(partition.mountpoint.join(path.trim_start_matches('/')))
+    }
+
+    fn expand_words(&self, words: &[Word]) -> Vec<String> {
+        words.iter().flat_map(|word| self.expand(word)).collect()
+    }
+
+    fn expand(&self, word: &Word) -> Vec<String> {
+        let mut fields = Vec::new();
+        let mut current = String::new();
+        let mut has_field = false;
+
+        for part in word {
+            match part {
+                WordPart::Literal(literal) => {
+                    current.push_str(literal);
+                    has_field = true;
+                }
+                WordPart::Var { name, quoted: true } => {
+                    current.push_str(self.vars.get(name).map(String::as_str).unwrap_or(""));
+                    has_field = true;
+                }
+                WordPart::Var {
+                    name,
+                    quoted: false,
+                } => {
+                    let val = self.vars.get(name).map(String::as_str).unwrap_or("");
+                    if val.starts_with(char::is_whitespace) && has_field {
+                        fields.push(std::mem::take(&mut current));
+                        has_field = false;
+                    }
+                    for (idx, piece) in val.split_whitespace().enumerate() {
+                        if idx > 0 {
+                            fields.push(std::mem::take(&mut current));
+                        }
+                        current.push_str(piece);
+                        has_field = true;
+                    }
+                    if val.ends_with(char::is_whitespace) && has_field {
+                        fields.push(std::mem::take(&mut current));
+                        has_field = false;
+                    }
+                }
+            }
+        }
+
+        if has_field {
+            fields.push(current);
+        }
+
+        fields
+    }
+}
+
+#[derive(Clone, Debug)]
+struct GrubEntry {
+    /// The titles of the submenus the entry is in, followed by its own.
+    titles: Vec<String>,
+    linux: PathBuf,
+    initrd: Vec<PathBuf>,
+    cmdline: Option<String>,
+    devicetree: Option<PathBuf>,
+    is_default: bool,
+}
+
+impl Display for GrubEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.titles.join(" > "))
+    }
+}
+
+impl BootEntry for GrubEntry {
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+
+    fn linux(&self) -> Option<PathBuf> {
+        Some(self.linux.clone())
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        Ok(LinuxBootParts {
+            linux: self.linux.clone(),
+            initrd: self.initrd.clone(),
+            cmdline: self.cmdline.clone(),
+            devicetree: self.devicetree.clone(),
+            devicetree_overlay: Vec::new(),
+            initrd_verified: false,
+        })
+    }
+}
+
+/// Lists the partitions of a disk along with their GRUB partition names.
+fn disk_partitions(disk_chardev_path: &Path) -> Vec<(u32, String)> {
+    let gpt_cfg = gpt::GptConfig::new().writable(false);
+
+    if let Ok(disk) = gpt_cfg.open(disk_chardev_path) {
+        disk.partitions()
+            .keys()
+            .map(|part_idx| (*part_idx, format!("gpt{part_idx}")))
+            .collect()
+    } else if let Ok(Ok(mbr_disk)) = std::fs::File::open(disk_chardev_path).map(|mut disk| {
+        mbr::ProtectiveMBR::from_disk(&mut disk, gpt::disk::LogicalBlockSize::Lb512)
+    }) {
+        (0u32..=3)
+            .filter(|idx| {
+                mbr_disk
+                    .partition(*idx as usize)
+                    .is_some_and(|part| part.os_type != 0)
+            })
+            .map(|idx| (idx + 1, format!("msdos{}", idx + 1)))
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Finds the grub.cfg files below the root of a partition.
+fn find_cfgs(mountpoint: &Path) -> Vec<PathBuf> {
+    let mut cfgs: Vec<PathBuf> = GRUB_CFG_PATHS
+        .iter()
+        .map(|cfg_path| mountpoint.join(cfg_path))
+        .filter(|cfg_path| cfg_path.is_file())
+        .collect();
+
+    let mut efi_cfgs: Vec<PathBuf> = std::fs::read_dir(mountpoint.join("EFI"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path().join("grub.cfg"))
+        .filter(|cfg_path| cfg_path.is_file())
+        .collect();
+    efi_cfgs.sort();
+
+    cfgs.extend(efi_cfgs);
+    cfgs
+}
+
+/// GrubBootLoader boots the kernels in the menus of grub.cfg files, for distributions that do
+/// not write boot loader specification entries.
+#[derive(Default)]
+pub struct GrubBootLoader {
+    partitions: Vec<GrubPartition>,
+    /// grub.cfg files, along with the index of the partition they are on.
+    cfgs: Vec<(usize, PathBuf)>,
+}
+
+impl GrubBootLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts every partition of a disk, along with the grub.cfg files on each of them.
+    fn mount_partitions(
+        disk_idx: usize,
+        block_dev: &BlockDevice,
+    ) -> Vec<(GrubPartition, Vec<PathBuf>)> {
+        let mut partitions = Vec::new();
+
+        for (part_idx, part_name) in disk_partitions(&block_dev.chardev_path) {
+            let partition_chardev_path = block_dev.partition_chardev_path(part_idx);
+            let mountpoint = PathBuf::from(GRUB_MNT_PATH)
+                .join(block_dev.diskseq.to_string())
+                .join(part_idx.to_string());
+
+            if let Err(e) = mount_partition(&partition_chardev_path, &mountpoint, true) {
+                debug!("failed to mount {}: {e}", partition_chardev_path.display());
+                continue;
+            }
+
+            let uuid = std::fs::File::open(&partition_chardev_path)
+                .ok()
+                .and_then(fs_uuid);
+
+            let cfgs = find_cfgs(&mountpoint);
+            for cfg_path in &cfgs {
+                debug!("found {}", cfg_path.display());
+            }
+
+            partitions.push((
+                GrubPartition {
+                    block_dev: block_dev.clone(),
+                    device: format!("hd{disk_idx},{part_name}"),
+                    mountpoint,
+                    uuid,
+                },
+                cfgs,
+            ));
+        }
+
+        partitions
+    }
+}
+
+impl BootLoader for GrubBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        // any partition can be referred to by grub.cfg, so mount all of them
+        let disks: Vec<(usize, BlockDevice)> = block_devices()?.into_iter().enumerate().collect();
+
+        for (partition, cfgs) in probe_in_parallel(&disks, |(disk_idx, block_dev)| {
+            Self::mount_partitions(*disk_idx, block_dev)
+        })
+        .into_iter()
+        .flatten()
+        {
+            for cfg_path in cfgs {
+                self.cfgs.push((self.partitions.len(), cfg_path));
+            }
+            self.partitions.push(partition);
+        }
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        let mut menus = Vec::new();
+
+        for (partition_idx, cfg_path) in &self.cfgs {
+            let partition = &self.partitions[*partition_idx];
+            match Interpreter::run(&self.partitions, partition, cfg_path) {
+                Ok(menu) => menus.push((partition, cfg_path, menu)),
+                Err(e) => warn!("{}: {e}", cfg_path.display()),
+            }
+        }
+
+        // a config pulled in by another one, like the stub grub.cfg on the ESP that loads the
+        // real one, is only shown as part of it
+        let included: Vec<PathBuf> = menus
+            .iter()
+            .flat_map(|(_, _, menu)| menu.included.iter().cloned())
+            .collect();
+
+        menus
+            .into_iter()
+            .filter(|(_, cfg_path, _)| !included.contains(cfg_path))
+            .filter_map(|(partition, cfg_path, menu)| {
+                let entries: Vec<Box<dyn BootEntry>> = menu
+                    .entries()
+                    .into_iter()
+                    .map(|entry| Box::new(entry) as Box<dyn BootEntry>)
+                    .collect();
+
+                if entries.is_empty() {
+                    debug!("no entries in {}", cfg_path.display());
+                    return None;
+                }
+
+                Some(BootDevice {
+                    name: format!("{} (GRUB)", partition.block_dev.name()),
+                    selectors: partition.block_dev.selectors(),
+                    entries,
+                    timeout: menu.timeout(),
+                    editor: true,
+                })
+            })
+            .collect()
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        self.cfgs.clear();
+
+        for partition in self.partitions.drain(..) {
+            if let Err(e) = mount::umount2(&partition.mountpoint, MntFlags::MNT_DETACH) {
+                error!("failed to unmount {}: {e}", partition.mountpoint.display());
+            }
+        }
+
+        if let Err(e) = std::fs::remove_dir_all(GRUB_MNT_PATH) {
+            error!("failed to remove {}: {e}", GRUB_MNT_PATH);
+        }
+    }
+
+    fn disks_changed(&mut self, events: &[DiskEvent]) -> bool {
+        let changed = events.iter().any(|event| match event {
+            DiskEvent::Added(diskseq) => !self
+                .partitions
+                .iter()
+                .any(|partition| partition.block_dev.diskseq == *diskseq),
+            DiskEvent::Removed(diskseq) => self
+                .partitions
+                .iter()
+                .any(|partition| partition.block_dev.diskseq == *diskseq),
+        });
+
+        if !changed {
+            return false;
+        }
+
+        // hdN numbers the disks in the order they are found, so start over instead of adding or
+        // removing a single disk
+        self.teardown();
+        if let Err(e) = self.setup() {
+            error!("failed to set up again: {e}");
+        }
+
+        true
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::Grub
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+    use crate::boot_loader::{disk::BlockDevice, BootEntry, Timeout};
+
+    use super::{
+        Command, GrubMenuEntry, GrubPartition, Interpreter, MenuItem, Parser, WordPart,
+        MAX_INCLUDE_DEPTH,
+    };
+
+    fn partition(device: &str, mountpoint: PathBuf, uuid: Option<&str>) -> GrubPartition {
+        GrubPartition {
+            block_dev: BlockDevice::new(0, PathBuf::from("/dev/sda"), PathBuf::from("/dev/sda")),
+            device: device.to_string(),
+            mountpoint,
+            uuid: uuid.map(str::to_string),
+        }
+    }
+
+    fn interpreter(partitions: &[GrubPartition]) -> Interpreter<'_> {
+        Interpreter {
+            partitions,
+            vars: HashMap::from([("root".to_string(), "hd0,gpt1".to_string())]),
+            items: Vec::new(),
+            entry: None,
+            included: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn run(interpreter: &mut Interpreter, script: &str) -> bool {
+        interpreter.run_commands(&Parser::parse(script).unwrap())
+    }
+
+    fn var<'a>(interpreter: &'a Interpreter, name: &str) -> Option<&'a str> {
+        interpreter.vars.get(name).map(String::as_str)
+    }
+
+    #[test]
+    fn test_parse_grub_cfg() {
+        let commands = Parser::parse(
+            r#"# comment
+set root='hd0,gpt2' ; set timeout=5
+if [ x"${feature_menuentry_id}" = xy ]; then
+  menuentry_id_option="--id"
+elif false; then
+  true
+else
+  menuentry_id_option=""
+fi
+function load_video {
+  insmod all_video
+}
+for x in a b; do echo $x; done
+menuentry "Linux \"6.6\"" $menuentry_id_option 'linux-6.6' {
+	linux /vmlinuz \
+		quiet
+}
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(commands.len(), 6);
+        assert_eq!(
+            commands[0],
+            Command::Simple(vec![
+                vec![WordPart::Literal("set".to_string())],
+                vec![WordPart::Literal("root=hd0,gpt2".to_string())],
+            ])
+        );
+        let Command::If {
+            branches,
+            otherwise,
+        } = &commands[2]
+        else {
+            panic!("expected if, got {:?}", commands[2]);
+        };
+        assert_eq!(branches.len(), 2);
+        assert_eq!(
+            branches[0].0,
+            vec![Command::Simple(vec![
+                vec![WordPart::Literal("[".to_string())],
+                vec![
+                    WordPart::Literal("x".to_string()),
+                    WordPart::Var {
+                        name: "feature_menuentry_id".to_string(),
+                        quoted: true
+                    }
+                ],
+                vec![WordPart::Literal("=".to_string())],
+                vec![WordPart::Literal("xy".to_string())],
+                vec![WordPart::Literal("]".to_string())],
+            ])]
+        );
+        assert_eq!(otherwise.len(), 1);
+        assert_eq!(commands[3], Command::Skipped);
+        assert_eq!(commands[4], Command::Skipped);
+        let Command::Menu {
+            submenu,
+            args,
+            body,
+        } = &commands[5]
+        else {
+            panic!("expected menuentry, got {:?}", commands[5]);
+        };
+        assert!(!submenu);
+        assert_eq!(
+            args[0],
+            vec![WordPart::Literal("Linux \"6.6\"".to_string())]
+        );
+        assert_eq!(body.len(), 1);
+
+        assert!(Parser::parse("menuentry 'unterminated").is_err());
+        assert!(Parser::parse("if true; then true").is_err());
+        assert!(Parser::parse("}").is_err());
+    }
+
+    #[test]
+    fn test_grub_cfg() {
+        let dir = std::env::temp_dir().join(format!("tboot-grub-{}", std::process::id()));
+        let esp = dir.join("esp");
+        let root = dir.join("root");
+        std::fs::create_dir_all(esp.join("EFI/ubuntu")).unwrap();
+        std::fs::create_dir_all(root.join("boot/grub")).unwrap();
+
+        // as written by grub-install on Ubuntu
+        std::fs::write(
+            esp.join("EFI/ubuntu/grub.cfg"),
+            r#"search.fs_uuid 0f3e8c2a-519b-4d7e-8f3c-112233445566 root hd0,gpt2
+set prefix=($root)'/boot/grub'
+configfile $prefix/grub.cfg
+"#,
+        )
+        .unwrap();
+
+        // trimmed down from what grub-mkconfig writes on Ubuntu
+        std::fs::write(
+            root.join("boot/grub/grub.cfg"),
+            r#"if [ -s $prefix/grubenv ]; then
+  set have_grubenv=true
+  load_env
+fi
+if [ "${next_entry}" ] ; then
+   set default="${next_entry}"
+   set next_entry=
+   save_env next_entry
+   set boot_once=true
+else
+   set default="0"
+fi
+
+if [ x"${feature_menuentry_id}" = xy ]; then
+  menuentry_id_option="--id"
+else
+  menuentry_id_option=""
+fi
+
+function recordfail {
+  set recordfail=1
+  if [ -n "${have_grubenv}" ]; then if [ -z "${boot_once}" ]; then save_env recordfail; fi; fi
+}
+
+set linux_gfx_mode=keep
+if [ "${linux_gfx_mode}" != "text" ]; then set vt_handoff=vt.handoff=7; else set vt_handoff= ; fi
+if [ "${recordfail}" = 1 ] ; then
+  set timeout=30
+else
+  if [ x$feature_timeout_style = xy ] ; then
+    set timeout_style=hidden
+    set timeout=0
+  fi
+fi
+menuentry 'Ubuntu' --class ubuntu --class gnu-linux --class gnu --class os $menuentry_id_option 'gnulinux-simple-0f3e8c2a' {
+	recordfail
+	load_video
+	gfxmode $linux_gfx_mode
+	insmod gzio
+	search --no-floppy --fs-uuid --set=root 0f3e8c2a-519b-4d7e-8f3c-112233445566
+	linux	/boot/vmlinuz-6.5.0-14-generic root=UUID=0f3e8c2a-519b-4d7e-8f3c-112233445566 ro  quiet splash $vt_handoff
+	initrd	/boot/initrd.img-6.5.0-14-generic
+}
+submenu 'Advanced options for Ubuntu' $menuentry_id_option 'gnulinux-advanced-0f3e8c2a' {
+	menuentry 'Ubuntu, with Linux 6.5.0-14-generic' --class ubuntu $menuentry_id_option 'gnulinux-6.5.0-14-generic-advanced-0f3e8c2a' {
+		linux	/boot/vmlinuz-6.5.0-14-generic root=UUID=0f3e8c2a-519b-4d7e-8f3c-112233445566 ro  quiet splash $vt_handoff
+		initrd	/boot/initrd.img-6.5.0-14-generic
+	}
+	menuentry 'Ubuntu, with Linux 6.5.0-14-generic (recovery mode)' --class ubuntu $menuentry_id_option 'gnulinux-6.5.0-14-generic-recovery-0f3e8c2a' {
+		linux	/boot/vmlinuz-6.5.0-14-generic root=UUID=0f3e8c2a-519b-4d7e-8f3c-112233445566 ro recovery nomodeset dis_ucode_ldr
+		initrd	/boot/initrd.img-6.5.0-14-generic
+	}
+}
+if [ -f ${prefix}/custom.cfg ]; then
+  source ${prefix}/custom.cfg
+fi
+menuentry 'UEFI Firmware Settings' $menuentry_id_option 'uefi-firmware' {
+	fwsetup
+}
+"#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            root.join("boot/grub/grubenv"),
+            "# GRUB Environment Block\nnext_entry=gnulinux-advanced-0f3e8c2a>gnulinux-6.5.0-14-generic-recovery-0f3e8c2a\n###############\n",
+        )
+        .unwrap();
+
+        let block_dev = BlockDevice::new(0, PathBuf::from("/dev/sda"), PathBuf::from("/dev/sda"));
+        let partitions = vec![
+            GrubPartition {
+                block_dev: block_dev.clone(),
+                device: "hd0,gpt1".to_string(),
+                mountpoint: esp.clone(),
+                uuid: Some("12CE-A600".to_string()),
+            },
+            GrubPartition {
+                block_dev,
+                device: "hd0,gpt2".to_string(),
+                mountpoint: root.clone(),
+                uuid: Some("0f3e8c2a-519b-4d7e-8f3c-112233445566".to_string()),
+            },
+        ];
+
+        let menu = Interpreter::run(
+            &partitions,
+            &partitions[0],
+            &esp.join("EFI/ubuntu/grub.cfg"),
+        )
+        .unwrap();
+
+        assert_eq!(menu.included, vec![root.join("boot/grub/grub.cfg")]);
+        assert_eq!(menu.timeout(), Timeout::MenuHidden);
+
+        let entries = menu.entries();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "Ubuntu",
+                "Advanced options for Ubuntu > Ubuntu, with Linux 6.5.0-14-generic",
+                "Advanced options for Ubuntu > Ubuntu, with Linux 6.5.0-14-generic (recovery mode)",
+            ]
+        );
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.is_default())
+                .collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+
+        let parts = entries[0].select().unwrap();
+        assert_eq!(parts.linux, root.join("boot/vmlinuz-6.5.0-14-generic"));
+        assert_eq!(
+            parts.initrd,
+            vec![root.join("boot/initrd.img-6.5.0-14-generic")]
+        );
+        assert_eq!(
+            parts.cmdline.as_deref(),
+            Some("root=UUID=0f3e8c2a-519b-4d7e-8f3c-112233445566 ro quiet splash vt.handoff=7")
+        );
+
+        // without grubenv, the first entry is the default
+        std::fs::remove_file(root.join("boot/grub/grubenv")).unwrap();
+        std::fs::write(root.join("boot/grub/custom.cfg"), "set timeout=-1\n").unwrap();
+        let menu = Interpreter::run(
+            &partitions,
+            &partitions[1],
+            &root.join("boot/grub/grub.cfg"),
+        )
+        .unwrap();
+        assert_eq!(menu.timeout(), Timeout::MenuForce);
+        assert!(menu.entries()[0].is_default());
+
+        std::fs::write(root.join("boot/grub/custom.cfg"), "set timeout=3\n").unwrap();
+        let menu = Interpreter::run(
+            &partitions,
+            &partitions[1],
+            &root.join("boot/grub/grub.cfg"),
+        )
+        .unwrap();
+        assert_eq!(menu.timeout(), Timeout::Countdown(Duration::from_secs(3)));
+
+        // a config that includes itself stops eventually
+        std::fs::write(
+            root.join("boot/grub/custom.cfg"),
+            "source ${prefix}/custom.cfg\n",
+        )
+        .unwrap();
+        assert!(Interpreter::run(
+            &partitions,
+            &partitions[1],
+            &root.join("boot/grub/grub.cfg")
+        )
+        .is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_test() {
+        let dir = std::env::temp_dir().join(format!("tboot-grub-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("dir")).unwrap();
+        std::fs::write(dir.join("file"), "contents").unwrap();
+        std::fs::write(dir.join("empty"), "").unwrap();
+
+        let partitions = [partition("hd0,gpt1", dir.clone(), None)];
+        let mut interpreter = interpreter(&partitions);
+
+        for (script, expected) in [
+            ("[ a = a ]", true),
+            ("[ a == b ]", false),
+            ("[ a != b ]", true),
+            ("[ a = a", false),
+            ("test -z \"$unset\"", true),
+            ("test -n \"$unset\"", false),
+            ("test foo", true),
+            ("test", false),
+            // numbers are compared as numbers, not as strings
+            ("[ 10 -gt 9 ]", true),
+            ("[ 9 -ge 10 ]", false),
+            ("[ 1 -lt 2 ]", true),
+            ("[ 2 -le 2 ]", true),
+            ("[ 01 -eq 1 ]", true),
+            ("[ 1 -ne 1 ]", false),
+            ("[ x -eq x ]", false),
+            ("[ ! 1 -lt 2 ]", false),
+            ("[ ! a = b ]", true),
+            ("[ -e /file ]", true),
+            ("[ -f /file ]", true),
+            ("[ -f /dir ]", false),
+            ("[ -d /dir ]", true),
+            ("[ -d (hd0,gpt1)/file ]", false),
+            ("[ -s /file ]", true),
+            ("[ -s /empty ]", false),
+            ("[ -e /missing ]", false),
+            ("[ -e (hd1,gpt1)/file ]", false),
+            ("[ a -foo b ]", false),
+        ] {
+            assert_eq!(run(&mut interpreter, script), expected, "{script}");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = std::env::temp_dir().join(format!("tboot-grub-search-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("esp")).unwrap();
+        std::fs::create_dir_all(dir.join("root/boot")).unwrap();
+        std::fs::write(dir.join("root/boot/marker"), "").unwrap();
+
+        let partitions = [
+            partition("hd0,gpt1", dir.join("esp"), Some("12CE-A600")),
+            partition(
+                "hd0,gpt2",
+                dir.join("root"),
+                Some("0f3e8c2a-519b-4d7e-8f3c-112233445566"),
+            ),
+        ];
+        let mut interpreter = interpreter(&partitions);
+
+        // UUIDs are compared without regard to case
+        assert!(run(
+            &mut interpreter,
+            "search --no-floppy --fs-uuid --set=found 0F3E8C2A-519B-4D7E-8F3C-112233445566"
+        ));
+        assert_eq!(var(&interpreter, "found"), Some("hd0,gpt2"));
+        assert_eq!(var(&interpreter, "root"), Some("hd0,gpt1"));
+
+        assert!(run(
+            &mut interpreter,
+            "search --fs-uuid --set 0f3e8c2a-519b-4d7e-8f3c-112233445566"
+        ));
+        assert_eq!(var(&interpreter, "root"), Some("hd0,gpt2"));
+
+        // search.fs_uuid takes the variable to set after the UUID, and sets root without one
+        assert!(run(
+            &mut interpreter,
+            "search.fs_uuid 12ce-a600 esp hd0,gpt1"
+        ));
+        assert_eq!(var(&interpreter, "esp"), Some("hd0,gpt1"));
+        assert!(run(&mut interpreter, "search.fs_uuid 12CE-A600"));
+        assert_eq!(var(&interpreter, "root"), Some("hd0,gpt1"));
+
+        assert!(run(
+            &mut interpreter,
+            "search --file --set=found --hint hd0,gpt1 /boot/marker"
+        ));
+        assert_eq!(var(&interpreter, "found"), Some("hd0,gpt2"));
+        assert!(run(&mut interpreter, "search.file /boot/marker marker"));
+        assert_eq!(var(&interpreter, "marker"), Some("hd0,gpt2"));
+
+        // nothing is set when nothing is found
+        assert!(!run(
+            &mut interpreter,
+            "search --fs-uuid --set=missing 1234-5678"
+        ));
+        assert_eq!(var(&interpreter, "missing"), None);
+        assert!(!run(&mut interpreter, "search --label --set=label ROOT"));
+        assert_eq!(var(&interpreter, "label"), None);
+        assert!(!run(&mut interpreter, "search --fs-uuid --set=missing"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_env() {
+        let dir = std::env::temp_dir().join(format!("tboot-grub-env-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("grub")).unwrap();
+        std::fs::write(
+            dir.join("grub/grubenv"),
+            "# GRUB Environment Block\nsaved_entry=1\nnext_entry=2\nfoo=a=b\n####\n",
+        )
+        .unwrap();
+
+        let partitions = [partition("hd0,gpt1", dir.clone(), None)];
+
+        // only the whitelisted variables are loaded
+        let mut interpreter = interpreter(&partitions);
+        assert!(run(
+            &mut interpreter,
+            "load_env --file (hd0,gpt1)/grub/grubenv saved_entry foo"
+        ));
+        assert_eq!(var(&interpreter, "saved_entry"), Some("1"));
+        assert_eq!(var(&interpreter, "next_entry"), None);
+        assert_eq!(var(&interpreter, "foo"), Some("a=b"));
+
+        // everything is loaded without a whitelist, from $prefix/grubenv by default
+        let mut interpreter = self::interpreter(&partitions);
+        interpreter
+            .vars
+            .insert("prefix".to_string(), "(hd0,gpt1)/grub".to_string());
+        assert!(run(&mut interpreter, "load_env"));
+        assert_eq!(var(&interpreter, "saved_entry"), Some("1"));
+        assert_eq!(var(&interpreter, "next_entry"), Some("2"));
+
+        assert!(!run(&mut interpreter, "load_env -f /missing"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_expand() {
+        let partitions = [];
+        let mut interpreter = interpreter(&partitions);
+        interpreter.vars.extend([
+            ("x".to_string(), "a b".to_string()),
+            ("padded".to_string(), " a  b ".to_string()),
+            ("empty".to_string(), String::new()),
+        ]);
+
+        let literal = |literal: &str| WordPart::Literal(literal.to_string());
+        let unquoted = |name: &str| WordPart::Var {
+            name: name.to_string(),
+            quoted: false,
+        };
+        let quoted = |name: &str| WordPart::Var {
+            name: name.to_string(),
+            quoted: true,
+        };
+
+        // unquoted variables are split into fields on whitespace
+        assert_eq!(
+            interpreter.expand(&vec![literal("pre"), unquoted("x"), literal("post")]),
+            vec!["prea", "bpost"]
+        );
+        assert_eq!(
+            interpreter.expand(&vec![literal("pre"), unquoted("padded"), literal("post")]),
+            vec!["pre", "a", "b", "post"]
+        );
+        assert_eq!(interpreter.expand(&vec![quoted("padded")]), vec![" a  b "]);
+        assert_eq!(
+            interpreter.expand(&vec![literal("pre"), quoted("x")]),
+            vec!["prea b"]
+        );
+
+        // an unquoted empty variable is no field at all, a quoted one is an empty field
+        assert!(interpreter.expand(&vec![unquoted("empty")]).is_empty());
+        assert!(interpreter.expand(&vec![unquoted("unset")]).is_empty());
+        assert_eq!(interpreter.expand(&vec![quoted("empty")]), vec![""]);
+
+        assert!(run(&mut interpreter, "set args=$x"));
+        assert_eq!(var(&interpreter, "args"), Some("a"));
+    }
+
+    #[test]
+    fn test_include_depth() {
+        let dir = std::env::temp_dir().join(format!("tboot-grub-depth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("grub.cfg"), "source /grub.cfg\nset after=y\n").unwrap();
+
+        let partitions = [partition("hd0,gpt1", dir.clone(), None)];
+        let menu = Interpreter::run(&partitions, &partitions[0], &dir.join("grub.cfg")).unwrap();
+
+        // the innermost source fails, and every config still runs to its end
+        assert_eq!(menu.included.len(), MAX_INCLUDE_DEPTH);
+        assert_eq!(menu.vars.get("after").map(String::as_str), Some("y"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_path() {
+        let entry = |title: &str, id: Option<&str>| {
+            MenuItem::Entry(GrubMenuEntry {
+                title: title.to_string(),
+                id: id.map(str::to_string),
+                linux: Some(PathBuf::from("/vmlinuz")),
+                ..Default::default()
+            })
+        };
+        let items = vec![
+            entry("Linux", Some("linux")),
+            MenuItem::Submenu {
+                title: "Advanced".to_string(),
+                id: Some("advanced".to_string()),
+                items: vec![
+                    entry("Linux 6.6", Some("linux-6.6")),
+                    entry("Linux 6.1", None),
+                ],
+            },
+        ];
+
+        for (default, expected) in [
+            ("0", Some(vec![0])),
+            ("linux", Some(vec![0])),
+            ("Linux", Some(vec![0])),
+            ("1>1", Some(vec![1, 1])),
+            ("advanced>linux-6.6", Some(vec![1, 0])),
+            ("Advanced>Linux 6.1", Some(vec![1, 1])),
+            ("1>linux-6.6", Some(vec![1, 0])),
+            // a submenu cannot be the default itself
+            ("1", None),
+            ("advanced", None),
+            // an entry has nothing below it
+            ("0>0", None),
+            ("1>2", None),
+            ("5", None),
+            ("missing", None),
+            ("advanced>missing", None),
+        ] {
+            assert_eq!(super::default_path(&items, default), expected, "{default}");
+        }
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        let partitions = [];
+        let mut interpreter = interpreter(&partitions);
+
+        // commands that are not understood fail, so the else branch is taken
+        assert!(run(
+            &mut interpreter,
+            "if frobnicate --all; then set branch=then; else set branch=else; fi"
+        ));
+        assert_eq!(var(&interpreter, "branch"), Some("else"));
+
+        assert!(run(
+            &mut interpreter,
+            "if frobnicate; then set branch=if; elif true; then set branch=elif; fi"
+        ));
+        assert_eq!(var(&interpreter, "branch"), Some("elif"));
+
+        // the status of an if is that of its last command
+        assert!(!run(&mut interpreter, "if true; then frobnicate; fi"));
+    }
+}
@@ -1,10 +1,27 @@
 use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
 
+use log::info;
+use tboot::{config::Config, dev::DiskEvent};
+
+pub mod chromeos;
 pub mod disk;
+pub mod extlinux;
+pub mod fit;
+pub mod flash;
+pub mod fw_cfg;
+pub mod grub;
+pub mod network;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LoaderType {
     Disk,
+    Network,
+    ChromeOs,
+    Extlinux,
+    Grub,
+    Fit,
+    Flash,
+    FwCfg,
 }
 
 impl Display for LoaderType {
@@ -14,6 +31,13 @@ impl Display for LoaderType {
             "{}",
             match self {
                 Self::Disk => "disk",
+                Self::Network => "network",
+                Self::ChromeOs => "chromeos",
+                Self::Extlinux => "extlinux",
+                Self::Grub => "grub",
+                Self::Fit => "fit",
+                Self::Flash => "flash",
+                Self::FwCfg => "fw_cfg",
             }
         )
     }
@@ -25,6 +49,13 @@ impl FromStr for LoaderType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "disk" => Ok(Self::Disk),
+            "network" => Ok(Self::Network),
+            "chromeos" => Ok(Self::ChromeOs),
+            "extlinux" => Ok(Self::Extlinux),
+            "grub" => Ok(Self::Grub),
+            "fit" => Ok(Self::Fit),
+            "flash" => Ok(Self::Flash),
+            "fw_cfg" => Ok(Self::FwCfg),
             _ => anyhow::bail!("invalid loader '{}'", s),
         }
     }
@@ -33,20 +64,154 @@ impl FromStr for LoaderType {
 #[derive(Clone, Debug, PartialEq)]
 pub struct LinuxBootParts {
     pub linux: PathBuf,
-    pub initrd: Option<PathBuf>,
+    /// Multiple initrds are concatenated into one before being loaded.
+    pub initrd: Vec<PathBuf>,
     pub cmdline: Option<String>,
+    pub devicetree: Option<PathBuf>,
+    /// Overlays are applied in order on top of the devicetree, or on top of the devicetree we were
+    /// booted with if there is none.
+    pub devicetree_overlay: Vec<PathBuf>,
+    /// The initrds were verified along with the image they were extracted from, like a signed
+    /// UKI, and are not verified again.
+    pub initrd_verified: bool,
 }
 
 pub trait BootEntry: Display {
     fn is_default(&self) -> bool;
 
-    fn select(&self) -> LinuxBootParts;
+    /// Entries that perform an action instead of booting a kernel return that action here. They
+    /// are never chosen automatically.
+    fn action(&self) -> Option<EntryAction> {
+        None
+    }
+
+    /// Indicates that the entry is known to not boot successfully and should not be chosen
+    /// automatically. It can still be booted explicitly.
+    fn is_bad(&self) -> bool {
+        false
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts>;
+
+    /// The kernel of the entry, for entries that boot a file that can be looked at without
+    /// selecting the entry.
+    fn linux(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Remembers that the entry was picked by the user, so that it can be used as the default
+    /// next time.
+    fn save(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the entry the default for the next boot only.
+    fn set_oneshot(&self) -> anyhow::Result<()> {
+        anyhow::bail!("'{self}' cannot be booted next")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryAction {
+    Reboot,
+    Poweroff,
+}
+
+impl Display for EntryAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Reboot => "Reboot",
+                Self::Poweroff => "Power Off",
+            }
+        )
+    }
+}
+
+impl BootEntry for EntryAction {
+    fn is_default(&self) -> bool {
+        false
+    }
+
+    fn action(&self) -> Option<EntryAction> {
+        Some(*self)
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        anyhow::bail!("'{self}' does not boot a kernel")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// Count down before booting the default entry.
+    Countdown(Duration),
+    /// Boot the default entry right away, unless the user is already present.
+    MenuHidden,
+    /// Never boot automatically, always wait for the user.
+    MenuForce,
+}
+
+/// Narrows down which devices of a loader a boot order item refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Usb,
+    Nvme,
+    Mmc,
+    Sata,
+    Virtio,
+    Removable,
+}
+
+impl DeviceSelector {
+    pub const ALL: [Self; 6] = [
+        Self::Usb,
+        Self::Nvme,
+        Self::Mmc,
+        Self::Sata,
+        Self::Virtio,
+        Self::Removable,
+    ];
+}
+
+impl Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Usb => "usb",
+                Self::Nvme => "nvme",
+                Self::Mmc => "mmc",
+                Self::Sata => "sata",
+                Self::Virtio => "virtio",
+                Self::Removable => "removable",
+            }
+        )
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|selector| selector.to_string() == s)
+            .ok_or_else(|| anyhow::anyhow!("invalid device selector '{}'", s))
+    }
 }
 
 pub struct BootDevice {
     pub name: String,
+    /// The selectors in a boot order that match this device.
+    pub selectors: Vec<DeviceSelector>,
     pub entries: Vec<Box<dyn BootEntry>>,
-    pub timeout: Duration,
+    pub timeout: Timeout,
+    /// Whether the kernel command line of entries can be changed before booting them.
+    pub editor: bool,
 }
 
 pub trait BootLoader {
@@ -57,6 +222,12 @@ pub trait BootLoader {
     fn probe(&mut self) -> Vec<BootDevice>;
 
     fn teardown(&mut self);
+
+    /// Picks up the disks that were plugged in or removed after setup. Returns whether the devices
+    /// of the loader may have changed, in which case it is probed again.
+    fn disks_changed(&mut self, _events: &[DiskEvent]) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -85,6 +256,11 @@ impl Loader {
     }
 
     pub fn setup(&mut self, force: bool) -> anyhow::Result<()> {
+        if force && matches!(self.state, LoaderState::Started | LoaderState::Probed) {
+            // loaders keep what they found in setup, so start from scratch
+            self.inner.teardown();
+        }
+
         if force || matches!(self.state, LoaderState::Unstarted | LoaderState::Shutdown) {
             self.inner.setup()?;
         }
@@ -125,6 +301,55 @@ impl Loader {
     pub fn loader_type(&mut self) -> LoaderType {
         self.inner.loader_type()
     }
+
+    /// Passes disk events on to the loader, and probes it again if its devices changed. Loaders
+    /// that are not set up yet find the new disks once they are. Returns whether the devices
+    /// changed.
+    pub fn disks_changed(&mut self, events: &[DiskEvent]) -> bool {
+        if !matches!(self.state, LoaderState::Started | LoaderState::Probed) {
+            return false;
+        }
+
+        if !self.inner.disks_changed(events) {
+            return false;
+        }
+
+        if self.state == LoaderState::Probed {
+            let old_names: Vec<String> = self
+                .boot_devices
+                .iter()
+                .map(|dev| dev.name.clone())
+                .collect();
+            self.boot_devices = self.inner.probe();
+
+            for dev in &self.boot_devices {
+                if !old_names.contains(&dev.name) {
+                    info!("found boot device {}", dev.name);
+                }
+            }
+
+            for name in old_names {
+                if !self.boot_devices.iter().any(|dev| dev.name == name) {
+                    info!("boot device {name} is gone");
+                }
+            }
+        }
+
+        true
+    }
+
+    pub fn from_type(loader_type: LoaderType, cfg: &Config) -> Self {
+        Self::new(match loader_type {
+            LoaderType::Disk => Box::new(disk::BlsBootLoader::new()),
+            LoaderType::Network => Box::new(network::NetworkBootLoader::new()),
+            LoaderType::ChromeOs => Box::new(chromeos::ChromeOsBootLoader::new()),
+            LoaderType::Extlinux => Box::new(extlinux::ExtlinuxBootLoader::new()),
+            LoaderType::Grub => Box::new(grub::GrubBootLoader::new()),
+            LoaderType::Fit => Box::new(fit::FitBootLoader::new()),
+            LoaderType::Flash => Box::new(flash::FlashBootLoader::new(cfg.programmer)),
+            LoaderType::FwCfg => Box::new(fw_cfg::FwCfgBootLoader::new()),
+        })
+    }
 }
 
 impl Drop for Loader {
@@ -0,0 +1,461 @@
+use crate::net::{
+    self, dhcp, dhcp6,
+    iface::{self, Interface},
+    Url,
+};
+use log::{debug, error, info, warn};
+use std::{
+    fmt::Display,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use super::{BootDevice, BootEntry, BootLoader, LinuxBootParts, LoaderType, Timeout};
+
+const NET_DOWNLOAD_PATH: &str = "/run/tboot/net";
+
+/// How long to wait for a cable to be detected after bringing a link up.
+const CARRIER_TIMEOUT: Duration = Duration::from_secs(3);
+
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Boot files ending in this are manifests describing what to boot, instead of a kernel.
+const MANIFEST_SUFFIX: &str = ".conf";
+
+/// An entry fetched from a server. Nothing but the manifest is downloaded until the entry is
+/// selected.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct NetworkEntry {
+    title: Option<String>,
+    version: Option<String>,
+    linux: Option<Url>,
+    initrd: Vec<Url>,
+    options: Vec<String>,
+    devicetree: Option<Url>,
+    devicetree_overlay: Vec<Url>,
+    /// Where the files are downloaded to when the entry is selected.
+    download_dir: PathBuf,
+}
+
+impl Display for NetworkEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.title, &self.version, &self.linux) {
+            (Some(title), Some(version), _) => write!(f, "{title} ({version})"),
+            (Some(title), None, _) => write!(f, "{title}"),
+            (None, _, Some(linux)) => write!(f, "{linux}"),
+            (None, _, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+impl BootEntry for NetworkEntry {
+    fn is_default(&self) -> bool {
+        // there is only ever one entry per interface
+        true
+    }
+
+    fn select(&self) -> anyhow::Result<LinuxBootParts> {
+        let Some(linux) = &self.linux else {
+            anyhow::bail!("no kernel to boot");
+        };
+
+        // start over in case the entry was selected before
+        if self.download_dir.exists() {
+            std::fs::remove_dir_all(&self.download_dir)?;
+        }
+        std::fs::create_dir_all(&self.download_dir)?;
+
+        let download = |url: &Url, name: String| -> anyhow::Result<PathBuf> {
+            let path = self.download_dir.join(name);
+            info!("downloading {url}");
+            let mut file = std::fs::File::create(&path)?;
+            let len = net::fetch(url, &mut file)
+                .map_err(|e| anyhow::anyhow!("failed to download {url}: {e}"))?;
+            debug!("downloaded {len} bytes to {}", path.display());
+            Ok(path)
+        };
+
+        let linux = download(linux, "linux".to_string())?;
+
+        let initrd = self
+            .initrd
+            .iter()
+            .enumerate()
+            .map(|(i, url)| download(url, format!("initrd-{i}")))
+            .collect::<anyhow::Result<_>>()?;
+
+        let devicetree = match &self.devicetree {
+            Some(url) => Some(download(url, "devicetree".to_string())?),
+            None => None,
+        };
+
+        let devicetree_overlay = self
+            .devicetree_overlay
+            .iter()
+            .enumerate()
+            .map(|(i, url)| download(url, format!("devicetree-overlay-{i}")))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(LinuxBootParts {
+            linux,
+            initrd,
+            cmdline: Some(self.options.join(" ")),
+            devicetree,
+            devicetree_overlay,
+            initrd_verified: false,
+        })
+    }
+}
+
+impl NetworkEntry {
+    /// Parses a manifest using the keys of a Boot Loader Specification type #1 entry. Paths are
+    /// relative to the URL of the manifest.
+    /// https://uapi-group.org/specifications/specs/boot_loader_specification/#type-1-boot-loader-entry-keys
+    fn parse_manifest(
+        manifest_url: &Url,
+        contents: &str,
+        download_dir: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let mut entry = NetworkEntry {
+            download_dir,
+            ..Default::default()
+        };
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, val)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let val = val.trim();
+
+            match key {
+                "title" => entry.title = Some(val.to_string()),
+                "version" => entry.version = Some(val.to_string()),
+                "linux" => entry.linux = Some(manifest_url.join(val)?),
+                "initrd" => entry.initrd.push(manifest_url.join(val)?),
+                "options" => entry.options.push(val.to_string()),
+                "devicetree" => entry.devicetree = Some(manifest_url.join(val)?),
+                "devicetree-overlay" => {
+                    for overlay in val.split_whitespace() {
+                        entry.devicetree_overlay.push(manifest_url.join(overlay)?);
+                    }
+                }
+                _ => debug!("ignoring manifest key '{key}'"),
+            }
+        }
+
+        if entry.linux.is_none() {
+            anyhow::bail!("{manifest_url} does not contain a linux key");
+        }
+
+        Ok(entry)
+    }
+}
+
+struct NetworkInterface {
+    interface: Interface,
+    ipv4: Option<dhcp::Lease>,
+    ipv6: Option<dhcp6::Lease>,
+}
+
+impl NetworkInterface {
+    /// The boot file handed out by the DHCP server, along with the kernel command line if the
+    /// server sent one.
+    fn boot_file(&self) -> Option<(String, Vec<String>)> {
+        if let Some(url) = self.ipv4.as_ref().and_then(|lease| lease.boot_url()) {
+            return Some((url, Vec::new()));
+        }
+
+        self.ipv6.as_ref().and_then(|lease| {
+            lease
+                .bootfile_url
+                .clone()
+                .map(|url| (url, lease.bootfile_params.clone()))
+        })
+    }
+
+    fn configure(&self) -> anyhow::Result<()> {
+        let mut nameservers: Vec<IpAddr> = Vec::new();
+
+        if let Some(lease) = &self.ipv4 {
+            iface::set_ipv4(&self.interface.name, lease.address, lease.prefix_len)?;
+            if let Some(router) = lease.router {
+                iface::add_ipv4_default_route(router)?;
+            }
+            nameservers.extend(lease.dns_servers.iter().map(|&addr| IpAddr::V4(addr)));
+        }
+
+        if let Some(lease) = &self.ipv6 {
+            if let Some(address) = lease.address {
+                iface::add_ipv6(self.interface.index, address, lease.prefix_len())?;
+            }
+            nameservers.extend(lease.dns_servers.iter().map(|&addr| IpAddr::V6(addr)));
+        }
+
+        if !nameservers.is_empty() {
+            iface::write_resolv_conf(&nameservers)?;
+        }
+
+        Ok(())
+    }
+
+    fn deconfigure(&self) {
+        if self.ipv4.is_some() {
+            if let Err(e) = iface::set_ipv4(&self.interface.name, [0, 0, 0, 0].into(), 0) {
+                debug!("failed to remove address from {}: {e}", self.interface.name);
+            }
+        }
+
+        if let Some((address, lease)) = self
+            .ipv6
+            .as_ref()
+            .and_then(|lease| lease.address.map(|address| (address, lease)))
+        {
+            if let Err(e) = iface::remove_ipv6(self.interface.index, address, lease.prefix_len()) {
+                debug!("failed to remove address from {}: {e}", self.interface.name);
+            }
+        }
+
+        if let Err(e) = iface::set_link(&self.interface.name, false) {
+            error!("failed to bring down {}: {e}", self.interface.name);
+        }
+    }
+
+    fn entry(&self) -> anyhow::Result<NetworkEntry> {
+        let Some((boot_file, bootfile_params)) = self.boot_file() else {
+            anyhow::bail!("DHCP server did not provide a boot file");
+        };
+
+        let url = Url::from_str(&boot_file)?;
+        let download_dir = Path::new(NET_DOWNLOAD_PATH).join(&self.interface.name);
+
+        if url.path.ends_with(MANIFEST_SUFFIX) {
+            let contents = net::fetch_to_string(&url)
+                .map_err(|e| anyhow::anyhow!("failed to fetch {url}: {e}"))?;
+            return NetworkEntry::parse_manifest(&url, &contents, download_dir);
+        }
+
+        Ok(NetworkEntry {
+            linux: Some(url),
+            options: bootfile_params,
+            download_dir,
+            ..Default::default()
+        })
+    }
+}
+
+pub struct NetworkBootLoader {
+    interfaces: Vec<NetworkInterface>,
+}
+
+impl NetworkBootLoader {
+    pub fn new() -> Self {
+        Self {
+            interfaces: Vec::new(),
+        }
+    }
+
+    /// Brings the interface up and gets a lease for it, preferring IPv4. DHCPv6 is only tried
+    /// when DHCPv4 did not produce a boot file.
+    fn lease(interface: Interface) -> Option<NetworkInterface> {
+        if let Err(e) = iface::set_link(&interface.name, true) {
+            error!("failed to bring up {}: {e}", interface.name);
+            return None;
+        }
+
+        let mut net_iface = NetworkInterface {
+            interface,
+            ipv4: None,
+            ipv6: None,
+        };
+        let name = net_iface.interface.name.clone();
+
+        if !iface::wait_for_carrier(&name, CARRIER_TIMEOUT) {
+            debug!("{name}: no carrier");
+            net_iface.deconfigure();
+            return None;
+        }
+
+        match dhcp::request_lease(&net_iface.interface) {
+            Ok(lease) => {
+                info!("{name}: leased {}/{}", lease.address, lease.prefix_len);
+                net_iface.ipv4 = Some(lease);
+            }
+            Err(e) => debug!("{name}: DHCPv4 failed: {e}"),
+        }
+
+        if net_iface.boot_file().is_none() {
+            match dhcp6::request_lease(&net_iface.interface) {
+                Ok(lease) => {
+                    if let Some(address) = lease.address {
+                        info!("{name}: leased {address}");
+                    }
+                    net_iface.ipv6 = Some(lease);
+                }
+                Err(e) => debug!("{name}: DHCPv6 failed: {e}"),
+            }
+        }
+
+        if net_iface.ipv4.is_none() && net_iface.ipv6.is_none() {
+            net_iface.deconfigure();
+            return None;
+        }
+
+        if let Err(e) = net_iface.configure() {
+            error!("failed to configure {name}: {e}");
+            net_iface.deconfigure();
+            return None;
+        }
+
+        Some(net_iface)
+    }
+}
+
+impl BootLoader for NetworkBootLoader {
+    fn setup(&mut self) -> anyhow::Result<()> {
+        debug!("setup");
+
+        std::fs::create_dir_all(NET_DOWNLOAD_PATH)?;
+
+        self.interfaces = iface::ethernet_interfaces()
+            .into_iter()
+            .filter_map(Self::lease)
+            .collect();
+
+        Ok(())
+    }
+
+    fn probe(&mut self) -> Vec<BootDevice> {
+        debug!("probe");
+
+        let mut devs = Vec::new();
+
+        for net_iface in &self.interfaces {
+            let entry = match net_iface.entry() {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("{}: {e}", net_iface.interface.name);
+                    continue;
+                }
+            };
+
+            devs.push(BootDevice {
+                name: format!("Network ({})", net_iface.interface.name),
+                selectors: Vec::new(),
+                entries: vec![Box::new(entry)],
+                timeout: Timeout::Countdown(NETWORK_TIMEOUT),
+                editor: true,
+            });
+        }
+
+        devs
+    }
+
+    fn teardown(&mut self) {
+        debug!("teardown");
+
+        for net_iface in self.interfaces.drain(..) {
+            net_iface.deconfigure();
+        }
+
+        if let Err(e) = std::fs::remove_dir_all(NET_DOWNLOAD_PATH) {
+            error!("failed to remove {}: {e}", NET_DOWNLOAD_PATH);
+        }
+    }
+
+    fn loader_type(&mut self) -> LoaderType {
+        LoaderType::Network
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use crate::net::Url;
+
+    use super::NetworkEntry;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest_url = Url::from_str("http://10.0.0.1/tboot/boot.conf").unwrap();
+
+        let entry = NetworkEntry::parse_manifest(
+            &manifest_url,
+            r#"# installer
+title NixOS Installer
+version 24.05
+linux bzImage
+initrd /common/initrd
+initrd tftp://10.0.0.2/extra-initrd
+options console=ttyS0
+options quiet
+devicetree-overlay a.dtbo b.dtbo
+"#,
+            PathBuf::from("/run/tboot/net/eth0"),
+        )
+        .unwrap();
+
+        assert_eq!(entry.to_string(), "NixOS Installer (24.05)");
+        assert_eq!(
+            entry.linux.as_ref().map(|url| url.to_string()).as_deref(),
+            Some("http://10.0.0.1/tboot/bzImage")
+        );
+        assert_eq!(
+            entry
+                .initrd
+                .iter()
+                .map(|url| url.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "http://10.0.0.1/common/initrd",
+                "tftp://10.0.0.2/extra-initrd"
+            ]
+        );
+        assert_eq!(entry.options, vec!["console=ttyS0", "quiet"]);
+        assert_eq!(entry.devicetree_overlay.len(), 2);
+
+        assert!(NetworkEntry::parse_manifest(
+            &manifest_url,
+            "title missing kernel\n",
+            PathBuf::from("/run/tboot/net/eth0")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_bad_manifest() {
+        let manifest_url = Url::from_str("http://10.0.0.1/tboot/boot.conf").unwrap();
+        let parse = |contents: &str| {
+            NetworkEntry::parse_manifest(
+                &manifest_url,
+                contents,
+                PathBuf::from("/run/tboot/net/eth0"),
+            )
+        };
+
+        // keys without a value are skipped
+        assert!(parse("linux\n").is_err());
+        assert!(parse("linux   \n").is_err());
+        assert!(parse("").is_err());
+        assert!(parse("\u{fffd}\u{0}\u{fffd}linux bzImage").is_err());
+
+        assert!(parse("linux ftp://10.0.0.1/bzImage\n").is_err());
+        assert!(parse("linux bzImage\ninitrd http:///initrd\n").is_err());
+        assert!(
+            parse("linux bzImage\ndevicetree-overlay a.dtbo tftp://[fe80::1/b.dtbo\n").is_err()
+        );
+
+        let entry = parse("linux bzImage\nunknown key\n# linux other\n").unwrap();
+        assert_eq!(
+            entry.linux.as_ref().map(|url| url.to_string()).as_deref(),
+            Some("http://10.0.0.1/tboot/bzImage")
+        );
+    }
+}
@@ -0,0 +1,481 @@
+use log::{debug, warn};
+use std::{
+    fmt::Display,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::boot_loader::{BootDevice, DeviceSelector, LoaderType};
+
+/// Used when no boot order is stored anywhere. Every loader in the boot order is probed on every
+/// boot, so only the ones that are cheap to probe are included. The flash comes last, as the
+/// recovery kernel to fall back to when nothing else boots.
+const DEFAULT_BOOT_ORDER: &str = "fw_cfg disk extlinux grub flash";
+
+/// The RW_VPD key holding the boot order, it can be set at build time with coreboot.vpd.rw or from
+/// the booted OS with the `vpd` tool.
+/// https://github.com/torvalds/linux/blob/master/drivers/firmware/google/vpd.c
+const VPD_BOOT_ORDER_PATH: &str = "/sys/firmware/vpd/rw/tboot_bootorder";
+
+/// https://qemu-project.gitlab.io/qemu/specs/fw_cfg.html
+const FW_CFG_BOOT_ORDER_PATH: &str =
+    "/sys/firmware/qemu_fw_cfg/by_name/opt/org.tboot/bootorder/raw";
+
+/// Exposes the CMOS bytes after the RTC registers.
+/// https://github.com/torvalds/linux/blob/master/drivers/char/nvram.c
+const NVRAM_PATH: &str = "/dev/nvram";
+
+const CMOS_MAGIC: u8 = 0x74;
+const CMOS_MAX_ITEMS: usize = 8;
+/// Magic, item count, items and checksum.
+const CMOS_RECORD_LEN: usize = 2 + CMOS_MAX_ITEMS + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootOrderItem {
+    pub loader: LoaderType,
+    /// Limits the item to some devices of the loader, all of them are used if this is not set.
+    pub selector: Option<DeviceSelector>,
+}
+
+impl BootOrderItem {
+    fn matches(&self, loader: LoaderType, boot_dev: &BootDevice) -> bool {
+        self.loader == loader
+            && self
+                .selector
+                .is_none_or(|selector| boot_dev.selectors.contains(&selector))
+    }
+}
+
+impl Display for BootOrderItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.selector {
+            Some(selector) => write!(f, "{}:{}", self.loader, selector),
+            None => write!(f, "{}", self.loader),
+        }
+    }
+}
+
+impl FromStr for BootOrderItem {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (loader, selector) = match s.split_once(':') {
+            Some((loader, selector)) => (loader, Some(DeviceSelector::from_str(selector)?)),
+            None => (s, None),
+        };
+
+        Ok(Self {
+            loader: LoaderType::from_str(loader)?,
+            selector,
+        })
+    }
+}
+
+/// The order to try boot devices in, e.g. "disk:usb disk:nvme network" tries USB disks, then NVMe
+/// disks, then the network. Loaders that are not mentioned are not used for booting automatically.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootOrder(pub Vec<BootOrderItem>);
+
+impl Default for BootOrder {
+    fn default() -> Self {
+        Self::from_str(DEFAULT_BOOT_ORDER).expect("default boot order is valid")
+    }
+}
+
+impl Display for BootOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let items: Vec<String> = self.0.iter().map(|item| item.to_string()).collect();
+        write!(f, "{}", items.join(" "))
+    }
+}
+
+impl FromStr for BootOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let items = s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|item| !item.is_empty())
+            .map(BootOrderItem::from_str)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if items.is_empty() {
+            anyhow::bail!("boot order is empty");
+        }
+
+        if items.len() > CMOS_MAX_ITEMS {
+            anyhow::bail!("boot order has more than {CMOS_MAX_ITEMS} items");
+        }
+
+        Ok(Self(items))
+    }
+}
+
+impl BootOrder {
+    /// The loaders needed for this boot order, in the order they are first used.
+    pub fn loader_types(&self) -> Vec<LoaderType> {
+        let mut loader_types: Vec<LoaderType> = Vec::new();
+        for item in &self.0 {
+            if !loader_types.contains(&item.loader) {
+                loader_types.push(item.loader);
+            }
+        }
+        loader_types
+    }
+
+    /// Orders the devices found by each loader, dropping the ones that no item matches. A device
+    /// is only listed once, for the first item that matches it.
+    pub fn order<'a>(&self, devices: &[(LoaderType, &'a [BootDevice])]) -> Vec<&'a BootDevice> {
+        let mut ordered: Vec<&BootDevice> = Vec::new();
+
+        for item in &self.0 {
+            for (loader, boot_devs) in devices {
+                for boot_dev in boot_devs.iter() {
+                    if item.matches(*loader, boot_dev)
+                        && !ordered
+                            .iter()
+                            .any(|ordered| std::ptr::eq(*ordered, boot_dev))
+                    {
+                        ordered.push(boot_dev);
+                    }
+                }
+            }
+        }
+
+        ordered
+    }
+
+    fn to_cmos_record(&self) -> [u8; CMOS_RECORD_LEN] {
+        let mut record = [0u8; CMOS_RECORD_LEN];
+        record[0] = CMOS_MAGIC;
+        record[1] = self.0.len() as u8;
+
+        for (byte, item) in record[2..].iter_mut().zip(&self.0) {
+            let loader = match item.loader {
+                LoaderType::Disk => 1,
+                LoaderType::Network => 2,
+                LoaderType::ChromeOs => 3,
+                LoaderType::Extlinux => 4,
+                LoaderType::Grub => 5,
+                LoaderType::Fit => 6,
+                LoaderType::Flash => 7,
+                LoaderType::FwCfg => 8,
+            };
+            let selector = match item.selector {
+                None => 0,
+                Some(selector) => {
+                    1 + DeviceSelector::ALL
+                        .iter()
+                        .position(|s| *s == selector)
+                        .expect("selector is in ALL") as u8
+                }
+            };
+            *byte = loader << 4 | selector;
+        }
+
+        record[CMOS_RECORD_LEN - 1] = cmos_checksum(&record[..CMOS_RECORD_LEN - 1]);
+
+        record
+    }
+
+    fn from_cmos_record(record: &[u8; CMOS_RECORD_LEN]) -> anyhow::Result<Option<Self>> {
+        if record[0] != CMOS_MAGIC {
+            return Ok(None);
+        }
+
+        if record[CMOS_RECORD_LEN - 1] != cmos_checksum(&record[..CMOS_RECORD_LEN - 1]) {
+            anyhow::bail!("invalid checksum");
+        }
+
+        let len = record[1] as usize;
+        if len == 0 || len > CMOS_MAX_ITEMS {
+            anyhow::bail!("invalid number of items {len}");
+        }
+
+        let items = record[2..2 + len]
+            .iter()
+            .map(|byte| {
+                let loader = match byte >> 4 {
+                    1 => LoaderType::Disk,
+                    2 => LoaderType::Network,
+                    3 => LoaderType::ChromeOs,
+                    4 => LoaderType::Extlinux,
+                    5 => LoaderType::Grub,
+                    6 => LoaderType::Fit,
+                    7 => LoaderType::Flash,
+                    8 => LoaderType::FwCfg,
+                    loader => anyhow::bail!("invalid loader {loader}"),
+                };
+                let selector = match byte & 0xf {
+                    0 => None,
+                    selector => Some(
+                        *DeviceSelector::ALL
+                            .get(selector as usize - 1)
+                            .ok_or_else(|| anyhow::anyhow!("invalid selector {selector}"))?,
+                    ),
+                };
+                Ok(BootOrderItem { loader, selector })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Some(Self(items)))
+    }
+}
+
+fn cmos_checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootOrderSource {
+    Cmos,
+    Vpd,
+    FwCfg,
+    Default,
+}
+
+impl Display for BootOrderSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Cmos => "CMOS",
+                Self::Vpd => "RW_VPD",
+                Self::FwCfg => "fw_cfg",
+                Self::Default => "default",
+            }
+        )
+    }
+}
+
+/// Where the boot order is kept. CMOS is the only storage that can be written from tinyboot, and
+/// only if a free range of it was set aside with the `tboot.bootorder-cmos` kernel parameter. The
+/// offset is relative to /dev/nvram and must not overlap any option coreboot checksums.
+pub struct BootOrderStorage {
+    cmos_offset: Option<u64>,
+}
+
+impl BootOrderStorage {
+    pub fn new(cmos_offset: Option<u64>) -> Self {
+        Self { cmos_offset }
+    }
+
+    fn read_cmos(&self, nvram: &Path) -> anyhow::Result<Option<BootOrder>> {
+        let Some(offset) = self.cmos_offset else {
+            return Ok(None);
+        };
+
+        let mut file = std::fs::File::open(nvram)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut record = [0u8; CMOS_RECORD_LEN];
+        file.read_exact(&mut record)?;
+
+        BootOrder::from_cmos_record(&record)
+    }
+
+    fn read_text(path: &Path) -> anyhow::Result<BootOrder> {
+        let contents = std::fs::read_to_string(path)?;
+        BootOrder::from_str(contents.trim_end_matches('\0'))
+    }
+
+    fn load_from(&self, nvram: &Path, vpd: &Path, fw_cfg: &Path) -> (BootOrder, BootOrderSource) {
+        match self.read_cmos(nvram) {
+            Ok(Some(boot_order)) => return (boot_order, BootOrderSource::Cmos),
+            Ok(None) => {}
+            Err(e) => warn!("failed to read boot order from CMOS: {e}"),
+        }
+
+        if cfg!(feature = "coreboot") {
+            match Self::read_text(vpd) {
+                Ok(boot_order) => return (boot_order, BootOrderSource::Vpd),
+                Err(e) => debug!("failed to read boot order from RW_VPD: {e}"),
+            }
+        }
+
+        if cfg!(feature = "fw_cfg") {
+            match Self::read_text(fw_cfg) {
+                Ok(boot_order) => return (boot_order, BootOrderSource::FwCfg),
+                Err(e) => debug!("failed to read boot order from fw_cfg: {e}"),
+            }
+        }
+
+        (BootOrder::default(), BootOrderSource::Default)
+    }
+
+    pub fn load(&self) -> (BootOrder, BootOrderSource) {
+        self.load_from(
+            Path::new(NVRAM_PATH),
+            Path::new(VPD_BOOT_ORDER_PATH),
+            Path::new(FW_CFG_BOOT_ORDER_PATH),
+        )
+    }
+
+    fn save_to(&self, nvram: &Path, boot_order: Option<&BootOrder>) -> anyhow::Result<()> {
+        let Some(offset) = self.cmos_offset else {
+            anyhow::bail!("no writable storage, set tboot.bootorder-cmos to use CMOS");
+        };
+
+        // an all-zero record has no magic, which resets the boot order
+        let record = match boot_order {
+            Some(boot_order) => boot_order.to_cmos_record(),
+            None => [0u8; CMOS_RECORD_LEN],
+        };
+
+        let mut file = std::fs::OpenOptions::new().write(true).open(nvram)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&record)?;
+
+        Ok(())
+    }
+
+    /// Stores the boot order for future boots, or removes the stored one if there is none.
+    pub fn save(&self, boot_order: Option<&BootOrder>) -> anyhow::Result<()> {
+        self.save_to(Path::new(NVRAM_PATH), boot_order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, str::FromStr, time::Duration};
+
+    use crate::boot_loader::{BootDevice, DeviceSelector, LoaderType, Timeout};
+
+    use super::{BootOrder, BootOrderItem, BootOrderSource, BootOrderStorage, CMOS_RECORD_LEN};
+
+    #[test]
+    fn test_parse_boot_order() {
+        assert_eq!(
+            BootOrder::from_str("disk:usb, disk:nvme network").unwrap(),
+            BootOrder(vec![
+                BootOrderItem {
+                    loader: LoaderType::Disk,
+                    selector: Some(DeviceSelector::Usb),
+                },
+                BootOrderItem {
+                    loader: LoaderType::Disk,
+                    selector: Some(DeviceSelector::Nvme),
+                },
+                BootOrderItem {
+                    loader: LoaderType::Network,
+                    selector: None,
+                },
+            ])
+        );
+        assert_eq!(
+            BootOrder::from_str("disk:usb,disk:nvme,network")
+                .unwrap()
+                .to_string(),
+            "disk:usb disk:nvme network"
+        );
+        assert!(BootOrder::from_str("").is_err());
+        assert!(BootOrder::from_str("floppy").is_err());
+        assert!(BootOrder::from_str("disk:scsi").is_err());
+        assert!(BootOrder::from_str(&["disk"; 9].join(" ")).is_err());
+
+        assert_eq!(
+            BootOrder::from_str("network disk:usb network disk")
+                .unwrap()
+                .loader_types(),
+            vec![LoaderType::Network, LoaderType::Disk]
+        );
+    }
+
+    fn boot_device(name: &str, selectors: &[DeviceSelector]) -> BootDevice {
+        BootDevice {
+            name: name.to_string(),
+            selectors: selectors.to_vec(),
+            entries: Vec::new(),
+            timeout: Timeout::Countdown(Duration::from_secs(5)),
+            editor: true,
+        }
+    }
+
+    #[test]
+    fn test_order_devices() {
+        let disks = [
+            boot_device("sata", &[DeviceSelector::Sata]),
+            boot_device("usb", &[DeviceSelector::Usb, DeviceSelector::Removable]),
+            boot_device("nvme", &[DeviceSelector::Nvme]),
+        ];
+        let network = [boot_device("eth0", &[])];
+        let devices = [
+            (LoaderType::Disk, &disks[..]),
+            (LoaderType::Network, &network[..]),
+        ];
+
+        let names = |boot_order: &str| -> Vec<String> {
+            BootOrder::from_str(boot_order)
+                .unwrap()
+                .order(&devices)
+                .into_iter()
+                .map(|boot_dev| boot_dev.name.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            names("disk:usb disk:nvme network"),
+            vec!["usb", "nvme", "eth0"]
+        );
+        assert_eq!(
+            names("disk:removable network disk"),
+            vec!["usb", "eth0", "sata", "nvme"]
+        );
+        assert_eq!(names("disk:mmc"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_cmos_storage() {
+        let dir = std::env::temp_dir().join(format!("tboot-bootorder-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let nvram = dir.join("nvram");
+        let vpd = dir.join("vpd");
+        let fw_cfg = dir.join("fw_cfg");
+        std::fs::write(&nvram, [0xffu8; 114]).unwrap();
+
+        let storage = BootOrderStorage::new(Some(50));
+        let boot_order =
+            BootOrder::from_str("network disk:virtio disk chromeos:usb extlinux grub fit flash")
+                .unwrap();
+
+        storage.save_to(&nvram, Some(&boot_order)).unwrap();
+        assert_eq!(
+            storage.load_from(&nvram, &vpd, &fw_cfg),
+            (boot_order, BootOrderSource::Cmos)
+        );
+
+        // the rest of CMOS is left alone
+        let contents = std::fs::read(&nvram).unwrap();
+        assert_eq!(contents.len(), 114);
+        assert!(contents[..50].iter().all(|byte| *byte == 0xff));
+        assert!(contents[50 + CMOS_RECORD_LEN..]
+            .iter()
+            .all(|byte| *byte == 0xff));
+
+        // a corrupted record is ignored
+        let mut corrupted = contents.clone();
+        corrupted[53] ^= 0x01;
+        std::fs::write(&nvram, corrupted).unwrap();
+        assert_eq!(
+            storage.load_from(&nvram, &vpd, &fw_cfg),
+            (BootOrder::default(), BootOrderSource::Default)
+        );
+
+        storage.save_to(&nvram, None).unwrap();
+        assert_eq!(
+            storage.load_from(&nvram, &vpd, &fw_cfg),
+            (BootOrder::default(), BootOrderSource::Default)
+        );
+
+        assert!(BootOrderStorage::new(None).save_to(&nvram, None).is_err());
+        assert_eq!(
+            BootOrderStorage::new(None).load_from(Path::new("/nonexistent"), &vpd, &fw_cfg),
+            (BootOrder::default(), BootOrderSource::Default)
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
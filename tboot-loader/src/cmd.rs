@@ -2,14 +2,21 @@ use std::str::{FromStr, SplitWhitespace};
 
 use log::error;
 
-use crate::boot_loader::LoaderType;
+use crate::{boot_loader::LoaderType, boot_order::BootOrder};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
-    Loader(Option<LoaderType>),
+    /// Print which loaders are shown, or change it if given.
+    Loader(Option<LoaderFilter>),
     Help(Option<String>),
     List,
     Boot((Option<usize>, Option<usize>)),
+    /// Boot an entry with a different kernel command line.
+    Edit((usize, usize, String)),
+    /// Boot an entry automatically on the next boot only.
+    BootNext((usize, usize)),
+    /// Print the boot order, or change it if given.
+    BootOrder(Option<BootOrderChange>),
     Reboot,
     Poweroff,
     Dmesg(u8),
@@ -17,6 +24,29 @@ pub enum Command {
     Shell,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootOrderChange {
+    Set(BootOrder),
+    /// Forget the stored boot order and use the one from firmware.
+    Reset,
+}
+
+/// Limits the interactive session to the devices of some loaders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoaderFilter {
+    Only(LoaderType),
+    All,
+}
+
+impl LoaderFilter {
+    pub fn shows(&self, loader: LoaderType) -> bool {
+        match self {
+            Self::Only(only) => *only == loader,
+            Self::All => true,
+        }
+    }
+}
+
 pub fn parse_input(input: String) -> anyhow::Result<Option<Command>> {
     let mut iter = input.split_whitespace().into_iter();
 
@@ -30,6 +60,9 @@ pub fn parse_input(input: String) -> anyhow::Result<Option<Command>> {
 
     Ok(Some(match cmd {
         "boot" => parse_boot(iter)?,
+        "edit" => parse_edit(iter)?,
+        "bootnext" => parse_bootnext(iter)?,
+        "bootorder" => parse_bootorder(iter)?,
         "help" => Command::Help(iter.next().map(|s| s.to_string())),
         "loader" => parse_loader(iter)?,
         "list" => Command::List,
@@ -52,9 +85,11 @@ fn parse_dmesg(mut iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
 }
 
 fn parse_loader(mut iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
-    Ok(Command::Loader(
-        iter.next().map(LoaderType::from_str).transpose()?,
-    ))
+    Ok(Command::Loader(match iter.next() {
+        None => None,
+        Some("all") => Some(LoaderFilter::All),
+        Some(loader) => Some(LoaderFilter::Only(LoaderType::from_str(loader)?)),
+    }))
 }
 
 fn parse_boot(mut iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
@@ -71,10 +106,43 @@ fn parse_boot(mut iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
     Ok(Command::Boot((dev, entry)))
 }
 
+fn parse_edit(mut iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
+    let (Some(dev), Some(entry)) = (iter.next(), iter.next()) else {
+        anyhow::bail!("edit requires a device and an entry");
+    };
+
+    Ok(Command::Edit((
+        dev.parse()?,
+        entry.parse()?,
+        iter.collect::<Vec<_>>().join(" "),
+    )))
+}
+
+fn parse_bootnext(mut iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
+    let (Some(dev), Some(entry)) = (iter.next(), iter.next()) else {
+        anyhow::bail!("bootnext requires a device and an entry");
+    };
+
+    Ok(Command::BootNext((dev.parse()?, entry.parse()?)))
+}
+
+fn parse_bootorder(iter: SplitWhitespace<'_>) -> anyhow::Result<Command> {
+    let items = iter.collect::<Vec<_>>();
+
+    Ok(Command::BootOrder(match items.as_slice() {
+        [] => None,
+        ["reset"] => Some(BootOrderChange::Reset),
+        items => Some(BootOrderChange::Set(BootOrder::from_str(&items.join(" "))?)),
+    }))
+}
+
 pub fn print_help(cmd_to_help: Option<&str>) {
     match cmd_to_help.as_deref() {
         Some("list") => print_list_usage(),
         Some("boot") => print_boot_usage(),
+        Some("edit") => print_edit_usage(),
+        Some("bootnext") => print_bootnext_usage(),
+        Some("bootorder") => print_bootorder_usage(),
         Some("reboot") => print_reboot_usage(),
         Some("poweroff") => print_poweroff_usage(),
         Some("loader") => print_loader_usage(),
@@ -108,14 +176,19 @@ fn print_reboot_usage() {
 fn print_all_usage() {
     println!();
     println!("list\t\tlist all boot entries");
+    println!("loader\t\tprint or change which loaders are listed");
     println!("boot\t\tboot from selection");
+    println!("edit\t\tboot from selection with a different kernel command line");
+    println!("bootnext\tboot selection automatically on the next boot only");
+    println!("bootorder\tprint or change the order boot devices are tried in");
     println!("dmesg\t\tprint kernel logs");
     println!("reboot\t\treboot the machine");
     println!("poweroff\tpoweroff the machine");
 }
 
 const BOOT_USAGE: &str = r#"
-Boot from the selected entry. If no entry is selected, boot from the default entry.
+Boot from the selected entry. If no entry is selected, boot from the default entry. If no
+device is selected either, the first device that is shown is used.
 "#;
 
 fn print_boot_usage() {
@@ -124,8 +197,44 @@ fn print_boot_usage() {
     println!("{BOOT_USAGE}");
 }
 
+const EDIT_USAGE: &str = r#"
+Boot the selected entry with the given kernel command line instead of its own. This can be
+disabled with "editor no" in loader.conf.
+"#;
+
+fn print_edit_usage() {
+    println!();
+    println!("edit <device> <entry> <cmdline>");
+    println!("{EDIT_USAGE}");
+}
+
+const BOOTNEXT_USAGE: &str = r#"
+Boot the selected entry automatically on the next boot, after which the default entry is used
+again. The running OS can do the same with "tboot-bless-boot next <entry>".
+"#;
+
+fn print_bootnext_usage() {
+    println!();
+    println!("bootnext <device> <entry>");
+    println!("{BOOTNEXT_USAGE}");
+}
+
+const BOOTORDER_USAGE: &str = r#"
+Print the order boot devices are tried in, or change it. Each item is a loader, optionally
+followed by a device selector (usb, nvme, mmc, sata, virtio or removable), for example
+"bootorder disk:usb disk:nvme network". Devices that no item selects are not booted
+automatically. "bootorder reset" goes back to the boot order from firmware.
+"#;
+
+fn print_bootorder_usage() {
+    println!();
+    println!("bootorder [<loader>[:<selector>]... | reset]");
+    println!("{BOOTORDER_USAGE}");
+}
+
 const LIST_USAGE: &str = r#"
-List all detected boot entries.
+List the boot entries of every loader, grouped by loader and device. Devices are numbered
+across all loaders, and "boot", "edit" and "bootnext" refer to them by these numbers.
 "#;
 
 fn print_list_usage() {
@@ -135,12 +244,14 @@ fn print_list_usage() {
 }
 
 const LOADER_USAGE: &str = r#"
-Select or print current boot loader.
+Only show the devices of the given loader, which is probed first if it is not in the boot
+order. "loader all" shows the devices of every loader again, and "loader" prints which are
+shown.
 "#;
 
 fn print_loader_usage() {
     println!();
-    println!("loader");
+    println!("loader [<loader> | all]");
     println!("{LOADER_USAGE}");
 }
 
@@ -155,7 +266,7 @@ fn print_dmesg_usage() {
 }
 
 const RESCAN_USAGE: &str = r#"
-Rescan loader for devices and boot entries.
+Rescan the shown loaders for devices and boot entries.
 "#;
 
 fn print_rescan_usage() {
@@ -0,0 +1,800 @@
+use std::collections::HashMap;
+
+const MACHINE_COMPATIBLE_PATH: &str = "/sys/firmware/devicetree/base/compatible";
+
+/// Flattened devicetree documentation: https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+mod fdt_constants {
+    pub const MAGIC: u32 = 0xd00d_feed;
+    pub const HEADER_LENGTH: usize = 40;
+    pub const VERSION: u32 = 17;
+    pub const LAST_COMPATIBLE_VERSION: u32 = 16;
+
+    pub const BEGIN_NODE: u32 = 0x1;
+    pub const END_NODE: u32 = 0x2;
+    pub const PROP: u32 = 0x3;
+    pub const NOP: u32 = 0x4;
+    pub const END: u32 = 0x9;
+
+    /// Sanity limit on how deeply nodes can be nested.
+    pub const MAX_DEPTH: usize = 64;
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub props: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+/// Returns the value of a string property without its trailing NUL byte.
+pub fn prop_str(value: &[u8]) -> Option<&str> {
+    std::str::from_utf8(value.strip_suffix(&[0]).unwrap_or(value)).ok()
+}
+
+/// Splits the value of a string list property, like compatible, into its strings.
+pub fn prop_str_list(value: &[u8]) -> Vec<String> {
+    value
+        .split(|byte| *byte == 0)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| std::str::from_utf8(s).ok())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The compatible strings of the machine we are running on, from most to least specific. Empty
+/// when it was not booted with a devicetree.
+pub fn machine_compatible() -> Vec<String> {
+    std::fs::read(MACHINE_COMPATIBLE_PATH)
+        .map(|compatible| prop_str_list(&compatible))
+        .unwrap_or_default()
+}
+
+/// Node names can be looked up without their unit address, e.g. "memory" matches "memory@0".
+fn name_matches(node_name: &str, component: &str) -> bool {
+    node_name == component
+        || (!component.contains('@')
+            && node_name.split_once('@').map(|(name, _)| name) == Some(component))
+}
+
+fn path_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|component| !component.is_empty())
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(prop_name, _)| prop_name == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn prop_mut(&mut self, name: &str) -> Option<&mut Vec<u8>> {
+        self.props
+            .iter_mut()
+            .find(|(prop_name, _)| prop_name == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn set_prop(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        let value = value.into();
+        match self.prop_mut(name) {
+            Some(existing) => *existing = value,
+            None => self.props.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn set_prop_str(&mut self, name: &str, value: &str) {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        self.set_prop(name, value);
+    }
+
+    pub fn remove_prop(&mut self, name: &str) {
+        self.props.retain(|(prop_name, _)| prop_name != name);
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop("phandle")
+            .or_else(|| self.prop("linux,phandle"))
+            .and_then(|value| Some(u32::from_be_bytes(value.try_into().ok()?)))
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children
+            .iter()
+            .find(|child| child.name == name)
+            .or_else(|| {
+                self.children
+                    .iter()
+                    .find(|child| name_matches(&child.name, name))
+            })
+    }
+
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        let index = self
+            .children
+            .iter()
+            .position(|child| child.name == name)
+            .or_else(|| {
+                self.children
+                    .iter()
+                    .position(|child| name_matches(&child.name, name))
+            })?;
+        Some(&mut self.children[index])
+    }
+
+    /// Returns the child with the exact given name, creating it if it does not exist.
+    pub fn child_mut_or_insert(&mut self, name: &str) -> &mut Node {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(Node::new(name));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        path_components(path).try_fold(self, |node, component| node.child(component))
+    }
+
+    pub fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path_components(path).try_fold(self, |node, component| node.child_mut(component))
+    }
+
+    pub fn node_mut_or_insert(&mut self, path: &str) -> &mut Node {
+        path_components(path).fold(self, |node, component| node.child_mut_or_insert(component))
+    }
+
+    /// Recursively merges the properties and children of another node into this one. Properties
+    /// that exist in both nodes take the value from the other node.
+    pub fn merge(&mut self, other: &Node) {
+        for (name, value) in &other.props {
+            self.set_prop(name, value.clone());
+        }
+
+        for child in &other.children {
+            self.child_mut_or_insert(&child.name).merge(child);
+        }
+    }
+
+    fn max_phandle(&self) -> u32 {
+        self.children
+            .iter()
+            .map(Node::max_phandle)
+            .chain(self.phandle())
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn path_of_phandle(&self, phandle: u32) -> Option<String> {
+        if self.phandle() == Some(phandle) {
+            return Some(String::new());
+        }
+
+        self.children.iter().find_map(|child| {
+            child
+                .path_of_phandle(phandle)
+                .map(|path| format!("/{}{}", child.name, path))
+        })
+    }
+
+    fn adjust_phandles(&mut self, delta: u32) {
+        for (name, value) in self.props.iter_mut() {
+            if name == "phandle" || name == "linux,phandle" {
+                adjust_cell(value, 0, delta);
+            }
+        }
+
+        for child in self.children.iter_mut() {
+            child.adjust_phandles(delta);
+        }
+    }
+
+    /// Walks `__local_fixups__` alongside the overlay tree. Each property in the fixup tree lists
+    /// the offsets of phandle references to nodes within the overlay itself.
+    fn apply_local_fixups(&mut self, fixups: &Node, delta: u32) -> anyhow::Result<()> {
+        for (name, offsets) in &fixups.props {
+            let value = self
+                .prop_mut(name)
+                .ok_or_else(|| anyhow::anyhow!("local fixup for missing property {name}"))?;
+
+            for offset in offsets.chunks_exact(4) {
+                let offset = u32::from_be_bytes(offset.try_into().expect("4 bytes")) as usize;
+                if !adjust_cell(value, offset, delta) {
+                    anyhow::bail!("local fixup offset {offset} is outside of property {name}");
+                }
+            }
+        }
+
+        for fixup_child in &fixups.children {
+            self.children
+                .iter_mut()
+                .find(|child| child.name == fixup_child.name)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("local fixup for missing node {}", fixup_child.name)
+                })?
+                .apply_local_fixups(fixup_child, delta)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds delta to the big-endian cell at the given offset, returning false if it is out of bounds.
+fn adjust_cell(value: &mut [u8], offset: usize, delta: u32) -> bool {
+    let Some(cell) = value.get_mut(offset..offset + 4) else {
+        return false;
+    };
+
+    let adjusted = u32::from_be_bytes((&*cell).try_into().expect("4 bytes")).wrapping_add(delta);
+    cell.copy_from_slice(&adjusted.to_be_bytes());
+    true
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Fdt {
+    pub boot_cpuid_phys: u32,
+    /// Memory reservation block entries as (address, size).
+    pub reserved: Vec<(u64, u64)>,
+    pub root: Node,
+}
+
+struct StructReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> StructReader<'a> {
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + 4)
+            .ok_or_else(|| anyhow::anyhow!("structure block is truncated"))?;
+        self.offset += 4;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| anyhow::anyhow!("structure block is truncated"))?;
+        self.offset = (self.offset + len).next_multiple_of(4);
+        Ok(bytes)
+    }
+
+    fn cstr(&mut self) -> anyhow::Result<&'a str> {
+        let remaining = self.data.get(self.offset..).unwrap_or_default();
+        let len = remaining
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| anyhow::anyhow!("unterminated node name"))?;
+        let name = std::str::from_utf8(self.bytes(len + 1)?[..len].as_ref())?;
+        Ok(name)
+    }
+}
+
+impl Fdt {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < fdt_constants::HEADER_LENGTH {
+            anyhow::bail!("devicetree is too small");
+        }
+
+        let header_field = |index: usize| {
+            u32::from_be_bytes(data[index * 4..index * 4 + 4].try_into().expect("4 bytes"))
+        };
+
+        if header_field(0) != fdt_constants::MAGIC {
+            anyhow::bail!("missing devicetree magic");
+        }
+
+        let total_size = header_field(1) as usize;
+        let off_dt_struct = header_field(2) as usize;
+        let off_dt_strings = header_field(3) as usize;
+        let off_mem_rsvmap = header_field(4) as usize;
+        let version = header_field(5);
+        let boot_cpuid_phys = header_field(7);
+        let size_dt_strings = header_field(8) as usize;
+        let size_dt_struct = header_field(9) as usize;
+
+        if version < fdt_constants::LAST_COMPATIBLE_VERSION {
+            anyhow::bail!("unsupported devicetree version {version}");
+        }
+
+        let data = data
+            .get(..total_size)
+            .ok_or_else(|| anyhow::anyhow!("devicetree is truncated"))?;
+
+        let strings = data
+            .get(off_dt_strings..off_dt_strings + size_dt_strings)
+            .ok_or_else(|| anyhow::anyhow!("strings block is out of bounds"))?;
+
+        let mut reserved = Vec::new();
+        for entry in data
+            .get(off_mem_rsvmap..)
+            .unwrap_or_default()
+            .chunks_exact(16)
+        {
+            let address = u64::from_be_bytes(entry[..8].try_into().expect("8 bytes"));
+            let size = u64::from_be_bytes(entry[8..].try_into().expect("8 bytes"));
+            if address == 0 && size == 0 {
+                break;
+            }
+            reserved.push((address, size));
+        }
+
+        let mut reader = StructReader {
+            data: data
+                .get(off_dt_struct..off_dt_struct + size_dt_struct)
+                .ok_or_else(|| anyhow::anyhow!("structure block is out of bounds"))?,
+            offset: 0,
+        };
+
+        let mut stack: Vec<Node> = Vec::new();
+        let mut root = None;
+
+        loop {
+            match reader.u32()? {
+                fdt_constants::BEGIN_NODE => {
+                    if root.is_some() {
+                        anyhow::bail!("multiple root nodes");
+                    }
+                    if stack.len() >= fdt_constants::MAX_DEPTH {
+                        anyhow::bail!("nodes are nested too deeply");
+                    }
+                    stack.push(Node::new(reader.cstr()?));
+                }
+                fdt_constants::END_NODE => {
+                    let node = stack
+                        .pop()
+                        .ok_or_else(|| anyhow::anyhow!("unbalanced end of node"))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => root = Some(node),
+                    }
+                }
+                fdt_constants::PROP => {
+                    let len = reader.u32()? as usize;
+                    let name_offset = reader.u32()? as usize;
+                    let value = reader.bytes(len)?;
+
+                    let name = strings
+                        .get(name_offset..)
+                        .and_then(|name| name.split(|byte| *byte == 0).next())
+                        .ok_or_else(|| anyhow::anyhow!("property name is out of bounds"))?;
+
+                    stack
+                        .last_mut()
+                        .ok_or_else(|| anyhow::anyhow!("property outside of a node"))?
+                        .props
+                        .push((std::str::from_utf8(name)?.to_string(), value.to_vec()));
+                }
+                fdt_constants::NOP => {}
+                fdt_constants::END => break,
+                token => anyhow::bail!("invalid structure token {token:#x}"),
+            }
+        }
+
+        if !stack.is_empty() {
+            anyhow::bail!("unterminated node");
+        }
+
+        Ok(Self {
+            boot_cpuid_phys,
+            reserved,
+            root: root.ok_or_else(|| anyhow::anyhow!("missing root node"))?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn write_node(
+            node: &Node,
+            out: &mut Vec<u8>,
+            strings: &mut Vec<u8>,
+            string_offsets: &mut HashMap<String, u32>,
+        ) {
+            out.extend(fdt_constants::BEGIN_NODE.to_be_bytes());
+            out.extend(node.name.as_bytes());
+            out.push(0);
+            out.resize(out.len().next_multiple_of(4), 0);
+
+            for (name, value) in &node.props {
+                let name_offset = *string_offsets.entry(name.clone()).or_insert_with(|| {
+                    let offset = strings.len() as u32;
+                    strings.extend(name.as_bytes());
+                    strings.push(0);
+                    offset
+                });
+
+                out.extend(fdt_constants::PROP.to_be_bytes());
+                out.extend((value.len() as u32).to_be_bytes());
+                out.extend(name_offset.to_be_bytes());
+                out.extend(value);
+                out.resize(out.len().next_multiple_of(4), 0);
+            }
+
+            for child in &node.children {
+                write_node(child, out, strings, string_offsets);
+            }
+
+            out.extend(fdt_constants::END_NODE.to_be_bytes());
+        }
+
+        let mut dt_struct = Vec::new();
+        let mut dt_strings = Vec::new();
+        write_node(
+            &self.root,
+            &mut dt_struct,
+            &mut dt_strings,
+            &mut HashMap::new(),
+        );
+        dt_struct.extend(fdt_constants::END.to_be_bytes());
+
+        let off_mem_rsvmap = fdt_constants::HEADER_LENGTH.next_multiple_of(8);
+        let off_dt_struct = off_mem_rsvmap + (self.reserved.len() + 1) * 16;
+        let off_dt_strings = off_dt_struct + dt_struct.len();
+        let total_size = off_dt_strings + dt_strings.len();
+
+        let mut out = Vec::with_capacity(total_size);
+        for field in [
+            fdt_constants::MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            fdt_constants::VERSION,
+            fdt_constants::LAST_COMPATIBLE_VERSION,
+            self.boot_cpuid_phys,
+            dt_strings.len() as u32,
+            dt_struct.len() as u32,
+        ] {
+            out.extend(field.to_be_bytes());
+        }
+        out.resize(off_mem_rsvmap, 0);
+
+        for (address, size) in self.reserved.iter().chain([(0, 0)].iter()) {
+            out.extend(address.to_be_bytes());
+            out.extend(size.to_be_bytes());
+        }
+
+        out.extend(dt_struct);
+        out.extend(dt_strings);
+
+        out
+    }
+
+    /// Resolves an overlay fragment target, which is either a phandle or a path. Paths not
+    /// starting with "/" are looked up as aliases.
+    fn fragment_target(&self, fragment: &Node) -> anyhow::Result<String> {
+        if let Some(target) = fragment.prop("target") {
+            let phandle = u32::from_be_bytes(
+                target
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid target in {}", fragment.name))?,
+            );
+            return self
+                .root
+                .path_of_phandle(phandle)
+                .map(|path| {
+                    if path.is_empty() {
+                        "/".to_string()
+                    } else {
+                        path
+                    }
+                })
+                .ok_or_else(|| anyhow::anyhow!("target of {} not found", fragment.name));
+        }
+
+        let target_path = fragment
+            .prop("target-path")
+            .and_then(prop_str)
+            .ok_or_else(|| anyhow::anyhow!("{} has no target", fragment.name))?;
+
+        if target_path.starts_with('/') {
+            return Ok(target_path.to_string());
+        }
+
+        let (alias, rest) = target_path
+            .split_once('/')
+            .map(|(alias, rest)| (alias, format!("/{rest}")))
+            .unwrap_or((target_path, String::new()));
+
+        self.root
+            .node("/aliases")
+            .and_then(|aliases| aliases.prop(alias))
+            .and_then(prop_str)
+            .map(|path| format!("{path}{rest}"))
+            .ok_or_else(|| anyhow::anyhow!("alias {alias} not found"))
+    }
+
+    /// Applies a compiled devicetree overlay (a .dtbo built with `dtc -@`), following the same
+    /// steps as libfdt's fdt_overlay_apply().
+    pub fn apply_overlay(&mut self, mut overlay: Fdt) -> anyhow::Result<()> {
+        // Move the overlay's phandles past the ones used in the base tree so they do not collide.
+        let delta = self.root.max_phandle();
+        overlay.root.adjust_phandles(delta);
+
+        if let Some(local_fixups) = overlay.root.child("__local_fixups__").cloned() {
+            overlay.root.apply_local_fixups(&local_fixups, delta)?;
+        }
+
+        // References to labels in the base tree.
+        if let Some(fixups) = overlay.root.child("__fixups__").cloned() {
+            for (label, locations) in &fixups.props {
+                let symbol_path = self
+                    .root
+                    .node("/__symbols__")
+                    .and_then(|symbols| symbols.prop(label))
+                    .and_then(prop_str)
+                    .ok_or_else(|| anyhow::anyhow!("symbol {label} not found"))?;
+
+                let phandle = self
+                    .root
+                    .node(symbol_path)
+                    .and_then(Node::phandle)
+                    .ok_or_else(|| anyhow::anyhow!("symbol {label} has no phandle"))?;
+
+                for location in locations
+                    .split(|byte| *byte == 0)
+                    .filter(|location| !location.is_empty())
+                {
+                    let location = std::str::from_utf8(location)?;
+                    let mut parts = location.rsplitn(3, ':');
+                    let (Some(offset), Some(prop), Some(path)) =
+                        (parts.next(), parts.next(), parts.next())
+                    else {
+                        anyhow::bail!("invalid fixup {location}");
+                    };
+                    let offset: usize = offset.parse()?;
+
+                    let cell = overlay
+                        .root
+                        .node_mut(path)
+                        .and_then(|node| node.prop_mut(prop))
+                        .and_then(|value| value.get_mut(offset..offset + 4))
+                        .ok_or_else(|| anyhow::anyhow!("fixup {location} not found"))?;
+                    cell.copy_from_slice(&phandle.to_be_bytes());
+                }
+            }
+        }
+
+        let mut fragment_targets = HashMap::new();
+        for fragment in &overlay.root.children {
+            let Some(contents) = fragment.child("__overlay__") else {
+                continue;
+            };
+
+            let target = self.fragment_target(fragment)?;
+            self.root
+                .node_mut(&target)
+                .ok_or_else(|| anyhow::anyhow!("target {target} not found"))?
+                .merge(contents);
+
+            fragment_targets.insert(fragment.name.as_str(), target);
+        }
+
+        // Symbols in the overlay point into its fragments, rewrite them to the merged location.
+        if let Some(symbols) = overlay.root.child("__symbols__") {
+            for (label, path) in &symbols.props {
+                let Some(path) = prop_str(path) else {
+                    continue;
+                };
+
+                let mut components = path_components(path);
+                let (Some(fragment), Some("__overlay__")) = (components.next(), components.next())
+                else {
+                    continue;
+                };
+
+                let Some(target) = fragment_targets.get(fragment) else {
+                    continue;
+                };
+
+                let rest = components.fold(String::new(), |path, component| {
+                    format!("{path}/{component}")
+                });
+                let merged_path = format!("{}{rest}", target.trim_end_matches('/'));
+
+                self.root.child_mut_or_insert("__symbols__").set_prop_str(
+                    label,
+                    if merged_path.is_empty() {
+                        "/"
+                    } else {
+                        &merged_path
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fdt, Node};
+
+    fn be_cells(cells: &[u32]) -> Vec<u8> {
+        cells.iter().flat_map(|cell| cell.to_be_bytes()).collect()
+    }
+
+    fn base() -> Fdt {
+        let mut root = Node::new("");
+        root.set_prop("#address-cells", be_cells(&[2]));
+        root.set_prop_str("model", "test board");
+
+        let mut memory = Node::new("memory@40000000");
+        memory.set_prop_str("device_type", "memory");
+        memory.set_prop("reg", be_cells(&[0, 0x4000_0000, 0, 0x8000_0000]));
+        root.children.push(memory);
+
+        let mut i2c = Node::new("i2c@11000000");
+        i2c.set_prop_str("status", "disabled");
+        i2c.set_prop("phandle", be_cells(&[1]));
+        root.children.push(i2c);
+
+        let mut gpio = Node::new("gpio@10005000");
+        gpio.set_prop("phandle", be_cells(&[2]));
+        root.children.push(gpio);
+
+        let mut aliases = Node::new("aliases");
+        aliases.set_prop_str("i2c0", "/i2c@11000000");
+        root.children.push(aliases);
+
+        let mut symbols = Node::new("__symbols__");
+        symbols.set_prop_str("i2c0", "/i2c@11000000");
+        symbols.set_prop_str("pio", "/gpio@10005000");
+        root.children.push(symbols);
+
+        Fdt {
+            boot_cpuid_phys: 0,
+            reserved: vec![(0x5000_0000, 0x1000)],
+            root,
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let fdt = base();
+        let bytes = fdt.to_bytes();
+        assert_eq!(&bytes[..4], &[0xd0, 0x0d, 0xfe, 0xed]);
+        assert_eq!(Fdt::parse(&bytes).unwrap(), fdt);
+    }
+
+    #[test]
+    fn lookup() {
+        let fdt = base();
+        assert_eq!(
+            fdt.root.node("/memory").unwrap().name,
+            String::from("memory@40000000")
+        );
+        assert_eq!(fdt.root.node("/i2c@11000000").unwrap().phandle(), Some(1));
+        assert!(fdt.root.node("/i2c@0").is_none());
+        assert_eq!(
+            fdt.root
+                .node("/")
+                .unwrap()
+                .prop("model")
+                .and_then(super::prop_str),
+            Some("test board")
+        );
+    }
+
+    #[test]
+    fn fail_to_parse_invalid() {
+        assert!(Fdt::parse(&[0u8; 64]).is_err());
+        let bytes = base().to_bytes();
+        assert!(Fdt::parse(&bytes[..bytes.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn apply_overlay() {
+        let mut fdt = base();
+
+        // Equivalent to:
+        //
+        // &i2c0 {
+        //     status = "okay";
+        //     touchscreen: touchscreen@10 {
+        //         interrupt-parent = <&pio>;
+        //         phandle = <1>;
+        //     };
+        // };
+        // / {
+        //     fragment@1 {
+        //         target-path = "i2c0/touchscreen@10";
+        //         __overlay__ { self = <&touchscreen>; };
+        //     };
+        // };
+        let mut overlay = Node::new("");
+
+        let mut fragment0 = Node::new("fragment@0");
+        fragment0.set_prop("target", be_cells(&[0xffff_ffff]));
+        let mut contents = Node::new("__overlay__");
+        contents.set_prop_str("status", "okay");
+        let mut touchscreen = Node::new("touchscreen@10");
+        touchscreen.set_prop("interrupt-parent", be_cells(&[0xffff_ffff]));
+        touchscreen.set_prop("phandle", be_cells(&[1]));
+        contents.children.push(touchscreen);
+        fragment0.children.push(contents);
+        overlay.children.push(fragment0);
+
+        let mut fragment1 = Node::new("fragment@1");
+        fragment1.set_prop_str("target-path", "i2c0/touchscreen@10");
+        let mut contents = Node::new("__overlay__");
+        contents.set_prop("self", be_cells(&[1]));
+        fragment1.children.push(contents);
+        overlay.children.push(fragment1);
+
+        let mut fixups = Node::new("__fixups__");
+        fixups.set_prop_str("i2c0", "/fragment@0:target:0");
+        fixups.set_prop_str(
+            "pio",
+            "/fragment@0/__overlay__/touchscreen@10:interrupt-parent:0",
+        );
+        overlay.children.push(fixups);
+
+        let mut local_fixups = Node::new("__local_fixups__");
+        local_fixups
+            .node_mut_or_insert("/fragment@1/__overlay__")
+            .set_prop("self", be_cells(&[0]));
+        overlay.children.push(local_fixups);
+
+        let mut symbols = Node::new("__symbols__");
+        symbols.set_prop_str("touchscreen", "/fragment@0/__overlay__/touchscreen@10");
+        overlay.children.push(symbols);
+
+        let overlay = Fdt {
+            root: overlay,
+            ..Default::default()
+        };
+        let overlay = Fdt::parse(&overlay.to_bytes()).unwrap();
+
+        fdt.apply_overlay(overlay).unwrap();
+
+        let i2c = fdt.root.node("/i2c@11000000").unwrap();
+        assert_eq!(i2c.prop("status").and_then(super::prop_str), Some("okay"));
+
+        let touchscreen = i2c.child("touchscreen").unwrap();
+        assert_eq!(touchscreen.phandle(), Some(3));
+        assert_eq!(
+            touchscreen.prop("interrupt-parent"),
+            Some(&be_cells(&[2])[..])
+        );
+        assert_eq!(touchscreen.prop("self"), Some(&be_cells(&[3])[..]));
+
+        assert_eq!(
+            fdt.root
+                .node("/__symbols__")
+                .unwrap()
+                .prop("touchscreen")
+                .and_then(super::prop_str),
+            Some("/i2c@11000000/touchscreen@10")
+        );
+    }
+
+    #[test]
+    fn fail_to_apply_overlay_with_unknown_symbol() {
+        let mut fdt = base();
+
+        let mut overlay = Fdt::default();
+        let fragment = overlay.root.node_mut_or_insert("/fragment@0");
+        fragment.set_prop("target", be_cells(&[0xffff_ffff]));
+        fragment.child_mut_or_insert("__overlay__");
+        overlay
+            .root
+            .node_mut_or_insert("/__fixups__")
+            .set_prop_str("missing", "/fragment@0:target:0");
+
+        assert!(fdt.apply_overlay(overlay).is_err());
+    }
+}
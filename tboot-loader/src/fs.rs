@@ -34,6 +34,10 @@ mod fs_constants {
     pub const EXT4_SUPERBLOCK_START: u64 = 0x400;
     pub const EXT4_MAGIC_SIGNATURE_START: u64 = EXT4_SUPERBLOCK_START + 0x38;
     pub const EXT4_MAGIC_SIGNATURE_LENGTH: usize = 2;
+    pub const EXT4_UUID_START: u64 = EXT4_SUPERBLOCK_START + 0x68;
+
+    pub const FAT32_VOLUME_ID_START: u64 = 67;
+    pub const FAT16_VOLUME_ID_START: u64 = 39;
 
     pub const ISO9660_MAGIC_SIGNATURE_START_1: u64 = 0x8001;
     pub const ISO9660_MAGIC_SIGNATURE_START_2: u64 = 0x8801;
@@ -144,7 +148,7 @@ where
 
         let mut buffer = [0; fs_constants::EXT4_MAGIC_SIGNATURE_LENGTH];
 
-        if fs.read_exact(&mut buffer).is_ok() {
+        if fs.read_exact(&mut buffer).is_err() {
             break 'ext4;
         }
 
@@ -159,6 +163,61 @@ where
     None
 }
 
+/// Reads the UUID that GRUB and blkid identify a filesystem by, which is the volume ID for FAT.
+pub fn fs_uuid<T>(mut fs: T) -> Option<String>
+where
+    T: Read + Seek,
+{
+    let fs_type = detect_fs_type(&mut fs)?;
+
+    let mut read_at = |start: u64, buffer: &mut [u8]| -> Option<()> {
+        fs.seek(io::SeekFrom::Start(start)).ok()?;
+        fs.read_exact(buffer).ok()
+    };
+
+    match fs_type {
+        FsType::Ext4 => {
+            let mut uuid = [0u8; 16];
+            read_at(fs_constants::EXT4_UUID_START, &mut uuid)?;
+
+            let hex = |bytes: &[u8]| -> String {
+                bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+            };
+
+            Some(format!(
+                "{}-{}-{}-{}-{}",
+                hex(&uuid[0..4]),
+                hex(&uuid[4..6]),
+                hex(&uuid[6..8]),
+                hex(&uuid[8..10]),
+                hex(&uuid[10..16])
+            ))
+        }
+        FsType::Vfat => {
+            let mut identifier = [0u8; fs_constants::FAT32_IDENTIFIER_LENGTH];
+            read_at(fs_constants::FAT32_IDENTIFIER_START, &mut identifier)?;
+
+            let mut volume_id = [0u8; 4];
+            read_at(
+                if &identifier == b"FAT32   " {
+                    fs_constants::FAT32_VOLUME_ID_START
+                } else {
+                    fs_constants::FAT16_VOLUME_ID_START
+                },
+                &mut volume_id,
+            )?;
+
+            let volume_id = u32::from_le_bytes(volume_id);
+            Some(format!(
+                "{:04X}-{:04X}",
+                volume_id >> 16,
+                volume_id & 0xffff
+            ))
+        }
+        FsType::Iso9660 => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -313,4 +372,34 @@ mod tests {
             super::FsType::Vfat
         );
     }
+
+    #[test]
+    fn detect_ext4() {
+        let mut ext4 = vec![0u8; 2048];
+        ext4[0x438..0x43a].copy_from_slice(&[0x53, 0xef]);
+        ext4[0x468..0x478].copy_from_slice(&[
+            0x0f, 0x3e, 0x8c, 0x2a, 0x51, 0x9b, 0x4d, 0x7e, 0x8f, 0x3c, 0x11, 0x22, 0x33, 0x44,
+            0x55, 0x66,
+        ]);
+
+        assert_eq!(
+            super::detect_fs_type(Cursor::new(&ext4)).unwrap(),
+            super::FsType::Ext4
+        );
+        assert_eq!(
+            super::fs_uuid(Cursor::new(&ext4)).as_deref(),
+            Some("0f3e8c2a-519b-4d7e-8f3c-112233445566")
+        );
+    }
+
+    #[test]
+    fn fat_volume_id() {
+        for fat in [FAT12, FAT16, FAT32] {
+            assert_eq!(
+                super::fs_uuid(Cursor::new(fat)).as_deref(),
+                Some("12CE-A600")
+            );
+        }
+        assert_eq!(super::fs_uuid(Cursor::new(NOTHING_MEANINGFUL)), None);
+    }
 }
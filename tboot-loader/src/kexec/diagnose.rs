@@ -0,0 +1,325 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{Read, Seek},
+    os::unix::fs::OpenOptionsExt,
+};
+
+use nix::libc;
+
+use crate::verify::AppraiseError;
+
+/// Why the kernel refused to load the next kernel, or why a file tinyboot verifies itself did not
+/// pass.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    SignatureMissing,
+    UnknownKey,
+    HashMismatch,
+    /// The signature does not verify, but it is not known whether that is because of the key or
+    /// the contents of the kernel.
+    InvalidSignature,
+    CmdlineTooLong {
+        len: usize,
+        max: usize,
+    },
+}
+
+impl Rejection {
+    /// Explains the rejection of the given file in plain words.
+    pub fn describe(&self, file: &str) -> String {
+        match self {
+            Self::SignatureMissing => {
+                format!(
+                    "{file} is not signed, sign it with sign-file and tinyboot's verification key"
+                )
+            }
+            Self::UnknownKey => format!(
+                "{file} is signed with a key tinyboot does not trust, sign it with tinyboot's \
+                 verification key instead"
+            ),
+            Self::HashMismatch => format!(
+                "{file} does not match its signature, it was changed after it was signed and has \
+                 to be signed again"
+            ),
+            Self::InvalidSignature => format!(
+                "signature of {file} is not valid, either it was made with a key tinyboot does \
+                 not trust or {file} was changed after it was signed"
+            ),
+            Self::CmdlineTooLong { len, max } => {
+                format!(
+                    "command line is {len} bytes but the kernel accepts at most {max}, shorten it"
+                )
+            }
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.describe("kernel"))
+    }
+}
+
+fn invalid_signature(signer_known: Option<bool>) -> Rejection {
+    match signer_known {
+        Some(true) => Rejection::HashMismatch,
+        Some(false) => Rejection::UnknownKey,
+        None => Rejection::InvalidSignature,
+    }
+}
+
+/// Works out why kexec_file_load() failed from the errno it returned, the cause IMA gave in its
+/// audit log, and whether the key the kernel was signed with is known.
+pub fn classify(
+    errno: i32,
+    audit_cause: Option<&str>,
+    signer_known: Option<bool>,
+    cmdline_len: usize,
+    max_cmdline_len: Option<usize>,
+) -> Option<Rejection> {
+    if let Some(max) = max_cmdline_len {
+        if matches!(errno, libc::EINVAL | libc::E2BIG) && cmdline_len > max {
+            return Some(Rejection::CmdlineTooLong {
+                len: cmdline_len,
+                max,
+            });
+        }
+    }
+
+    // https://github.com/torvalds/linux/blob/master/security/integrity/ima/ima_appraise.c
+    match audit_cause {
+        Some("IMA-signature-required" | "missing-hash" | "missing-signature") => {
+            return Some(Rejection::SignatureMissing)
+        }
+        Some("invalid-hash") => return Some(Rejection::HashMismatch),
+        Some("invalid-signature") => return Some(invalid_signature(signer_known)),
+        _ => {}
+    }
+
+    // errors from verifying the signature embedded in the kernel's PE image
+    match errno {
+        libc::ENODATA | libc::ENOPKG => Some(Rejection::SignatureMissing),
+        libc::ENOKEY => Some(Rejection::UnknownKey),
+        libc::EKEYREJECTED | libc::EBADMSG => Some(invalid_signature(signer_known)),
+        _ => None,
+    }
+}
+
+/// Works out why a file that tinyboot verifies itself, instead of the kernel, did not pass, in
+/// the same terms as when the kernel rejects the next kernel.
+pub fn classify_appraisal(e: &anyhow::Error, signer_known: Option<bool>) -> Option<Rejection> {
+    match e.downcast_ref::<AppraiseError>()? {
+        AppraiseError::NotSigned => Some(Rejection::SignatureMissing),
+        AppraiseError::Rejected => Some(invalid_signature(signer_known)),
+    }
+}
+
+/// Pulls the message out of a /dev/kmsg record, which looks like
+/// "<priority>,<sequence>,<timestamp>,<flags>;<message>" followed by key/value lines.
+fn kmsg_message(record: &str) -> Option<&str> {
+    let (_, message) = record.split_once(';')?;
+    message.lines().next()
+}
+
+/// Whether the kernel logged the message while loading the file with the given name. Files are
+/// loaded from memfds, which IMA names "/memfd:<name> (deleted)".
+pub fn is_relevant(message: &str, file_name: &str) -> bool {
+    message.contains(&format!("memfd:{file_name}"))
+        || [
+            "kexec",
+            "PEFILE:",
+            "PKCS7:",
+            "Lockdown:",
+            "integrity:",
+            "ima:",
+        ]
+        .iter()
+        .any(|prefix| message.starts_with(prefix))
+}
+
+/// Finds the cause IMA gave for rejecting a file in its audit messages.
+pub fn audit_cause(messages: &[String]) -> Option<&str> {
+    messages
+        .iter()
+        .filter(|message| message.contains("op=appraise_data"))
+        .flat_map(|message| message.split_whitespace())
+        .find_map(|field| field.strip_prefix("cause="))
+}
+
+/// Follows the kernel log from the moment it was opened, to find out what the kernel had to say
+/// about a failed kexec.
+pub struct KernelLog(File);
+
+impl KernelLog {
+    pub fn open() -> std::io::Result<Self> {
+        let mut kmsg = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/kmsg")?;
+        kmsg.seek(std::io::SeekFrom::End(0))?;
+
+        Ok(Self(kmsg))
+    }
+
+    /// Reads the messages logged since the last call.
+    pub fn read_new(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut record = [0u8; 8192];
+
+        loop {
+            // every read returns a single record
+            match self.0.read(&mut record) {
+                Ok(0) => break,
+                Ok(len) => {
+                    if let Some(message) = kmsg_message(&String::from_utf8_lossy(&record[..len])) {
+                        messages.push(message.to_string());
+                    }
+                }
+                // older records were overwritten while we were not looking
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => {}
+                Err(_) => break,
+            }
+        }
+
+        messages
+    }
+}
+
+/// A failed kexec_file_load(), along with what is known about why it failed.
+#[derive(Debug)]
+pub struct KexecError {
+    pub errno: i32,
+    pub rejection: Option<Rejection>,
+    /// What the kernel logged about the failure.
+    pub log: Vec<String>,
+}
+
+impl Display for KexecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let error = std::io::Error::from_raw_os_error(self.errno);
+        match &self.rejection {
+            Some(rejection) => write!(f, "{rejection} ({error})")?,
+            None => write!(f, "{error}")?,
+        }
+
+        for message in &self.log {
+            write!(f, "\n  kernel: {message}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for KexecError {}
+
+#[cfg(test)]
+mod tests {
+    use nix::libc;
+
+    use super::Rejection;
+
+    #[test]
+    fn classify() {
+        assert_eq!(
+            super::classify(libc::EACCES, Some("IMA-signature-required"), None, 10, None),
+            Some(Rejection::SignatureMissing)
+        );
+        assert_eq!(
+            super::classify(
+                libc::EACCES,
+                Some("invalid-signature"),
+                Some(false),
+                10,
+                None
+            ),
+            Some(Rejection::UnknownKey)
+        );
+        assert_eq!(
+            super::classify(
+                libc::EACCES,
+                Some("invalid-signature"),
+                Some(true),
+                10,
+                None
+            ),
+            Some(Rejection::HashMismatch)
+        );
+        assert_eq!(
+            super::classify(libc::EACCES, Some("invalid-signature"), None, 10, None),
+            Some(Rejection::InvalidSignature)
+        );
+        assert_eq!(
+            super::classify(libc::EKEYREJECTED, None, None, 10, Some(2047)),
+            Some(Rejection::InvalidSignature)
+        );
+        assert_eq!(
+            super::classify(libc::ENODATA, None, None, 10, None),
+            Some(Rejection::SignatureMissing)
+        );
+        assert_eq!(
+            super::classify(libc::EINVAL, None, None, 4096, Some(2047)),
+            Some(Rejection::CmdlineTooLong {
+                len: 4096,
+                max: 2047
+            })
+        );
+        assert_eq!(
+            super::classify(libc::EINVAL, None, None, 10, Some(2047)),
+            None
+        );
+        assert_eq!(super::classify(libc::EPERM, None, None, 10, None), None);
+    }
+
+    #[test]
+    fn classify_appraisal() {
+        use crate::verify::AppraiseError;
+
+        assert_eq!(
+            super::classify_appraisal(&AppraiseError::NotSigned.into(), None),
+            Some(Rejection::SignatureMissing)
+        );
+        assert_eq!(
+            super::classify_appraisal(&AppraiseError::Rejected.into(), Some(false)),
+            Some(Rejection::UnknownKey)
+        );
+        assert_eq!(
+            super::classify_appraisal(&AppraiseError::Rejected.into(), Some(true)),
+            Some(Rejection::HashMismatch)
+        );
+        assert_eq!(
+            super::classify_appraisal(&AppraiseError::Rejected.into(), None),
+            Some(Rejection::InvalidSignature)
+        );
+        assert_eq!(
+            super::classify_appraisal(&anyhow::anyhow!("truncated DER element"), None),
+            None
+        );
+
+        assert_eq!(
+            Rejection::SignatureMissing.describe("initrd"),
+            "initrd is not signed, sign it with sign-file and tinyboot's verification key"
+        );
+    }
+
+    #[test]
+    fn kernel_log() {
+        let messages = [
+            "6,1234,5678,-;audit: type=1800 audit(1.2:3): pid=1 uid=0 op=appraise_data \
+             cause=invalid-signature comm=\"tboot-loader\" name=\"/memfd:vmlinuz (deleted)\" \
+             dev=\"tmpfs\" ino=2 res=0 errno=0\n SUBSYSTEM=audit\n",
+            "5,1235,5679,-;kexec_file: kernel signature verification failed (-129).\n",
+            "6,1236,5680,-;usb 1-1: new high-speed USB device number 2\n",
+        ]
+        .iter()
+        .filter_map(|record| super::kmsg_message(record))
+        .filter(|message| super::is_relevant(message, "vmlinuz"))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].ends_with("errno=0"));
+        assert_eq!(super::audit_cause(&messages), Some("invalid-signature"));
+        assert_eq!(super::audit_cause(&messages[1..]), None);
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::Write;
+
+use super::segment::{arm64_constants, x86_constants};
+
+/// Documentation: https://learn.microsoft.com/en-us/windows/win32/debug/pe-format
+mod pe_constants {
+    pub const MZ_MAGIC: &[u8; 2] = b"MZ";
+    pub const PE_OFFSET_OFFSET: usize = 0x3c;
+    pub const PE_MAGIC: &[u8; 4] = b"PE\0\0";
+
+    pub const MACHINE_I386: u16 = 0x14c;
+    pub const MACHINE_ARMNT: u16 = 0x1c4;
+    pub const MACHINE_AMD64: u16 = 0x8664;
+    pub const MACHINE_ARM64: u16 = 0xaa64;
+    pub const MACHINE_RISCV64: u16 = 0x5064;
+    pub const MACHINE_LOONGARCH64: u16 = 0x6264;
+}
+
+/// Documentation: https://github.com/torvalds/linux/blob/master/drivers/firmware/efi/libstub/zboot-header.S
+mod zboot_constants {
+    pub const MAGIC_OFFSET: usize = 4;
+    pub const MAGIC: &[u8; 4] = b"zimg";
+    pub const PAYLOAD_OFFSET_OFFSET: usize = 8;
+    pub const PAYLOAD_SIZE_OFFSET: usize = 12;
+    pub const COMPRESSION_OFFSET: usize = 24;
+    pub const COMPRESSION_LENGTH: usize = 32;
+}
+
+/// The start of linux_banner, which is also what /proc/version shows.
+const LINUX_BANNER: &[u8] = b"Linux version ";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    BzImage,
+    Arm64Image,
+    /// A compressed kernel wrapped in an EFI application that decompresses it, along with the
+    /// compression that was used.
+    Zboot(String),
+    /// A PE image that is none of the above, like a unified kernel image or a plain EFI program.
+    Pe,
+    Unknown,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct KernelImage {
+    pub format: ImageFormat,
+    /// The architecture the kernel is built for, named like std::env::consts::ARCH.
+    pub arch: Option<&'static str>,
+}
+
+impl KernelImage {
+    pub fn check_arch(&self) -> anyhow::Result<()> {
+        match self.arch {
+            Some(arch) if arch != std::env::consts::ARCH => {
+                anyhow::bail!("kernel is built for {arch}, not {}", std::env::consts::ARCH)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn u16_at(kernel: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        kernel.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(kernel: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        kernel.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn has_magic(kernel: &[u8], offset: usize, magic: &[u8]) -> bool {
+    kernel.get(offset..offset + magic.len()) == Some(magic)
+}
+
+fn pe_arch(kernel: &[u8]) -> Option<&'static str> {
+    if !has_magic(kernel, 0, pe_constants::MZ_MAGIC) {
+        return None;
+    }
+
+    let pe_offset = u32_at(kernel, pe_constants::PE_OFFSET_OFFSET)? as usize;
+    if !has_magic(kernel, pe_offset, pe_constants::PE_MAGIC) {
+        return None;
+    }
+
+    match u16_at(kernel, pe_offset + pe_constants::PE_MAGIC.len())? {
+        pe_constants::MACHINE_I386 => Some("x86"),
+        pe_constants::MACHINE_ARMNT => Some("arm"),
+        pe_constants::MACHINE_AMD64 => Some("x86_64"),
+        pe_constants::MACHINE_ARM64 => Some("aarch64"),
+        pe_constants::MACHINE_RISCV64 => Some("riscv64"),
+        pe_constants::MACHINE_LOONGARCH64 => Some("loongarch64"),
+        _ => None,
+    }
+}
+
+fn zboot_compression(kernel: &[u8]) -> Option<String> {
+    if !has_magic(kernel, 0, pe_constants::MZ_MAGIC)
+        || !has_magic(
+            kernel,
+            zboot_constants::MAGIC_OFFSET,
+            zboot_constants::MAGIC,
+        )
+    {
+        return None;
+    }
+
+    let compression = kernel.get(
+        zboot_constants::COMPRESSION_OFFSET
+            ..zboot_constants::COMPRESSION_OFFSET + zboot_constants::COMPRESSION_LENGTH,
+    )?;
+    let len = compression
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(compression.len());
+
+    Some(String::from_utf8_lossy(&compression[..len]).into_owned())
+}
+
+/// Figures out what kind of kernel image this is from its headers. Kernels with an EFI stub are
+/// also PE images, so the Linux specific headers are looked for first.
+pub fn inspect(kernel: &[u8]) -> KernelImage {
+    if let Some(compression) = zboot_compression(kernel) {
+        return KernelImage {
+            format: ImageFormat::Zboot(compression),
+            arch: pe_arch(kernel),
+        };
+    }
+
+    if u16_at(kernel, x86_constants::BOOT_FLAG_OFFSET) == Some(x86_constants::BOOT_FLAG)
+        && has_magic(
+            kernel,
+            x86_constants::HEADER_MAGIC_OFFSET,
+            x86_constants::HEADER_MAGIC,
+        )
+    {
+        let is_64bit = u16_at(kernel, x86_constants::XLOADFLAGS_OFFSET)
+            .is_some_and(|xloadflags| xloadflags & x86_constants::XLF_KERNEL_64 != 0);
+
+        return KernelImage {
+            format: ImageFormat::BzImage,
+            arch: Some(if is_64bit { "x86_64" } else { "x86" }),
+        };
+    }
+
+    if has_magic(
+        kernel,
+        arm64_constants::IMAGE_MAGIC_OFFSET,
+        arm64_constants::IMAGE_MAGIC,
+    ) {
+        return KernelImage {
+            format: ImageFormat::Arm64Image,
+            arch: Some("aarch64"),
+        };
+    }
+
+    if has_magic(kernel, 0, pe_constants::MZ_MAGIC) {
+        return KernelImage {
+            format: ImageFormat::Pe,
+            arch: pe_arch(kernel),
+        };
+    }
+
+    KernelImage {
+        format: ImageFormat::Unknown,
+        arch: None,
+    }
+}
+
+/// Collects decompressed data, failing once there is more of it than allowed, so that a crafted
+/// image cannot decompress to more memory than there is.
+struct LimitedWriter {
+    data: Vec<u8>,
+    max_size: u64,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if (self.data.len() + buf.len()) as u64 > self.max_size {
+            return Err(std::io::Error::other(format!(
+                "decompressed kernel is larger than the {} bytes allowed",
+                self.max_size
+            )));
+        }
+
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Extracts the kernel from a zboot image, refusing to decompress more than `max_size` bytes.
+/// Only the compression types that tinyboot can decompress elsewhere are supported.
+pub fn decompress_zboot(kernel: &[u8], max_size: u64) -> anyhow::Result<Vec<u8>> {
+    let Some(compression) = zboot_compression(kernel) else {
+        anyhow::bail!("kernel is not a zboot image");
+    };
+
+    let payload = u32_at(kernel, zboot_constants::PAYLOAD_OFFSET_OFFSET)
+        .zip(u32_at(kernel, zboot_constants::PAYLOAD_SIZE_OFFSET))
+        .and_then(|(offset, size)| {
+            kernel.get(offset as usize..(offset as usize).checked_add(size as usize)?)
+        })
+        .ok_or_else(|| anyhow::anyhow!("zboot payload is out of bounds"))?;
+
+    let mut decompressed = LimitedWriter {
+        data: Vec::new(),
+        max_size,
+    };
+    match compression.as_str() {
+        "gzip" => {
+            std::io::copy(
+                &mut flate2::read::GzDecoder::new(payload),
+                &mut decompressed,
+            )?;
+        }
+        "lzma" => {
+            lzma_rs::lzma_decompress(&mut &payload[..], &mut decompressed)
+                .map_err(|e| anyhow::anyhow!("failed to decompress zboot payload: {e}"))?;
+        }
+        compression => anyhow::bail!("zboot compression {compression} is not supported"),
+    }
+
+    Ok(decompressed.data)
+}
+
+/// Finds the release of the kernel, as `uname -r` would report it once it is running. zboot
+/// images are decompressed to find it, up to `max_size` bytes.
+pub fn version(kernel: &[u8], max_size: u64) -> Option<String> {
+    let image = inspect(kernel);
+
+    let version = match image.format {
+        // The setup header points at the version string, which saves searching the whole image.
+        ImageFormat::BzImage => {
+            let offset = u16_at(kernel, x86_constants::KERNEL_VERSION_OFFSET)?;
+            if offset == 0 {
+                return None;
+            }
+            // the offset does not count the 512 byte boot sector
+            kernel.get(offset as usize + 0x200..)?
+        }
+        ImageFormat::Zboot(_) => {
+            return version(&decompress_zboot(kernel, max_size).ok()?, max_size)
+        }
+        _ => {
+            let start = kernel.windows(LINUX_BANNER.len() + 1).position(|window| {
+                window.starts_with(LINUX_BANNER) && window[LINUX_BANNER.len()].is_ascii_digit()
+            })?;
+            &kernel[start + LINUX_BANNER.len()..]
+        }
+    };
+
+    let release = version
+        .split(|byte| byte.is_ascii_whitespace() || *byte == 0)
+        .next()
+        .filter(|release| !release.is_empty())?;
+
+    Some(String::from_utf8_lossy(release).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{ImageFormat, KernelImage};
+
+    const MAX_SIZE: u64 = 1 << 20;
+
+    fn bzimage(version: &str) -> Vec<u8> {
+        let mut kernel = vec![0u8; 0x1000];
+        kernel[0x1fe..0x200].copy_from_slice(&0xaa55u16.to_le_bytes());
+        kernel[0x202..0x206].copy_from_slice(b"HdrS");
+        kernel[0x20e..0x210].copy_from_slice(&0x800u16.to_le_bytes());
+        kernel[0x236] = 1;
+        kernel[0xa00..0xa00 + version.len()].copy_from_slice(version.as_bytes());
+        kernel
+    }
+
+    fn arm64_image(banner: &str) -> Vec<u8> {
+        let mut kernel = vec![0u8; 0x1000];
+        kernel[0..2].copy_from_slice(b"MZ");
+        kernel[0x38..0x3c].copy_from_slice(b"ARM\x64");
+        kernel[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        kernel[0x40..0x44].copy_from_slice(b"PE\0\0");
+        kernel[0x44..0x46].copy_from_slice(&0xaa64u16.to_le_bytes());
+        kernel.extend_from_slice(banner.as_bytes());
+        kernel.push(0);
+        kernel
+    }
+
+    fn zboot(payload: &[u8]) -> Vec<u8> {
+        let mut compressed = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        compressed.write_all(payload).unwrap();
+        let compressed = compressed.finish().unwrap();
+
+        let mut kernel = vec![0u8; 0x200];
+        kernel[0..2].copy_from_slice(b"MZ");
+        kernel[4..8].copy_from_slice(b"zimg");
+        kernel[8..12].copy_from_slice(&0x200u32.to_le_bytes());
+        kernel[12..16].copy_from_slice(&(compressed.len() as u32).to_le_bytes());
+        kernel[24..28].copy_from_slice(b"gzip");
+        kernel[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        kernel[0x40..0x44].copy_from_slice(b"PE\0\0");
+        kernel[0x44..0x46].copy_from_slice(&0xaa64u16.to_le_bytes());
+        kernel.extend_from_slice(&compressed);
+        kernel
+    }
+
+    #[test]
+    fn inspect() {
+        assert_eq!(
+            super::inspect(&bzimage("6.8.0")),
+            KernelImage {
+                format: ImageFormat::BzImage,
+                arch: Some("x86_64")
+            }
+        );
+        assert_eq!(
+            super::inspect(&arm64_image("")),
+            KernelImage {
+                format: ImageFormat::Arm64Image,
+                arch: Some("aarch64")
+            }
+        );
+        assert_eq!(
+            super::inspect(&zboot(b"")),
+            KernelImage {
+                format: ImageFormat::Zboot("gzip".to_string()),
+                arch: Some("aarch64")
+            }
+        );
+
+        let mut pe = arm64_image("");
+        pe[0x38..0x3c].fill(0);
+        pe[0x44..0x46].copy_from_slice(&0x5064u16.to_le_bytes());
+        assert_eq!(
+            super::inspect(&pe),
+            KernelImage {
+                format: ImageFormat::Pe,
+                arch: Some("riscv64")
+            }
+        );
+
+        assert_eq!(
+            super::inspect(b"\x7fELF"),
+            KernelImage {
+                format: ImageFormat::Unknown,
+                arch: None
+            }
+        );
+    }
+
+    #[test]
+    fn check_arch() {
+        let native = KernelImage {
+            format: ImageFormat::Unknown,
+            arch: Some(std::env::consts::ARCH),
+        };
+        assert!(native.check_arch().is_ok());
+
+        let foreign = KernelImage {
+            format: ImageFormat::Unknown,
+            arch: Some("mips"),
+        };
+        assert!(foreign.check_arch().is_err());
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(
+            super::version(
+                &bzimage("6.8.0-1-amd64 (debian-kernel@lists.debian.org) #1 SMP"),
+                MAX_SIZE
+            ),
+            Some("6.8.0-1-amd64".to_string())
+        );
+        assert_eq!(super::version(&bzimage(""), MAX_SIZE), None);
+
+        let image = arm64_image("Linux version 6.9.7-arm64 (builder@host) #1 SMP PREEMPT\n");
+        assert_eq!(
+            super::version(&image, MAX_SIZE),
+            Some("6.9.7-arm64".to_string())
+        );
+        assert_eq!(
+            super::version(&arm64_image("Linux version %s"), MAX_SIZE),
+            None
+        );
+
+        assert_eq!(
+            super::version(&zboot(&image), MAX_SIZE),
+            Some("6.9.7-arm64".to_string())
+        );
+        // the banner is not looked for past the decompression limit
+        assert_eq!(super::version(&zboot(&image), 0x100), None);
+    }
+
+    #[test]
+    fn decompress_zboot() {
+        let image = arm64_image("Linux version 6.9.7");
+        assert_eq!(
+            super::decompress_zboot(&zboot(&image), MAX_SIZE).unwrap(),
+            image
+        );
+        assert_eq!(
+            super::decompress_zboot(&zboot(&image), image.len() as u64).unwrap(),
+            image
+        );
+        assert!(super::decompress_zboot(&zboot(&image), image.len() as u64 - 1).is_err());
+
+        // a payload that decompresses to far more than it takes up
+        let bomb = zboot(&vec![0u8; 16 << 20]);
+        assert!(bomb.len() < 1 << 20);
+        assert!(super::decompress_zboot(&bomb, MAX_SIZE).is_err());
+
+        let mut zstd = zboot(&image);
+        zstd[24..32].copy_from_slice(b"zstd22\0\0");
+        assert!(super::decompress_zboot(&zstd, MAX_SIZE).is_err());
+
+        assert!(super::decompress_zboot(&image, MAX_SIZE).is_err());
+    }
+}
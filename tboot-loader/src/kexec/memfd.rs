@@ -0,0 +1,209 @@
+use log::{debug, trace, warn};
+use nix::{
+    fcntl::{fcntl, FcntlArg, SealFlag},
+    libc,
+    sys::memfd::{memfd_create, MemFdCreateFlag},
+};
+use std::{
+    ffi,
+    fs::File,
+    io::{Read, Write},
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use crate::{keys, verify};
+
+/// Files are copied in chunks of this size, and progress is reported after each one.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Files smaller than this load quickly enough that reporting progress would only be noise.
+const PROGRESS_MIN_SIZE: u64 = 8 << 20;
+
+pub fn create(name: &str) -> anyhow::Result<File> {
+    let name = ffi::CString::new(name)?;
+    Ok(File::from(memfd_create(
+        &name,
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )?))
+}
+
+/// Seals the memfd so its contents can no longer change, and reopens it read-only. The kernel
+/// refuses to kexec files that are open for writing, which includes the fd the memfd was filled
+/// through.
+pub fn seal(memfd: File) -> anyhow::Result<File> {
+    fcntl(
+        memfd.as_raw_fd(),
+        FcntlArg::F_ADD_SEALS(
+            SealFlag::F_SEAL_SEAL
+                | SealFlag::F_SEAL_SHRINK
+                | SealFlag::F_SEAL_GROW
+                | SealFlag::F_SEAL_WRITE,
+        ),
+    )?;
+
+    Ok(File::open(format!("/proc/self/fd/{}", memfd.as_raw_fd()))?)
+}
+
+/// Prints how much of a file has been read, for media slow enough that loading a kernel takes a
+/// noticeable amount of time.
+struct Progress<'a> {
+    name: &'a str,
+    total: u64,
+    percent: Option<u64>,
+}
+
+impl<'a> Progress<'a> {
+    fn new(name: &'a str, total: u64) -> Self {
+        Self {
+            name,
+            total,
+            percent: None,
+        }
+    }
+
+    fn update(&mut self, done: u64) {
+        if self.total < PROGRESS_MIN_SIZE {
+            return;
+        }
+
+        let percent = done.min(self.total) * 100 / self.total;
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            print!("\rloading {} {percent}%", self.name);
+            _ = std::io::stdout().flush();
+        }
+    }
+
+    fn finish(&self) {
+        if self.percent.is_some() {
+            println!();
+        }
+    }
+}
+
+/// The kernel only accepts signatures in the "security.ima" xattr of the file it is given, so the
+/// xattr is carried over to the memfd. This needs tmpfs to be built with xattr support, without
+/// which a kernel signed this way cannot be booted with boot verification on.
+fn copy_ima_xattr(from: &File, to: &File) -> anyhow::Result<()> {
+    let name = ffi::CString::new(verify::IMA_XATTR_NAME)?;
+
+    let mut xattr = [0u8; 1024];
+    let len = unsafe {
+        libc::fgetxattr(
+            from.as_raw_fd(),
+            name.as_ptr(),
+            xattr.as_mut_ptr() as *mut libc::c_void,
+            xattr.len(),
+        )
+    };
+    if len < 0 {
+        trace!("no IMA xattr: {}", std::io::Error::last_os_error());
+        return Ok(());
+    }
+
+    let ret = unsafe {
+        libc::fsetxattr(
+            to.as_raw_fd(),
+            name.as_ptr(),
+            xattr.as_ptr() as *const libc::c_void,
+            len as usize,
+            0,
+        )
+    };
+    if ret < 0 {
+        let e = std::io::Error::last_os_error();
+        if keys::verification_on() {
+            anyhow::bail!("failed to copy IMA signature: {e}");
+        }
+        warn!("failed to copy IMA signature: {e}");
+    }
+
+    Ok(())
+}
+
+/// Reads a file into a sealed memfd, so that it cannot change between being verified and being
+/// loaded, and so that the disk it came from does not have to stay mounted. Files larger than
+/// `max_size` are refused.
+pub fn load(path: &Path, max_size: Option<u64>) -> anyhow::Result<File> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    if let Some(max_size) = max_size {
+        if len > max_size {
+            anyhow::bail!(
+                "{} is {len} bytes, larger than the {max_size} bytes allowed",
+                path.display()
+            );
+        }
+    }
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    debug!("reading {} into memory", path.display());
+
+    let mut memfd = create(&name)?;
+    copy_ima_xattr(&file, &memfd)?;
+
+    let mut progress = Progress::new(&name, len);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut copied = 0u64;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        copied += n as u64;
+        // the file may have grown since we looked at its size
+        if max_size.is_some_and(|max_size| copied > max_size) {
+            progress.finish();
+            anyhow::bail!("{} grew past the allowed size", path.display());
+        }
+
+        memfd.write_all(&buf[..n])?;
+        progress.update(copied);
+    }
+    progress.finish();
+
+    trace!("read {copied} bytes from {}", path.display());
+
+    seal(memfd)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    #[test]
+    fn load() {
+        let path = std::env::temp_dir().join(format!("tboot-memfd-{}", std::process::id()));
+        std::fs::write(&path, b"hello kernel").unwrap();
+
+        let mut sealed = super::load(&path, None).unwrap();
+
+        // the copy does not change along with the file
+        std::fs::write(&path, b"goodbye").unwrap();
+
+        let mut contents = String::new();
+        sealed.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello kernel");
+
+        assert!(sealed.write_all(b"x").is_err());
+        let mut writable = std::fs::OpenOptions::new()
+            .write(true)
+            .open(format!(
+                "/proc/self/fd/{}",
+                std::os::fd::AsRawFd::as_raw_fd(&sealed)
+            ))
+            .unwrap();
+        assert!(writable.write_all(b"x").is_err());
+
+        assert!(super::load(&path, Some(7)).is_ok());
+        assert!(super::load(&path, Some(6)).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
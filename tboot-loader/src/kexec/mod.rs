@@ -0,0 +1,536 @@
+use log::{debug, info, trace, warn};
+use nix::{
+    libc,
+    mount::{self, MntFlags, MsFlags},
+};
+use std::{
+    ffi,
+    fs::OpenOptions,
+    io::{Read, Seek, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+use syscalls::{syscall, Errno, Sysno};
+use tboot::config::{Config, KexecMethod};
+
+use crate::{boot_loader::LinuxBootParts, fdt::Fdt, keys, verify};
+
+mod diagnose;
+mod image;
+pub(crate) mod memfd;
+mod segment;
+
+use image::ImageFormat;
+
+/// The devicetree the running kernel was booted with.
+const FIRMWARE_FDT_PATH: &str = "/sys/firmware/fdt";
+
+/// How long to wait for the kernel to log why a kexec failed.
+const KERNEL_LOG_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Concatenated initrds must each start on a 4-byte boundary for the kernel to unpack them.
+const INITRD_ALIGNMENT: u64 = 4;
+
+/// Where the ramfs that combined initrds are written to is mounted, only for as long as it takes
+/// to create the file.
+const INITRD_MOUNT_PATH: &str = "/run/tboot/initrd";
+
+/// How large a zboot kernel may decompress to when `tboot.max-kernel-size` is not set.
+const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 256 << 20;
+
+/// The most a kernel may take up once decompressed, so that a crafted zboot image cannot use up
+/// all memory.
+fn max_decompressed_size(max_kernel_size: Option<u64>) -> u64 {
+    max_kernel_size.unwrap_or(DEFAULT_MAX_DECOMPRESSED_SIZE)
+}
+
+/// Creates a file on a ramfs of its own, returning a writable and a read-only handle to it. The
+/// IMA policy does not appraise initrds on ramfs (see etc/ima_policy.conf), as nothing but tinyboot
+/// writes to one. The ramfs is detached as soon as the file is created, so the file cannot be
+/// reached by its path, and once the writable handle is dropped its contents can no longer change.
+fn private_ramfs_file(name: &str) -> anyhow::Result<(std::fs::File, std::fs::File)> {
+    std::fs::create_dir_all(INITRD_MOUNT_PATH)?;
+    mount::mount(
+        Some("none"),
+        INITRD_MOUNT_PATH,
+        Some("ramfs"),
+        MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=0700"),
+    )?;
+
+    let path = Path::new(INITRD_MOUNT_PATH).join(name);
+    let files = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|writable| Ok((writable, std::fs::File::open(&path)?)));
+
+    if let Err(e) = mount::umount2(INITRD_MOUNT_PATH, MntFlags::MNT_DETACH) {
+        anyhow::bail!("failed to detach {INITRD_MOUNT_PATH}: {e}");
+    }
+
+    Ok(files?)
+}
+
+/// Concatenates initrds into a single file. The kernel cannot appraise the result, so it is
+/// written to a private ramfs that the IMA policy exempts, and each piece is read into memory and
+/// verified against the IMA keyring before it is added, unless it was already verified. Appended
+/// signatures are stripped so they do not end up between cpio archives.
+fn combine_initrds(
+    initrds: &[PathBuf],
+    verified: bool,
+    max_size: Option<u64>,
+) -> anyhow::Result<std::fs::File> {
+    let keyring_id = if verified {
+        debug!("initrds were already verified");
+        None
+    } else {
+        let keyring_id = keys::ima_keyring()?;
+        if keyring_id.is_none() {
+            debug!("boot verification is OFF, not verifying initrds");
+        }
+        keyring_id
+    };
+
+    let (mut combined, sealed) = private_ramfs_file("initrd")?;
+
+    let mut combined_len = 0u64;
+
+    for initrd in initrds {
+        debug!("adding initrd {}", initrd.display());
+
+        let mut piece = memfd::load(
+            initrd,
+            max_size.map(|max_size| max_size.saturating_sub(combined_len)),
+        )?;
+
+        let len = match keyring_id {
+            Some(keyring_id) => appraise(&mut piece, initrd, keyring_id)?,
+            None => verify::unsigned_len(&mut piece)?,
+        };
+
+        piece.rewind()?;
+        combined_len += std::io::copy(&mut piece.take(len), &mut combined)?;
+
+        let padding = combined_len.next_multiple_of(INITRD_ALIGNMENT) - combined_len;
+        combined.write_all(&vec![0u8; padding as usize])?;
+        combined_len += padding;
+    }
+
+    trace!(
+        "combined {} initrds into {} bytes",
+        initrds.len(),
+        combined_len
+    );
+
+    drop(combined);
+    Ok(sealed)
+}
+
+/// Verifies a file against the IMA keyring, the same way the kernel does for the next kernel, and
+/// explains why it did not pass in the same terms. Returns the length of the signed contents.
+pub fn appraise(file: &mut std::fs::File, path: &Path, keyring_id: i32) -> anyhow::Result<u64> {
+    verify::appraise(file, keyring_id).map_err(|e| {
+        let signer_known = file
+            .rewind()
+            .map_err(anyhow::Error::from)
+            .and_then(|_| verify::is_signer_known(file, keyring_id))
+            .map_err(|e| debug!("failed to check signer of {}: {e}", path.display()))
+            .ok()
+            .flatten();
+
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+
+        match diagnose::classify_appraisal(&e, signer_known) {
+            Some(rejection) => anyhow::anyhow!("{} ({e})", rejection.describe(&file_name)),
+            None => anyhow::anyhow!("failed to verify {}: {e}", path.display()),
+        }
+    })
+}
+
+/// Reads a file into memory, leaving out any appended signature.
+fn read_unsigned(path: &Path, max_size: Option<u64>) -> anyhow::Result<Vec<u8>> {
+    let mut file = memfd::load(path, max_size)?;
+    let len = verify::unsigned_len(&mut file)?;
+
+    file.rewind()?;
+    let mut contents = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Looks at the kernel before it is loaded, so that a kernel built for another architecture is
+/// refused with a clear error instead of whatever the kernel makes of it.
+fn inspect_kernel(kernel: &[u8], max_size: u64) -> anyhow::Result<image::KernelImage> {
+    let image = image::inspect(kernel);
+
+    match &image.format {
+        ImageFormat::Pe => {
+            warn!("kernel is a PE image without a Linux boot header, it may not load")
+        }
+        ImageFormat::Unknown => warn!("kernel image format is not recognized, it may not load"),
+        format => debug!("kernel image format is {format:?}"),
+    }
+
+    image.check_arch()?;
+
+    match image::version(kernel, max_size) {
+        Some(version) => info!("kernel version {version}"),
+        None => debug!("kernel version not found"),
+    }
+
+    Ok(image)
+}
+
+/// Finds the release of the kernel at the given path without loading it, to show it to the user.
+/// Kernels larger than `max_kernel_size` are not looked at.
+pub fn kernel_version(path: &Path, max_kernel_size: Option<u64>) -> Option<String> {
+    let max_size = max_decompressed_size(max_kernel_size);
+
+    let mut kernel = Vec::new();
+    std::fs::File::open(path)
+        .ok()?
+        .take(max_size + 1)
+        .read_to_end(&mut kernel)
+        .ok()?;
+    if kernel.len() as u64 > max_size {
+        return None;
+    }
+
+    image::version(&kernel, max_size)
+}
+
+/// A devicetree shipped alongside the kernel does not know about anything filled in by firmware at
+/// boot time, so the memory layout (and the coreboot tables on Chromebooks) are taken from the
+/// devicetree we were booted with.
+fn carry_over_firmware_nodes(fdt: &mut Fdt, firmware: &Fdt) {
+    let is_memory = |node: &crate::fdt::Node| {
+        node.prop("device_type").and_then(crate::fdt::prop_str) == Some("memory")
+    };
+
+    fdt.root.children.retain(|node| !is_memory(node));
+    fdt.root.children.extend(
+        firmware
+            .root
+            .children
+            .iter()
+            .filter(|node| is_memory(node))
+            .cloned(),
+    );
+
+    if let Some(coreboot) = firmware.root.node("/firmware/coreboot") {
+        if fdt.root.node("/firmware/coreboot").is_none() {
+            fdt.root
+                .node_mut_or_insert("/firmware")
+                .children
+                .push(coreboot.clone());
+        }
+    }
+
+    fdt.reserved.extend(&firmware.reserved);
+}
+
+/// Loads the devicetree from the boot entry (or the one we were booted with if the entry only has
+/// overlays) and applies overlays in the order they were given.
+fn load_devicetree(boot_entry: &LinuxBootParts) -> anyhow::Result<Fdt> {
+    let firmware = std::fs::read(FIRMWARE_FDT_PATH)
+        .map_err(anyhow::Error::from)
+        .and_then(|firmware| Fdt::parse(&firmware));
+
+    let mut fdt = match &boot_entry.devicetree {
+        Some(devicetree) => {
+            debug!("loading devicetree from {}", devicetree.display());
+            let mut fdt = Fdt::parse(&read_unsigned(devicetree, None)?)
+                .map_err(|e| anyhow::anyhow!("invalid devicetree {}: {e}", devicetree.display()))?;
+
+            match firmware {
+                Ok(firmware) => carry_over_firmware_nodes(&mut fdt, &firmware),
+                Err(e) => warn!("failed to read firmware devicetree: {e}"),
+            }
+
+            fdt
+        }
+        None => firmware?,
+    };
+
+    for overlay in &boot_entry.devicetree_overlay {
+        debug!("applying devicetree overlay {}", overlay.display());
+        Fdt::parse(&read_unsigned(overlay, None)?)
+            .and_then(|overlay| fdt.apply_overlay(overlay))
+            .map_err(|e| anyhow::anyhow!("failed to apply {}: {e}", overlay.display()))?;
+    }
+
+    Ok(fdt)
+}
+
+/// Loads the next kernel with kexec_load(), building its segments ourselves. kexec_file_load()
+/// always hands the next kernel the devicetree we were booted with, so this is also how entries
+/// with a devicetree are loaded. When boot verification is on, the IMA policy makes the kernel
+/// refuse kexec_load() outright, so this only works with boot verification off.
+fn kexec_load_with_segments(boot_entry: &LinuxBootParts, cfg: &Config) -> anyhow::Result<()> {
+    if keys::verification_on() {
+        anyhow::bail!(
+            "kexec_load() is blocked by the IMA policy while boot verification is on, only \
+             kexec_file_load() can load the kernel"
+        );
+    }
+
+    debug!("loading kernel from {}", boot_entry.linux.display());
+    let mut kernel = read_unsigned(&boot_entry.linux, cfg.max_kernel_size)?;
+
+    // kexec_load() has no idea what a zboot image is, and neither does kexec_file_load() before
+    // Linux 6.10.
+    let max_size = max_decompressed_size(cfg.max_kernel_size);
+    if let ImageFormat::Zboot(compression) = inspect_kernel(&kernel, max_size)?.format {
+        debug!("decompressing {compression} zboot kernel");
+        kernel = image::decompress_zboot(&kernel, max_size)?;
+    }
+
+    let mut initrd = Vec::new();
+    if !boot_entry.initrd.is_empty() {
+        combine_initrds(
+            &boot_entry.initrd,
+            boot_entry.initrd_verified,
+            cfg.max_initrd_size,
+        )?
+        .read_to_end(&mut initrd)?;
+    }
+
+    let cmdline = boot_entry.cmdline.as_deref().unwrap_or_default();
+    debug!("loading cmdline as {}", cmdline);
+
+    if cfg!(target_arch = "aarch64") {
+        let fdt = load_devicetree(boot_entry)?;
+        segment::load_arm64(&kernel, fdt, &initrd, cmdline)?;
+    } else if cfg!(target_arch = "x86_64") {
+        if has_devicetree(boot_entry) {
+            warn!("ignoring devicetree, handing one off is only supported on aarch64");
+        }
+        segment::load_bzimage(&kernel, &initrd, cmdline)?;
+    } else {
+        anyhow::bail!("kexec_load() is not supported on this architecture");
+    }
+
+    wait_for_kexec_loaded()
+}
+
+fn wait_for_kexec_loaded() -> anyhow::Result<()> {
+    while std::fs::read("/sys/kernel/kexec_loaded")? != [b'1', b'\n'] {
+        debug!("waiting for kexec_loaded");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    Ok(())
+}
+
+fn has_devicetree(boot_entry: &LinuxBootParts) -> bool {
+    boot_entry.devicetree.is_some() || !boot_entry.devicetree_overlay.is_empty()
+}
+
+/// Whether kexec_file_load() failed because it cannot load the kernel at all, as opposed to the
+/// kernel being rejected.
+fn is_unsupported(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<diagnose::KexecError>()
+        .is_some_and(|e| e.errno == libc::ENOSYS || e.errno == libc::ENOEXEC)
+        || e.downcast_ref::<Errno>()
+            .is_some_and(|errno| matches!(*errno, Errno::ENOSYS | Errno::ENOEXEC))
+        || e.downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::raw_os_error)
+            .is_some_and(|code| code == libc::ENOSYS || code == libc::ENOEXEC)
+}
+
+/// Loads the next kernel. Everything is read into memory first, so nothing on disk is needed
+/// anymore once this returns.
+pub fn kexec_load(boot_entry: LinuxBootParts, cfg: &Config) -> anyhow::Result<()> {
+    match cfg.kexec {
+        KexecMethod::Segments => kexec_load_with_segments(&boot_entry, cfg),
+        KexecMethod::File => {
+            if has_devicetree(&boot_entry) {
+                warn!("ignoring devicetree, kexec_file_load() hands off the running one");
+            }
+            kexec_file_load(&boot_entry, cfg)
+        }
+        KexecMethod::Auto => {
+            // the IMA policy blocks kexec_load() while boot verification is on
+            let can_use_segments = !keys::verification_on();
+
+            if has_devicetree(&boot_entry) {
+                if !cfg!(target_arch = "aarch64") {
+                    warn!("ignoring devicetree, handing one off is only supported on aarch64");
+                } else if can_use_segments {
+                    return kexec_load_with_segments(&boot_entry, cfg);
+                } else {
+                    // booting with the running kernel's devicetree instead could describe the
+                    // wrong hardware
+                    let devicetree = boot_entry
+                        .devicetree
+                        .iter()
+                        .chain(&boot_entry.devicetree_overlay)
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    anyhow::bail!(
+                        "entry with kernel {} needs devicetree {devicetree}, which can only be \
+                         handed off with kexec_load(), and that is blocked by the IMA policy \
+                         while boot verification is on",
+                        boot_entry.linux.display()
+                    );
+                }
+            }
+
+            match kexec_file_load(&boot_entry, cfg) {
+                Err(e) if can_use_segments && is_unsupported(&e) => {
+                    warn!("kexec_file_load() cannot load the kernel ({e}), using kexec_load()");
+                    kexec_load_with_segments(&boot_entry, cfg)
+                }
+                result => result,
+            }
+        }
+    }
+}
+
+fn kexec_file_load(boot_entry: &LinuxBootParts, cfg: &Config) -> anyhow::Result<()> {
+    let kernel = &boot_entry.linux;
+    let initrds = &boot_entry.initrd;
+    let cmdline = boot_entry.cmdline.as_deref().unwrap_or_default();
+
+    debug!("loading kernel from {}", kernel.display());
+    let kernel_name = kernel
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut kernel = memfd::load(kernel, cfg.max_kernel_size)?;
+
+    let mut contents = Vec::new();
+    kernel.read_to_end(&mut contents)?;
+    let is_zboot = matches!(
+        inspect_kernel(&contents, max_decompressed_size(cfg.max_kernel_size))?.format,
+        ImageFormat::Zboot(_)
+    );
+    let max_cmdline_len = segment::parse_bzimage(&contents)
+        .ok()
+        .map(|image| image.cmdline_size as usize);
+    drop(contents);
+    kernel.rewind()?;
+    let kernel_fd = kernel.as_raw_fd() as libc::c_int;
+    trace!("kernel loaded as fd {}", kernel_fd);
+
+    debug!("loading cmdline as {}", cmdline);
+    let cmdline = ffi::CString::new(cmdline)?;
+    let cmdline = cmdline.to_bytes_with_nul();
+
+    let initrd = match initrds.as_slice() {
+        [] => None,
+        // the kernel appraises a single initrd itself, its IMA signature is carried over to the
+        // memfd
+        [initrd] if !boot_entry.initrd_verified => {
+            debug!("loading initrd from {}", initrd.display());
+            Some(memfd::load(initrd, cfg.max_initrd_size)?)
+        }
+        initrds => Some(combine_initrds(
+            initrds,
+            boot_entry.initrd_verified,
+            cfg.max_initrd_size,
+        )?),
+    };
+
+    let mut kernel_log = diagnose::KernelLog::open()
+        .map_err(|e| debug!("failed to open kernel log: {e}"))
+        .ok();
+
+    let result = if let Some(initrd) = initrd {
+        let initrd_fd = initrd.as_raw_fd() as libc::c_int;
+        trace!("initrd loaded as fd {}", initrd_fd);
+
+        unsafe {
+            syscall!(
+                Sysno::kexec_file_load,
+                kernel_fd,
+                initrd_fd,
+                cmdline.len(),
+                cmdline.as_ptr(),
+                0 as libc::c_ulong
+            )
+        }
+    } else {
+        unsafe {
+            syscall!(
+                Sysno::kexec_file_load,
+                kernel_fd,
+                0 as libc::c_int, // this gets ignored when KEXEC_FILE_NO_INITRAMFS is set
+                cmdline.len(),
+                cmdline.as_ptr(),
+                nix::libc::KEXEC_FILE_NO_INITRAMFS as libc::c_ulong
+            )
+        }
+    };
+
+    let retval = match result {
+        Ok(retval) => retval,
+        Err(errno) => {
+            let errno = errno.into_raw();
+
+            // audit messages are printed by a kernel thread, which may not have gotten to them yet
+            std::thread::sleep(KERNEL_LOG_DELAY);
+            let log = kernel_log
+                .as_mut()
+                .map(diagnose::KernelLog::read_new)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|message| diagnose::is_relevant(message, &kernel_name))
+                .collect::<Vec<_>>();
+
+            let signer_known = keys::ima_keyring().ok().flatten().and_then(|keyring_id| {
+                kernel.rewind().ok()?;
+                verify::is_signer_known(&mut kernel, keyring_id)
+                    .map_err(|e| debug!("failed to check kernel signer: {e}"))
+                    .ok()
+                    .flatten()
+            });
+
+            let rejection = diagnose::classify(
+                errno,
+                diagnose::audit_cause(&log),
+                signer_known,
+                cmdline.len() - 1,
+                max_cmdline_len,
+            );
+
+            let e = anyhow::Error::from(diagnose::KexecError {
+                errno,
+                rejection,
+                log,
+            });
+
+            // zboot kernels are only decompressed for kexec_load(), which the IMA policy blocks
+            if is_zboot && errno == libc::ENOEXEC && keys::verification_on() {
+                return Err(e.context(
+                    "this kernel needs Linux 6.10 or newer to load zboot images while boot \
+                     verification is on",
+                ));
+            }
+
+            return Err(e);
+        }
+    };
+
+    if retval > -4096isize as usize {
+        let code = -(retval as isize) as i32;
+        return Err(std::io::Error::from_raw_os_error(code).into());
+    }
+
+    wait_for_kexec_loaded()
+}
+
+pub fn kexec_execute() -> std::io::Result<()> {
+    let ret = unsafe { libc::reboot(libc::LINUX_REBOOT_CMD_KEXEC) };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
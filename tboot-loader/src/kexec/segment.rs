@@ -0,0 +1,1045 @@
+use log::{debug, trace, warn};
+use nix::libc;
+use std::{ffi, path::Path};
+use syscalls::{syscall, Errno, Sysno};
+
+use crate::fdt::Fdt;
+
+const PAGE_SIZE: u64 = 4096;
+const SZ_2M: u64 = 2 * 1024 * 1024;
+
+/// The kernel refuses to load more segments than this.
+const KEXEC_SEGMENT_MAX: usize = 16;
+const KEXEC_ARCH_DEFAULT: libc::c_ulong = 0;
+
+/// Documentation: https://docs.kernel.org/arch/arm64/booting.html
+pub(super) mod arm64_constants {
+    pub const IMAGE_MAGIC: &[u8; 4] = b"ARM\x64";
+    pub const IMAGE_MAGIC_OFFSET: usize = 56;
+    pub const HEADER_LENGTH: usize = 64;
+
+    /// Used by kernels older than 3.17, which have an image_size of zero.
+    pub const DEFAULT_TEXT_OFFSET: u64 = 0x80000;
+
+    /// The devicetree blob must not exceed 2 megabytes in size.
+    pub const MAX_DTB_SIZE: u64 = super::SZ_2M;
+}
+
+/// Documentation: https://docs.kernel.org/arch/x86/boot.html
+pub(super) mod x86_constants {
+    pub const SETUP_SECTS_OFFSET: usize = 0x1f1;
+    pub const BOOT_FLAG_OFFSET: usize = 0x1fe;
+    pub const BOOT_FLAG: u16 = 0xaa55;
+    pub const JUMP_OFFSET: usize = 0x201;
+    pub const HEADER_MAGIC_OFFSET: usize = 0x202;
+    pub const HEADER_MAGIC: &[u8; 4] = b"HdrS";
+    pub const VERSION_OFFSET: usize = 0x206;
+    pub const KERNEL_VERSION_OFFSET: usize = 0x20e;
+    pub const TYPE_OF_LOADER_OFFSET: usize = 0x210;
+    pub const LOADFLAGS_OFFSET: usize = 0x211;
+    pub const CODE32_START_OFFSET: usize = 0x214;
+    pub const RAMDISK_IMAGE_OFFSET: usize = 0x218;
+    pub const RAMDISK_SIZE_OFFSET: usize = 0x21c;
+    pub const CMD_LINE_PTR_OFFSET: usize = 0x228;
+    pub const INITRD_ADDR_MAX_OFFSET: usize = 0x22c;
+    pub const KERNEL_ALIGNMENT_OFFSET: usize = 0x230;
+    pub const RELOCATABLE_KERNEL_OFFSET: usize = 0x234;
+    pub const XLOADFLAGS_OFFSET: usize = 0x236;
+    pub const CMDLINE_SIZE_OFFSET: usize = 0x238;
+    pub const PREF_ADDRESS_OFFSET: usize = 0x258;
+    pub const INIT_SIZE_OFFSET: usize = 0x260;
+
+    /// Version 2.12 added xloadflags, which tells whether the kernel has a 64-bit entry point.
+    pub const MIN_VERSION: u16 = 0x020c;
+    pub const XLF_KERNEL_64: u16 = 1 << 0;
+    pub const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+    pub const LOADED_HIGH: u8 = 1 << 0;
+    /// Boot loaders without an assigned ID use 0xff.
+    pub const TYPE_OF_LOADER_UNDEFINED: u8 = 0xff;
+
+    /// The 64-bit entry point is at a fixed offset into the protected-mode kernel.
+    pub const ENTRY_64_OFFSET: u64 = 0x200;
+    /// Nothing is loaded below 1M, where real-mode leftovers and the BIOS data live.
+    pub const MIN_LOAD_ADDRESS: u64 = 0x10_0000;
+
+    /// Fields of `struct boot_params` (the "zero page") outside of the setup header.
+    pub const BOOT_PARAMS_LENGTH: usize = 4096;
+    pub const EXT_RAMDISK_IMAGE_OFFSET: usize = 0x0c0;
+    pub const EXT_RAMDISK_SIZE_OFFSET: usize = 0x0c4;
+    pub const EXT_CMD_LINE_PTR_OFFSET: usize = 0x0c8;
+    pub const E820_ENTRIES_OFFSET: usize = 0x1e8;
+    pub const E820_TABLE_OFFSET: usize = 0x2d0;
+    pub const E820_ENTRY_LENGTH: usize = 20;
+    pub const E820_MAX_ENTRIES: usize = 128;
+
+    /// Segment selectors the kernel expects for its 64-bit entry point.
+    pub const BOOT_CS: u8 = 0x10;
+    pub const BOOT_DS: u8 = 0x18;
+}
+
+/// The memory map the firmware handed to the running kernel, which the next one needs as well.
+/// https://github.com/torvalds/linux/blob/master/drivers/firmware/memmap.c
+const FIRMWARE_MEMMAP_PATH: &str = "/sys/firmware/memmap";
+
+/// Mirrors `struct kexec_segment` from include/uapi/linux/kexec.h.
+#[repr(C)]
+struct KexecSegment {
+    buf: *const libc::c_void,
+    bufsz: libc::size_t,
+    mem: libc::c_ulong,
+    memsz: libc::size_t,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MemoryRange {
+    pub start: u64,
+    /// Exclusive
+    pub end: u64,
+}
+
+/// Parses usable RAM out of /proc/iomem. Memory used for the crash kernel or reserved by
+/// firmware is nested below "System RAM" and is excluded.
+pub fn parse_iomem(iomem: &str) -> Vec<MemoryRange> {
+    let mut ram: Vec<MemoryRange> = Vec::new();
+    let mut excluded: Vec<MemoryRange> = Vec::new();
+
+    for line in iomem.lines() {
+        let Some((range, name)) = line.split_once(" : ") else {
+            continue;
+        };
+
+        let is_nested = range.starts_with(char::is_whitespace);
+
+        let Some((start, end)) = range.trim().split_once('-') else {
+            continue;
+        };
+        let (Ok(start), Ok(end)) = (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16))
+        else {
+            continue;
+        };
+        let range = MemoryRange {
+            start,
+            end: end.saturating_add(1),
+        };
+
+        match (is_nested, name) {
+            (false, "System RAM") => ram.push(range),
+            (true, "Crash kernel" | "reserved") => excluded.push(range),
+            _ => {}
+        }
+    }
+
+    for excluded in excluded {
+        ram = ram
+            .into_iter()
+            .flat_map(|range| {
+                if excluded.end <= range.start || excluded.start >= range.end {
+                    return vec![range];
+                }
+
+                [
+                    MemoryRange {
+                        start: range.start,
+                        end: excluded.start,
+                    },
+                    MemoryRange {
+                        start: excluded.end,
+                        end: range.end,
+                    },
+                ]
+                .into_iter()
+                .filter(|range| range.start < range.end)
+                .collect()
+            })
+            .collect();
+    }
+
+    ram
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Arm64Image {
+    pub text_offset: u64,
+    pub image_size: u64,
+}
+
+pub fn parse_arm64_image(kernel: &[u8]) -> anyhow::Result<Arm64Image> {
+    if kernel.len() < arm64_constants::HEADER_LENGTH
+        || &kernel[arm64_constants::IMAGE_MAGIC_OFFSET..arm64_constants::IMAGE_MAGIC_OFFSET + 4]
+            != arm64_constants::IMAGE_MAGIC
+    {
+        anyhow::bail!("kernel is not an uncompressed arm64 Image");
+    }
+
+    let u64_at =
+        |start: usize| u64::from_le_bytes(kernel[start..start + 8].try_into().expect("8 bytes"));
+
+    let image_size = u64_at(16);
+    if image_size == 0 {
+        return Ok(Arm64Image {
+            text_offset: arm64_constants::DEFAULT_TEXT_OFFSET,
+            image_size: kernel.len() as u64,
+        });
+    }
+
+    Ok(Arm64Image {
+        text_offset: u64_at(8),
+        image_size,
+    })
+}
+
+/// The kernel does not set up any registers when jumping to a kexec_load() entry point, so this
+/// takes the place of kexec-tools' purgatory and follows the arm64 boot protocol: x0 holds the
+/// address of the devicetree, x1-x3 are zero, and execution continues at the kernel image.
+fn arm64_trampoline(dtb_addr: u64, kernel_addr: u64) -> Vec<u8> {
+    let mut code = Vec::with_capacity(40);
+    for instruction in [
+        0x5800_00c0u32, // ldr x0, dtb_addr
+        0xaa1f_03e1,    // mov x1, xzr
+        0xaa1f_03e2,    // mov x2, xzr
+        0xaa1f_03e3,    // mov x3, xzr
+        0x5800_0084,    // ldr x4, kernel_addr
+        0xd61f_0080,    // br x4
+    ] {
+        code.extend(instruction.to_le_bytes());
+    }
+    code.extend(dtb_addr.to_le_bytes());
+    code.extend(kernel_addr.to_le_bytes());
+    code
+}
+
+fn random_bytes(len: usize) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut buf = vec![0u8; len];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Points /chosen at the new command line and initrd, dropping properties that only make sense for
+/// the currently running kernel.
+fn fixup_chosen(fdt: &mut Fdt, cmdline: &str, initrd: Option<MemoryRange>) -> anyhow::Result<()> {
+    let chosen = fdt.root.node_mut_or_insert("/chosen");
+
+    chosen.set_prop_str("bootargs", cmdline);
+
+    for prop in [
+        "linux,initrd-start",
+        "linux,initrd-end",
+        "linux,elfcorehdr",
+        "linux,usable-memory-range",
+    ] {
+        chosen.remove_prop(prop);
+    }
+
+    if let Some(initrd) = initrd {
+        chosen.set_prop("linux,initrd-start", initrd.start.to_be_bytes());
+        chosen.set_prop("linux,initrd-end", initrd.end.to_be_bytes());
+    }
+
+    // The running kernel consumes (and zeroes) these, so they have to be refilled.
+    chosen.set_prop("kaslr-seed", random_bytes(8)?);
+    chosen.set_prop("rng-seed", random_bytes(64)?);
+
+    Ok(())
+}
+
+/// Where each piece is placed in physical memory, relative to a 2M aligned base.
+#[derive(Debug, PartialEq, Eq)]
+struct Arm64Layout {
+    kernel: u64,
+    dtb: u64,
+    trampoline: u64,
+    initrd: u64,
+    size: u64,
+}
+
+impl Arm64Layout {
+    fn new(image: &Arm64Image, kernel_len: u64, dtb_len: u64, initrd_len: u64) -> Self {
+        let kernel = image.text_offset;
+        let kernel_end = kernel + image.image_size.max(kernel_len).next_multiple_of(PAGE_SIZE);
+
+        // Keeping the devicetree 2M aligned ensures it never crosses a 2M boundary.
+        let dtb = kernel_end.next_multiple_of(SZ_2M);
+        let trampoline = dtb + dtb_len.next_multiple_of(PAGE_SIZE);
+        let initrd = trampoline + PAGE_SIZE;
+        let size = initrd + initrd_len.next_multiple_of(PAGE_SIZE);
+
+        Self {
+            kernel,
+            dtb,
+            trampoline,
+            initrd,
+            size,
+        }
+    }
+
+    /// Finds the lowest 2M aligned base address in RAM that fits the entire layout.
+    fn place(&self, ram: &[MemoryRange]) -> Option<u64> {
+        ram.iter().find_map(|range| {
+            let base = range.start.next_multiple_of(SZ_2M);
+            (base.checked_add(self.size)? <= range.end).then_some(base)
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct BzImage {
+    /// Length of the real-mode setup code in front of the protected-mode kernel.
+    pub setup_len: usize,
+    /// End of the setup header, which is copied into boot_params.
+    pub header_end: usize,
+    pub initrd_addr_max: u64,
+    pub kernel_alignment: u64,
+    pub relocatable: bool,
+    pub can_be_loaded_above_4g: bool,
+    pub cmdline_size: u64,
+    pub pref_address: u64,
+    /// How much memory the kernel needs from where it is loaded, while it decompresses itself.
+    pub init_size: u64,
+}
+
+pub fn parse_bzimage(kernel: &[u8]) -> anyhow::Result<BzImage> {
+    let u16_at = |start: usize| u16::from_le_bytes([kernel[start], kernel[start + 1]]);
+    let u32_at =
+        |start: usize| u32::from_le_bytes(kernel[start..start + 4].try_into().expect("4 bytes"));
+    let u64_at =
+        |start: usize| u64::from_le_bytes(kernel[start..start + 8].try_into().expect("8 bytes"));
+
+    if kernel.len() < x86_constants::INIT_SIZE_OFFSET + 4
+        || u16_at(x86_constants::BOOT_FLAG_OFFSET) != x86_constants::BOOT_FLAG
+        || &kernel[x86_constants::HEADER_MAGIC_OFFSET..x86_constants::HEADER_MAGIC_OFFSET + 4]
+            != x86_constants::HEADER_MAGIC
+    {
+        anyhow::bail!("kernel is not a bzImage");
+    }
+
+    let version = u16_at(x86_constants::VERSION_OFFSET);
+    if version < x86_constants::MIN_VERSION {
+        anyhow::bail!(
+            "boot protocol {}.{} is too old",
+            version >> 8,
+            version & 0xff
+        );
+    }
+
+    let xloadflags = u16_at(x86_constants::XLOADFLAGS_OFFSET);
+    if xloadflags & x86_constants::XLF_KERNEL_64 == 0 {
+        anyhow::bail!("kernel has no 64-bit entry point");
+    }
+
+    let setup_sects = match kernel[x86_constants::SETUP_SECTS_OFFSET] {
+        0 => 4,
+        setup_sects => setup_sects as usize,
+    };
+    let setup_len = (setup_sects + 1) * 512;
+    if setup_len >= kernel.len() {
+        anyhow::bail!("bzImage is truncated");
+    }
+
+    Ok(BzImage {
+        setup_len,
+        header_end: x86_constants::JUMP_OFFSET + 1 + kernel[x86_constants::JUMP_OFFSET] as usize,
+        initrd_addr_max: u32_at(x86_constants::INITRD_ADDR_MAX_OFFSET) as u64,
+        kernel_alignment: u32_at(x86_constants::KERNEL_ALIGNMENT_OFFSET) as u64,
+        relocatable: kernel[x86_constants::RELOCATABLE_KERNEL_OFFSET] != 0,
+        can_be_loaded_above_4g: xloadflags & x86_constants::XLF_CAN_BE_LOADED_ABOVE_4G != 0,
+        cmdline_size: u32_at(x86_constants::CMDLINE_SIZE_OFFSET) as u64,
+        pref_address: u64_at(x86_constants::PREF_ADDRESS_OFFSET),
+        init_size: u32_at(x86_constants::INIT_SIZE_OFFSET) as u64,
+    })
+}
+
+/// An entry of the e820 memory map in boot_params.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct E820Entry {
+    pub addr: u64,
+    pub size: u64,
+    pub kind: u32,
+}
+
+/// Maps the names in /sys/firmware/memmap back to e820 types.
+/// https://github.com/torvalds/linux/blob/master/arch/x86/kernel/e820.c
+fn e820_kind(name: &str) -> u32 {
+    match name {
+        "System RAM" => 1,
+        "ACPI Tables" => 3,
+        "ACPI Non-volatile Storage" => 4,
+        "Unusable memory" => 5,
+        "Persistent Memory (legacy)" => 7,
+        "Persistent Memory" => 7,
+        "Soft Reserved" => 0xefff_ffff,
+        _ => 2,
+    }
+}
+
+pub fn read_memmap(memmap: &Path) -> anyhow::Result<Vec<E820Entry>> {
+    let read_addr = |path: &Path| -> anyhow::Result<u64> {
+        let addr = std::fs::read_to_string(path)?;
+        Ok(u64::from_str_radix(
+            addr.trim().trim_start_matches("0x"),
+            16,
+        )?)
+    };
+
+    let mut entries = Vec::new();
+
+    for entry in std::fs::read_dir(memmap)? {
+        let entry = entry?.path();
+        let start = read_addr(&entry.join("start"))?;
+        let end = read_addr(&entry.join("end"))?;
+        let kind = std::fs::read_to_string(entry.join("type"))?;
+
+        entries.push(E820Entry {
+            addr: start,
+            size: end.saturating_sub(start) + 1,
+            kind: e820_kind(kind.trim()),
+        });
+    }
+
+    entries.sort_by_key(|entry| entry.addr);
+
+    if entries.len() > x86_constants::E820_MAX_ENTRIES {
+        warn!(
+            "memory map has {} entries, only passing on the first {}",
+            entries.len(),
+            x86_constants::E820_MAX_ENTRIES
+        );
+        entries.truncate(x86_constants::E820_MAX_ENTRIES);
+    }
+
+    Ok(entries)
+}
+
+/// Fills in the "zero page" the kernel is entered with: the setup header from the image, along
+/// with where the command line and initrd are and the memory map.
+fn boot_params(
+    kernel: &[u8],
+    image: &BzImage,
+    e820: &[E820Entry],
+    kernel_addr: u64,
+    cmdline_addr: u64,
+    initrd: Option<MemoryRange>,
+) -> Vec<u8> {
+    let mut params = vec![0u8; x86_constants::BOOT_PARAMS_LENGTH];
+
+    params[x86_constants::SETUP_SECTS_OFFSET..image.header_end]
+        .copy_from_slice(&kernel[x86_constants::SETUP_SECTS_OFFSET..image.header_end]);
+
+    let mut set_u32 = |offset: usize, value: u32| {
+        params[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    };
+
+    set_u32(x86_constants::CODE32_START_OFFSET, kernel_addr as u32);
+    set_u32(x86_constants::CMD_LINE_PTR_OFFSET, cmdline_addr as u32);
+    set_u32(
+        x86_constants::EXT_CMD_LINE_PTR_OFFSET,
+        (cmdline_addr >> 32) as u32,
+    );
+
+    if let Some(initrd) = initrd {
+        let size = initrd.end - initrd.start;
+        set_u32(x86_constants::RAMDISK_IMAGE_OFFSET, initrd.start as u32);
+        set_u32(
+            x86_constants::EXT_RAMDISK_IMAGE_OFFSET,
+            (initrd.start >> 32) as u32,
+        );
+        set_u32(x86_constants::RAMDISK_SIZE_OFFSET, size as u32);
+        set_u32(x86_constants::EXT_RAMDISK_SIZE_OFFSET, (size >> 32) as u32);
+    }
+
+    params[x86_constants::TYPE_OF_LOADER_OFFSET] = x86_constants::TYPE_OF_LOADER_UNDEFINED;
+    params[x86_constants::LOADFLAGS_OFFSET] |= x86_constants::LOADED_HIGH;
+
+    params[x86_constants::E820_ENTRIES_OFFSET] = e820.len() as u8;
+    for (idx, entry) in e820.iter().enumerate() {
+        let offset = x86_constants::E820_TABLE_OFFSET + idx * x86_constants::E820_ENTRY_LENGTH;
+        params[offset..offset + 8].copy_from_slice(&entry.addr.to_le_bytes());
+        params[offset + 8..offset + 16].copy_from_slice(&entry.size.to_le_bytes());
+        params[offset + 16..offset + 20].copy_from_slice(&entry.kind.to_le_bytes());
+    }
+
+    params
+}
+
+/// Takes the place of purgatory on x86_64, since the kernel enters a kexec_load() image with
+/// every register cleared. It loads the GDT the 64-bit boot protocol asks for along with a stack
+/// at the end of its own page, and enters the kernel with %rsi pointing at boot_params.
+fn x86_64_trampoline(trampoline_addr: u64, boot_params_addr: u64, kernel_entry: u64) -> Vec<u8> {
+    const GDT_OFFSET: u64 = 64;
+
+    let mut code = Vec::with_capacity(136);
+    code.extend([0x0f, 0x01, 0x15, 89, 0, 0, 0]); // lgdt gdt_desc(%rip)
+    code.extend([0xb8, x86_constants::BOOT_DS, 0, 0, 0]); // mov $BOOT_DS, %eax
+    code.extend([0x8e, 0xd8]); // mov %eax, %ds
+    code.extend([0x8e, 0xc0]); // mov %eax, %es
+    code.extend([0x8e, 0xd0]); // mov %eax, %ss
+    code.extend([0x8e, 0xe0]); // mov %eax, %fs
+    code.extend([0x8e, 0xe8]); // mov %eax, %gs
+    code.extend([0x48, 0x8b, 0x25, 83, 0, 0, 0]); // mov stack_top(%rip), %rsp
+    code.extend([0x48, 0x8d, 0x05, 5, 0, 0, 0]); // lea 1f(%rip), %rax
+    code.extend([0x6a, x86_constants::BOOT_CS]); // push $BOOT_CS
+    code.push(0x50); // push %rax
+    code.extend([0x48, 0xcb]); // lretq
+    code.extend([0x48, 0x8b, 0x35, 72, 0, 0, 0]); // 1: mov boot_params(%rip), %rsi
+    code.extend([0x48, 0x8b, 0x05, 73, 0, 0, 0]); // mov kernel_entry(%rip), %rax
+    code.extend([0xff, 0xe0]); // jmp *%rax
+    code.resize(GDT_OFFSET as usize, 0xcc);
+
+    // null, unused, __BOOT_CS and __BOOT_DS
+    for descriptor in [0u64, 0, 0x00af_9a00_0000_ffff, 0x00cf_9200_0000_ffff] {
+        code.extend(descriptor.to_le_bytes());
+    }
+
+    // gdt_desc
+    code.extend(31u16.to_le_bytes());
+    code.extend((trampoline_addr + GDT_OFFSET).to_le_bytes());
+    code.resize(112, 0);
+
+    code.extend((trampoline_addr + PAGE_SIZE).to_le_bytes()); // stack_top
+    code.extend(boot_params_addr.to_le_bytes()); // boot_params
+    code.extend(kernel_entry.to_le_bytes()); // kernel_entry
+    code
+}
+
+/// Where each piece is placed in physical memory, relative to where the kernel is loaded.
+#[derive(Debug, PartialEq, Eq)]
+struct X86Layout {
+    boot_params: u64,
+    cmdline: u64,
+    trampoline: u64,
+    initrd: u64,
+    size: u64,
+}
+
+impl X86Layout {
+    fn new(image: &BzImage, kernel_len: u64, cmdline_len: u64, initrd_len: u64) -> Self {
+        let boot_params = image.init_size.max(kernel_len).next_multiple_of(PAGE_SIZE);
+        let cmdline = boot_params + PAGE_SIZE;
+        let trampoline = cmdline + cmdline_len.next_multiple_of(PAGE_SIZE);
+        let initrd = trampoline + PAGE_SIZE;
+        let size = initrd + initrd_len.next_multiple_of(PAGE_SIZE);
+
+        Self {
+            boot_params,
+            cmdline,
+            trampoline,
+            initrd,
+            size,
+        }
+    }
+
+    /// Finds the lowest address in RAM that the kernel can be loaded at and that fits the entire
+    /// layout. Kernels that are not relocatable can only be loaded at their preferred address.
+    fn place(&self, image: &BzImage, ram: &[MemoryRange]) -> Option<u64> {
+        let alignment = image.kernel_alignment.max(PAGE_SIZE);
+        let max_end = if image.can_be_loaded_above_4g {
+            u64::MAX
+        } else {
+            image.initrd_addr_max.saturating_add(1)
+        };
+
+        ram.iter().find_map(|range| {
+            let base = if image.relocatable {
+                range
+                    .start
+                    .max(x86_constants::MIN_LOAD_ADDRESS)
+                    .next_multiple_of(alignment)
+            } else {
+                image.pref_address
+            };
+            let end = base.checked_add(self.size)?;
+
+            (base >= range.start && end <= range.end && end <= max_end).then_some(base)
+        })
+    }
+}
+
+/// Loads an x86_64 bzImage along with an initrd using kexec_load(), entering the kernel through
+/// its 64-bit entry point.
+pub fn load_bzimage(kernel: &[u8], initrd: &[u8], cmdline: &str) -> anyhow::Result<()> {
+    let image = parse_bzimage(kernel)?;
+
+    let cmdline = ffi::CString::new(cmdline)?;
+    if cmdline.as_bytes().len() as u64 > image.cmdline_size {
+        anyhow::bail!(
+            "command line is longer than the {} bytes the kernel accepts",
+            image.cmdline_size
+        );
+    }
+    let cmdline = cmdline.as_bytes_with_nul();
+
+    let ram = parse_iomem(&std::fs::read_to_string("/proc/iomem")?);
+    let e820 = read_memmap(Path::new(FIRMWARE_MEMMAP_PATH))?;
+
+    let protected_mode_kernel = &kernel[image.setup_len..];
+
+    let layout = X86Layout::new(
+        &image,
+        protected_mode_kernel.len() as u64,
+        cmdline.len() as u64,
+        initrd.len() as u64,
+    );
+    let base = layout
+        .place(&image, &ram)
+        .ok_or_else(|| anyhow::anyhow!("no memory range large enough for kexec segments"))?;
+
+    let has_initrd = !initrd.is_empty();
+    let initrd_range = has_initrd.then_some(MemoryRange {
+        start: base + layout.initrd,
+        end: base + layout.initrd + initrd.len() as u64,
+    });
+
+    let params = boot_params(
+        kernel,
+        &image,
+        &e820,
+        base,
+        base + layout.cmdline,
+        initrd_range,
+    );
+
+    debug!("loading kernel at {:#x}", base);
+
+    let trampoline = x86_64_trampoline(
+        base + layout.trampoline,
+        base + layout.boot_params,
+        base + x86_constants::ENTRY_64_OFFSET,
+    );
+
+    let mut segments = vec![
+        (protected_mode_kernel, base),
+        (params.as_slice(), base + layout.boot_params),
+        (cmdline, base + layout.cmdline),
+        (trampoline.as_slice(), base + layout.trampoline),
+    ];
+    if has_initrd {
+        segments.push((initrd, base + layout.initrd));
+    }
+
+    kexec_load_segments(base + layout.trampoline, &segments)
+}
+
+fn kexec_load_segments(entry: u64, segments: &[(&[u8], u64)]) -> anyhow::Result<()> {
+    if segments.len() > KEXEC_SEGMENT_MAX {
+        anyhow::bail!("too many kexec segments");
+    }
+
+    let segments: Vec<KexecSegment> = segments
+        .iter()
+        .map(|(buf, mem)| KexecSegment {
+            buf: buf.as_ptr() as *const libc::c_void,
+            bufsz: buf.len(),
+            mem: *mem as libc::c_ulong,
+            memsz: (buf.len() as u64).next_multiple_of(PAGE_SIZE) as libc::size_t,
+        })
+        .collect();
+
+    for segment in &segments {
+        trace!(
+            "kexec segment {:#x}-{:#x}",
+            segment.mem,
+            segment.mem + segment.memsz as libc::c_ulong
+        );
+    }
+
+    let retval = unsafe {
+        syscall!(
+            Sysno::kexec_load,
+            entry as libc::c_ulong,
+            segments.len(),
+            segments.as_ptr(),
+            KEXEC_ARCH_DEFAULT
+        )
+    }
+    .map_err(|e| match e {
+        Errno::EACCES => anyhow::anyhow!("kexec_load() is blocked by the IMA policy ({e})"),
+        Errno::EPERM => {
+            anyhow::anyhow!("kexec_load() is blocked by kernel lockdown ({e})")
+        }
+        e => anyhow::anyhow!("kexec_load() failed ({e})"),
+    })?;
+
+    if retval > -4096isize as usize {
+        let code = -(retval as isize) as i32;
+        return Err(std::io::Error::from_raw_os_error(code).into());
+    }
+
+    Ok(())
+}
+
+/// Loads an arm64 Image along with a devicetree and initrd using kexec_load(), which unlike
+/// kexec_file_load() lets us choose the devicetree that is handed to the next kernel.
+pub fn load_arm64(kernel: &[u8], mut fdt: Fdt, initrd: &[u8], cmdline: &str) -> anyhow::Result<()> {
+    let image = parse_arm64_image(kernel)?;
+
+    let ram = parse_iomem(&std::fs::read_to_string("/proc/iomem")?);
+
+    // The devicetree size depends on where the initrd ends up, so first fill in /chosen with
+    // placeholder values to get an upper bound on its size.
+    let has_initrd = !initrd.is_empty();
+    let placeholder = has_initrd.then_some(MemoryRange { start: 0, end: 0 });
+    fixup_chosen(&mut fdt, cmdline, placeholder)?;
+    let dtb_len = fdt.to_bytes().len() as u64;
+
+    if dtb_len > arm64_constants::MAX_DTB_SIZE {
+        anyhow::bail!("devicetree is larger than 2M");
+    }
+
+    let layout = Arm64Layout::new(&image, kernel.len() as u64, dtb_len, initrd.len() as u64);
+    let base = layout
+        .place(&ram)
+        .ok_or_else(|| anyhow::anyhow!("no memory range large enough for kexec segments"))?;
+
+    let initrd_range = has_initrd.then_some(MemoryRange {
+        start: base + layout.initrd,
+        end: base + layout.initrd + initrd.len() as u64,
+    });
+    fixup_chosen(&mut fdt, cmdline, initrd_range)?;
+    let dtb = fdt.to_bytes();
+
+    debug!("loading kernel at {:#x}", base + layout.kernel);
+
+    let trampoline = arm64_trampoline(base + layout.dtb, base + layout.kernel);
+
+    let mut segments = vec![
+        (kernel, base + layout.kernel),
+        (dtb.as_slice(), base + layout.dtb),
+        (trampoline.as_slice(), base + layout.trampoline),
+    ];
+    if has_initrd {
+        segments.push((initrd, base + layout.initrd));
+    }
+
+    kexec_load_segments(base + layout.trampoline, &segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        x86_constants, Arm64Image, Arm64Layout, BzImage, E820Entry, MemoryRange, X86Layout,
+        PAGE_SIZE, SZ_2M,
+    };
+
+    fn bzimage() -> Vec<u8> {
+        let mut kernel = vec![0u8; 8192];
+        kernel[0x1f1] = 3; // setup_sects
+        kernel[0x1fe..0x200].copy_from_slice(&0xaa55u16.to_le_bytes());
+        kernel[0x200] = 0xeb;
+        kernel[0x201] = 0x6a; // header ends at 0x26c
+        kernel[0x202..0x206].copy_from_slice(b"HdrS");
+        kernel[0x206..0x208].copy_from_slice(&0x020fu16.to_le_bytes());
+        kernel[0x22c..0x230].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+        kernel[0x230..0x234].copy_from_slice(&0x20_0000u32.to_le_bytes());
+        kernel[0x234] = 1;
+        kernel[0x236..0x238].copy_from_slice(&0x7fu16.to_le_bytes());
+        kernel[0x238..0x23c].copy_from_slice(&0x7ffu32.to_le_bytes());
+        kernel[0x258..0x260].copy_from_slice(&0x100_0000u64.to_le_bytes());
+        kernel[0x260..0x264].copy_from_slice(&0x300_0000u32.to_le_bytes());
+        kernel
+    }
+
+    #[test]
+    fn parse_iomem() {
+        let iomem = r#"09000000-09000fff : pl011@9000000
+  09000000-09000fff : pl011@9000000
+40000000-bfffffff : System RAM
+  40210000-417bffff : Kernel code
+  417c0000-41a4ffff : reserved
+  41a50000-41ddffff : Kernel data
+  48000000-48dfffff : reserved
+  b0000000-bfffffff : Crash kernel
+4010000000-401fffffff : PCI ECAM
+"#;
+
+        assert_eq!(
+            super::parse_iomem(iomem),
+            vec![
+                MemoryRange {
+                    start: 0x4000_0000,
+                    end: 0x417c_0000
+                },
+                MemoryRange {
+                    start: 0x41a5_0000,
+                    end: 0x4800_0000
+                },
+                MemoryRange {
+                    start: 0x48e0_0000,
+                    end: 0xb000_0000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_arm64_image() {
+        let mut kernel = vec![0u8; 4096];
+        kernel[8..16].copy_from_slice(&0u64.to_le_bytes());
+        kernel[16..24].copy_from_slice(&0x200_0000u64.to_le_bytes());
+        kernel[56..60].copy_from_slice(b"ARM\x64");
+
+        assert_eq!(
+            super::parse_arm64_image(&kernel).unwrap(),
+            Arm64Image {
+                text_offset: 0,
+                image_size: 0x200_0000
+            }
+        );
+
+        assert!(super::parse_arm64_image(&kernel[..32]).is_err());
+        assert!(super::parse_arm64_image(&[0u8; 4096]).is_err());
+    }
+
+    #[test]
+    fn arm64_layout() {
+        let image = Arm64Image {
+            text_offset: 0,
+            image_size: 0x210_0000,
+        };
+
+        let layout = Arm64Layout::new(&image, 0x180_0000, 0x1_2345, 0x10_0001);
+        assert_eq!(
+            layout,
+            Arm64Layout {
+                kernel: 0,
+                dtb: 0x220_0000,
+                trampoline: 0x221_3000,
+                initrd: 0x221_4000,
+                size: 0x231_5000,
+            }
+        );
+
+        assert_eq!(
+            layout.place(&[
+                MemoryRange {
+                    start: 0x4000_1000,
+                    end: 0x4240_0000
+                },
+                MemoryRange {
+                    start: 0x5000_0000,
+                    end: 0x6000_0000
+                },
+            ]),
+            Some(0x5000_0000)
+        );
+
+        assert_eq!(
+            layout.place(&[MemoryRange {
+                start: 0,
+                end: SZ_2M
+            }]),
+            None
+        );
+    }
+
+    #[test]
+    fn arm64_trampoline() {
+        let code = super::arm64_trampoline(0x4800_0000, 0x4020_0000);
+        assert_eq!(code.len(), 40);
+        assert_eq!(&code[24..32], &0x4800_0000u64.to_le_bytes());
+        assert_eq!(&code[32..40], &0x4020_0000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn parse_bzimage() {
+        let kernel = bzimage();
+
+        assert_eq!(
+            super::parse_bzimage(&kernel).unwrap(),
+            BzImage {
+                setup_len: 2048,
+                header_end: 0x26c,
+                initrd_addr_max: 0x7fff_ffff,
+                kernel_alignment: 0x20_0000,
+                relocatable: true,
+                can_be_loaded_above_4g: true,
+                cmdline_size: 0x7ff,
+                pref_address: 0x100_0000,
+                init_size: 0x300_0000,
+            }
+        );
+
+        let mut kernel_32 = kernel.clone();
+        kernel_32[0x236] = 0;
+        assert!(super::parse_bzimage(&kernel_32).is_err());
+
+        let mut old = kernel.clone();
+        old[0x206..0x208].copy_from_slice(&0x020bu16.to_le_bytes());
+        assert!(super::parse_bzimage(&old).is_err());
+
+        assert!(super::parse_bzimage(&kernel[..0x200]).is_err());
+        assert!(super::parse_bzimage(&[0u8; 8192]).is_err());
+    }
+
+    #[test]
+    fn boot_params() {
+        let kernel = bzimage();
+        let image = super::parse_bzimage(&kernel).unwrap();
+        let e820 = [
+            E820Entry {
+                addr: 0,
+                size: 0x9_f000,
+                kind: 1,
+            },
+            E820Entry {
+                addr: 0x10_0000,
+                size: 0x1_0000_0000,
+                kind: 1,
+            },
+        ];
+
+        let params = super::boot_params(
+            &kernel,
+            &image,
+            &e820,
+            0x100_0000,
+            0x1_2000_0000,
+            Some(MemoryRange {
+                start: 0x400_0000,
+                end: 0x500_0000,
+            }),
+        );
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(params[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!(params.len(), x86_constants::BOOT_PARAMS_LENGTH);
+        assert_eq!(&params[0x202..0x206], b"HdrS");
+        assert_eq!(params[0x26c], 0);
+        assert_eq!(params[x86_constants::TYPE_OF_LOADER_OFFSET], 0xff);
+        assert_eq!(params[x86_constants::LOADFLAGS_OFFSET], 1);
+        assert_eq!(u32_at(x86_constants::CODE32_START_OFFSET), 0x100_0000);
+        assert_eq!(u32_at(x86_constants::CMD_LINE_PTR_OFFSET), 0x2000_0000);
+        assert_eq!(u32_at(x86_constants::EXT_CMD_LINE_PTR_OFFSET), 1);
+        assert_eq!(u32_at(x86_constants::RAMDISK_IMAGE_OFFSET), 0x400_0000);
+        assert_eq!(u32_at(x86_constants::RAMDISK_SIZE_OFFSET), 0x100_0000);
+        assert_eq!(params[x86_constants::E820_ENTRIES_OFFSET], 2);
+        assert_eq!(
+            &params[0x2d0 + 20..0x2d0 + 40],
+            [
+                0x10_0000u64.to_le_bytes().as_slice(),
+                0x1_0000_0000u64.to_le_bytes().as_slice(),
+                1u32.to_le_bytes().as_slice()
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn read_memmap() {
+        let memmap = std::env::temp_dir().join(format!("tboot-memmap-{}", std::process::id()));
+
+        for (idx, start, end, kind) in [
+            (0, "0x100000", "0x7fffffff", "System RAM"),
+            (1, "0x0", "0x9efff", "System RAM"),
+            (2, "0x7ff00000", "0x7fffffff", "ACPI Tables"),
+            (3, "0xfed00000", "0xfed00fff", "Reserved"),
+        ] {
+            let entry = memmap.join(idx.to_string());
+            std::fs::create_dir_all(&entry).unwrap();
+            std::fs::write(entry.join("start"), format!("{start}\n")).unwrap();
+            std::fs::write(entry.join("end"), format!("{end}\n")).unwrap();
+            std::fs::write(entry.join("type"), format!("{kind}\n")).unwrap();
+        }
+
+        assert_eq!(
+            super::read_memmap(&memmap).unwrap(),
+            vec![
+                E820Entry {
+                    addr: 0,
+                    size: 0x9_f000,
+                    kind: 1
+                },
+                E820Entry {
+                    addr: 0x10_0000,
+                    size: 0x7ff0_0000,
+                    kind: 1
+                },
+                E820Entry {
+                    addr: 0x7ff0_0000,
+                    size: 0x10_0000,
+                    kind: 3
+                },
+                E820Entry {
+                    addr: 0xfed0_0000,
+                    size: 0x1000,
+                    kind: 2
+                },
+            ]
+        );
+
+        std::fs::remove_dir_all(memmap).unwrap();
+    }
+
+    #[test]
+    fn x86_layout() {
+        let image = super::parse_bzimage(&bzimage()).unwrap();
+
+        let layout = X86Layout::new(&image, 0x80_0000, 0x100, 0x10_0001);
+        assert_eq!(
+            layout,
+            X86Layout {
+                boot_params: 0x300_0000,
+                cmdline: 0x300_1000,
+                trampoline: 0x300_2000,
+                initrd: 0x300_3000,
+                size: 0x310_4000,
+            }
+        );
+
+        // below 1M is skipped, and the kernel is aligned to 2M
+        assert_eq!(
+            layout.place(
+                &image,
+                &[
+                    MemoryRange {
+                        start: 0x1000,
+                        end: 0x9_f000
+                    },
+                    MemoryRange {
+                        start: 0x10_0000,
+                        end: 0x8000_0000
+                    },
+                ]
+            ),
+            Some(0x20_0000)
+        );
+
+        let mut fixed = image;
+        fixed.relocatable = false;
+        assert_eq!(
+            layout.place(
+                &fixed,
+                &[MemoryRange {
+                    start: 0x10_0000,
+                    end: 0x8000_0000
+                }]
+            ),
+            Some(0x100_0000)
+        );
+
+        fixed.can_be_loaded_above_4g = false;
+        fixed.initrd_addr_max = 0x200_0000;
+        assert_eq!(
+            layout.place(
+                &fixed,
+                &[MemoryRange {
+                    start: 0x10_0000,
+                    end: 0x8000_0000
+                }]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn x86_64_trampoline() {
+        let code = super::x86_64_trampoline(0x300_2000, 0x300_0000, 0x20_0200);
+        assert_eq!(code.len(), 136);
+        // gdt_desc points at the GDT
+        assert_eq!(&code[96..98], &31u16.to_le_bytes());
+        assert_eq!(&code[98..106], &0x300_2040u64.to_le_bytes());
+        assert_eq!(&code[112..120], &(0x300_2000 + PAGE_SIZE).to_le_bytes());
+        assert_eq!(&code[120..128], &0x300_0000u64.to_le_bytes());
+        assert_eq!(&code[128..136], &0x20_0200u64.to_le_bytes());
+    }
+}
@@ -1,4 +1,7 @@
-use std::ffi::{c_char, c_void, CString};
+use std::{
+    ffi::{c_char, c_void, CString},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use base64::{engine::general_purpose, Engine as _};
 use log::{debug, warn};
@@ -9,6 +12,10 @@ use syscalls::{syscall, Sysno};
 // CONFIG_INTEGRITY_TRUSTED_KEYRING=y in our kernel config.
 const IMA_KEYRING_NAME: &str = "_ima";
 
+/// Set once the verification key and the IMA policy are in place. From then on, everything that is
+/// booted has to be verified.
+static VERIFICATION_ON: AtomicBool = AtomicBool::new(false);
+
 enum KeySerial {
     UserKeyring,
 }
@@ -63,6 +70,40 @@ fn add_key(keyring_id: i32, key_content: &[u8]) -> anyhow::Result<i32> {
     Ok(key_id.try_into()?)
 }
 
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/keyctl.h
+const KEYCTL_SEARCH: usize = 10;
+
+pub fn verification_on() -> bool {
+    VERIFICATION_ON.load(Ordering::SeqCst)
+}
+
+/// Returns the ID of the keyring that holds the verification key, or None if boot verification is
+/// off. Fails if verification is on but the keyring cannot be found, so that a broken keyring
+/// never lets anything through unverified.
+pub fn ima_keyring() -> anyhow::Result<Option<i32>> {
+    if !verification_on() {
+        return Ok(None);
+    }
+
+    let key_type = CString::new("keyring")?;
+    let key_desc = CString::new(IMA_KEYRING_NAME)?;
+    let key_serial: i32 = KeySerial::UserKeyring.into();
+
+    let keyring_id = unsafe {
+        syscall!(
+            Sysno::keyctl,
+            KEYCTL_SEARCH,
+            key_serial,
+            key_type.as_ptr(),
+            key_desc.as_ptr(),
+            0
+        )
+    }
+    .map_err(|e| anyhow::anyhow!("boot verification is on, but the IMA keyring is gone: {e}"))?;
+
+    Ok(Some(keyring_id.try_into()?))
+}
+
 // https://github.com/torvalds/linux/blob/3b517966c5616ac011081153482a5ba0e91b17ff/security/integrity/digsig.c#L193
 pub fn load_verification_key() -> anyhow::Result<()> {
     let Some(pubkey) = ('pubkey: {
@@ -108,5 +149,7 @@ pub fn load_verification_key() -> anyhow::Result<()> {
     // only install the IMA policy after we have loaded the key
     std::fs::copy("/etc/ima/policy.conf", "/sys/kernel/security/ima/policy")?;
 
+    VERIFICATION_ON.store(true, Ordering::SeqCst);
+
     Ok(())
 }
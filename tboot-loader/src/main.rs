@@ -1,29 +1,41 @@
 pub(crate) mod boot_loader;
+pub(crate) mod boot_order;
 pub(crate) mod cmd;
+pub(crate) mod fdt;
 pub(crate) mod fs;
 pub(crate) mod kexec;
 pub(crate) mod keys;
+pub(crate) mod net;
+pub(crate) mod pe;
 pub(crate) mod shell;
+pub(crate) mod verify;
 
 const VERSION: Option<&'static str> = option_env!("version");
 const TICK_DURATION: Duration = Duration::from_secs(1);
+/// How long to wait for more disk events before passing them on to the loaders, so that a new disk
+/// is only probed once its partitions are there.
+const DISK_SETTLE_DURATION: Duration = Duration::from_millis(500);
 
 use crate::{
-    cmd::Command,
-    kexec::{kexec_execute, kexec_load},
+    boot_order::BootOrderStorage,
+    cmd::{BootOrderChange, Command, LoaderFilter},
+    kexec::{kernel_version, kexec_execute, kexec_load},
 };
-use boot_loader::{disk::BlsBootLoader, Loader, LoaderType};
+use boot_loader::{BootDevice, BootEntry, EntryAction, Loader, LoaderType, Timeout};
 use cmd::print_help;
 use log::{debug, error, info, warn, LevelFilter};
 use nix::libc::{self};
 use shell::{run_shell, wait_for_user_presence};
+use std::{collections::HashMap, os::fd::AsRawFd, path::PathBuf, sync::mpsc};
 use std::{io::Write, time::Duration};
-use std::{os::fd::AsRawFd, path::PathBuf, sync::mpsc};
+use tboot::{config::Config, dev::DiskEvent};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ClientToServer {
     Command(Command),
     UserIsPresent,
+    /// Disks were plugged in or removed.
+    DisksChanged(Vec<DiskEvent>),
 }
 
 #[derive(Clone, Debug)]
@@ -38,38 +50,111 @@ enum Outcome {
     Kexec,
 }
 
-fn prepare_boot() -> anyhow::Result<Outcome> {
+fn prepare_boot(
+    cfg: &Config,
+    boot_order_storage: &BootOrderStorage,
+    disk_events: mpsc::Receiver<DiskEvent>,
+) -> anyhow::Result<Outcome> {
     let (client_tx, server_rx) = mpsc::channel::<ClientToServer>();
     let (server_tx, client_rx) = mpsc::channel::<ServerToClient>();
 
     let user_presence_tx = client_tx.clone();
     let user_presence_thread = std::thread::spawn(move || wait_for_user_presence(user_presence_tx));
 
+    // Disks that show up late, like slow USB sticks, or that are plugged in while waiting are
+    // passed on to the loaders as they come.
+    let disk_events_tx = client_tx.clone();
+    std::thread::spawn(move || {
+        while let Ok(event) = disk_events.recv() {
+            let events = tboot::dev::settle_disk_events(event, &disk_events, DISK_SETTLE_DURATION);
+            if disk_events_tx
+                .send(ClientToServer::DisksChanged(events))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
     let mut user_is_present = false;
+    let mut menu_forced = false;
     let mut outcome: Option<Outcome> = None;
     let mut stdout = std::io::stdout();
 
-    // TODO(jared): fetch boot order from some nonvolatile storage
-    let boot_loaders: Vec<Loader> = vec![Loader::new(Box::new(BlsBootLoader::new()))];
-
-    'autoboot: for loader in boot_loaders {
-        let mut loader = Loader::from(loader);
-
-        match loader.boot_devices() {
-            Err(e) => {
-                error!("failed to probe loader: {e}");
-                continue;
-            }
-            Ok(boot_devices) => {
-                for boot_dev in boot_devices {
-                    info!("using boot device {}", boot_dev.name);
-
+    let (boot_order, boot_order_source) = boot_order_storage.load();
+    info!("boot order: {boot_order} ({boot_order_source})");
+
+    let mut boot_loaders: Vec<Loader> = boot_order
+        .loader_types()
+        .into_iter()
+        .map(|loader_type| Loader::from_type(loader_type, cfg))
+        .collect();
+
+    // kept across disk changes, so that plugging in a disk does not restart the countdown
+    let mut time_left: Option<Duration> = None;
+
+    'autoboot: loop {
+        let disk_events = 'probe: {
+            // Probe every loader before counting down, so that there is only one countdown no
+            // matter how many devices there are.
+            let probed: Vec<(LoaderType, &[BootDevice])> = boot_loaders
+                .iter_mut()
+                .filter_map(|loader| {
+                    let loader_type = loader.loader_type();
+                    match loader.boot_devices() {
+                        Ok(boot_devices) => Some((loader_type, boot_devices)),
+                        Err(e) => {
+                            error!("failed to probe {loader_type} loader: {e}");
+                            None
+                        }
+                    }
+                })
+                .collect();
+
+            let boot_devices = boot_order.order(&probed);
+
+            let candidates: Vec<(&BootDevice, Vec<&dyn BootEntry>)> = boot_devices
+                .into_iter()
+                .filter_map(|boot_dev| {
+                    let entries = autoboot_entries(boot_dev);
+                    if entries.is_empty() {
+                        info!("boot device {} contains no good entries", boot_dev.name);
+                        None
+                    } else {
+                        Some((boot_dev, entries))
+                    }
+                })
+                .collect();
+
+            // The first device with something to boot decides how long to wait.
+            let Some((boot_dev, _)) = candidates.first() else {
+                error!("no boot device contains any good entries");
+                break 'autoboot;
+            };
+
+            info!("using boot device {}", boot_dev.name);
+
+            match boot_dev.timeout {
+                Timeout::MenuForce => {
+                    info!("boot device {} forces the menu", boot_dev.name);
+                    menu_forced = true;
+                    break 'autoboot;
+                }
+                Timeout::MenuHidden => match server_rx.try_recv() {
+                    Ok(ClientToServer::UserIsPresent) => {
+                        user_is_present = true;
+                        break 'autoboot;
+                    }
+                    Ok(ClientToServer::DisksChanged(events)) => break 'probe events,
+                    _ => {}
+                },
+                Timeout::Countdown(timeout) => {
                     println!("press <ENTER> to stop boot");
 
                     print!("booting in ");
                     stdout.flush().expect("flush failed");
 
-                    let mut time_left = boot_dev.timeout;
+                    let time_left = time_left.get_or_insert(timeout);
                     while !time_left.is_zero() {
                         print!("{}.", time_left.as_secs());
                         stdout.flush().expect("flush failed");
@@ -79,57 +164,68 @@ fn prepare_boot() -> anyhow::Result<Outcome> {
                                 user_is_present = true;
                                 break 'autoboot;
                             }
+                            Ok(ClientToServer::DisksChanged(events)) => {
+                                println!();
+                                break 'probe events;
+                            }
                             _ => {}
                         }
 
-                        time_left -= TICK_DURATION;
+                        *time_left -= TICK_DURATION;
                     }
                     println!();
+                }
+            }
 
-                    if boot_dev.entries.is_empty() {
-                        info!("boot device {} contains no entries", boot_dev.name);
-                        continue;
-                    } else {
-                        let mut entries = boot_dev.entries.iter();
-                        let first_entry = entries.next().expect("boot device has no entries");
-
-                        let default_entry = 'default: {
-                            while let Some(entry) = entries.next() {
-                                if entry.is_default() {
-                                    break 'default entry;
-                                }
-                            }
-                            first_entry
-                        };
+            for (boot_dev, entries) in candidates {
+                for entry in entries {
+                    if let Ok(ClientToServer::UserIsPresent) = server_rx.try_recv() {
+                        user_is_present = true;
+                        break 'autoboot;
+                    }
 
-                        let boot_parts = default_entry.select();
+                    info!("booting entry '{}' from {}", entry, boot_dev.name);
 
-                        match kexec_load(boot_parts) {
-                            Ok(()) => {
-                                outcome = Some(Outcome::Kexec);
-                                break 'autoboot;
-                            }
-                            Err(e) => {
-                                error!("failed to kexec load: {e}");
-                                outcome = None;
-                                break 'autoboot;
-                            }
+                    match entry.select().and_then(|parts| kexec_load(parts, cfg)) {
+                        Ok(()) => {
+                            outcome = Some(Outcome::Kexec);
+                            break 'autoboot;
                         }
+                        Err(e) => error!("failed to kexec load '{}': {e}", entry),
                     }
                 }
+
+                error!("no entry on boot device {} could be loaded", boot_dev.name);
             }
-        }
+
+            break 'autoboot;
+        };
+
+        disks_changed(&mut boot_loaders, &disk_events);
     }
 
     if let Some(outcome) = outcome {
+        // unmount everything before kexec'ing
+        boot_loaders.clear();
+
         Ok(outcome)
     } else {
         if !user_is_present {
-            error!("failed to boot");
+            if !menu_forced {
+                error!("failed to boot");
+            }
             print!("press <ENTER> to enter interactive mode");
             stdout.flush().expect("flush failed");
 
-            assert_eq!(server_rx.recv().unwrap(), ClientToServer::UserIsPresent);
+            loop {
+                match server_rx.recv().unwrap() {
+                    ClientToServer::UserIsPresent => break,
+                    ClientToServer::DisksChanged(events) => {
+                        disks_changed(&mut boot_loaders, &events);
+                    }
+                    ClientToServer::Command(_) => unreachable!("the shell is not running yet"),
+                }
+            }
         }
 
         user_presence_thread
@@ -137,7 +233,8 @@ fn prepare_boot() -> anyhow::Result<Outcome> {
             .expect("failed to join user presence thread");
 
         let shell_thread = std::thread::spawn(move || run_shell(client_tx, client_rx));
-        let outcome = handle_commands(server_tx, server_rx);
+        // the interactive session starts out with the devices that were already probed
+        let outcome = handle_commands(server_tx, server_rx, cfg, boot_order_storage, boot_loaders);
 
         shell_thread.join().expect("failed to join shell thread");
 
@@ -145,11 +242,104 @@ fn prepare_boot() -> anyhow::Result<Outcome> {
     }
 }
 
+impl From<EntryAction> for Outcome {
+    fn from(action: EntryAction) -> Self {
+        match action {
+            EntryAction::Reboot => Outcome::Reboot,
+            EntryAction::Poweroff => Outcome::Poweroff,
+        }
+    }
+}
+
+/// Replaces the kernel command line of an entry, keeping the parameters tinyboot adds for itself.
+fn edit_cmdline(original: Option<&str>, cmdline: &str) -> String {
+    original
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|param| param.starts_with("tboot."))
+        .fold(cmdline.to_string(), |cmdline, param| {
+            format!("{cmdline} {param}")
+        })
+}
+
+/// The devices of every loader in the interactive session, in the order `list` numbers them.
+/// Loaders that fail to probe are left out.
+fn session_devices(loaders: &mut [Loader]) -> Vec<(LoaderType, &BootDevice)> {
+    loaders
+        .iter_mut()
+        .flat_map(|loader| {
+            let loader_type = loader.loader_type();
+            match loader.boot_devices() {
+                Ok(boot_devices) => boot_devices
+                    .iter()
+                    .map(|boot_dev| (loader_type, boot_dev))
+                    .collect(),
+                Err(e) => {
+                    error!("failed to probe {loader_type} loader: {e}");
+                    Vec::new()
+                }
+            }
+        })
+        .collect()
+}
+
+/// Passes disk events on to every loader.
+fn disks_changed(loaders: &mut [Loader], events: &[DiskEvent]) {
+    debug!("disks changed: {events:?}");
+
+    for loader in loaders.iter_mut() {
+        loader.disks_changed(events);
+    }
+}
+
+/// Looks up an entry by the 1-based device and entry indices shown by the `list` command.
+fn find_entry<'a>(
+    devs: &[(LoaderType, &'a BootDevice)],
+    dev_idx: usize,
+    entry_idx: usize,
+) -> Result<(&'a BootDevice, &'a dyn BootEntry), &'static str> {
+    let (_, boot_dev) = dev_idx
+        .checked_sub(1)
+        .and_then(|idx| devs.get(idx))
+        .ok_or("cannot select non-existent device")?;
+
+    let entry = entry_idx
+        .checked_sub(1)
+        .and_then(|idx| boot_dev.entries.get(idx))
+        .ok_or("cannot select non-existent entry")?;
+
+    Ok((boot_dev, entry.as_ref()))
+}
+
+/// Entries of a device that can be booted automatically, ordered by preference. The default entry
+/// is tried first and the rest are fallbacks in the order they are listed. Entries that have run
+/// out of tries or that do not boot a kernel are never booted automatically.
+fn autoboot_entries(boot_dev: &BootDevice) -> Vec<&dyn BootEntry> {
+    let mut entries: Vec<&dyn BootEntry> = boot_dev
+        .entries
+        .iter()
+        .map(|entry| entry.as_ref())
+        .filter(|entry| !entry.is_bad() && entry.action().is_none())
+        .collect();
+
+    if let Some(default_idx) = entries.iter().position(|entry| entry.is_default()) {
+        let default_entry = entries.remove(default_idx);
+        entries.insert(0, default_entry);
+    }
+
+    entries
+}
+
 fn handle_commands(
     server_tx: mpsc::Sender<ServerToClient>,
     server_rx: mpsc::Receiver<ClientToServer>,
+    cfg: &Config,
+    boot_order_storage: &BootOrderStorage,
+    mut loaders: Vec<Loader>,
 ) -> Outcome {
-    let mut loader: Option<Loader> = None;
+    let mut filter = LoaderFilter::All;
+    // reading kernels can be slow, so every kernel is only looked at once
+    let mut kernel_versions: HashMap<PathBuf, Option<String>> = HashMap::new();
 
     loop {
         // ensure that stdout buffer is flushed before indicating that the server is ready to
@@ -157,8 +347,19 @@ fn handle_commands(
         std::io::stdout().flush().unwrap();
         server_tx.send(ServerToClient::ServerIsReady).unwrap();
 
-        match server_rx.recv().unwrap() {
-            ClientToServer::UserIsPresent => {}
+        // disk changes are handled in between commands, so that `list` is always up to date
+        let msg = loop {
+            match server_rx.recv().unwrap() {
+                ClientToServer::DisksChanged(events) => {
+                    disks_changed(&mut loaders, &events);
+                    kernel_versions.clear();
+                }
+                msg => break msg,
+            }
+        };
+
+        match msg {
+            ClientToServer::UserIsPresent | ClientToServer::DisksChanged(_) => {}
             ClientToServer::Command(Command::Shell) => {
                 if std::process::Command::new("/bin/busybox")
                     .arg("sh")
@@ -186,88 +387,188 @@ fn handle_commands(
                 server_tx.send(ServerToClient::Stop).unwrap();
                 return Outcome::Poweroff;
             }
-            ClientToServer::Command(Command::Rescan) => match loader {
-                None => println!("no loader selected"),
-                Some(ref mut loader) => {
+            ClientToServer::Command(Command::Rescan) => {
+                for loader in loaders.iter_mut() {
+                    let loader_type = loader.loader_type();
+                    if !filter.shows(loader_type) {
+                        continue;
+                    }
+
                     if let Err(e) = loader.probe(true) {
-                        error!("failed to rescan: {e}");
+                        error!("failed to rescan {loader_type} loader: {e}");
                     }
                 }
-            },
-            ClientToServer::Command(Command::List) => match loader {
-                None => println!("no loader selected"),
-                Some(ref mut loader) => match loader.boot_devices() {
-                    Err(e) => println!("failed to get boot devices: {e}"),
-                    Ok(devs) => {
-                        devs.iter().enumerate().for_each(|(dev_idx, dev)| {
-                            println!("{}: {}", dev_idx + 1, dev.name);
-
-                            dev.entries
-                                .iter()
-                                .enumerate()
-                                .for_each(|(entry_idx, entry)| {
-                                    println!("   {}: {}", entry_idx + 1, entry);
-                                });
+            }
+            ClientToServer::Command(Command::List) => {
+                let mut current_loader = None;
+
+                for (dev_idx, (loader_type, dev)) in
+                    session_devices(&mut loaders).into_iter().enumerate()
+                {
+                    if !filter.shows(loader_type) {
+                        continue;
+                    }
+
+                    if current_loader != Some(loader_type) {
+                        println!("{loader_type}");
+                        current_loader = Some(loader_type);
+                    }
+
+                    println!("  {}: {}", dev_idx + 1, dev.name);
+
+                    dev.entries
+                        .iter()
+                        .enumerate()
+                        .for_each(|(entry_idx, entry)| {
+                            let version = entry.linux().and_then(|linux| {
+                                kernel_versions
+                                    .entry(linux)
+                                    .or_insert_with_key(|linux| {
+                                        kernel_version(linux, cfg.max_kernel_size)
+                                    })
+                                    .clone()
+                            });
+
+                            match version {
+                                Some(version) => {
+                                    println!("     {}: {} ({version})", entry_idx + 1, entry)
+                                }
+                                None => println!("     {}: {}", entry_idx + 1, entry),
+                            }
                         });
+                }
+
+                if current_loader.is_none() {
+                    println!("no boot devices found");
+                }
+            }
+            ClientToServer::Command(Command::Boot((dev_idx, entry_idx))) => {
+                let devs = session_devices(&mut loaders);
+
+                let boot_dev = match dev_idx {
+                    Some(dev_idx) => dev_idx.checked_sub(1).and_then(|idx| devs.get(idx)),
+                    None => devs
+                        .iter()
+                        .find(|(loader_type, _)| filter.shows(*loader_type)),
+                };
+
+                let entry = boot_dev.and_then(|(_, boot_dev)| match entry_idx {
+                    Some(entry_idx) => entry_idx
+                        .checked_sub(1)
+                        .and_then(|idx| boot_dev.entries.get(idx)),
+                    None => boot_dev.entries.iter().find(|entry| entry.is_default()),
+                });
+
+                let Some(entry) = entry else {
+                    println!("cannot select non-existent entry");
+                    continue;
+                };
+
+                println!("selected entry '{}'", entry);
+
+                if let Some(action) = entry.action() {
+                    server_tx.send(ServerToClient::Stop).unwrap();
+                    return action.into();
+                }
+
+                if let Err(e) = entry.select().and_then(|parts| kexec_load(parts, cfg)) {
+                    println!("failed to load entry: {e}");
+                } else {
+                    if let Err(e) = entry.save() {
+                        error!("failed to save entry: {e}");
                     }
+
+                    server_tx.send(ServerToClient::Stop).unwrap();
+                    return Outcome::Kexec;
+                }
+            }
+            ClientToServer::Command(Command::Edit((dev_idx, entry_idx, cmdline))) => {
+                let devs = session_devices(&mut loaders);
+
+                let (boot_dev, entry) = match find_entry(&devs, dev_idx, entry_idx) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        println!("{e}");
+                        continue;
+                    }
+                };
+
+                if !boot_dev.editor {
+                    println!("editing is disabled for {}", boot_dev.name);
+                    continue;
+                }
+
+                println!("selected entry '{}'", entry);
+
+                match entry
+                    .select()
+                    .map(|mut parts| {
+                        parts.cmdline = Some(edit_cmdline(parts.cmdline.as_deref(), &cmdline));
+                        parts
+                    })
+                    .and_then(|parts| kexec_load(parts, cfg))
+                {
+                    Err(e) => println!("failed to load entry: {e}"),
+                    Ok(()) => {
+                        server_tx.send(ServerToClient::Stop).unwrap();
+                        return Outcome::Kexec;
+                    }
+                }
+            }
+            ClientToServer::Command(Command::BootNext((dev_idx, entry_idx))) => {
+                let devs = session_devices(&mut loaders);
+
+                match find_entry(&devs, dev_idx, entry_idx) {
+                    Err(e) => println!("{e}"),
+                    Ok((_, entry)) => match entry.set_oneshot() {
+                        Ok(()) => println!("entry '{}' will be booted next", entry),
+                        Err(e) => println!("failed to set entry to boot next: {e}"),
+                    },
+                }
+            }
+            ClientToServer::Command(Command::BootOrder(change)) => match change {
+                None => {
+                    let (boot_order, source) = boot_order_storage.load();
+                    println!("{boot_order} ({source})");
+                }
+                Some(BootOrderChange::Set(boot_order)) => {
+                    match boot_order_storage.save(Some(&boot_order)) {
+                        Ok(()) => println!("boot order set to '{boot_order}'"),
+                        Err(e) => println!("failed to save boot order: {e}"),
+                    }
+                }
+                Some(BootOrderChange::Reset) => match boot_order_storage.save(None) {
+                    Ok(()) => {
+                        let (boot_order, source) = boot_order_storage.load();
+                        println!("boot order reset to '{boot_order}' ({source})");
+                    }
+                    Err(e) => println!("failed to reset boot order: {e}"),
                 },
             },
-            ClientToServer::Command(Command::Boot((dev_idx, entry_idx))) => match loader {
-                None => println!("no loader selected"),
-                Some(ref mut loader) => {
-                    match loader.boot_devices().map(|devs| {
-                        let Some(boot_dev) = dev_idx
-                            .map(|dev_idx| dev_idx.checked_sub(1).map(|idx| devs.get(idx)))
-                            .flatten()
-                            .unwrap_or_else(|| devs.first())
-                        else {
-                            return None;
-                        };
-
-                        entry_idx
-                            .map(|entry_idx| {
-                                entry_idx
-                                    .checked_sub(1)
-                                    .map(|idx| boot_dev.entries.get(idx))
-                            })
-                            .flatten()
-                            .unwrap_or_else(|| {
-                                boot_dev.entries.iter().find(|entry| entry.is_default())
-                            })
-                    }) {
-                        Ok(Some(entry)) => {
-                            println!("selected entry '{}'", entry);
-
-                            if let Err(e) = kexec_load(entry.select()) {
-                                println!("failed to load entry: {e}");
-                            } else {
-                                server_tx.send(ServerToClient::Stop).unwrap();
-                                return Outcome::Kexec;
-                            }
+            ClientToServer::Command(Command::Loader(new_filter)) => match new_filter {
+                None => {
+                    let loader_types: Vec<String> = loaders
+                        .iter_mut()
+                        .map(|loader| loader.loader_type())
+                        .filter(|loader_type| filter.shows(*loader_type))
+                        .map(|loader_type| loader_type.to_string())
+                        .collect();
+                    println!("showing devices of {}", loader_types.join(", "));
+                }
+                Some(new_filter) => {
+                    // loaders that are not in the boot order are only started when asked for
+                    if let LoaderFilter::Only(loader_type) = new_filter {
+                        if !loaders
+                            .iter_mut()
+                            .any(|loader| loader.loader_type() == loader_type)
+                        {
+                            loaders.push(Loader::from_type(loader_type, cfg));
                         }
-                        Ok(None) => println!("cannot select non-existent entry"),
-                        Err(e) => println!("failed to get entries: {e}"),
                     }
+
+                    filter = new_filter;
                 }
             },
-            ClientToServer::Command(Command::Loader(desired_loader)) => {
-                if let Some(desired_loader) = desired_loader {
-                    // shutdown the current loader
-                    match loader {
-                        Some(ref mut loader) => loader.shutdown(),
-                        None => {}
-                    };
-
-                    // create the new loader
-                    loader = Some(Loader::new(Box::new(match desired_loader {
-                        LoaderType::Disk => BlsBootLoader::new(),
-                    })));
-                } else if let Some(ref mut loader) = loader {
-                    println!("currently using '{}' loader", loader.loader_type());
-                } else {
-                    println!("no loader selected");
-                }
-            }
         }
     }
 }
@@ -278,7 +579,7 @@ pub fn main() -> ! {
     tboot::system::setup_system();
 
     let args: Vec<String> = std::env::args().collect();
-    let cfg = tboot::config::Config::from_args(&args);
+    let cfg = Config::from_args(&args);
 
     if cfg.log_level >= LevelFilter::Debug {
         debug!("enabling backtrace printing");
@@ -308,7 +609,8 @@ pub fn main() -> ! {
         error!("unable to open tty {}", tty.display());
     }
 
-    let (new_dev_tx, new_dev_rx) = std::sync::mpsc::channel::<()>();
+    // subscribed to before any device is created, so that no disk event is missed
+    let disk_events = tboot::dev::subscribe_disk_events();
 
     // listen_and_create_devices prints logs, so make sure it starts after logging and output
     // console is setup
@@ -318,7 +620,7 @@ pub fn main() -> ! {
         // us missing a bunch of devices.
         tboot::dev::scan_and_create_devices();
 
-        if let Err(e) = tboot::dev::listen_and_create_devices(new_dev_tx) {
+        if let Err(e) = tboot::dev::listen_and_create_devices() {
             error!("listen_and_create_devices failed: {e}");
             panic!()
         }
@@ -339,10 +641,18 @@ pub fn main() -> ! {
         info!("boot verification is ON");
     }
 
-    debug!("waiting for new events to settle");
-    tboot::dev::wait_for_settle(new_dev_rx, Duration::from_secs(2));
+    // The loaders probe every disk that is there when they start, so the events up to then only
+    // tell us when disks stop showing up. Disks that come later are passed on to the loaders.
+    debug!("waiting for new disks to settle");
+    if let Ok(event) = disk_events.recv_timeout(DISK_SETTLE_DURATION) {
+        tboot::dev::settle_disk_events(event, &disk_events, DISK_SETTLE_DURATION);
+    }
 
-    match prepare_boot() {
+    match prepare_boot(
+        &cfg,
+        &BootOrderStorage::new(cfg.bootorder_cmos),
+        disk_events,
+    ) {
         Ok(Outcome::Kexec) => {
             debug!("kexec'ing");
             kexec_execute().expect("kexec execute failed")
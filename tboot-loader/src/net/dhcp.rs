@@ -0,0 +1,440 @@
+use log::debug;
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use super::iface::{self, Interface};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHER: u8 = 1;
+/// Asks the server to broadcast its replies, since we cannot receive unicast before having an
+/// address.
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_VENDOR_CLASS_ID: u8 = 60;
+const OPTION_CLIENT_ID: u8 = 61;
+const OPTION_TFTP_SERVER_NAME: u8 = 66;
+const OPTION_BOOTFILE_NAME: u8 = 67;
+const OPTION_END: u8 = 255;
+
+/// Lets the DHCP server hand out boot files meant for tinyboot.
+const VENDOR_CLASS_ID: &str = "tinyboot";
+
+/// How long to wait for an answer to each attempt, so that a missing DHCP server does not hold up
+/// booting from other devices for too long.
+const ATTEMPT_TIMEOUTS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Ack = 5,
+    Nak = 6,
+}
+
+impl MessageType {
+    fn from_u8(val: u8) -> Option<Self> {
+        Some(match val {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub server_id: Option<Ipv4Addr>,
+    /// The siaddr field, the server to fetch the boot file from if option 66 is missing.
+    pub next_server: Option<Ipv4Addr>,
+    pub tftp_server_name: Option<String>,
+    pub bootfile: Option<String>,
+}
+
+impl Default for Lease {
+    fn default() -> Self {
+        Self {
+            address: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            router: None,
+            dns_servers: Vec::new(),
+            server_id: None,
+            next_server: None,
+            tftp_server_name: None,
+            bootfile: None,
+        }
+    }
+}
+
+impl Lease {
+    /// Where to fetch the boot file from, either a URL or a TFTP path.
+    pub fn boot_url(&self) -> Option<String> {
+        let bootfile = self.bootfile.as_ref()?;
+
+        if bootfile.contains("://") {
+            return Some(bootfile.clone());
+        }
+
+        let server = match (&self.tftp_server_name, self.next_server, self.server_id) {
+            (Some(server), _, _) => server.clone(),
+            (None, Some(server), _) | (None, None, Some(server)) => server.to_string(),
+            (None, None, None) => return None,
+        };
+
+        Some(format!(
+            "tftp://{server}/{}",
+            bootfile.trim_start_matches('/')
+        ))
+    }
+}
+
+fn build_message(
+    message_type: MessageType,
+    xid: u32,
+    mac: &[u8; 6],
+    offer: Option<(Ipv4Addr, Ipv4Addr)>,
+) -> Vec<u8> {
+    let mut packet = vec![BOOTREQUEST, HTYPE_ETHER, mac.len() as u8, 0];
+    packet.extend(xid.to_be_bytes());
+    packet.extend([0, 0]); // secs
+    packet.extend(FLAG_BROADCAST.to_be_bytes());
+    packet.extend([0; 16]); // ciaddr, yiaddr, siaddr, giaddr
+    packet.extend(mac);
+    packet.extend([0; 10]); // chaddr padding
+    packet.extend([0; 64]); // sname
+    packet.extend([0; 128]); // file
+    packet.extend(MAGIC_COOKIE);
+
+    packet.extend([OPTION_MESSAGE_TYPE, 1, message_type as u8]);
+
+    packet.extend([OPTION_CLIENT_ID, 1 + mac.len() as u8, HTYPE_ETHER]);
+    packet.extend(mac);
+
+    if let Some((address, server_id)) = offer {
+        packet.extend([OPTION_REQUESTED_ADDRESS, 4]);
+        packet.extend(address.octets());
+        packet.extend([OPTION_SERVER_ID, 4]);
+        packet.extend(server_id.octets());
+    }
+
+    packet.extend([OPTION_VENDOR_CLASS_ID, VENDOR_CLASS_ID.len() as u8]);
+    packet.extend(VENDOR_CLASS_ID.as_bytes());
+
+    let parameters = [
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS_SERVERS,
+        OPTION_TFTP_SERVER_NAME,
+        OPTION_BOOTFILE_NAME,
+    ];
+    packet.extend([OPTION_PARAMETER_REQUEST_LIST, parameters.len() as u8]);
+    packet.extend(parameters);
+
+    packet.push(OPTION_END);
+
+    packet
+}
+
+fn ipv4_addrs(val: &[u8]) -> Vec<Ipv4Addr> {
+    val.chunks_exact(4)
+        .map(|addr| Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+        .collect()
+}
+
+/// Reads a NUL-padded string out of the sname or file field, or out of an option.
+fn parse_string(val: &[u8]) -> Option<String> {
+    let val = match val.iter().position(|&b| b == 0) {
+        Some(end) => &val[..end],
+        None => val,
+    };
+
+    if val.is_empty() {
+        return None;
+    }
+
+    String::from_utf8(val.to_vec()).ok()
+}
+
+/// Parses a reply from a server, ignoring anything that is not an answer to our transaction.
+fn parse_message(packet: &[u8], xid: u32, mac: &[u8; 6]) -> Option<(MessageType, Lease)> {
+    if packet.len() < 240
+        || packet[0] != BOOTREPLY
+        || packet[4..8] != xid.to_be_bytes()
+        || packet[28..34] != *mac
+        || packet[236..240] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut lease = Lease {
+        address: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        next_server: Some(Ipv4Addr::new(
+            packet[20], packet[21], packet[22], packet[23],
+        ))
+        .filter(|addr| !addr.is_unspecified()),
+        tftp_server_name: parse_string(&packet[44..108]),
+        bootfile: parse_string(&packet[108..236]),
+        ..Default::default()
+    };
+
+    let mut message_type = None;
+    let mut options = &packet[240..];
+
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+
+        let (&len, rest) = rest.split_first()?;
+        if rest.len() < len as usize {
+            return None;
+        }
+        let (val, rest) = rest.split_at(len as usize);
+        options = rest;
+
+        match code {
+            OPTION_MESSAGE_TYPE => {
+                message_type = val.first().copied().and_then(MessageType::from_u8)
+            }
+            OPTION_SUBNET_MASK if val.len() == 4 => {
+                lease.prefix_len = u32::from_be_bytes(val.try_into().unwrap()).count_ones() as u8;
+            }
+            OPTION_ROUTER => lease.router = ipv4_addrs(val).first().copied(),
+            OPTION_DNS_SERVERS => lease.dns_servers = ipv4_addrs(val),
+            OPTION_SERVER_ID => lease.server_id = ipv4_addrs(val).first().copied(),
+            OPTION_TFTP_SERVER_NAME => lease.tftp_server_name = parse_string(val),
+            OPTION_BOOTFILE_NAME => lease.bootfile = parse_string(val),
+            _ => {}
+        }
+    }
+
+    if lease.prefix_len == 0 {
+        // fall back to the classful netmask
+        lease.prefix_len = match lease.address.octets()[0] {
+            0..=127 => 8,
+            128..=191 => 16,
+            _ => 24,
+        };
+    }
+
+    Some((message_type?, lease))
+}
+
+/// Sends `packet` and waits for a reply of one of the expected types, retrying with increasing
+/// timeouts.
+fn exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    xid: u32,
+    mac: &[u8; 6],
+    expected: &[MessageType],
+) -> anyhow::Result<(MessageType, Lease)> {
+    let server = SocketAddr::from((Ipv4Addr::BROADCAST, SERVER_PORT));
+    let mut buf = [0u8; 1500];
+
+    for timeout in ATTEMPT_TIMEOUTS {
+        socket.send_to(packet, server)?;
+
+        let start = Instant::now();
+        while let Some(remaining) = timeout.checked_sub(start.elapsed()) {
+            socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            if let Some((message_type, lease)) = parse_message(&buf[..len], xid, mac) {
+                if expected.contains(&message_type) {
+                    return Ok((message_type, lease));
+                }
+            }
+        }
+    }
+
+    anyhow::bail!("no answer from a DHCP server")
+}
+
+/// Gets an IPv4 lease for the interface.
+/// https://www.rfc-editor.org/rfc/rfc2131
+pub fn request_lease(interface: &Interface) -> anyhow::Result<Lease> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_PORT))?;
+    socket.set_broadcast(true)?;
+    iface::bind_to_device(&socket, &interface.name)?;
+
+    let xid = super::random_u32();
+
+    let (_, offer) = exchange(
+        &socket,
+        &build_message(MessageType::Discover, xid, &interface.mac, None),
+        xid,
+        &interface.mac,
+        &[MessageType::Offer],
+    )?;
+
+    let Some(server_id) = offer.server_id else {
+        anyhow::bail!("offer without a server identifier");
+    };
+
+    debug!(
+        "{}: offered {} by {server_id}",
+        interface.name, offer.address
+    );
+
+    match exchange(
+        &socket,
+        &build_message(
+            MessageType::Request,
+            xid,
+            &interface.mac,
+            Some((offer.address, server_id)),
+        ),
+        xid,
+        &interface.mac,
+        &[MessageType::Ack, MessageType::Nak],
+    )? {
+        (MessageType::Ack, lease) => Ok(lease),
+        _ => anyhow::bail!("{server_id} declined our request"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{
+        build_message, parse_message, Lease, MessageType, BOOTREPLY, OPTION_BOOTFILE_NAME,
+        OPTION_DNS_SERVERS, OPTION_END, OPTION_MESSAGE_TYPE, OPTION_ROUTER, OPTION_SERVER_ID,
+        OPTION_SUBNET_MASK,
+    };
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    #[test]
+    fn test_build_message() {
+        let packet = build_message(
+            MessageType::Request,
+            0xdeadbeef,
+            &MAC,
+            Some((Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(10, 0, 0, 1))),
+        );
+
+        assert_eq!(&packet[..4], &[1, 1, 6, 0]);
+        assert_eq!(&packet[4..8], &0xdeadbeefu32.to_be_bytes());
+        assert_eq!(&packet[28..34], &MAC);
+        assert_eq!(&packet[240..243], &[OPTION_MESSAGE_TYPE, 1, 3]);
+        assert!(packet
+            .windows(6)
+            .any(|option| option == [50, 4, 10, 0, 0, 5]));
+        assert!(packet
+            .windows(6)
+            .any(|option| option == [OPTION_SERVER_ID, 4, 10, 0, 0, 1]));
+        assert_eq!(packet.last(), Some(&OPTION_END));
+    }
+
+    fn reply(xid: u32, siaddr: [u8; 4], file: &[u8], options: &[u8]) -> Vec<u8> {
+        let mut packet = build_message(MessageType::Discover, xid, &MAC, None);
+        packet.truncate(240);
+        packet[0] = BOOTREPLY;
+        packet[16..20].copy_from_slice(&[10, 0, 0, 5]);
+        packet[20..24].copy_from_slice(&siaddr);
+        packet[108..108 + file.len()].copy_from_slice(file);
+        packet.extend(options);
+        packet
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let mut options = vec![OPTION_MESSAGE_TYPE, 1, 5, 0, 0];
+        options.extend([OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+        options.extend([OPTION_ROUTER, 4, 10, 0, 0, 1]);
+        options.extend([OPTION_DNS_SERVERS, 8, 10, 0, 0, 1, 10, 0, 0, 2]);
+        options.extend([OPTION_SERVER_ID, 4, 10, 0, 0, 1]);
+        options.extend([OPTION_BOOTFILE_NAME, 10]);
+        options.extend(b"boot.conf\0");
+        options.push(OPTION_END);
+
+        let packet = reply(1234, [10, 0, 0, 3], b"ignored", &options);
+
+        assert_eq!(parse_message(&packet, 4321, &MAC), None);
+
+        let (message_type, lease) = parse_message(&packet, 1234, &MAC).unwrap();
+        assert_eq!(message_type, MessageType::Ack);
+        assert_eq!(
+            lease,
+            Lease {
+                address: Ipv4Addr::new(10, 0, 0, 5),
+                prefix_len: 24,
+                router: Some(Ipv4Addr::new(10, 0, 0, 1)),
+                dns_servers: vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)],
+                server_id: Some(Ipv4Addr::new(10, 0, 0, 1)),
+                next_server: Some(Ipv4Addr::new(10, 0, 0, 3)),
+                tftp_server_name: None,
+                bootfile: Some("boot.conf".to_string()),
+            }
+        );
+        assert_eq!(
+            lease.boot_url().as_deref(),
+            Some("tftp://10.0.0.3/boot.conf")
+        );
+
+        // truncated options are rejected
+        let packet = reply(1234, [0; 4], b"", &[OPTION_MESSAGE_TYPE, 4, 5]);
+        assert_eq!(parse_message(&packet, 1234, &MAC), None);
+    }
+
+    #[test]
+    fn test_boot_url() {
+        let lease = Lease {
+            server_id: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            bootfile: Some("/linux".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(lease.boot_url().as_deref(), Some("tftp://10.0.0.1/linux"));
+
+        let lease = Lease {
+            tftp_server_name: Some("boot.example.com".to_string()),
+            bootfile: Some("http://10.0.0.2/boot.conf".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            lease.boot_url().as_deref(),
+            Some("http://10.0.0.2/boot.conf")
+        );
+
+        assert_eq!(Lease::default().boot_url(), None);
+    }
+}
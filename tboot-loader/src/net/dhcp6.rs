@@ -0,0 +1,367 @@
+use log::debug;
+use std::{
+    io::ErrorKind,
+    net::{Ipv6Addr, SocketAddrV6, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use super::iface::{self, Interface};
+
+const SERVER_PORT: u16 = 547;
+const CLIENT_PORT: u16 = 546;
+/// All_DHCP_Relay_Agents_and_Servers
+const SERVERS_ADDRESS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+
+const MSG_SOLICIT: u8 = 1;
+const MSG_ADVERTISE: u8 = 2;
+const MSG_REQUEST: u8 = 3;
+const MSG_REPLY: u8 = 7;
+
+const OPTION_CLIENTID: u16 = 1;
+const OPTION_SERVERID: u16 = 2;
+const OPTION_IA_NA: u16 = 3;
+const OPTION_IAADDR: u16 = 5;
+const OPTION_ORO: u16 = 6;
+const OPTION_ELAPSED_TIME: u16 = 8;
+const OPTION_STATUS_CODE: u16 = 13;
+const OPTION_DNS_SERVERS: u16 = 23;
+const OPTION_BOOTFILE_URL: u16 = 59;
+const OPTION_BOOTFILE_PARAM: u16 = 60;
+
+const STATUS_SUCCESS: u16 = 0;
+
+/// DUID-LL, based on the link-layer address.
+const DUID_LL: u16 = 3;
+const HTYPE_ETHER: u16 = 1;
+
+/// DHCPv6 addresses do not carry a prefix length, the on-link prefix is learned from router
+/// advertisements.
+const ADDRESS_PREFIX_LEN: u8 = 128;
+
+const ATTEMPT_TIMEOUTS: [Duration; 2] = [Duration::from_secs(1), Duration::from_secs(2)];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Option<Ipv6Addr>,
+    pub dns_servers: Vec<Ipv6Addr>,
+    pub bootfile_url: Option<String>,
+    /// The kernel command line, if the server sent one.
+    pub bootfile_params: Vec<String>,
+}
+
+impl Lease {
+    pub fn prefix_len(&self) -> u8 {
+        ADDRESS_PREFIX_LEN
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Message {
+    message_type: u8,
+    transaction_id: [u8; 3],
+    client_id: Option<Vec<u8>>,
+    server_id: Option<Vec<u8>>,
+    /// Kept around so that it can be sent back in the request.
+    ia_na: Option<Vec<u8>>,
+    status: Option<u16>,
+    lease: Lease,
+}
+
+fn push_option(packet: &mut Vec<u8>, code: u16, val: &[u8]) {
+    packet.extend(code.to_be_bytes());
+    packet.extend((val.len() as u16).to_be_bytes());
+    packet.extend(val);
+}
+
+fn duid(mac: &[u8; 6]) -> Vec<u8> {
+    let mut duid = DUID_LL.to_be_bytes().to_vec();
+    duid.extend(HTYPE_ETHER.to_be_bytes());
+    duid.extend(mac);
+    duid
+}
+
+fn build_message(
+    message_type: u8,
+    transaction_id: [u8; 3],
+    interface: &Interface,
+    advertise: Option<&Message>,
+) -> Vec<u8> {
+    let mut packet = vec![message_type];
+    packet.extend(transaction_id);
+
+    push_option(&mut packet, OPTION_CLIENTID, &duid(&interface.mac));
+    push_option(&mut packet, OPTION_ELAPSED_TIME, &[0, 0]);
+
+    let mut oro = Vec::new();
+    for option in [
+        OPTION_BOOTFILE_URL,
+        OPTION_BOOTFILE_PARAM,
+        OPTION_DNS_SERVERS,
+    ] {
+        oro.extend(option.to_be_bytes());
+    }
+    push_option(&mut packet, OPTION_ORO, &oro);
+
+    match advertise {
+        Some(advertise) => {
+            if let Some(server_id) = &advertise.server_id {
+                push_option(&mut packet, OPTION_SERVERID, server_id);
+            }
+            if let Some(ia_na) = &advertise.ia_na {
+                push_option(&mut packet, OPTION_IA_NA, ia_na);
+            }
+        }
+        None => {
+            // IAID, T1 and T2, letting the server pick the timers
+            let mut ia_na = interface.index.to_be_bytes().to_vec();
+            ia_na.extend([0; 8]);
+            push_option(&mut packet, OPTION_IA_NA, &ia_na);
+        }
+    }
+
+    packet
+}
+
+/// Iterates over the code and value of the options in `buf`, stopping at the first truncated one.
+fn options(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let code = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if buf.len() < 4 + len {
+            return None;
+        }
+        let val = &buf[4..4 + len];
+        buf = &buf[4 + len..];
+        Some((code, val))
+    })
+}
+
+fn ipv6_addr(val: &[u8]) -> Option<Ipv6Addr> {
+    Some(Ipv6Addr::from(<[u8; 16]>::try_from(val.get(..16)?).ok()?))
+}
+
+fn parse_message(packet: &[u8]) -> Option<Message> {
+    if packet.len() < 4 {
+        return None;
+    }
+
+    let mut message = Message {
+        message_type: packet[0],
+        transaction_id: [packet[1], packet[2], packet[3]],
+        ..Default::default()
+    };
+
+    for (code, val) in options(&packet[4..]) {
+        match code {
+            OPTION_CLIENTID => message.client_id = Some(val.to_vec()),
+            OPTION_SERVERID => message.server_id = Some(val.to_vec()),
+            OPTION_STATUS_CODE if val.len() >= 2 => {
+                message.status = Some(u16::from_be_bytes([val[0], val[1]]));
+            }
+            OPTION_IA_NA if val.len() >= 12 => {
+                message.ia_na = Some(val.to_vec());
+                for (code, val) in options(&val[12..]) {
+                    match code {
+                        OPTION_IAADDR => message.lease.address = ipv6_addr(val),
+                        OPTION_STATUS_CODE if val.len() >= 2 => {
+                            let status = u16::from_be_bytes([val[0], val[1]]);
+                            if status != STATUS_SUCCESS {
+                                debug!("IA_NA status {status}");
+                                message.lease.address = None;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            OPTION_DNS_SERVERS => {
+                message.lease.dns_servers = val.chunks_exact(16).filter_map(ipv6_addr).collect();
+            }
+            OPTION_BOOTFILE_URL => {
+                message.lease.bootfile_url = String::from_utf8(val.to_vec()).ok();
+            }
+            OPTION_BOOTFILE_PARAM => {
+                // a list of length-prefixed strings
+                let mut val = val;
+                while val.len() >= 2 {
+                    let len = u16::from_be_bytes([val[0], val[1]]) as usize;
+                    let Some(param) = val.get(2..2 + len) else {
+                        break;
+                    };
+                    message
+                        .lease
+                        .bootfile_params
+                        .push(String::from_utf8_lossy(param).to_string());
+                    val = &val[2 + len..];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(message)
+}
+
+fn exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    interface: &Interface,
+    expected_type: u8,
+) -> anyhow::Result<Message> {
+    let server = SocketAddrV6::new(SERVERS_ADDRESS, SERVER_PORT, 0, interface.index);
+    let transaction_id = [packet[1], packet[2], packet[3]];
+    let client_id = duid(&interface.mac);
+    let mut buf = [0u8; 1500];
+
+    for timeout in ATTEMPT_TIMEOUTS {
+        let start = Instant::now();
+
+        // sending fails until the link-local address passed duplicate address detection
+        if let Err(e) = socket.send_to(packet, server) {
+            debug!("{}: failed to send to {server}: {e}", interface.name);
+            std::thread::sleep(timeout);
+            continue;
+        }
+
+        while let Some(remaining) = timeout.checked_sub(start.elapsed()) {
+            socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            let Some(message) = parse_message(&buf[..len]) else {
+                continue;
+            };
+
+            if message.message_type == expected_type
+                && message.transaction_id == transaction_id
+                && message.client_id.as_ref() == Some(&client_id)
+                && message.server_id.is_some()
+            {
+                return Ok(message);
+            }
+        }
+    }
+
+    anyhow::bail!("no answer from a DHCPv6 server")
+}
+
+/// Gets an IPv6 lease for the interface, along with the boot file URL and parameters.
+/// https://www.rfc-editor.org/rfc/rfc8415
+/// https://www.rfc-editor.org/rfc/rfc5970
+pub fn request_lease(interface: &Interface) -> anyhow::Result<Lease> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, CLIENT_PORT))?;
+    iface::bind_to_device(&socket, &interface.name)?;
+
+    let xid = super::random_u32().to_be_bytes();
+    let transaction_id = [xid[1], xid[2], xid[3]];
+
+    let advertise = exchange(
+        &socket,
+        &build_message(MSG_SOLICIT, transaction_id, interface, None),
+        interface,
+        MSG_ADVERTISE,
+    )?;
+
+    let reply = exchange(
+        &socket,
+        &build_message(MSG_REQUEST, transaction_id, interface, Some(&advertise)),
+        interface,
+        MSG_REPLY,
+    )?;
+
+    if let Some(status) = reply.status.filter(|&status| status != STATUS_SUCCESS) {
+        anyhow::bail!("request failed with status {status}");
+    }
+
+    Ok(reply.lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::{
+        build_message, duid, parse_message, push_option, Interface, Lease, MSG_REPLY, MSG_REQUEST,
+        MSG_SOLICIT, OPTION_BOOTFILE_PARAM, OPTION_BOOTFILE_URL, OPTION_CLIENTID, OPTION_IAADDR,
+        OPTION_IA_NA, OPTION_SERVERID,
+    };
+
+    fn interface() -> Interface {
+        Interface {
+            name: "eth0".to_string(),
+            index: 2,
+            mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+        }
+    }
+
+    #[test]
+    fn test_request_echoes_advertise() {
+        let interface = interface();
+
+        let solicit = build_message(MSG_SOLICIT, [1, 2, 3], &interface, None);
+        let solicit = parse_message(&solicit).unwrap();
+        assert_eq!(solicit.message_type, MSG_SOLICIT);
+        assert_eq!(solicit.client_id, Some(duid(&interface.mac)));
+        assert_eq!(
+            solicit.ia_na,
+            Some(vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0])
+        );
+
+        let mut advertise = solicit;
+        advertise.server_id = Some(vec![0, 3, 0, 1, 1, 2, 3, 4, 5, 6]);
+
+        let request = build_message(MSG_REQUEST, [1, 2, 3], &interface, Some(&advertise));
+        let request = parse_message(&request).unwrap();
+        assert_eq!(request.message_type, MSG_REQUEST);
+        assert_eq!(request.transaction_id, [1, 2, 3]);
+        assert_eq!(request.server_id, advertise.server_id);
+        assert_eq!(request.ia_na, advertise.ia_na);
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let address: Ipv6Addr = "fd00::5".parse().unwrap();
+
+        let mut iaaddr = address.octets().to_vec();
+        iaaddr.extend([0, 0, 0x0e, 0x10, 0, 0, 0x1c, 0x20]);
+        let mut ia_na = vec![0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0];
+        push_option(&mut ia_na, OPTION_IAADDR, &iaaddr);
+
+        let mut params = Vec::new();
+        for param in ["console=ttyS0", "quiet"] {
+            params.extend((param.len() as u16).to_be_bytes());
+            params.extend(param.as_bytes());
+        }
+
+        let mut packet = vec![MSG_REPLY, 1, 2, 3];
+        push_option(&mut packet, OPTION_CLIENTID, &duid(&interface().mac));
+        push_option(
+            &mut packet,
+            OPTION_SERVERID,
+            &[0, 3, 0, 1, 1, 2, 3, 4, 5, 6],
+        );
+        push_option(&mut packet, OPTION_IA_NA, &ia_na);
+        push_option(&mut packet, OPTION_BOOTFILE_URL, b"http://[fd00::1]/linux");
+        push_option(&mut packet, OPTION_BOOTFILE_PARAM, &params);
+        // a truncated option at the end is ignored
+        packet.extend([0, 1, 0, 10]);
+
+        let reply = parse_message(&packet).unwrap();
+        assert_eq!(
+            reply.lease,
+            Lease {
+                address: Some(address),
+                dns_servers: Vec::new(),
+                bootfile_url: Some("http://[fd00::1]/linux".to_string()),
+                bootfile_params: vec!["console=ttyS0".to_string(), "quiet".to_string()],
+            }
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Response {
+    /// The body was written out, this many bytes of it.
+    Done(u64),
+    /// The server pointed us somewhere else, the location can be relative.
+    Redirect(String),
+}
+
+fn read_line(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        anyhow::bail!("connection closed");
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn copy_chunked(reader: &mut impl BufRead, out: &mut impl Write) -> anyhow::Result<u64> {
+    let mut total = 0;
+
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| anyhow::anyhow!("invalid chunk size '{size}'"))?;
+
+        if size == 0 {
+            // skip any trailers
+            while !read_line(reader)?.is_empty() {}
+            return Ok(total);
+        }
+
+        let copied = std::io::copy(&mut reader.take(size), out)?;
+        if copied != size {
+            anyhow::bail!("connection closed in the middle of a chunk");
+        }
+        total += copied;
+
+        if !read_line(reader)?.is_empty() {
+            anyhow::bail!("missing newline after chunk");
+        }
+    }
+}
+
+/// Does a plain HTTP GET for `path` on the server at `addr`, writing the body to `out`. Redirects
+/// are returned to the caller instead of being followed.
+pub fn fetch(
+    host: &str,
+    addr: SocketAddr,
+    path: &str,
+    out: &mut impl Write,
+) -> anyhow::Result<Response> {
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    let host = if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    };
+
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: tinyboot\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+    )?;
+
+    let mut reader = BufReader::new(stream);
+
+    let status_line = read_line(&mut reader)?;
+    let mut status_parts = status_line.splitn(3, ' ');
+    let (Some(version), Some(status)) = (status_parts.next(), status_parts.next()) else {
+        anyhow::bail!("invalid status line '{status_line}'");
+    };
+    if !version.starts_with("HTTP/1.") {
+        anyhow::bail!("unsupported HTTP version '{version}'");
+    }
+    let Ok(status) = status.parse::<u16>() else {
+        anyhow::bail!("invalid status line '{status_line}'");
+    };
+
+    let mut content_length: Option<u64> = None;
+    let mut chunked = false;
+    let mut location: Option<String> = None;
+
+    loop {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+
+        let Some((name, val)) = line.split_once(':') else {
+            continue;
+        };
+        let val = val.trim();
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => content_length = val.parse().ok(),
+            "transfer-encoding" => chunked = val.eq_ignore_ascii_case("chunked"),
+            "location" => location = Some(val.to_string()),
+            _ => {}
+        }
+    }
+
+    match status {
+        200 => {}
+        301 | 302 | 303 | 307 | 308 => {
+            let Some(location) = location else {
+                anyhow::bail!("redirect without a location");
+            };
+            return Ok(Response::Redirect(location));
+        }
+        _ => {
+            anyhow::bail!(
+                "server responded with '{}'",
+                status_line.split_once(' ').unwrap_or_default().1
+            );
+        }
+    }
+
+    let len = if chunked {
+        copy_chunked(&mut reader, out)?
+    } else if let Some(content_length) = content_length {
+        let copied = std::io::copy(&mut (&mut reader).take(content_length), out)?;
+        if copied != content_length {
+            anyhow::bail!("connection closed after {copied} of {content_length} bytes");
+        }
+        copied
+    } else {
+        std::io::copy(&mut reader, out)?
+    };
+
+    Ok(Response::Done(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{SocketAddr, TcpListener},
+    };
+
+    use super::Response;
+
+    /// Answers a single request with `response`, checking that the request was for `/linux`.
+    fn serve(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            assert_eq!(request_line, "GET /linux HTTP/1.1\r\n");
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+
+            reader.get_mut().write_all(response).unwrap();
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut out = Vec::new();
+        let response = super::fetch(
+            "localhost",
+            serve(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"),
+            "/linux",
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(response, Response::Done(5));
+        assert_eq!(out, b"hello");
+
+        let mut out = Vec::new();
+        let response = super::fetch(
+            "localhost",
+            serve(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2;ext=1\r\nlo\r\n0\r\n\r\n"),
+            "/linux",
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(response, Response::Done(5));
+        assert_eq!(out, b"hello");
+
+        let mut out = Vec::new();
+        let response = super::fetch(
+            "localhost",
+            serve(b"HTTP/1.0 200 OK\r\n\r\nhello"),
+            "/linux",
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(response, Response::Done(5));
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn test_fetch_errors() {
+        let mut out = Vec::new();
+        let response = super::fetch(
+            "localhost",
+            serve(b"HTTP/1.1 302 Found\r\nLocation: /boot/linux\r\n\r\n"),
+            "/linux",
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(response, Response::Redirect("/boot/linux".to_string()));
+
+        let err = super::fetch(
+            "localhost",
+            serve(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"),
+            "/linux",
+            &mut out,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "server responded with '404 Not Found'");
+
+        let err = super::fetch(
+            "localhost",
+            serve(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello"),
+            "/linux",
+            &mut out,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "connection closed after 5 of 10 bytes");
+    }
+}
@@ -0,0 +1,291 @@
+use nix::libc;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+/// ARPHRD_ETHER from include/uapi/linux/if_arp.h
+const ARPHRD_ETHER: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    pub mac: [u8; 6],
+}
+
+fn read_attribute(name: &str, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(Path::new(SYS_CLASS_NET).join(name).join(attribute))
+        .ok()
+        .map(|val| val.trim().to_string())
+}
+
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut octets = s.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(octets.next()?, 16).ok()?;
+    }
+    octets.next().is_none().then_some(mac)
+}
+
+/// Lists the ethernet interfaces of the machine, sorted by name.
+pub fn ethernet_interfaces() -> Vec<Interface> {
+    let Ok(entries) = std::fs::read_dir(SYS_CLASS_NET) else {
+        return Vec::new();
+    };
+
+    let mut interfaces: Vec<Interface> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().to_str()?.to_string();
+
+            if read_attribute(&name, "type")?.parse::<u32>().ok()? != ARPHRD_ETHER {
+                return None;
+            }
+
+            Some(Interface {
+                index: read_attribute(&name, "ifindex")?.parse().ok()?,
+                mac: parse_mac(&read_attribute(&name, "address")?)?,
+                name,
+            })
+        })
+        .collect();
+
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+
+    interfaces
+}
+
+/// Waits for the interface to report that a cable is plugged in.
+pub fn wait_for_carrier(name: &str, timeout: Duration) -> bool {
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        if read_attribute(name, "carrier").as_deref() == Some("1") {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    false
+}
+
+fn control_socket(domain: libc::c_int) -> std::io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn ifreq(name: &str) -> anyhow::Result<libc::ifreq> {
+    let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+
+    if name.len() >= ifreq.ifr_name.len() {
+        anyhow::bail!("interface name '{name}' is too long");
+    }
+
+    for (dst, src) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    Ok(ifreq)
+}
+
+fn ioctl<T>(fd: &OwnedFd, request: libc::c_ulong, arg: &mut T) -> std::io::Result<()> {
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), request as _, arg as *mut T) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Brings the link of the interface up or down.
+pub fn set_link(name: &str, up: bool) -> anyhow::Result<()> {
+    let fd = control_socket(libc::AF_INET)?;
+    let mut ifreq = ifreq(name)?;
+
+    ioctl(&fd, libc::SIOCGIFFLAGS, &mut ifreq)?;
+
+    unsafe {
+        if up {
+            ifreq.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        } else {
+            ifreq.ifr_ifru.ifru_flags &= !(libc::IFF_UP as libc::c_short);
+        }
+    }
+
+    ioctl(&fd, libc::SIOCSIFFLAGS, &mut ifreq)?;
+
+    Ok(())
+}
+
+fn sockaddr_in(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(addr.octets()),
+        },
+        sin_zero: [0; 8],
+    };
+
+    // sockaddr_in and sockaddr have the same size
+    unsafe { std::mem::transmute(sin) }
+}
+
+fn netmask(prefix_len: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0))
+}
+
+/// Assigns an IPv4 address to the interface, replacing the one it had. Assigning 0.0.0.0 removes
+/// the address.
+pub fn set_ipv4(name: &str, addr: Ipv4Addr, prefix_len: u8) -> anyhow::Result<()> {
+    let fd = control_socket(libc::AF_INET)?;
+
+    let mut ifreq = ifreq(name)?;
+    ifreq.ifr_ifru.ifru_addr = sockaddr_in(addr);
+    ioctl(&fd, libc::SIOCSIFADDR, &mut ifreq)?;
+
+    if !addr.is_unspecified() {
+        let mut ifreq = self::ifreq(name)?;
+        ifreq.ifr_ifru.ifru_netmask = sockaddr_in(netmask(prefix_len));
+        ioctl(&fd, libc::SIOCSIFNETMASK, &mut ifreq)?;
+    }
+
+    Ok(())
+}
+
+/// Layout of `struct rtentry` from include/uapi/linux/route.h, which the libc crate does not have
+/// for every target.
+#[repr(C)]
+struct RtEntry {
+    rt_pad1: libc::c_ulong,
+    rt_dst: libc::sockaddr,
+    rt_gateway: libc::sockaddr,
+    rt_genmask: libc::sockaddr,
+    rt_flags: libc::c_ushort,
+    rt_pad2: libc::c_short,
+    rt_pad3: libc::c_ulong,
+    rt_pad4: *mut libc::c_void,
+    rt_metric: libc::c_short,
+    rt_dev: *mut libc::c_char,
+    rt_mtu: libc::c_ulong,
+    rt_window: libc::c_ulong,
+    rt_irtt: libc::c_ushort,
+}
+
+/// Routes all IPv4 traffic through the gateway.
+pub fn add_ipv4_default_route(gateway: Ipv4Addr) -> anyhow::Result<()> {
+    let fd = control_socket(libc::AF_INET)?;
+
+    let mut rtentry = RtEntry {
+        rt_pad1: 0,
+        rt_dst: sockaddr_in(Ipv4Addr::UNSPECIFIED),
+        rt_gateway: sockaddr_in(gateway),
+        rt_genmask: sockaddr_in(Ipv4Addr::UNSPECIFIED),
+        rt_flags: libc::RTF_UP | libc::RTF_GATEWAY,
+        rt_pad2: 0,
+        rt_pad3: 0,
+        rt_pad4: std::ptr::null_mut(),
+        rt_metric: 0,
+        rt_dev: std::ptr::null_mut(),
+        rt_mtu: 0,
+        rt_window: 0,
+        rt_irtt: 0,
+    };
+
+    match ioctl(&fd, libc::SIOCADDRT, &mut rtentry) {
+        Err(e) if e.raw_os_error() != Some(libc::EEXIST) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn in6_ifreq(index: u32, addr: Ipv6Addr, prefix_len: u8) -> libc::in6_ifreq {
+    libc::in6_ifreq {
+        ifr6_addr: libc::in6_addr {
+            s6_addr: addr.octets(),
+        },
+        ifr6_prefixlen: prefix_len as u32,
+        ifr6_ifindex: index as libc::c_int,
+    }
+}
+
+/// Assigns an IPv6 address to the interface. On-link prefixes and the default route come from
+/// router advertisements, which the kernel handles by itself.
+pub fn add_ipv6(index: u32, addr: Ipv6Addr, prefix_len: u8) -> anyhow::Result<()> {
+    let fd = control_socket(libc::AF_INET6)?;
+
+    match ioctl(
+        &fd,
+        libc::SIOCSIFADDR,
+        &mut in6_ifreq(index, addr, prefix_len),
+    ) {
+        Err(e) if e.raw_os_error() != Some(libc::EEXIST) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn remove_ipv6(index: u32, addr: Ipv6Addr, prefix_len: u8) -> anyhow::Result<()> {
+    let fd = control_socket(libc::AF_INET6)?;
+    ioctl(
+        &fd,
+        libc::SIOCDIFADDR,
+        &mut in6_ifreq(index, addr, prefix_len),
+    )?;
+    Ok(())
+}
+
+/// Restricts a socket to sending and receiving on a single interface, which is needed for
+/// broadcasting before the interface has an address.
+pub fn bind_to_device(socket: &impl AsRawFd, name: &str) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes the name resolver use the given DNS servers.
+pub fn write_resolv_conf(nameservers: &[std::net::IpAddr]) -> std::io::Result<()> {
+    let mut contents = String::new();
+    for nameserver in nameservers {
+        contents.push_str(&format!("nameserver {nameserver}\n"));
+    }
+
+    std::fs::create_dir_all("/etc")?;
+    std::fs::write("/etc/resolv.conf", contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(
+            super::parse_mac("52:54:00:12:34:ab"),
+            Some([0x52, 0x54, 0x00, 0x12, 0x34, 0xab])
+        );
+        assert_eq!(super::parse_mac("52:54:00:12:34"), None);
+        assert_eq!(super::parse_mac("52:54:00:12:34:ab:cd"), None);
+    }
+
+    #[test]
+    fn test_netmask() {
+        assert_eq!(super::netmask(24), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(super::netmask(32), Ipv4Addr::BROADCAST);
+        assert_eq!(super::netmask(0), Ipv4Addr::UNSPECIFIED);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+pub mod dhcp;
+pub mod dhcp6;
+pub mod http;
+pub mod iface;
+pub mod tftp;
+
+/// How many HTTP redirects are followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Tftp,
+    Http,
+}
+
+impl Scheme {
+    fn default_port(&self) -> u16 {
+        match self {
+            Self::Tftp => 69,
+            Self::Http => 80,
+        }
+    }
+}
+
+impl Display for Scheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Tftp => "tftp",
+                Self::Http => "http",
+            }
+        )
+    }
+}
+
+/// The subset of URLs that can be fetched during network boot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Url {
+    pub scheme: Scheme,
+    /// A hostname or an IP address, without the brackets around IPv6 addresses.
+    pub host: String,
+    pub port: u16,
+    /// Always starts with a '/'.
+    pub path: String,
+}
+
+impl Url {
+    /// Resolves a reference found in a document fetched from this URL, which may be a full URL, an
+    /// absolute path on the same server or a path relative to this URL.
+    pub fn join(&self, reference: &str) -> anyhow::Result<Url> {
+        if reference.contains("://") {
+            return Url::from_str(reference);
+        }
+
+        let path = if reference.starts_with('/') {
+            reference.to_string()
+        } else {
+            let dir = match self.path.rfind('/') {
+                Some(idx) => &self.path[..=idx],
+                None => "/",
+            };
+            format!("{dir}{reference}")
+        };
+
+        Ok(Url {
+            path,
+            ..self.clone()
+        })
+    }
+
+    fn socket_addr(&self) -> anyhow::Result<SocketAddr> {
+        let Some(addr) = (self.host.as_str(), self.port).to_socket_addrs()?.next() else {
+            anyhow::bail!("'{}' did not resolve to any address", self.host);
+        };
+
+        Ok(addr)
+    }
+}
+
+impl FromStr for Url {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, rest)) = s.split_once("://") else {
+            anyhow::bail!("'{s}' is not a URL");
+        };
+
+        let scheme = match scheme.to_ascii_lowercase().as_str() {
+            "tftp" => Scheme::Tftp,
+            "http" => Scheme::Http,
+            _ => anyhow::bail!("unsupported URL scheme '{scheme}'"),
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+            let Some((host, rest)) = rest.split_once(']') else {
+                anyhow::bail!("unterminated IPv6 address in '{s}'");
+            };
+            (host, rest.strip_prefix(':'))
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        if host.is_empty() {
+            anyhow::bail!("missing host in '{s}'");
+        }
+
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid port in '{s}'"))?,
+            None => scheme.default_port(),
+        };
+
+        Ok(Url {
+            scheme,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://", self.scheme)?;
+
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            write!(f, "{}", self.host)?;
+        }
+
+        if self.port != self.scheme.default_port() {
+            write!(f, ":{}", self.port)?;
+        }
+
+        write!(f, "{}", self.path)
+    }
+}
+
+/// Downloads the resource at the URL, returning the number of bytes written.
+pub fn fetch(url: &Url, out: &mut impl Write) -> anyhow::Result<u64> {
+    let mut url = url.clone();
+
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = url.socket_addr()?;
+
+        match url.scheme {
+            Scheme::Tftp => return tftp::fetch(addr, &url.path, out),
+            Scheme::Http => match http::fetch(&url.host, addr, &url.path, out)? {
+                http::Response::Done(len) => return Ok(len),
+                http::Response::Redirect(location) => {
+                    url = url.join(&location)?;
+                }
+            },
+        }
+    }
+
+    anyhow::bail!("too many redirects")
+}
+
+/// Downloads a small text file, like a boot manifest.
+pub fn fetch_to_string(url: &Url) -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    fetch(url, &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+pub(crate) fn random_u32() -> u32 {
+    let mut buf = [0u8; 4];
+    if let Err(e) = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+        log::debug!("failed to read /dev/urandom: {e}");
+        return std::process::id()
+            ^ std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|now| now.subsec_nanos())
+                .unwrap_or_default();
+    }
+    u32::from_ne_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{Scheme, Url};
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            Url::from_str("tftp://10.0.0.1/boot/linux").unwrap(),
+            Url {
+                scheme: Scheme::Tftp,
+                host: "10.0.0.1".to_string(),
+                port: 69,
+                path: "/boot/linux".to_string(),
+            }
+        );
+        assert_eq!(
+            Url::from_str("HTTP://[fd00::1]:8080").unwrap(),
+            Url {
+                scheme: Scheme::Http,
+                host: "fd00::1".to_string(),
+                port: 8080,
+                path: "/".to_string(),
+            }
+        );
+        assert!(Url::from_str("https://example.com/linux").is_err());
+        assert!(Url::from_str("http://:80/linux").is_err());
+        assert!(Url::from_str("/boot/linux").is_err());
+
+        let url = Url::from_str("http://[fd00::1]:8080/a/b").unwrap();
+        assert_eq!(url.to_string(), "http://[fd00::1]:8080/a/b");
+    }
+
+    #[test]
+    fn test_join_url() {
+        let base = Url::from_str("http://example.com/tboot/boot.conf").unwrap();
+
+        assert_eq!(
+            base.join("linux").unwrap().to_string(),
+            "http://example.com/tboot/linux"
+        );
+        assert_eq!(
+            base.join("/other/initrd").unwrap().to_string(),
+            "http://example.com/other/initrd"
+        );
+        assert_eq!(
+            base.join("tftp://10.0.0.1/linux").unwrap().to_string(),
+            "tftp://10.0.0.1/linux"
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use log::debug;
+use std::{
+    io::{ErrorKind, Write},
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+const OPCODE_OACK: u16 = 6;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Fits in a single ethernet frame once the IPv6, UDP and TFTP headers are added.
+const REQUESTED_BLOCK_SIZE: usize = 1428;
+
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRIES: usize = 5;
+
+fn read_request(path: &str) -> Vec<u8> {
+    let mut packet = OPCODE_RRQ.to_be_bytes().to_vec();
+    for field in [
+        path.trim_start_matches('/'),
+        "octet",
+        "blksize",
+        &REQUESTED_BLOCK_SIZE.to_string(),
+        "tsize",
+        "0",
+    ] {
+        packet.extend(field.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+fn ack(block: u16) -> Vec<u8> {
+    let mut packet = OPCODE_ACK.to_be_bytes().to_vec();
+    packet.extend(block.to_be_bytes());
+    packet
+}
+
+/// Parses the NUL-terminated key/value pairs of an option acknowledgement.
+fn parse_options(buf: &[u8]) -> Vec<(String, String)> {
+    let fields: Vec<String> = buf
+        .split(|&b| b == 0)
+        .map(|field| String::from_utf8_lossy(field).to_ascii_lowercase())
+        .collect();
+
+    fields
+        .chunks_exact(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect()
+}
+
+/// Downloads `path` from the TFTP server listening at `server`, returning the number of bytes
+/// written. The blksize option is requested to speed up the transfer, servers that do not support
+/// options fall back to 512 byte blocks.
+/// https://www.rfc-editor.org/rfc/rfc1350
+/// https://www.rfc-editor.org/rfc/rfc2348
+pub fn fetch(server: SocketAddr, path: &str, out: &mut impl Write) -> anyhow::Result<u64> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(RETRANSMIT_TIMEOUT))?;
+
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut buf = vec![0u8; REQUESTED_BLOCK_SIZE + 4];
+
+    // The server answers from a new port that is used for the rest of the transfer.
+    let mut peer: Option<SocketAddr> = None;
+    let mut last_packet = read_request(path);
+    let mut expected_block: u16 = 1;
+    let mut total: u64 = 0;
+    let mut retries = 0;
+
+    socket.send_to(&last_packet, server)?;
+
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    anyhow::bail!("timed out waiting for {server}");
+                }
+                socket.send_to(&last_packet, peer.unwrap_or(server))?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if from.ip() != server.ip() || peer.is_some_and(|peer| peer != from) || len < 4 {
+            continue;
+        }
+
+        let packet = &buf[..len];
+        let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+
+        match opcode {
+            OPCODE_ERROR => {
+                let message = String::from_utf8_lossy(&packet[4..]);
+                anyhow::bail!(
+                    "server error {}: {}",
+                    u16::from_be_bytes([packet[2], packet[3]]),
+                    message.trim_end_matches('\0')
+                );
+            }
+            OPCODE_OACK if peer.is_none() => {
+                for (key, val) in parse_options(&packet[2..]) {
+                    match key.as_str() {
+                        "blksize" => {
+                            block_size = val.parse().unwrap_or(DEFAULT_BLOCK_SIZE);
+                            if block_size > REQUESTED_BLOCK_SIZE {
+                                anyhow::bail!("server chose a block size of {block_size}");
+                            }
+                        }
+                        "tsize" => debug!("{path} is {val} bytes"),
+                        _ => {}
+                    }
+                }
+
+                peer = Some(from);
+                last_packet = ack(0);
+                socket.send_to(&last_packet, from)?;
+            }
+            OPCODE_DATA => {
+                let block = u16::from_be_bytes([packet[2], packet[3]]);
+                let data = &packet[4..];
+
+                if peer.is_none() {
+                    // the server does not support options
+                    peer = Some(from);
+                }
+
+                if block == expected_block {
+                    out.write_all(data)?;
+                    total += data.len() as u64;
+                    retries = 0;
+                    last_packet = ack(block);
+                    socket.send_to(&last_packet, from)?;
+
+                    if data.len() < block_size {
+                        return Ok(total);
+                    }
+
+                    expected_block = expected_block.wrapping_add(1);
+                } else if block == expected_block.wrapping_sub(1) {
+                    // our ack got lost
+                    socket.send_to(&last_packet, from)?;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+
+    use super::{parse_options, OPCODE_ACK, OPCODE_DATA, OPCODE_ERROR, OPCODE_OACK, OPCODE_RRQ};
+
+    /// Serves a single read request for `contents`, acknowledging the blksize option if
+    /// `block_size` is set.
+    fn serve(contents: Vec<u8>, block_size: Option<usize>) -> SocketAddr {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let (len, client) = listener.recv_from(&mut buf).unwrap();
+            assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), OPCODE_RRQ);
+
+            let fields = parse_options(&buf[2..len]);
+            let filename = &fields[0].0;
+
+            let transfer = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            if filename != "boot/linux" {
+                let mut packet = OPCODE_ERROR.to_be_bytes().to_vec();
+                packet.extend(1u16.to_be_bytes());
+                packet.extend(b"File not found\0");
+                transfer.send_to(&packet, client).unwrap();
+                return;
+            }
+
+            let block_size = match block_size {
+                Some(block_size) => {
+                    let mut packet = OPCODE_OACK.to_be_bytes().to_vec();
+                    packet.extend(format!("blksize\0{block_size}\0").as_bytes());
+                    transfer.send_to(&packet, client).unwrap();
+                    let len = transfer.recv(&mut buf).unwrap();
+                    assert_eq!(&buf[..len], &[0, OPCODE_ACK as u8, 0, 0]);
+                    block_size
+                }
+                None => 512,
+            };
+
+            // a transfer always ends with a block shorter than the block size, even if it is empty
+            let mut blocks: Vec<&[u8]> = contents.chunks(block_size).collect();
+            if blocks.last().is_none_or(|last| last.len() == block_size) {
+                blocks.push(&[]);
+            }
+
+            for (block, chunk) in (1u16..).zip(blocks) {
+                let mut packet = OPCODE_DATA.to_be_bytes().to_vec();
+                packet.extend(block.to_be_bytes());
+                packet.extend(chunk);
+                transfer.send_to(&packet, client).unwrap();
+
+                let len = transfer.recv(&mut buf).unwrap();
+                assert_eq!(u16::from_be_bytes([buf[0], buf[1]]), OPCODE_ACK);
+                assert_eq!(&buf[2..len], block.to_be_bytes());
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_fetch() {
+        let contents: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+
+        let mut out = Vec::new();
+        let len =
+            super::fetch(serve(contents.clone(), Some(1024)), "/boot/linux", &mut out).unwrap();
+        assert_eq!(len, 3000);
+        assert_eq!(out, contents);
+
+        // a multiple of the block size ends with an empty block
+        let contents = vec![0xaa; 1024];
+        let mut out = Vec::new();
+        super::fetch(serve(contents.clone(), None), "/boot/linux", &mut out).unwrap();
+        assert_eq!(out, contents);
+    }
+
+    #[test]
+    fn test_fetch_error() {
+        let mut out = Vec::new();
+        let err = super::fetch(serve(Vec::new(), None), "/missing", &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "server error 1: File not found");
+    }
+}
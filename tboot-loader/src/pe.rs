@@ -0,0 +1,219 @@
+use std::io::{self, Read, Seek, Write};
+
+/// PE/COFF documentation: https://learn.microsoft.com/en-us/windows/win32/debug/pe-format
+mod pe_constants {
+    pub const DOS_MAGIC: &[u8; 2] = b"MZ";
+    pub const DOS_PE_OFFSET_START: u64 = 0x3c;
+
+    pub const PE_MAGIC: &[u8; 4] = b"PE\0\0";
+    pub const COFF_HEADER_LENGTH: usize = 20;
+
+    pub const SECTION_HEADER_LENGTH: usize = 40;
+    pub const SECTION_NAME_LENGTH: usize = 8;
+
+    /// Sanity limit, the PE spec limits the number of sections to 96.
+    pub const MAX_SECTIONS: u16 = 96;
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Section {
+    pub name: String,
+    pub virtual_size: u32,
+    pub size_of_raw_data: u32,
+    pub pointer_to_raw_data: u32,
+}
+
+impl Section {
+    /// The size of the section data in the file. SizeOfRawData is rounded up to the file
+    /// alignment, so VirtualSize is preferred when it is smaller.
+    pub fn len(&self) -> u64 {
+        if self.virtual_size == 0 {
+            self.size_of_raw_data as u64
+        } else {
+            self.virtual_size.min(self.size_of_raw_data) as u64
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PeFile {
+    pub machine: u16,
+    pub sections: Vec<Section>,
+}
+
+impl PeFile {
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses the COFF header and section table of a PE image. Section contents are not read.
+pub fn parse<T>(mut pe: T) -> io::Result<PeFile>
+where
+    T: Read + Seek,
+{
+    let mut dos_magic = [0u8; 2];
+    pe.rewind()?;
+    pe.read_exact(&mut dos_magic)?;
+    if &dos_magic != pe_constants::DOS_MAGIC {
+        return Err(invalid("missing DOS magic"));
+    }
+
+    let mut pe_offset = [0u8; 4];
+    pe.seek(io::SeekFrom::Start(pe_constants::DOS_PE_OFFSET_START))?;
+    pe.read_exact(&mut pe_offset)?;
+    let pe_offset = u32::from_le_bytes(pe_offset) as u64;
+
+    let mut pe_magic = [0u8; 4];
+    pe.seek(io::SeekFrom::Start(pe_offset))?;
+    pe.read_exact(&mut pe_magic)?;
+    if &pe_magic != pe_constants::PE_MAGIC {
+        return Err(invalid("missing PE magic"));
+    }
+
+    let mut coff_header = [0u8; pe_constants::COFF_HEADER_LENGTH];
+    pe.read_exact(&mut coff_header)?;
+
+    let machine = u16::from_le_bytes([coff_header[0], coff_header[1]]);
+    let number_of_sections = u16::from_le_bytes([coff_header[2], coff_header[3]]);
+    let size_of_optional_header = u16::from_le_bytes([coff_header[16], coff_header[17]]);
+
+    if number_of_sections > pe_constants::MAX_SECTIONS {
+        return Err(invalid("too many sections"));
+    }
+
+    pe.seek(io::SeekFrom::Current(size_of_optional_header as i64))?;
+
+    let mut sections = Vec::with_capacity(number_of_sections as usize);
+    for _ in 0..number_of_sections {
+        let mut header = [0u8; pe_constants::SECTION_HEADER_LENGTH];
+        pe.read_exact(&mut header)?;
+
+        let name = &header[..pe_constants::SECTION_NAME_LENGTH];
+        let name = name.split(|byte| *byte == 0).next().unwrap_or_default();
+
+        let u32_at = |start: usize| {
+            u32::from_le_bytes(header[start..start + 4].try_into().expect("4 bytes"))
+        };
+
+        sections.push(Section {
+            name: String::from_utf8_lossy(name).to_string(),
+            virtual_size: u32_at(8),
+            size_of_raw_data: u32_at(16),
+            pointer_to_raw_data: u32_at(20),
+        });
+    }
+
+    Ok(PeFile { machine, sections })
+}
+
+/// Copies the contents of a section into the given writer, returning the number of bytes copied.
+pub fn copy_section<T, W>(mut pe: T, section: &Section, out: &mut W) -> io::Result<u64>
+where
+    T: Read + Seek,
+    W: Write,
+{
+    pe.seek(io::SeekFrom::Start(section.pointer_to_raw_data as u64))?;
+
+    let copied = io::copy(&mut pe.take(section.len()), out)?;
+    if copied != section.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("section {} is truncated", section.name),
+        ));
+    }
+
+    Ok(copied)
+}
+
+/// Reads the contents of a section into memory. Only meant for small sections.
+pub fn read_section<T>(pe: T, section: &Section) -> io::Result<Vec<u8>>
+where
+    T: Read + Seek,
+{
+    let mut buf = Vec::with_capacity(section.len() as usize);
+    copy_section(pe, section, &mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    pub const MACHINE_X64: u16 = 0x8664;
+
+    /// Builds a minimal PE image containing the given sections.
+    pub fn build_pe(machine: u16, sections: &[(&str, &[u8])]) -> Vec<u8> {
+        const PE_OFFSET: usize = 0x40;
+        const OPTIONAL_HEADER_LENGTH: usize = 0xf0;
+
+        let mut pe = vec![0u8; PE_OFFSET];
+        pe[0..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&(PE_OFFSET as u32).to_le_bytes());
+
+        pe.extend(b"PE\0\0");
+        pe.extend(machine.to_le_bytes());
+        pe.extend((sections.len() as u16).to_le_bytes());
+        pe.extend([0u8; 12]);
+        pe.extend((OPTIONAL_HEADER_LENGTH as u16).to_le_bytes());
+        pe.extend([0u8; 2]);
+        pe.extend([0u8; OPTIONAL_HEADER_LENGTH]);
+
+        let mut data_offset = (pe.len() + sections.len() * 40 + 511) & !511;
+        for (name, data) in sections {
+            let mut header = [0u8; 40];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            // pad raw data size like a real file alignment would
+            header[16..20].copy_from_slice(&((data.len() as u32 + 511) & !511).to_le_bytes());
+            header[20..24].copy_from_slice(&(data_offset as u32).to_le_bytes());
+            pe.extend(header);
+            data_offset += (data.len() + 511) & !511;
+        }
+
+        for (_, data) in sections {
+            pe.resize((pe.len() + 511) & !511, 0);
+            pe.extend(*data);
+            pe.resize((pe.len() + 511) & !511, 0);
+        }
+
+        pe
+    }
+
+    #[test]
+    fn parse_sections() {
+        let pe = build_pe(
+            MACHINE_X64,
+            &[(".osrel", b"ID=nixos\n"), (".linux", b"kernel")],
+        );
+
+        let parsed = super::parse(Cursor::new(&pe)).unwrap();
+        assert_eq!(parsed.machine, MACHINE_X64);
+        assert_eq!(parsed.sections.len(), 2);
+
+        let osrel = parsed.section(".osrel").unwrap();
+        assert_eq!(osrel.len(), 9);
+        assert_eq!(
+            super::read_section(Cursor::new(&pe), osrel).unwrap(),
+            b"ID=nixos\n"
+        );
+
+        let linux = parsed.section(".linux").unwrap();
+        assert_eq!(
+            super::read_section(Cursor::new(&pe), linux).unwrap(),
+            b"kernel"
+        );
+
+        assert!(parsed.section(".initrd").is_none());
+    }
+
+    #[test]
+    fn fail_to_parse_non_pe() {
+        assert!(super::parse(Cursor::new(&[0u8; 512])).is_err());
+        assert!(super::parse(Cursor::new(b"MZ")).is_err());
+    }
+}
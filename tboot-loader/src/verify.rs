@@ -0,0 +1,559 @@
+use std::{
+    ffi::CString,
+    io::{self, Read, Seek},
+    os::fd::AsRawFd,
+};
+
+use log::{debug, trace};
+use nix::libc;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use syscalls::{syscall, Sysno};
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/keyctl.h
+const KEYCTL_DESCRIBE: usize = 6;
+const KEYCTL_READ: usize = 11;
+const KEYCTL_PKEY_VERIFY: usize = 28;
+
+// https://github.com/torvalds/linux/blob/master/include/linux/module_signature.h
+const MODULE_SIG_MAGIC: &[u8] = b"~Module signature appended~\n";
+const MODULE_SIGNATURE_LENGTH: usize = 12;
+const PKEY_ID_PKCS7: u8 = 2;
+
+// https://github.com/torvalds/linux/blob/master/security/integrity/integrity.h
+pub const IMA_XATTR_NAME: &str = "security.ima";
+const EVM_IMA_XATTR_DIGSIG: u8 = 3;
+const DIGSIG_VERSION_2: u8 = 2;
+
+mod der {
+    pub const INTEGER: u8 = 0x02;
+    pub const OCTET_STRING: u8 = 0x04;
+    pub const OID: u8 = 0x06;
+    pub const SEQUENCE: u8 = 0x30;
+    pub const SET: u8 = 0x31;
+    pub const CONTEXT_0: u8 = 0xa0;
+    pub const CONTEXT_0_PRIMITIVE: u8 = 0x80;
+
+    /// 1.2.840.113549.1.7.2
+    pub const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+    /// 2.16.840.1.101.3.4.2.1
+    pub const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+    /// 2.16.840.1.101.3.4.2.2
+    pub const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+    /// 2.16.840.1.101.3.4.2.3
+    pub const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+    /// A minimal DER reader, only capable of walking far enough into a PKCS#7 message to find the
+    /// signature.
+    pub struct Reader<'a>(pub &'a [u8]);
+
+    impl<'a> Reader<'a> {
+        pub fn next(&mut self) -> anyhow::Result<(u8, &'a [u8])> {
+            let [tag, len, rest @ ..] = self.0 else {
+                anyhow::bail!("truncated DER element");
+            };
+
+            let (len, rest) = if len & 0x80 == 0 {
+                (*len as usize, rest)
+            } else {
+                let num_bytes = (len & 0x7f) as usize;
+                if num_bytes == 0 || num_bytes > 4 || rest.len() < num_bytes {
+                    anyhow::bail!("invalid DER length");
+                }
+                let len = rest[..num_bytes]
+                    .iter()
+                    .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+                (len, &rest[num_bytes..])
+            };
+
+            if rest.len() < len {
+                anyhow::bail!("truncated DER element");
+            }
+
+            self.0 = &rest[len..];
+
+            Ok((*tag, &rest[..len]))
+        }
+
+        pub fn expect(&mut self, expected_tag: u8) -> anyhow::Result<&'a [u8]> {
+            let (tag, contents) = self.next()?;
+            if tag != expected_tag {
+                anyhow::bail!(
+                    "unexpected DER tag {:#x}, expected {:#x}",
+                    tag,
+                    expected_tag
+                );
+            }
+            Ok(contents)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashAlgo {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgo {
+    fn from_oid(oid: &[u8]) -> Option<Self> {
+        Some(match oid {
+            der::OID_SHA256 => Self::Sha256,
+            der::OID_SHA384 => Self::Sha384,
+            der::OID_SHA512 => Self::Sha512,
+            _ => return None,
+        })
+    }
+
+    /// https://github.com/torvalds/linux/blob/master/include/uapi/linux/hash_info.h
+    fn from_hash_info(id: u8) -> Option<Self> {
+        Some(match id {
+            4 => Self::Sha256,
+            5 => Self::Sha384,
+            6 => Self::Sha512,
+            _ => return None,
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha384 => "sha384",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// Hashes the first `len` bytes read from `reader`.
+    fn digest(&self, reader: impl Read, len: u64) -> io::Result<Vec<u8>> {
+        fn digest<D: Digest>(reader: impl Read, len: u64) -> io::Result<Vec<u8>> {
+            let mut hasher = D::new();
+            let mut reader = reader.take(len);
+            let mut buf = [0u8; 1 << 16];
+            let mut hashed = 0u64;
+
+            loop {
+                let bytes_read = reader.read(&mut buf)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..bytes_read]);
+                hashed += bytes_read as u64;
+            }
+
+            if hashed != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            Ok(hasher.finalize().to_vec())
+        }
+
+        match self {
+            Self::Sha256 => digest::<Sha256>(reader, len),
+            Self::Sha384 => digest::<Sha384>(reader, len),
+            Self::Sha512 => digest::<Sha512>(reader, len),
+        }
+    }
+}
+
+/// Returns the length of the file contents that precede an appended module signature (as created
+/// by the kernel's sign-file), along with the PKCS#7 signature itself. Returns None if no
+/// signature is appended.
+fn read_modsig<T>(mut file: T) -> anyhow::Result<Option<(u64, Vec<u8>)>>
+where
+    T: Read + Seek,
+{
+    let len = file.seek(io::SeekFrom::End(0))?;
+
+    let trailer_len = (MODULE_SIG_MAGIC.len() + MODULE_SIGNATURE_LENGTH) as u64;
+    if len < trailer_len {
+        return Ok(None);
+    }
+
+    let mut trailer = [0u8; MODULE_SIG_MAGIC.len() + MODULE_SIGNATURE_LENGTH];
+    file.seek(io::SeekFrom::End(-(trailer_len as i64)))?;
+    file.read_exact(&mut trailer)?;
+
+    let (module_signature, magic) = trailer.split_at(MODULE_SIGNATURE_LENGTH);
+    if magic != MODULE_SIG_MAGIC {
+        return Ok(None);
+    }
+
+    // struct module_signature { algo, hash, id_type, signer_len, key_id_len, __pad[3], sig_len }
+    if module_signature[2] != PKEY_ID_PKCS7 {
+        anyhow::bail!("appended signature is not PKCS#7");
+    }
+
+    let sig_len = u32::from_be_bytes(module_signature[8..12].try_into().expect("4 bytes")) as u64;
+    let Some(signed_len) = len.checked_sub(trailer_len + sig_len) else {
+        anyhow::bail!("appended signature is larger than the file");
+    };
+
+    let mut pkcs7 = vec![0u8; sig_len as usize];
+    file.seek(io::SeekFrom::Start(signed_len))?;
+    file.read_exact(&mut pkcs7)?;
+
+    Ok(Some((signed_len, pkcs7)))
+}
+
+/// Walks into a PKCS#7 message up to the identifier of the first signer.
+fn pkcs7_signer_info(pkcs7: &[u8]) -> anyhow::Result<der::Reader<'_>> {
+    let mut content_info = der::Reader(der::Reader(pkcs7).expect(der::SEQUENCE)?);
+    if content_info.expect(der::OID)? != der::OID_SIGNED_DATA {
+        anyhow::bail!("PKCS#7 message does not contain signed data");
+    }
+
+    let mut signed_data =
+        der::Reader(der::Reader(content_info.expect(der::CONTEXT_0)?).expect(der::SEQUENCE)?);
+    signed_data.expect(der::INTEGER)?; // version
+    signed_data.expect(der::SET)?; // digestAlgorithms
+    signed_data.expect(der::SEQUENCE)?; // contentInfo
+
+    // skip over optional certificates and crls
+    let signer_infos = loop {
+        let (tag, contents) = signed_data.next()?;
+        if tag == der::SET {
+            break contents;
+        }
+    };
+
+    let mut signer_info = der::Reader(der::Reader(signer_infos).expect(der::SEQUENCE)?);
+    signer_info.expect(der::INTEGER)?; // version
+
+    Ok(signer_info)
+}
+
+/// Pulls the digest algorithm and signature out of a detached PKCS#7 message. Only messages
+/// without authenticated attributes are supported, which is what sign-file produces.
+fn parse_pkcs7(pkcs7: &[u8]) -> anyhow::Result<(HashAlgo, &[u8])> {
+    let mut signer_info = pkcs7_signer_info(pkcs7)?;
+    signer_info.next()?; // issuerAndSerialNumber or subjectKeyIdentifier
+
+    let digest_algorithm = der::Reader(signer_info.expect(der::SEQUENCE)?).expect(der::OID)?;
+    let hash_algo = HashAlgo::from_oid(digest_algorithm)
+        .ok_or(anyhow::anyhow!("unsupported PKCS#7 digest algorithm"))?;
+
+    let (tag, _) = signer_info.next()?; // digestEncryptionAlgorithm
+    if tag == der::CONTEXT_0 {
+        anyhow::bail!("PKCS#7 authenticated attributes are not supported");
+    }
+
+    let signature = signer_info.expect(der::OCTET_STRING)?;
+
+    Ok((hash_algo, signature))
+}
+
+/// Returns the subject key identifier of the key that made a PKCS#7 signature, if the signer is
+/// identified that way and not by issuer and serial number.
+fn pkcs7_signer_skid(pkcs7: &[u8]) -> anyhow::Result<Option<&[u8]>> {
+    let (tag, sid) = pkcs7_signer_info(pkcs7)?.next()?;
+    Ok((tag == der::CONTEXT_0_PRIMITIVE).then_some(sid))
+}
+
+struct ImaSignature {
+    hash_algo: HashAlgo,
+    /// The last 4 bytes of the subject key identifier of the key that made the signature.
+    keyid: [u8; 4],
+    sig: Vec<u8>,
+}
+
+/// Reads a version 2 IMA signature from the "security.ima" xattr of the file, if present.
+fn read_imasig(file: &impl AsRawFd) -> anyhow::Result<Option<ImaSignature>> {
+    let name = CString::new(IMA_XATTR_NAME)?;
+    let mut xattr = [0u8; 1024];
+
+    let len = unsafe {
+        libc::fgetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            xattr.as_mut_ptr() as *mut libc::c_void,
+            xattr.len(),
+        )
+    };
+
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(err.into()),
+        };
+    }
+
+    // struct signature_v2_hdr { type, version, hash_algo, keyid[4], sig_size[2], sig[] }
+    let Some(
+        [EVM_IMA_XATTR_DIGSIG, DIGSIG_VERSION_2, hash_algo, k0, k1, k2, k3, sig_size_hi, sig_size_lo, sig @ ..],
+    ) = xattr.get(..len as usize)
+    else {
+        // IMA hashes without a signature cannot be verified
+        return Ok(None);
+    };
+
+    let hash_algo = HashAlgo::from_hash_info(*hash_algo)
+        .ok_or(anyhow::anyhow!("unsupported IMA signature hash algorithm"))?;
+
+    let sig_size = u16::from_be_bytes([*sig_size_hi, *sig_size_lo]) as usize;
+    let sig = sig
+        .get(..sig_size)
+        .ok_or(anyhow::anyhow!("truncated IMA signature"))?;
+
+    Ok(Some(ImaSignature {
+        hash_algo,
+        keyid: [*k0, *k1, *k2, *k3],
+        sig: sig.to_vec(),
+    }))
+}
+
+#[repr(C)]
+struct KeyctlPkeyParams {
+    key_id: i32,
+    in_len: u32,
+    in2_len: u32,
+    __spare: [u32; 7],
+}
+
+fn keyring_keys(keyring_id: i32) -> anyhow::Result<Vec<i32>> {
+    let mut keys = [0i32; 64];
+
+    let len = unsafe {
+        syscall!(
+            Sysno::keyctl,
+            KEYCTL_READ,
+            keyring_id,
+            keys.as_mut_ptr(),
+            std::mem::size_of_val(&keys)
+        )?
+    };
+
+    let num_keys = (len / std::mem::size_of::<i32>()).min(keys.len());
+
+    Ok(keys[..num_keys].to_vec())
+}
+
+/// The kernel describes X.509 keys as "<subject>: <subject key identifier in hex>", and
+/// KEYCTL_DESCRIBE puts the type, uid, gid and permissions in front of that.
+fn skid_from_description(description: &str) -> Option<Vec<u8>> {
+    let (_, skid) = description.rsplit_once(": ")?;
+    if skid.is_empty() || skid.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..skid.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(skid.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn key_skid(key_id: i32) -> Option<Vec<u8>> {
+    let mut description = [0u8; 512];
+
+    let len = unsafe {
+        syscall!(
+            Sysno::keyctl,
+            KEYCTL_DESCRIBE,
+            key_id,
+            description.as_mut_ptr(),
+            description.len()
+        )
+    }
+    .ok()?;
+
+    let description = description.get(..len.min(description.len()))?;
+    skid_from_description(
+        std::str::from_utf8(description)
+            .ok()?
+            .trim_end_matches('\0'),
+    )
+}
+
+/// Whether the file was signed by a key in the given keyring, judged only by the key identifier
+/// in its signature. Returns None if the file is not signed or its signature does not say which
+/// key made it.
+pub fn is_signer_known(file: &mut std::fs::File, keyring_id: i32) -> anyhow::Result<Option<bool>> {
+    let skids = keyring_keys(keyring_id)?
+        .into_iter()
+        .filter_map(key_skid)
+        .collect::<Vec<_>>();
+
+    if let Some((_, pkcs7)) = read_modsig(&mut *file)? {
+        return Ok(pkcs7_signer_skid(&pkcs7)?.map(|skid| skids.iter().any(|known| known == skid)));
+    }
+
+    if let Some(imasig) = read_imasig(file)? {
+        return Ok(Some(
+            skids.iter().any(|known| known.ends_with(&imasig.keyid)),
+        ));
+    }
+
+    Ok(None)
+}
+
+fn pkey_verify(key_id: i32, hash_algo: HashAlgo, digest: &[u8], sig: &[u8]) -> io::Result<()> {
+    let info = CString::new(format!("enc=pkcs1 hash={}", hash_algo.as_str()))?;
+
+    let params = KeyctlPkeyParams {
+        key_id,
+        in_len: digest.len() as u32,
+        in2_len: sig.len() as u32,
+        __spare: [0; 7],
+    };
+
+    unsafe {
+        syscall!(
+            Sysno::keyctl,
+            KEYCTL_PKEY_VERIFY,
+            &params as *const KeyctlPkeyParams,
+            info.as_ptr(),
+            digest.as_ptr(),
+            sig.as_ptr()
+        )?
+    };
+
+    Ok(())
+}
+
+/// Why a file did not pass appraise().
+#[derive(Debug, PartialEq, Eq)]
+pub enum AppraiseError {
+    NotSigned,
+    /// No key in the keyring verifies the signature.
+    Rejected,
+}
+
+impl std::fmt::Display for AppraiseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotSigned => write!(f, "file is not signed"),
+            Self::Rejected => write!(f, "signature was rejected by all keys in the keyring"),
+        }
+    }
+}
+
+impl std::error::Error for AppraiseError {}
+
+/// Verifies the signature of a file against the keys in the given keyring, the same way the
+/// kernel appraises files passed to kexec_file_load with the "imasig|modsig" appraise type. This
+/// is used for files that are not passed to the kernel as-is. Returns the length of the signed
+/// file contents, which excludes an appended signature.
+pub fn appraise(file: &mut std::fs::File, keyring_id: i32) -> anyhow::Result<u64> {
+    let (hash_algo, digest, sig, signed_len) =
+        if let Some((signed_len, pkcs7)) = read_modsig(&mut *file)? {
+            trace!("found appended signature");
+            let (hash_algo, sig) = parse_pkcs7(&pkcs7)?;
+            file.rewind()?;
+            let digest = hash_algo.digest(&mut *file, signed_len)?;
+            (hash_algo, digest, sig.to_vec(), signed_len)
+        } else if let Some(ImaSignature { hash_algo, sig, .. }) = read_imasig(file)? {
+            trace!("found IMA signature xattr");
+            let len = file.seek(io::SeekFrom::End(0))?;
+            file.rewind()?;
+            let digest = hash_algo.digest(&mut *file, len)?;
+            (hash_algo, digest, sig, len)
+        } else {
+            return Err(AppraiseError::NotSigned.into());
+        };
+
+    for key_id in keyring_keys(keyring_id)? {
+        match pkey_verify(key_id, hash_algo, &digest, &sig) {
+            Ok(()) => {
+                debug!("signature verified with key {}", key_id);
+                return Ok(signed_len);
+            }
+            Err(e) => trace!("signature not verified with key {}: {e}", key_id),
+        }
+    }
+
+    Err(AppraiseError::Rejected.into())
+}
+
+/// Returns the length of the file contents that precede an appended signature, or the length of
+/// the entire file if there is no appended signature.
+pub fn unsigned_len<T>(mut file: T) -> anyhow::Result<u64>
+where
+    T: Read + Seek,
+{
+    match read_modsig(&mut file)? {
+        Some((signed_len, _)) => Ok(signed_len),
+        None => Ok(file.seek(io::SeekFrom::End(0))?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    /// Contents of the signed file used to create PKCS7
+    const CONTENT: &[u8] = b"hello initrd\n";
+
+    /// Created with `openssl cms -sign -in content -signer test/keys/tboot/key.crt -inkey
+    /// test/keys/tboot/key.pem -outform DER -noattr -binary -nocerts -md sha256`
+    const PKCS7: &[u8] = include_bytes!("../testdata/initrd.p7");
+
+    fn with_modsig(content: &[u8], pkcs7: &[u8]) -> Vec<u8> {
+        let mut file = content.to_vec();
+        file.extend(pkcs7);
+        file.extend([0, 0, super::PKEY_ID_PKCS7, 0, 0, 0, 0, 0]);
+        file.extend((pkcs7.len() as u32).to_be_bytes());
+        file.extend(super::MODULE_SIG_MAGIC);
+        file
+    }
+
+    #[test]
+    fn read_modsig() {
+        let file = with_modsig(CONTENT, PKCS7);
+
+        let (signed_len, pkcs7) = super::read_modsig(Cursor::new(&file)).unwrap().unwrap();
+        assert_eq!(signed_len, CONTENT.len() as u64);
+        assert_eq!(pkcs7, PKCS7);
+
+        assert_eq!(
+            super::unsigned_len(Cursor::new(&file)).unwrap(),
+            CONTENT.len() as u64
+        );
+    }
+
+    #[test]
+    fn read_no_modsig() {
+        assert!(super::read_modsig(Cursor::new(CONTENT)).unwrap().is_none());
+        assert_eq!(
+            super::unsigned_len(Cursor::new(CONTENT)).unwrap(),
+            CONTENT.len() as u64
+        );
+    }
+
+    #[test]
+    fn parse_pkcs7() {
+        let (hash_algo, sig) = super::parse_pkcs7(PKCS7).unwrap();
+        assert_eq!(hash_algo, super::HashAlgo::Sha256);
+        // 4096 bit RSA key, the encrypted digest is the last element of the message
+        assert_eq!(sig.len(), 512);
+        assert_eq!(sig, &PKCS7[PKCS7.len() - 512..]);
+    }
+
+    #[test]
+    fn pkcs7_signer_skid() {
+        // signed by issuer and serial number
+        assert_eq!(super::pkcs7_signer_skid(PKCS7).unwrap(), None);
+    }
+
+    #[test]
+    fn skid_from_description() {
+        assert_eq!(
+            super::skid_from_description("asymmetric;0;0;3f010000;tinyboot: 0a1b2cff"),
+            Some(vec![0x0a, 0x1b, 0x2c, 0xff])
+        );
+        assert_eq!(
+            super::skid_from_description("asymmetric;0;0;3f010000;tinyboot: 0a1b2"),
+            None
+        );
+        assert_eq!(
+            super::skid_from_description("keyring;0;0;3f010000;_ima"),
+            None
+        );
+    }
+
+    #[test]
+    fn fail_to_parse_invalid_pkcs7() {
+        assert!(super::parse_pkcs7(&[]).is_err());
+        assert!(super::parse_pkcs7(&PKCS7[..PKCS7.len() - 1]).is_err());
+        assert!(super::parse_pkcs7(&[0x30, 0x03, 0x06, 0x01, 0x00]).is_err());
+    }
+}
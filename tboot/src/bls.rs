@@ -1,8 +1,9 @@
-use std::fmt::Display;
+use std::{cmp::Ordering, fmt::Display};
 
 #[derive(Debug, PartialEq)]
 pub enum BlsEntryError {
     MissingConfSuffix,
+    MissingEfiSuffix,
     InvalidTriesSyntax,
     MissingFileName,
 }
@@ -13,6 +14,10 @@ impl Display for BlsEntryError {
     }
 }
 
+/// Where the id of an entry to boot exactly once is kept, relative to the ESP. The entry takes
+/// precedence over the default entry and the file is removed before booting.
+pub const ONESHOT_ENTRY_PATH: &str = "loader/tboot/oneshot-entry";
+
 pub type BlsEntryMetadata = (String, Option<u32>, Option<u32>);
 
 /// Parses an entry filename and returns a tuple of the form (entry name, tries done, tries left).
@@ -23,6 +28,21 @@ pub fn parse_entry_filename(filename: &str) -> Result<BlsEntryMetadata, BlsEntry
         .strip_suffix(".conf")
         .ok_or(BlsEntryError::MissingConfSuffix)?;
 
+    parse_counted_name(filename)
+}
+
+/// Parses the filename of a Type #2 entry (a unified kernel image under /EFI/Linux) and returns
+/// the same tuple as `parse_entry_filename`.
+/// https://uapi-group.org/specifications/specs/boot_loader_specification/#type-2-efi-unified-kernel-images
+pub fn parse_uki_filename(filename: &str) -> Result<BlsEntryMetadata, BlsEntryError> {
+    let filename = filename
+        .strip_suffix(".efi")
+        .ok_or(BlsEntryError::MissingEfiSuffix)?;
+
+    parse_counted_name(filename)
+}
+
+fn parse_counted_name(filename: &str) -> Result<BlsEntryMetadata, BlsEntryError> {
     match filename.split_once('+') {
         None => Ok((filename.to_string(), None, None)),
         Some((name, counter_info)) => match counter_info.split_once('-') {
@@ -42,6 +62,108 @@ pub fn parse_entry_filename(filename: &str) -> Result<BlsEntryMetadata, BlsEntry
     }
 }
 
+fn is_valid_version_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, b'~' | b'-' | b'^' | b'.')
+}
+
+/// Compares two version strings using the algorithm from the UAPI Version Format Specification,
+/// which is also used by the BootLoaderSpec to order entries (e.g. "6.10" is newer than "6.9").
+/// https://uapi-group.org/specifications/specs/version_format_specification/
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+
+    // Compares whether each string is prefixed by the separator. The string that is prefixed by
+    // the separator is older.
+    fn cmp_prefix(a: &[u8], b: &[u8], separator: u8) -> Ordering {
+        (a.first() != Some(&separator)).cmp(&(b.first() != Some(&separator)))
+    }
+
+    loop {
+        // drop leading invalid characters
+        while a.first().is_some_and(|c| !is_valid_version_char(*c)) {
+            a = &a[1..];
+        }
+        while b.first().is_some_and(|c| !is_valid_version_char(*c)) {
+            b = &b[1..];
+        }
+
+        // '~' is used for pre-releases, e.g. 123~rc1
+        if a.first() == Some(&b'~') || b.first() == Some(&b'~') {
+            let ord = cmp_prefix(a, b, b'~');
+            if ord.is_ne() {
+                return ord;
+            }
+            (a, b) = (&a[1..], &b[1..]);
+        }
+
+        // except for '~' prefixed segments, a string with more segments is newer
+        if a.is_empty() || b.is_empty() {
+            return a.cmp(b);
+        }
+
+        // '-' separates version and release, '^' is used for patched releases, '.' is used for
+        // point releases
+        if let Some(separator) = [b'-', b'^', b'.']
+            .into_iter()
+            .find(|separator| a.first() == Some(separator) || b.first() == Some(separator))
+        {
+            let ord = cmp_prefix(a, b, separator);
+            if ord.is_ne() {
+                return ord;
+            }
+            (a, b) = (&a[1..], &b[1..]);
+
+            // the separator may be followed by a '~' or another separator
+            continue;
+        }
+
+        let (a_segment, b_segment);
+        let (a_is_numeric, b_is_numeric) = (
+            a.first().is_some_and(u8::is_ascii_digit),
+            b.first().is_some_and(u8::is_ascii_digit),
+        );
+        if a_is_numeric || b_is_numeric {
+            // a numeric segment is newer than a non-numeric one, this has to be checked before
+            // leading zeros are dropped, as "0" would be left empty
+            let ord = a_is_numeric.cmp(&b_is_numeric);
+            if ord.is_ne() {
+                return ord;
+            }
+
+            // leading zeros are ignored
+            while a.first() == Some(&b'0') {
+                a = &a[1..];
+            }
+            while b.first() == Some(&b'0') {
+                b = &b[1..];
+            }
+
+            a_segment = &a[..a.iter().take_while(|c| c.is_ascii_digit()).count()];
+            b_segment = &b[..b.iter().take_while(|c| c.is_ascii_digit()).count()];
+
+            // compare numbers by length and value
+            let ord = a_segment
+                .len()
+                .cmp(&b_segment.len())
+                .then(a_segment.cmp(b_segment));
+            if ord.is_ne() {
+                return ord;
+            }
+        } else {
+            a_segment = &a[..a.iter().take_while(|c| c.is_ascii_alphabetic()).count()];
+            b_segment = &b[..b.iter().take_while(|c| c.is_ascii_alphabetic()).count()];
+
+            // the longer string is newer, e.g. abc vs abcd
+            let ord = a_segment.cmp(b_segment);
+            if ord.is_ne() {
+                return ord;
+            }
+        }
+
+        (a, b) = (&a[a_segment.len()..], &b[b_segment.len()..]);
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -95,4 +217,112 @@ mod tests {
             Ok(("my-entry-3".to_string(), Some(2), None))
         );
     }
+
+    #[test]
+    fn test_parse_uki_filename() {
+        // error cases
+        assert_eq!(
+            super::parse_uki_filename("my-uki.conf"),
+            Err(super::BlsEntryError::MissingEfiSuffix)
+        );
+        assert_eq!(
+            super::parse_uki_filename("my-uki+foo.efi"),
+            Err(super::BlsEntryError::InvalidTriesSyntax)
+        );
+
+        // happy path
+        assert_eq!(
+            super::parse_uki_filename("my-uki.efi"),
+            Ok(("my-uki".to_string(), None, None))
+        );
+        assert_eq!(
+            super::parse_uki_filename("my-uki+3-0.efi"),
+            Ok(("my-uki".to_string(), Some(3), Some(0)))
+        );
+        assert_eq!(
+            super::parse_uki_filename("my-uki-6.1.27+2.efi"),
+            Ok(("my-uki-6.1.27".to_string(), Some(2), None))
+        );
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        // taken from systemd's test-string-util.c, each version is newer than the previous one
+        const VERSIONS: &[&str] = &[
+            "~1",
+            "",
+            "ab",
+            "abb",
+            "abc",
+            "0001",
+            "002",
+            "12",
+            "122",
+            "122.9",
+            "123~rc1",
+            "123",
+            "123-a",
+            "123-a.1",
+            "123-a1",
+            "123-a1.1",
+            "123-3",
+            "123-3.1",
+            "123^patch1",
+            "123^1",
+            "123.a-1",
+            "123.1-1",
+            "123a-1",
+            "124",
+        ];
+
+        for (i, a) in VERSIONS.iter().enumerate() {
+            assert_eq!(super::compare_versions(a, a), std::cmp::Ordering::Equal);
+
+            for b in &VERSIONS[i + 1..] {
+                assert_eq!(
+                    super::compare_versions(a, b),
+                    std::cmp::Ordering::Less,
+                    "{a} < {b}"
+                );
+                assert_eq!(
+                    super::compare_versions(b, a),
+                    std::cmp::Ordering::Greater,
+                    "{b} > {a}"
+                );
+            }
+        }
+
+        assert_eq!(
+            super::compare_versions("6.9", "6.10"),
+            std::cmp::Ordering::Less
+        );
+        assert_eq!(
+            super::compare_versions("1.0", "1.00"),
+            std::cmp::Ordering::Equal
+        );
+
+        // a numeric segment is newer than a non-numeric one, even when it is all zeros
+        assert_eq!(
+            super::compare_versions("0", "a"),
+            std::cmp::Ordering::Greater
+        );
+        assert_eq!(
+            super::compare_versions("1.00", "1.a"),
+            std::cmp::Ordering::Greater
+        );
+
+        // a '~' after a separator is still a pre-release
+        assert_eq!(
+            super::compare_versions("1.~rc", "1."),
+            std::cmp::Ordering::Less
+        );
+        assert_eq!(
+            super::compare_versions("1.~rc", "1.0"),
+            std::cmp::Ordering::Less
+        );
+        assert_eq!(
+            super::compare_versions("1-~rc", "1-1"),
+            std::cmp::Ordering::Less
+        );
+    }
 }
@@ -2,19 +2,65 @@ use std::{collections::HashMap, str::FromStr};
 
 use log::LevelFilter;
 
+/// How the next kernel is loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KexecMethod {
+    /// Use kexec_file_load(), and kexec_load() for what it cannot do.
+    #[default]
+    Auto,
+    /// Only use kexec_file_load(), which lets the kernel verify what it loads.
+    File,
+    /// Only use kexec_load(), building the segments of the next kernel ourselves.
+    Segments,
+}
+
+impl std::fmt::Display for KexecMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Auto => "auto",
+                Self::File => "file",
+                Self::Segments => "segments",
+            }
+        )
+    }
+}
+
+impl FromStr for KexecMethod {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "file" => Ok(Self::File),
+            "segments" => Ok(Self::Segments),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config<'a> {
     pub log_level: LevelFilter,
     pub tty: &'a str,
     pub programmer: &'a str,
+    /// Offset into /dev/nvram where the boot order can be stored.
+    pub bootorder_cmos: Option<u64>,
+    pub kexec: KexecMethod,
+    /// Kernels larger than this are refused before they are read into memory.
+    pub max_kernel_size: Option<u64>,
+    /// Limit on the size of all initrds of an entry combined.
+    pub max_initrd_size: Option<u64>,
 }
 
 impl std::fmt::Display for Config<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "log level: {}, tty: {}, programmer: {}",
-            self.log_level, self.tty, self.programmer
+            "log level: {}, tty: {}, programmer: {}, kexec: {}",
+            self.log_level, self.tty, self.programmer, self.kexec
         )
     }
 }
@@ -25,10 +71,27 @@ impl Default for Config<'_> {
             log_level: LevelFilter::Info,
             tty: "tty1",
             programmer: "internal",
+            bootorder_cmos: None,
+            kexec: KexecMethod::default(),
+            max_kernel_size: None,
+            max_initrd_size: None,
         }
     }
 }
 
+/// Parses a size in bytes with an optional K, M or G suffix, like the kernel does for its own
+/// command line.
+fn parse_size(s: &str) -> Option<u64> {
+    let (num, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
 impl<'a> Config<'a> {
     pub fn from_args(args: &'a [String]) -> Self {
         let mut map = args
@@ -72,6 +135,29 @@ impl<'a> Config<'a> {
             }
         }
 
+        if let Some(bootorder_cmos) = map.remove("bootorder-cmos") {
+            cfg.bootorder_cmos = bootorder_cmos
+                .first()
+                .and_then(|offset| offset.parse().ok());
+        }
+
+        if let Some(kexec) = map.remove("kexec").and_then(|kexec| {
+            kexec
+                .into_iter()
+                .next()
+                .and_then(|kexec| KexecMethod::from_str(kexec).ok())
+        }) {
+            cfg.kexec = kexec;
+        }
+
+        if let Some(max_kernel_size) = map.remove("max-kernel-size") {
+            cfg.max_kernel_size = max_kernel_size.first().and_then(|size| parse_size(size));
+        }
+
+        if let Some(max_initrd_size) = map.remove("max-initrd-size") {
+            cfg.max_initrd_size = max_initrd_size.first().and_then(|size| parse_size(size));
+        }
+
         cfg
     }
 }
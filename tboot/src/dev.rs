@@ -3,7 +3,10 @@ use std::{
     ffi::CString,
     path::PathBuf,
     str::FromStr,
-    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
     time::Duration,
 };
 
@@ -11,7 +14,51 @@ use log::{debug, error, warn};
 use netlink_sys::{protocols::NETLINK_KOBJECT_UEVENT, Socket, SocketAddr};
 use nix::libc;
 
-pub fn listen_and_create_devices(tx: Sender<()>) -> std::io::Result<()> {
+/// A disk that was plugged in or removed, by its diskseq. Partitions being added are reported as
+/// their disk being added, so that a disk can be probed again once all of its partitions are
+/// there. Partitions being removed are reported as their disk being removed and added again, so
+/// that a disk that is still there is probed again without them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskEvent {
+    Added(u64),
+    Removed(u64),
+}
+
+static DISK_EVENT_SUBSCRIBERS: Mutex<Vec<Sender<DiskEvent>>> = Mutex::new(Vec::new());
+
+/// Receives the disk events from listen_and_create_devices that happen from now on.
+pub fn subscribe_disk_events() -> Receiver<DiskEvent> {
+    let (tx, rx) = mpsc::channel();
+    DISK_EVENT_SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+fn publish_disk_event(event: DiskEvent) {
+    DISK_EVENT_SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|tx| tx.send(event).is_ok());
+}
+
+/// Collects the disk events following the first one until none have arrived for `settle`, since
+/// a new disk is followed by one event for each of its partitions.
+pub fn settle_disk_events(
+    first: DiskEvent,
+    rx: &Receiver<DiskEvent>,
+    settle: Duration,
+) -> Vec<DiskEvent> {
+    let mut events = vec![first];
+
+    while let Ok(event) = rx.recv_timeout(settle) {
+        if !events.contains(&event) {
+            events.push(event);
+        }
+    }
+
+    events
+}
+
+pub fn listen_and_create_devices() -> std::io::Result<()> {
     let mut socket = Socket::new(NETLINK_KOBJECT_UEVENT)?;
     let sa = SocketAddr::new(std::process::id(), 1);
     socket.bind(&sa)?;
@@ -41,8 +88,6 @@ pub fn listen_and_create_devices(tx: Sender<()>) -> std::io::Result<()> {
 
         debug!("{:?}->{} {},{}", uevent.event, devname, major, minor);
 
-        _ = tx.send(());
-
         let path = PathBuf::from("/dev").join(devname);
         let parent = path.parent().expect("/dev should always exist");
         std::fs::create_dir_all(&parent).unwrap();
@@ -66,9 +111,13 @@ pub fn listen_and_create_devices(tx: Sender<()>) -> std::io::Result<()> {
                 } else {
                     match uevent.devtype {
                         DevType::Character => {}
-                        DevType::Disk(diskseq) => add_disk_symlink(devname, diskseq),
+                        DevType::Disk(diskseq) => {
+                            add_disk_symlink(devname, diskseq);
+                            publish_disk_event(DiskEvent::Added(diskseq.into()));
+                        }
                         DevType::Partition(diskseq, partnum) => {
-                            add_partition_symlink(devname, diskseq, partnum)
+                            add_partition_symlink(devname, diskseq, partnum);
+                            publish_disk_event(DiskEvent::Added(diskseq.into()));
                         }
                     }
                 }
@@ -80,9 +129,14 @@ pub fn listen_and_create_devices(tx: Sender<()>) -> std::io::Result<()> {
 
                 match uevent.devtype {
                     DevType::Character => {}
-                    DevType::Disk(diskseq) => remove_disk_symlink(diskseq),
+                    DevType::Disk(diskseq) => {
+                        remove_disk_symlink(diskseq);
+                        publish_disk_event(DiskEvent::Removed(diskseq.into()));
+                    }
                     DevType::Partition(diskseq, partnum) => {
-                        remove_partition_symlink(diskseq, partnum)
+                        remove_partition_symlink(diskseq, partnum);
+                        publish_disk_event(DiskEvent::Removed(diskseq.into()));
+                        publish_disk_event(DiskEvent::Added(diskseq.into()));
                     }
                 }
             }
@@ -328,6 +382,29 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn settle_disk_events() {
+        use super::DiskEvent;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        for event in [
+            DiskEvent::Added(3),
+            DiskEvent::Added(3),
+            DiskEvent::Removed(1),
+        ] {
+            tx.send(event).unwrap();
+        }
+
+        assert_eq!(
+            super::settle_disk_events(
+                DiskEvent::Added(3),
+                &rx,
+                std::time::Duration::from_millis(10)
+            ),
+            vec![DiskEvent::Added(3), DiskEvent::Removed(1)]
+        );
+    }
 }
 
 /// This function relies on the linux kernel option CONFIG_DEVTMPFS being enabled, since this means
@@ -423,13 +500,3 @@ pub fn parse_uevent(contents: String) -> HashMap<String, String> {
         map
     })
 }
-
-/// Blocks until no new messages have been received in some maximum wait time
-pub fn wait_for_settle(new_dev_rx: Receiver<()>, max_wait: Duration) {
-    loop {
-        match new_dev_rx.recv_timeout(max_wait) {
-            Err(RecvTimeoutError::Timeout) => break,
-            _ => {}
-        }
-    }
-}